@@ -1,23 +1,31 @@
 use rservice::server::{srv_fn, ServiceServer};
-use std::sync::{Arc, Mutex};
+use tracing_subscriber::EnvFilter;
 
-fn append_hello(_: Arc<Mutex<Box<()>>>, mut args: Vec<String>) -> String {
-    if let Some(first) = args.get_mut(0) {
+fn append_hello(_: &(), args: Vec<String>) -> String {
+    if let Some(first) = args.first() {
         format!("hello {}", first)
     } else {
         format!("hello world")
     }
 }
 
-fn ping(_: Arc<Mutex<Box<()>>>, _: Vec<String>) -> String {
+fn ping(_: &(), _: Vec<String>) -> String {
     "pong".to_string()
 }
 
 fn main() {
-    let srv = ServiceServer::new("example",
-        [
-            srv_fn!(append_hello),
-            srv_fn!(ping),
-        ], ()).expect("Failed to register service");
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug")))
+        .init();
+
+    let srv = ServiceServer::builder("example", ())
+        .methods([
+            srv_fn!(shared append_hello),
+            srv_fn!(shared ping),
+        ])
+        .method_args("append_hello", ["name?"])
+        .method_args("ping", [""; 0])
+        .build()
+        .expect("Failed to register service");
     srv.run();
 }
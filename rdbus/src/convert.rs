@@ -0,0 +1,188 @@
+//! Between rservice's string arguments and D-Bus values.
+
+use zbus::{
+    message::Body,
+    zvariant::{Array, ObjectPath, Signature, Structure, StructureBuilder, Value},
+};
+
+/// How a value reads as an rservice argument or reply. Strings and numbers
+/// come out as they are, containers in GVariant text form.
+pub fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::Str(s) => s.to_string(),
+        // the text form would type them, as in `byte 0x03` or `uint32 7`
+        Value::U8(n) => n.to_string(),
+        Value::I16(n) => n.to_string(),
+        Value::U16(n) => n.to_string(),
+        Value::U32(n) => n.to_string(),
+        Value::I64(n) => n.to_string(),
+        Value::U64(n) => n.to_string(),
+        Value::ObjectPath(p) => p.to_string(),
+        Value::Signature(s) => s.to_string(),
+        Value::Value(v) => value_to_string(v),
+        v => v.to_string(),
+    }
+}
+
+/// Types an rservice argument can be turned into.
+pub fn is_basic(sig: &Signature) -> bool {
+    matches!(sig,
+        Signature::U8 | Signature::Bool | Signature::I16 | Signature::U16 | Signature::I32 | Signature::U32
+        | Signature::I64 | Signature::U64 | Signature::F64 | Signature::Str | Signature::ObjectPath
+        | Signature::Signature | Signature::Variant
+    )
+}
+
+/// Whether a D-Bus method taking `args` can be called with rservice arguments:
+/// one basic value each, the last one may be an array of them taking whatever is left.
+pub fn callable(args: &[Signature]) -> bool {
+    let Some((last, rest)) = args.split_last() else {
+        return true;
+    };
+    rest.iter().all(is_basic) && match last {
+        Signature::Array(child) => is_basic(child),
+        sig => is_basic(sig),
+    }
+}
+
+fn parse_basic(sig: &Signature, arg: String) -> Result<Value<'static>, String> {
+    fn num<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+        arg.parse().map_err(|_| format!("not a number: {}", arg))
+    }
+    Ok(match sig {
+        Signature::U8 => num::<u8>(&arg)?.into(),
+        Signature::Bool => arg.parse::<bool>().map_err(|_| format!("not true or false: {}", arg))?.into(),
+        Signature::I16 => num::<i16>(&arg)?.into(),
+        Signature::U16 => num::<u16>(&arg)?.into(),
+        Signature::I32 => num::<i32>(&arg)?.into(),
+        Signature::U32 => num::<u32>(&arg)?.into(),
+        Signature::I64 => num::<i64>(&arg)?.into(),
+        Signature::U64 => num::<u64>(&arg)?.into(),
+        Signature::F64 => num::<f64>(&arg)?.into(),
+        Signature::Str => arg.into(),
+        Signature::ObjectPath => ObjectPath::try_from(arg).map_err(|e| e.to_string())?.into(),
+        Signature::Signature => Signature::try_from(arg.as_str()).map_err(|e| e.to_string())?.into(),
+        // all we have is a string, so that's what goes in it
+        Signature::Variant => Value::Value(Box::new(arg.into())),
+        sig => return Err(format!("can't pass {} from rservice", sig)),
+    })
+}
+
+/// Body of a call to a method taking `sigs`, `None` if it takes nothing.
+pub fn args_to_body(sigs: &[Signature], args: Vec<String>) -> Result<Option<Structure<'static>>, String> {
+    let mut args = args.into_iter();
+    let mut body = StructureBuilder::new();
+    for (i, sig) in sigs.iter().enumerate() {
+        let value = match sig {
+            Signature::Array(child) if i == sigs.len() - 1 => {
+                let mut array = Array::new(child);
+                for arg in args.by_ref() {
+                    array.append(parse_basic(child, arg)?).map_err(|e| e.to_string())?;
+                }
+                Value::Array(array)
+            },
+            sig => parse_basic(sig, args.next().ok_or_else(|| format!("takes {} arguments", sigs.len()))?)?,
+        };
+        body = body.append_field(value);
+    }
+    if args.next().is_some() {
+        return Err(format!("takes {} arguments", sigs.len()));
+    }
+    if sigs.is_empty() {
+        return Ok(None);
+    }
+    body.build().map(Some).map_err(|e| e.to_string())
+}
+
+/// Values of a message body, arrays flattened into their elements.
+pub fn body_to_strings(body: &Body) -> zbus::Result<Vec<String>> {
+    if body.signature() == &Signature::Unit {
+        return Ok(Vec::new());
+    }
+    let fields: Structure = body.deserialize()?;
+    let mut ret = Vec::new();
+    for field in fields.fields() {
+        match field {
+            Value::Array(array) => ret.extend(array.inner().iter().map(value_to_string)),
+            v => ret.push(value_to_string(v)),
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zbus::Message;
+
+    fn sigs(values: &[&str]) -> Vec<Signature> {
+        values.iter().map(|v| Signature::try_from(*v).unwrap()).collect()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn call() -> zbus::message::Builder<'static> {
+        Message::method_call("/", "Test").unwrap()
+    }
+
+    fn body_of(msg: zbus::Result<Message>) -> Vec<String> {
+        body_to_strings(&msg.unwrap().body()).unwrap()
+    }
+
+    #[test]
+    fn callable_takes_basic_values_and_a_trailing_array() {
+        assert!(callable(&[]));
+        assert!(callable(&sigs(&["s", "i", "b", "o", "g", "v", "d"])));
+        assert!(callable(&sigs(&["s", "as"])));
+        assert!(!callable(&sigs(&["as", "s"])));
+        assert!(!callable(&sigs(&["a{sv}"])));
+        assert!(!callable(&sigs(&["(ii)"])));
+        assert!(!callable(&sigs(&["aas"])));
+        assert!(!callable(&sigs(&["h"])));
+    }
+
+    #[test]
+    fn arguments_are_parsed_by_signature() {
+        let body = args_to_body(&sigs(&["s", "i", "b", "t", "d", "o"]), strings(&["pad0", "-3", "true", "7", "0.5", "/org/pad"])).unwrap().unwrap();
+        assert_eq!(body.signature().to_string(), "(sibtdo)");
+        assert_eq!(body.fields()[..4], [Value::from("pad0"), Value::from(-3i32), Value::from(true), Value::from(7u64)]);
+
+        let body = args_to_body(&sigs(&["v"]), strings(&["anything"])).unwrap().unwrap();
+        assert_eq!(body.fields(), [Value::Value(Box::new(Value::from("anything")))]);
+    }
+
+    #[test]
+    fn trailing_arrays_take_the_rest() {
+        let body = args_to_body(&sigs(&["s", "ai"]), strings(&["sum", "1", "2", "3"])).unwrap().unwrap();
+        assert_eq!(body.signature().to_string(), "(sai)");
+        let Value::Array(array) = &body.fields()[1] else {
+            panic!("not an array: {:?}", body.fields()[1]);
+        };
+        assert_eq!(array.inner(), &[Value::from(1i32), Value::from(2i32), Value::from(3i32)]);
+
+        let body = args_to_body(&sigs(&["s", "ai"]), strings(&["sum"])).unwrap().unwrap();
+        assert!(matches!(&body.fields()[1], Value::Array(array) if array.is_empty()));
+    }
+
+    #[test]
+    fn bad_arguments_are_refused() {
+        assert_eq!(args_to_body(&[], Vec::new()), Ok(None));
+        assert!(args_to_body(&[], strings(&["extra"])).is_err());
+        assert!(args_to_body(&sigs(&["s", "s"]), strings(&["one"])).is_err());
+        assert!(args_to_body(&sigs(&["y"]), strings(&["256"])).is_err());
+        assert!(args_to_body(&sigs(&["b"]), strings(&["yes"])).is_err());
+        assert!(args_to_body(&sigs(&["o"]), strings(&["not a path"])).is_err());
+        assert!(args_to_body(&sigs(&["ai"]), strings(&["1", "two"])).is_err());
+    }
+
+    #[test]
+    fn bodies_come_out_flattened() {
+        assert!(body_of(call().build(&())).is_empty());
+        assert_eq!(body_of(call().build(&("pad0",))), strings(&["pad0"]));
+        assert_eq!(body_of(call().build(&("pad0", vec!["a", "b"], 3u8, false))), strings(&["pad0", "a", "b", "3", "false"]));
+        assert_eq!(body_of(call().build(&(Value::from("inside"),))), strings(&["inside"]));
+        assert_eq!(body_of(call().build(&(1u8, -2i16, 3u16, -4i32, 5u32, -6i64, 7u64, 0.5f64))), strings(&["1", "-2", "3", "-4", "5", "-6", "7", "0.5"]));
+    }
+}
@@ -0,0 +1,412 @@
+//! rservice services as D-Bus objects.
+//!
+//! Service `foo` becomes the object `/org/rsystem/foo` implementing
+//! `org.rsystem.foo`, owned by the bus name of the same name. Methods take
+//! strings and answer with the reply as a string, properties are strings too.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use rservice::{
+    async_client::{self, ServiceClient},
+    introspect::{Introspection, INTROSPECT},
+    properties,
+    Bus, Error,
+};
+use zbus::{
+    message::{Header, Type},
+    names::BusName,
+    zvariant::Value,
+    Connection, Message, MessageStream,
+};
+
+use crate::convert;
+
+const PATH_PREFIX: &str = "/org/rsystem";
+const NAME_PREFIX: &str = "org.rsystem";
+
+/// What D-Bus clients wait for by default, no point in waiting longer.
+const CALL_TIMEOUT: Duration = Duration::from_secs(25);
+
+const STANDARD_INTERFACES: &str = r#" <interface name="org.freedesktop.DBus.Introspectable">
+  <method name="Introspect"><arg name="xml_data" type="s" direction="out"/></method>
+ </interface>
+ <interface name="org.freedesktop.DBus.Peer">
+  <method name="Ping"/>
+ </interface>
+ <interface name="org.freedesktop.DBus.Properties">
+  <method name="Get"><arg name="interface_name" type="s" direction="in"/><arg name="property_name" type="s" direction="in"/><arg name="value" type="v" direction="out"/></method>
+  <method name="GetAll"><arg name="interface_name" type="s" direction="in"/><arg name="props" type="a{sv}" direction="out"/></method>
+  <method name="Set"><arg name="interface_name" type="s" direction="in"/><arg name="property_name" type="s" direction="in"/><arg name="value" type="v" direction="in"/></method>
+  <signal name="PropertiesChanged"><arg name="interface_name" type="s"/><arg name="changed_properties" type="a{sv}"/><arg name="invalidated_properties" type="as"/></signal>
+ </interface>
+"#;
+
+/// D-Bus names are made of `[A-Za-z0-9_]` and can't start with a digit.
+fn sanitize(name: &str) -> String {
+    let mut ret: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if !ret.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        ret.insert(0, '_');
+    }
+    ret
+}
+
+fn is_member_name(name: &str) -> bool {
+    !name.is_empty() && sanitize(name) == name
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// D-Bus error name and message of a failed call.
+fn dbus_error(e: Error) -> (&'static str, String) {
+    match e {
+        Error::Remote(msg) => ("org.rsystem.Error.Remote", msg),
+        Error::Busy(msg) => ("org.rsystem.Error.Busy", msg),
+        Error::Timeout => ("org.freedesktop.DBus.Error.Timeout", e.to_string()),
+        Error::Io(_) | Error::Cancelled => ("org.rsystem.Error.Unavailable", e.to_string()),
+    }
+}
+
+struct Service {
+    name: String,
+    interface: String,
+    path: String,
+    methods: BTreeSet<String>,
+    /// Exported properties, whether they're writable.
+    properties: HashMap<String, bool>,
+    xml: String,
+    // one connection can only carry one call at a time
+    idle: Mutex<Vec<ServiceClient>>,
+}
+
+impl Service {
+    fn new(name: &str, info: &Introspection) -> Self {
+        let interface = format!("{}.{}", NAME_PREFIX, sanitize(name));
+        let path = format!("{}/{}", PATH_PREFIX, sanitize(name));
+
+        let hidden: BTreeSet<&str> = [INTROSPECT, properties::GET, properties::SET, properties::GET_ALL, properties::WATCH].into_iter()
+            .chain(info.streams.iter().map(String::as_str))
+            .collect();
+        let mut methods = BTreeSet::new();
+        let mut xml = format!("<node>\n <interface name=\"{}\">\n", interface);
+        for method in &info.methods {
+            if hidden.contains(method.as_str()) {
+                continue;
+            }
+            if !is_member_name(method) {
+                eprintln!("{}: can't export {}, it isn't a valid D-Bus member name", name, method);
+                continue;
+            }
+            methods.insert(method.clone());
+
+            let _ = write!(xml, "  <method name=\"{}\">", method);
+            match info.args.get(method) {
+                // strings in, in order, unless some are optional
+                Some(args) if !args.iter().any(|a| a.ends_with('?') || a.ends_with("...")) => {
+                    for arg in args {
+                        let _ = write!(xml, "<arg name=\"{}\" type=\"s\" direction=\"in\"/>", escape_xml(arg));
+                    }
+                },
+                _ => xml.push_str("<arg name=\"args\" type=\"as\" direction=\"in\"/>"),
+            }
+            xml.push_str("<arg name=\"reply\" type=\"s\" direction=\"out\"/></method>\n");
+        }
+
+        let mut props = HashMap::new();
+        for prop in &info.properties {
+            if !is_member_name(&prop.name) {
+                eprintln!("{}: can't export property {}, it isn't a valid D-Bus member name", name, prop.name);
+                continue;
+            }
+            props.insert(prop.name.clone(), prop.writable);
+            let access = if prop.writable { "readwrite" } else { "read" };
+            let _ = writeln!(xml, "  <property name=\"{}\" type=\"s\" access=\"{}\"/>", prop.name, access);
+        }
+        xml.push_str(" </interface>\n");
+        xml.push_str(STANDARD_INTERFACES);
+        xml.push_str("</node>\n");
+
+        Self {
+            name: name.to_string(),
+            interface,
+            path,
+            methods,
+            properties: props,
+            xml,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    async fn with_client<R>(&self, bus: &Bus, f: impl AsyncFnOnce(&mut ServiceClient) -> rservice::Result<R>) -> rservice::Result<R> {
+        let client = self.idle.lock().unwrap().pop();
+        let mut client = match client {
+            Some(client) => client,
+            None => async_client::get_service_on(bus, &self.name).await
+                .ok_or_else(|| Error::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} isn't running", self.name))))?,
+        };
+        client.set_timeout(Some(CALL_TIMEOUT));
+        let ret = f(&mut client).await;
+        // a connection that failed is of no further use
+        if matches!(ret, Ok(_) | Err(Error::Remote(_) | Error::Busy(_))) {
+            self.idle.lock().unwrap().push(client);
+        }
+        ret
+    }
+}
+
+/// Answer to a D-Bus call, each kind goes out with its own signature.
+enum Answer {
+    Nothing,
+    Str(String),
+    Variant(Value<'static>),
+    Dict(HashMap<String, Value<'static>>),
+}
+
+type CallResult = Result<Answer, (&'static str, String)>;
+
+/// Services put on a D-Bus connection.
+pub struct Exporter {
+    conn: Connection,
+    bus: Bus,
+    services: HashMap<String, Arc<Service>>,
+}
+
+impl Exporter {
+    /// Looks at what the services offer, starting them if needed, and claims their bus names.
+    pub async fn new(conn: Connection, bus: Bus, names: &[String]) -> Result<Self> {
+        let mut services = HashMap::new();
+        for name in names {
+            let mut client = async_client::get_service_on(&bus, name).await
+                .with_context(|| format!("Failed to connect to {}", name))?;
+            let info = client.introspect().await
+                .with_context(|| format!("Failed to introspect {}", name))?;
+            let service = Service::new(name, &info);
+            conn.request_name(service.interface.as_str()).await
+                .with_context(|| format!("Failed to own {} on D-Bus", service.interface))?;
+            services.insert(service.path.clone(), Arc::new(service));
+        }
+        Ok(Self { conn, bus, services })
+    }
+
+    /// Answers calls until the connection goes away.
+    pub async fn run(self) {
+        let this = Arc::new(self);
+        for service in this.services.values() {
+            if !service.properties.is_empty() {
+                tokio::spawn(Arc::clone(&this).forward_changes(Arc::clone(service)));
+            }
+        }
+
+        let mut stream = MessageStream::from(&this.conn);
+        while let Some(msg) = stream.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    eprintln!("D-Bus error: {}", e);
+                    continue;
+                },
+            };
+            if msg.message_type() != Type::MethodCall {
+                continue;
+            }
+            let this = Arc::clone(&this);
+            tokio::spawn(async move {
+                let header = msg.header();
+                let result = match this.dispatch(&header, &msg).await {
+                    Ok(Answer::Nothing) => this.conn.reply(&header, &()).await,
+                    Ok(Answer::Str(s)) => this.conn.reply(&header, &s).await,
+                    Ok(Answer::Variant(v)) => this.conn.reply(&header, &v).await,
+                    Ok(Answer::Dict(d)) => this.conn.reply(&header, &d).await,
+                    Err((name, e)) => this.conn.reply_error(&header, name, &e).await,
+                };
+                if let Err(e) = result {
+                    eprintln!("Failed to answer a D-Bus call: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn dispatch(&self, header: &Header<'_>, msg: &Message) -> CallResult {
+        let path = header.path().map(|p| p.as_str()).unwrap_or_default();
+        let interface = header.interface().map(|i| i.as_str());
+        let member = header.member().map(|m| m.as_str()).unwrap_or_default();
+        let args = convert::body_to_strings(&msg.body())
+            .map_err(|e| ("org.freedesktop.DBus.Error.InvalidArgs", e.to_string()))?;
+
+        match (interface, member) {
+            (Some("org.freedesktop.DBus.Introspectable"), "Introspect") => return Ok(Answer::Str(self.introspect(path))),
+            (Some("org.freedesktop.DBus.Peer"), "Ping") => return Ok(Answer::Nothing),
+            _ => (),
+        }
+
+        let Some(service) = self.services.get(path) else {
+            return Err(("org.freedesktop.DBus.Error.UnknownObject", format!("no object at {}", path)));
+        };
+        let unknown = || ("org.freedesktop.DBus.Error.UnknownMethod", format!("no method {} at {}", member, path));
+
+        if interface == Some("org.freedesktop.DBus.Properties") {
+            return self.properties(service, member, args).await;
+        }
+        if interface.is_some_and(|i| i != service.interface) {
+            return Err(unknown());
+        }
+        if !service.methods.contains(member) {
+            return Err(unknown());
+        }
+        let reply = service.with_client(&self.bus, async |c| c.call(member, args).await).await
+            .map_err(dbus_error)?;
+        Ok(Answer::Str(reply))
+    }
+
+    async fn properties(&self, service: &Service, member: &str, args: Vec<String>) -> CallResult {
+        let invalid = || ("org.freedesktop.DBus.Error.InvalidArgs", format!("wrong arguments for {}", member));
+        let check = |name: &str| match service.properties.get(name) {
+            Some(writable) => Ok(*writable),
+            None => Err(("org.freedesktop.DBus.Error.UnknownProperty", format!("no property {}", name))),
+        };
+
+        match (member, args.as_slice()) {
+            ("Get", [iface, name]) if *iface == service.interface => {
+                check(name)?;
+                let value: String = service.with_client(&self.bus, async |c| c.get_property(name).await).await
+                    .map_err(dbus_error)?;
+                Ok(Answer::Variant(Value::new(value)))
+            },
+            ("GetAll", [iface]) if *iface == service.interface => {
+                let all = service.with_client(&self.bus, async |c| c.get_all_properties().await).await
+                    .map_err(dbus_error)?;
+                let all: HashMap<String, Value> = all.into_iter()
+                    .filter(|(name, _)| service.properties.contains_key(name))
+                    .map(|(name, value)| (name, Value::new(value)))
+                    .collect();
+                Ok(Answer::Dict(all))
+            },
+            ("Set", [iface, name, value]) if *iface == service.interface => {
+                if !check(name)? {
+                    return Err(("org.freedesktop.DBus.Error.PropertyReadOnly", format!("{} is read-only", name)));
+                }
+                service.with_client(&self.bus, async |c| c.set_property(name, value).await).await
+                    .map_err(dbus_error)?;
+                Ok(Answer::Nothing)
+            },
+            ("Get" | "GetAll" | "Set", [iface, ..]) if *iface != service.interface => {
+                Err(("org.freedesktop.DBus.Error.UnknownInterface", format!("no interface {}", iface)))
+            },
+            ("Get" | "GetAll" | "Set", _) => Err(invalid()),
+            _ => Err(("org.freedesktop.DBus.Error.UnknownMethod", format!("no method {}", member))),
+        }
+    }
+
+    /// The object at `path`, or the nodes leading to one.
+    fn introspect(&self, path: &str) -> String {
+        if let Some(service) = self.services.get(path) {
+            return service.xml.clone();
+        }
+        let prefix = if path == "/" { "/".to_string() } else { format!("{}/", path) };
+        let children: BTreeSet<&str> = self.services.keys()
+            .filter_map(|p| p.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .collect();
+        let mut xml = String::from("<node>\n");
+        xml.push_str(STANDARD_INTERFACES.split(" <interface name=\"org.freedesktop.DBus.Properties\">").next().unwrap_or_default());
+        for child in children {
+            let _ = writeln!(xml, " <node name=\"{}\"/>", child);
+        }
+        xml.push_str("</node>\n");
+        xml
+    }
+
+    /// Turns property changes of `service` into `PropertiesChanged` signals.
+    async fn forward_changes(self: Arc<Self>, service: Arc<Service>) {
+        loop {
+            if let Err(e) = self.watch(&service).await {
+                eprintln!("Lost the properties of {}: {}", service.name, e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn watch(&self, service: &Service) -> Result<()> {
+        let client = async_client::get_service_on(&self.bus, &service.name).await
+            .with_context(|| format!("{} isn't running", service.name))?;
+        let names: Vec<&String> = service.properties.keys().collect();
+        let mut watcher = client.watch(names).await?;
+        loop {
+            let change = watcher.next_change().await?;
+            let changed = HashMap::from([(change.name, Value::new(change.value))]);
+            let invalidated: Vec<String> = Vec::new();
+            self.conn.emit_signal(
+                None::<BusName>,
+                service.path.as_str(),
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+                &(service.interface.as_str(), changed, invalidated),
+            ).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rservice::properties::PropertyInfo;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn names_are_sanitized() {
+        assert_eq!(sanitize("input"), "input");
+        assert_eq!(sanitize("audio-mixer.2"), "audio_mixer_2");
+        assert_eq!(sanitize("2d"), "_2d");
+        assert_eq!(sanitize("_private"), "_private");
+        assert_eq!(sanitize("zażółć"), "za____");
+        assert_eq!(sanitize(""), "_");
+    }
+
+    #[test]
+    fn member_names_need_no_sanitizing() {
+        assert!(is_member_name("set_led"));
+        assert!(!is_member_name("set-led"));
+        assert!(!is_member_name("1up"));
+        assert!(!is_member_name(""));
+    }
+
+    #[test]
+    fn services_describe_what_can_be_exported() {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<String>>();
+        let info = Introspection {
+            service: "input-mixer".to_string(),
+            methods: strings(&["introspect", "get_property", "list", "set_led", "log", "bad-name", "events"]),
+            args: BTreeMap::from([
+                ("set_led".to_string(), strings(&["pad", "state"])),
+                ("log".to_string(), strings(&["level", "message..."])),
+            ]),
+            properties: vec![
+                PropertyInfo { name: "volume".to_string(), type_name: "u8".to_string(), writable: true },
+                PropertyInfo { name: "in-use".to_string(), type_name: "bool".to_string(), writable: false },
+            ],
+            streams: strings(&["events"]),
+            ..Default::default()
+        };
+        let service = Service::new("input-mixer", &info);
+
+        assert_eq!(service.interface, "org.rsystem.input_mixer");
+        assert_eq!(service.path, "/org/rsystem/input_mixer");
+        assert_eq!(service.methods, BTreeSet::from(["list".to_string(), "log".to_string(), "set_led".to_string()]));
+        assert_eq!(service.properties, HashMap::from([("volume".to_string(), true)]));
+
+        // named strings where the arguments are fixed, a string array otherwise
+        assert!(service.xml.contains(r#"<method name="set_led"><arg name="pad" type="s" direction="in"/><arg name="state" type="s" direction="in"/>"#));
+        assert!(service.xml.contains(r#"<method name="log"><arg name="args" type="as" direction="in"/>"#));
+        assert!(service.xml.contains(r#"<property name="volume" type="s" access="readwrite"/>"#));
+        zbus_xml::Node::from_reader(service.xml.as_bytes()).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! D-Bus objects as rservice services.
+//!
+//! Every method of the object's interfaces becomes a method of the service,
+//! taking its arguments as strings and answering with the values it returns,
+//! one per line. Properties are mirrored as read-only string properties.
+
+use std::{collections::HashMap, str::FromStr};
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use rservice::{
+    async_server::{Method, ServiceServer, Shared},
+    Bus, Properties, Property, Reply,
+};
+use zbus::{
+    match_rule::MatchRule,
+    message::Type,
+    zvariant::{OwnedValue, Signature},
+    Connection, MessageStream,
+};
+use zbus_xml::{ArgDirection, Node};
+
+use crate::convert;
+
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+/// `<service>=<bus name>:<object path>` from the command line.
+#[derive(Clone, Debug)]
+pub struct Import {
+    pub service: String,
+    pub destination: String,
+    pub path: String,
+}
+
+impl FromStr for Import {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((service, rest)) = s.split_once('=') else {
+            bail!("Imports look like <service>=<bus name>:<object path>, not {}", s);
+        };
+        let Some((destination, path)) = rest.split_once(':') else {
+            bail!("Imports look like <service>=<bus name>:<object path>, not {}", s);
+        };
+        Ok(Self { service: service.to_string(), destination: destination.to_string(), path: path.to_string() })
+    }
+}
+
+/// The object calls go to, state of the imported service.
+pub struct Target {
+    conn: Connection,
+    destination: String,
+    path: String,
+}
+
+impl Target {
+    async fn call(&self, interface: &str, member: &str, sigs: &[Signature], args: Vec<String>) -> Reply {
+        let body = match convert::args_to_body(sigs, args) {
+            Ok(body) => body,
+            Err(e) => return Reply::error(e),
+        };
+        let dest = self.destination.as_str();
+        let path = self.path.as_str();
+        let reply = match body {
+            Some(body) => self.conn.call_method(Some(dest), path, Some(interface), member, &body).await,
+            None => self.conn.call_method(Some(dest), path, Some(interface), member, &()).await,
+        };
+        let values = reply.and_then(|msg| convert::body_to_strings(&msg.body()));
+        match values {
+            Ok(values) if values.is_empty() => Reply::new("OK"),
+            Ok(values) => Reply::new(values.join("\n")),
+            Err(zbus::Error::MethodError(name, msg, _)) => Reply::error(match msg {
+                Some(msg) => format!("{}: {}", name, msg),
+                None => name.to_string(),
+            }),
+            Err(e) => Reply::error(e.to_string()),
+        }
+    }
+    async fn get_all(&self, interface: &str) -> zbus::Result<HashMap<String, OwnedValue>> {
+        let msg = self.conn.call_method(Some(self.destination.as_str()), self.path.as_str(), Some(PROPERTIES), "GetAll", &interface).await?;
+        msg.body().deserialize()
+    }
+    async fn get(&self, interface: &str, name: &str) -> zbus::Result<OwnedValue> {
+        let msg = self.conn.call_method(Some(self.destination.as_str()), self.path.as_str(), Some(PROPERTIES), "Get", &(interface, name)).await?;
+        msg.body().deserialize()
+    }
+}
+
+/// A D-Bus object wrapped up as a service, ready to run.
+pub struct Imported {
+    pub server: ServiceServer<Target>,
+    /// `None` if the object has no properties.
+    pub mirror: Option<Mirror>,
+}
+
+/// Keeps the properties of an imported service in sync with the D-Bus object.
+pub struct Mirror {
+    target: Target,
+    service: String,
+    // property name to interface
+    names: HashMap<String, String>,
+    properties: Properties,
+}
+
+impl Mirror {
+    /// Runs until the connection goes away.
+    pub async fn run(self) {
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .path(self.target.path.as_str())
+            .and_then(|r| r.interface(PROPERTIES))
+            .and_then(|r| r.member("PropertiesChanged"))
+            .map(|r| r.build());
+        let stream = match rule {
+            Ok(rule) => MessageStream::for_match_rule(rule, &self.target.conn, None).await,
+            Err(e) => Err(e),
+        };
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{}: can't follow property changes: {}", self.service, e);
+                return;
+            },
+        };
+
+        while let Some(msg) = stream.next().await {
+            let Ok(msg) = msg else {
+                continue;
+            };
+            let body = msg.body();
+            let Ok((interface, changed, invalidated)) = body.deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>() else {
+                continue;
+            };
+            for (name, value) in changed {
+                if self.names.get(&name) == Some(&interface) {
+                    self.properties.set(&name, convert::value_to_string(&value));
+                }
+            }
+            for name in invalidated {
+                if self.names.get(&name) != Some(&interface) {
+                    continue;
+                }
+                match self.target.get(&interface, &name).await {
+                    Ok(value) => {
+                        self.properties.set(&name, convert::value_to_string(&value));
+                    },
+                    Err(e) => eprintln!("{}: failed to get {}: {}", self.service, name, e),
+                }
+            }
+        }
+    }
+}
+
+/// Introspects the D-Bus object and builds a service calling it.
+pub async fn import(conn: &Connection, bus: &Bus, import: &Import) -> Result<Imported> {
+    let target = Target {
+        conn: conn.clone(),
+        destination: import.destination.clone(),
+        path: import.path.clone(),
+    };
+    let xml: String = conn.call_method(Some(import.destination.as_str()), import.path.as_str(), Some("org.freedesktop.DBus.Introspectable"), "Introspect", &()).await
+        .and_then(|msg| msg.body().deserialize())
+        .with_context(|| format!("Failed to introspect {} on {}", import.path, import.destination))?;
+    let node = Node::from_reader(xml.as_bytes())
+        .with_context(|| format!("{} on {} sent invalid introspection data", import.path, import.destination))?;
+
+    let service = &import.service;
+    let mut methods = Vec::new();
+    let mut method_args = Vec::new();
+    let mut props = Vec::new();
+    let mut names = HashMap::new();
+    for interface in node.interfaces() {
+        let iface = interface.name().to_string();
+        if iface.starts_with("org.freedesktop.DBus.") {
+            continue;
+        }
+
+        for method in interface.methods() {
+            let member = method.name().to_string();
+            if methods.iter().any(|(m, _)| *m == member) {
+                eprintln!("{}: {}.{} is also in another interface, skipping it", service, iface, member);
+                continue;
+            }
+            let inputs: Vec<_> = method.args().iter()
+                .filter(|a| a.direction() != Some(ArgDirection::Out))
+                .collect();
+            let sigs: Vec<Signature> = inputs.iter().map(|a| a.ty().inner().clone()).collect();
+            if !convert::callable(&sigs) {
+                eprintln!("{}: can't call {}.{} with strings, skipping it", service, iface, member);
+                continue;
+            }
+
+            let args: Vec<String> = inputs.iter().enumerate().map(|(i, a)| {
+                let name = a.name().map_or_else(|| format!("arg{}", i), str::to_string);
+                match a.ty().inner() {
+                    Signature::Array(_) => format!("{}...", name),
+                    _ => name,
+                }
+            }).collect();
+            method_args.push((member.clone(), args));
+
+            let (iface, name) = (iface.clone(), member.clone());
+            methods.push((member, Method::shared(move |target: Shared<Target>, args| {
+                let (iface, name, sigs) = (iface.clone(), name.clone(), sigs.clone());
+                async move { target.call(&iface, &name, &sigs, args).await }
+            })));
+        }
+
+        let readable: Vec<_> = interface.properties().iter().filter(|p| p.access().read()).collect();
+        if readable.is_empty() {
+            continue;
+        }
+        let values = target.get_all(&iface).await
+            .with_context(|| format!("Failed to get the properties of {}", iface))?;
+        for prop in readable {
+            let name = prop.name().to_string();
+            if names.contains_key(&name) {
+                eprintln!("{}: property {} is also in another interface, skipping it", service, name);
+                continue;
+            }
+            let value = values.get(&name).map(|v| convert::value_to_string(v)).unwrap_or_default();
+            names.insert(name.clone(), iface.clone());
+            props.push((name, value));
+        }
+    }
+
+    let mut builder = ServiceServer::builder(service, target)
+        .bus(bus.clone())
+        .methods(methods);
+    for (method, args) in method_args {
+        builder = builder.method_args(method, args);
+    }
+    for (name, value) in props {
+        builder = builder.property(name, Property::read_only(value));
+    }
+    let server = builder.build()
+        .with_context(|| format!("Failed to start {}", service))?;
+
+    let mirror = (!names.is_empty()).then(|| Mirror {
+        target: Target { conn: conn.clone(), destination: import.destination.clone(), path: import.path.clone() },
+        service: service.clone(),
+        names,
+        properties: server.properties(),
+    });
+    Ok(Imported { server, mirror })
+}
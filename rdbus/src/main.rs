@@ -0,0 +1,196 @@
+mod convert;
+mod export;
+mod import;
+
+use std::env;
+use anyhow::{bail, Context, Result};
+use rservice::{Bus, Shutdown};
+use zbus::connection;
+
+use crate::{export::Exporter, import::Import};
+
+static HELP_TEXT: &str = "Usage: rdbus [--dbus session|system|<address>] [--export <service>]... [--import <service>=<bus name>:<object path>]...
+
+Connects the rservice bus picked by RSERVICE_BUS with D-Bus.
+
+--export  puts an rservice service on D-Bus as /org/rsystem/<service>,
+          implementing and owning the name org.rsystem.<service>. Methods
+          take and return strings, properties show up as D-Bus properties
+--import  serves a D-Bus object as an rservice service. Its methods take
+          their arguments as strings and answer with the returned values, one
+          per line, its properties are mirrored read-only
+--dbus    the D-Bus to connect to, the session bus by default, or an address
+          like unix:path=/tmp/test-bus for a private one
+";
+
+enum DBus {
+    Session,
+    System,
+    Address(String),
+}
+
+struct Args {
+    dbus: DBus,
+    exports: Vec<String>,
+    imports: Vec<Import>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut ret = Args { dbus: DBus::Session, exports: Vec::new(), imports: Vec::new() };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dbus" => {
+                ret.dbus = match args.next().context("--dbus needs a bus")?.as_str() {
+                    "session" => DBus::Session,
+                    "system" => DBus::System,
+                    address => DBus::Address(address.to_string()),
+                };
+            },
+            "--export" => ret.exports.push(args.next().context("--export needs a service")?),
+            "--import" => ret.imports.push(args.next().context("--import needs a service and an object")?.parse()?),
+            "--help" => {
+                print!("{}", HELP_TEXT);
+                std::process::exit(0);
+            },
+            _ => bail!("Unknown argument {}\n\n{}", arg, HELP_TEXT),
+        }
+    }
+    if ret.exports.is_empty() && ret.imports.is_empty() {
+        bail!("Nothing to do\n\n{}", HELP_TEXT);
+    }
+    Ok(ret)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+    let bus = Bus::from_env();
+    let shutdown = Shutdown::on_signals();
+
+    let builder = match &args.dbus {
+        DBus::Session => connection::Builder::session(),
+        DBus::System => connection::Builder::system(),
+        DBus::Address(address) => connection::Builder::address(address.as_str()),
+    };
+    let conn = builder.context("Failed to find D-Bus")?
+        .build().await
+        .context("Failed to connect to D-Bus")?;
+
+    let mut servers = Vec::new();
+    for import in &args.imports {
+        let imported = import::import(&conn, &bus, import).await?;
+        if let Some(mirror) = imported.mirror {
+            tokio::spawn(mirror.run());
+        }
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move { imported.server.run_until(&shutdown).await }));
+    }
+
+    let exporter = Exporter::new(conn, bus, &args.exports).await?;
+    tokio::select! {
+        _ = exporter.run(), if !args.exports.is_empty() => (),
+        _ = shutdown.triggered() => (),
+    }
+
+    // the exports just stop, imported services get to finish their calls
+    shutdown.trigger();
+    for server in servers {
+        let _ = server.await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+    use rservice::{async_client, testing::{MockService, TestBus}};
+    use zbus::Connection;
+
+    /// A private D-Bus, gone on drop.
+    struct Daemon {
+        child: Child,
+        address: String,
+    }
+
+    impl Daemon {
+        fn start() -> Option<Self> {
+            let mut child = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            let read = child.stdout.take().map(|out| BufReader::new(out).read_line(&mut address));
+            let mut ret = Self { child, address: address.trim().to_string() };
+            if !matches!(read, Some(Ok(n)) if n > 0) {
+                ret.stop();
+                return None;
+            }
+            Some(ret)
+        }
+        fn stop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        async fn connect(&self) -> Connection {
+            connection::Builder::address(self.address.as_str()).unwrap().build().await.unwrap()
+        }
+    }
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            self.stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_and_imports_round_trip() {
+        let Some(daemon) = Daemon::start() else {
+            eprintln!("Skipping, no dbus-daemon to run");
+            return;
+        };
+        let test_bus = TestBus::new();
+        let bus = test_bus.bus();
+        let mock = MockService::builder("input")
+            .bus(bus.clone())
+            .on("list", |args| format!("pads {}", args.join(" ")))
+            .start();
+
+        let exporter = Exporter::new(daemon.connect().await, bus.clone(), &["input".to_string()]).await.unwrap();
+        tokio::spawn(exporter.run());
+
+        // the service as D-Bus clients see it
+        let conn = daemon.connect().await;
+        let reply = conn.call_method(Some("org.rsystem.input"), "/org/rsystem/input", Some("org.rsystem.input"), "list", &(vec!["a", "b"],)).await.unwrap();
+        assert_eq!(reply.body().deserialize::<String>().unwrap(), "pads a b");
+        let unknown = conn.call_method(Some("org.rsystem.input"), "/org/rsystem/input", Some("org.rsystem.input"), "reboot", &()).await;
+        assert!(matches!(unknown, Err(zbus::Error::MethodError(name, _, _)) if name == "org.freedesktop.DBus.Error.UnknownMethod"));
+
+        // and imported back onto the rservice bus
+        let imported = import::import(&conn, &bus, &"back=org.rsystem.input:/org/rsystem/input".parse().unwrap()).await.unwrap();
+        assert!(imported.mirror.is_none());
+        let shutdown = Shutdown::new();
+        let server = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { imported.server.run_until(&shutdown).await })
+        };
+        let mut client = async_client::get_service_on(&bus, "back").await.unwrap();
+        let info = client.introspect().await.unwrap();
+        assert_eq!(info.args.get("list"), Some(&vec!["args...".to_string()]));
+        assert_eq!(client.call("list", ["c"]).await.unwrap(), "pads c");
+        assert_eq!(client.call("list", [""; 0]).await.unwrap(), "pads ");
+
+        shutdown.trigger();
+        server.await.unwrap();
+        let calls = mock.calls().into_iter().map(|c| c.args).collect::<Vec<Vec<String>>>();
+        assert_eq!(calls, vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()], vec![]]);
+        mock.verify();
+    }
+}
@@ -1,14 +1,17 @@
 use ozone::{init, Config};
-use rservice::server::{srv_fn, ServiceServer};
+use rservice::{
+    registry::Registry,
+    server::{srv_fn, ServiceServer, Shutdown},
+};
+use tracing_subscriber::EnvFilter;
 use std::{
     thread,
-    sync::{Arc, Mutex},
     path::PathBuf,
     process::Command,
     fs,
 };
 
-fn launch_service(_: Arc<Mutex<Box<()>>>, args: Vec<String>) -> String {
+fn launch_service(_: &Registry, args: Vec<String>) -> String {
     let Some(service) = args.get(0) else {
         return String::from("ERR");
     };
@@ -42,14 +45,27 @@ fn launch_service(_: Arc<Mutex<Box<()>>>, args: Vec<String>) -> String {
 }
 
 fn start_rservice() {
-    let srv = ServiceServer::new("init",
-        [
-            srv_fn!(launch_service),
-        ], ()).expect("Failed to register service");
-    srv.run();
+    let srv = ServiceServer::builder("init", Registry::new())
+        .methods([
+            srv_fn!(shared launch_service),
+        ])
+        .methods(Registry::methods())
+        .method_args("launch_service", ["service"])
+        .method_args("register", ["service", "entries..."])
+        .method_args("resolve", ["name"])
+        .method_args("find", ["interface", "min_version"])
+        .build()
+        .expect("Failed to register service");
+    // init going away on SIGTERM would take the whole system down, so never shut down
+    srv.run_until(&Shutdown::new());
 }
 
 fn main() {
+    // RUST_LOG=debug shows every call made to init
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
     let conf = Config::new()
         .mount_sys(true);
     init(&conf).expect("Basic init failed!");
@@ -0,0 +1,371 @@
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use rservice::server::caller_gone;
+
+use crate::{
+    profile,
+    sink::{self, calibration::Calibration, desktop, gyro::GyroAim, response::Response, Sink},
+    source::{self, hotplug, OpenedEventSource, SourceId},
+};
+
+/// Name rinputer4 registers under, `/bin/srv/input` is what init launches.
+pub static SERVICE_NAME: &str = "input";
+pub static INTERFACE: &str = "org.rsystem.Input";
+
+/// Threads handling calls to the service.
+pub const WORKERS: usize = 8;
+/// `add_sink` and `calibrate` keep a worker until the user is done, at most
+/// this many at once so the other calls always have workers left.
+const MAX_WAITING: usize = WORKERS / 2;
+
+#[derive(Default)]
+struct SinkList {
+    // ids stay the same when other sinks go away
+    next_id: usize,
+    sinks: BTreeMap<usize, (Box<dyn Sink>, Vec<SourceId>)>,
+}
+
+/// State of the input service, every sink currently in use.
+#[derive(Default)]
+pub struct Sinks {
+    list: Mutex<SinkList>,
+    // calls waiting on the user, see MAX_WAITING
+    waiting: AtomicUsize,
+}
+
+// one of the MAX_WAITING slots, given back on drop
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn take(waiting: &'a AtomicUsize) -> Result<Self, String> {
+        waiting.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < MAX_WAITING).then_some(n + 1))
+            .map(|_| Self(waiting))
+            .map_err(|_| format!("already waiting on {} calls, try again later", MAX_WAITING))
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// replies are one record per line, fields separated by tabs
+fn field(s: &str) -> String {
+    s.replace(['\t', '\n'], " ")
+}
+
+/// `<index>\t<name>\t<nodes>` per sink type, the index is what `add_sink` takes
+/// and nodes are the devices it creates, separated by commas.
+pub fn list_sink_types(_: &Sinks, _: Vec<String>) -> String {
+    sink::list_names().iter().enumerate()
+        .map(|(i, t)| {
+            let nodes = t.nodes.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(",");
+            format!("{}\t{}\t{}", i, field(&t.name), nodes)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// `<id>\t<sink type>\t<source name>\t<active profile>\t<nodes>` per sink,
+/// nodes being `<node>:<path>` of every device it created, separated by commas.
+pub fn list_sinks(sinks: &Sinks, _: Vec<String>) -> String {
+    let list = sinks.list.lock().unwrap();
+    list.sinks.iter()
+        .map(|(id, (sink, _))| {
+            let profile = sink.profile().map(|p| p.lock().unwrap().active_name().to_string()).unwrap_or_default();
+            let nodes = sink.nodes().iter()
+                .map(|(node, path)| format!("{}:{}", node, path.display()))
+                .collect::<Vec<String>>()
+                .join(",");
+            format!("{}\t{}\t{}\t{}\t{}", id, field(sink.name()), field(&sink.source_name()), profile, field(&nodes))
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// How long `add_sink` waits for L+R before giving up.
+const ADD_SINK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Waits for L+R to be pressed on a controller and binds it to a new sink,
+/// answers with the id of the sink. Controllers plugged in meanwhile count too.
+pub fn add_sink(sinks: &Sinks, args: Vec<String>) -> Result<String, String> {
+    let Some(arg) = args.first() else {
+        return Err("add_sink takes a sink type, see list_sink_types".to_string());
+    };
+    let sink_types = sink::list_names();
+    let Some(new_fn) = arg.parse::<usize>().ok().and_then(|i| sink_types.get(i)).map(|t| t.new) else {
+        return Err(format!("no such sink type: {}", arg));
+    };
+    let _waiting = Waiting::take(&sinks.waiting)?;
+
+    // subscribed first, so nothing plugged in during enumeration is missed
+    let hotplugged = hotplug::subscribe();
+    let cur_sources = source::enumerate().into_iter()
+        .map(source::into_opened)
+        .collect::<Vec<OpenedEventSource>>();
+    // the list isn't locked while waiting, this can take a while
+    let deadline = Instant::now() + ADD_SINK_TIMEOUT;
+    let new_source = source::wait_for_lr(cur_sources, &hotplugged, || caller_gone() || Instant::now() >= deadline)
+        .ok_or_else(|| format!("nothing had L+R pressed within {} seconds", ADD_SINK_TIMEOUT.as_secs()))?;
+    let ids = new_source.ids.clone();
+    let sink = new_fn(new_source).map_err(|e| format!("failed to make a new sink: {}", e))?;
+    hotplug::bind(&ids);
+
+    let mut list = sinks.list.lock().unwrap();
+    let id = list.next_id;
+    list.next_id += 1;
+    list.sinks.insert(id, (sink, ids));
+    Ok(id.to_string())
+}
+
+pub fn del_sink(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let Some(arg) = args.first() else {
+        return Err("del_sink takes a sink id, see list_sinks".to_string());
+    };
+    let mut list = sinks.list.lock().unwrap();
+    match arg.parse::<usize>().ok().and_then(|id| list.sinks.remove(&id)) {
+        Some((_, ids)) => {
+            hotplug::unbind(&ids);
+            Ok("OK")
+        },
+        None => Err(format!("no such sink: {}", arg)),
+    }
+}
+
+/// How long `calibrate` records for if not told otherwise, and at most.
+const CALIBRATION_SECS: u64 = 5;
+const MAX_CALIBRATION_SECS: u64 = 60;
+
+/// What `part` gets out of the sink `arg` names, `lacking` tells why if it gets nothing.
+fn sink_of<T>(sinks: &Sinks, arg: Option<&String>, part: impl FnOnce(&dyn Sink) -> Option<T>, lacking: &str) -> Result<T, String> {
+    let Some(arg) = arg else {
+        return Err("takes a sink id, see list_sinks".to_string());
+    };
+    let list = sinks.list.lock().unwrap();
+    let Some((sink, _)) = arg.parse::<usize>().ok().and_then(|id| list.sinks.get(&id)) else {
+        return Err(format!("no such sink: {}", arg));
+    };
+    part(sink.as_ref()).ok_or_else(|| format!("sink {} {}", arg, lacking))
+}
+
+fn calibration_of(sinks: &Sinks, arg: Option<&String>) -> Result<Arc<Mutex<Calibration>>, String> {
+    sink_of(sinks, arg, |sink| sink.calibration(), "has no axes to calibrate")
+}
+
+/// `<axis>\t<min>\t<center>\t<max>\t<flat>\t<inverted>` per raw axis of the sink's source.
+pub fn get_calibration(sinks: &Sinks, args: Vec<String>) -> Result<String, String> {
+    let calibration = calibration_of(sinks, args.first())?;
+    let desc = calibration.lock().unwrap().describe();
+    Ok(desc)
+}
+
+/// Recalibrates the source of a sink: the sticks have to rest when it's called,
+/// then every stick and trigger goes to its limits until it answers with
+/// the new calibration.
+pub fn calibrate(sinks: &Sinks, args: Vec<String>) -> Result<String, String> {
+    let calibration = calibration_of(sinks, args.first())?;
+    let secs = match args.get(1) {
+        Some(secs) => match secs.parse::<u64>() {
+            Ok(secs @ 1..=MAX_CALIBRATION_SECS) => secs,
+            _ => return Err(format!("calibrating takes 1 to {} seconds, got {}", MAX_CALIBRATION_SECS, secs)),
+        },
+        None => CALIBRATION_SECS,
+    };
+    let _waiting = Waiting::take(&sinks.waiting)?;
+
+    calibration.lock().unwrap().start();
+    // the list isn't locked meanwhile
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    while Instant::now() < deadline {
+        if caller_gone() {
+            calibration.lock().unwrap().cancel();
+            return Err("caller gone".to_string());
+        }
+        thread::sleep(Duration::from_millis(100).min(deadline.saturating_duration_since(Instant::now())));
+    }
+    let mut calibration = calibration.lock().unwrap();
+    calibration.finish()?;
+    Ok(calibration.describe())
+}
+
+/// Like `calibrate`, for callers that decide themselves when it's done: the
+/// sticks have to rest when it's called, `finish_calibration` takes it from there.
+pub fn start_calibration(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let calibration = calibration_of(sinks, args.first())?;
+    calibration.lock().unwrap().start();
+    Ok("OK")
+}
+
+/// Stores what `start_calibration` recorded and answers with the new calibration.
+pub fn finish_calibration(sinks: &Sinks, args: Vec<String>) -> Result<String, String> {
+    let calibration = calibration_of(sinks, args.first())?;
+    let mut calibration = calibration.lock().unwrap();
+    calibration.finish()?;
+    Ok(calibration.describe())
+}
+
+pub fn set_axis_inverted(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let (Some(axis), Some(inverted)) = (args.get(1), args.get(2)) else {
+        return Err("set_axis_inverted takes a sink id, an axis and true or false".to_string());
+    };
+    let inverted = inverted.parse::<bool>().map_err(|_| format!("not true or false: {}", inverted))?;
+    let calibration = calibration_of(sinks, args.first())?;
+    let res = calibration.lock().unwrap().set_inverted(axis, inverted);
+    res.map(|()| "OK")
+}
+
+/// Forgets the stored calibration, the ranges the device reports are used again.
+pub fn reset_calibration(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let calibration = calibration_of(sinks, args.first())?;
+    let res = calibration.lock().unwrap().reset();
+    res.map(|()| "OK")
+}
+
+fn response_of(sinks: &Sinks, arg: Option<&String>) -> Result<Arc<Mutex<Response>>, String> {
+    sink_of(sinks, arg, |sink| sink.response(), "has no sticks or triggers")
+}
+
+/// `<control>\t<deadzone>\t<radial|axial>\t<anti_deadzone>\t<saturation>\t<curve>` per stick and trigger.
+pub fn get_response(sinks: &Sinks, args: Vec<String>) -> Result<String, String> {
+    let response = response_of(sinks, args.first())?;
+    let desc = response.lock().unwrap().describe();
+    Ok(desc)
+}
+
+/// Changes one setting of a stick or trigger of a sink's source, it's kept for
+/// the next time the source is used.
+pub fn set_response(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let (Some(control), Some(setting), Some(value)) = (args.get(1), args.get(2), args.get(3)) else {
+        return Err("set_response takes a sink id, a stick or trigger, a setting and its value".to_string());
+    };
+    let response = response_of(sinks, args.first())?;
+    let res = response.lock().unwrap().set(control, setting, value);
+    res.map(|()| "OK")
+}
+
+pub fn reset_response(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let response = response_of(sinks, args.first())?;
+    let res = response.lock().unwrap().reset();
+    res.map(|()| "OK")
+}
+
+fn gyro_of(sinks: &Sinks, arg: Option<&String>) -> Result<Arc<Mutex<GyroAim>>, String> {
+    sink_of(sinks, arg, |sink| sink.gyro(), "has no motion sensors")
+}
+
+/// `<setting>\t<value>` per gyro aiming setting.
+pub fn get_gyro(sinks: &Sinks, args: Vec<String>) -> Result<String, String> {
+    let gyro = gyro_of(sinks, args.first())?;
+    let desc = gyro.lock().unwrap().describe();
+    Ok(desc)
+}
+
+/// Changes how a sink's source aims with its gyro, it's kept for the next
+/// time the source is used.
+pub fn set_gyro(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let (Some(setting), Some(value)) = (args.get(1), args.get(2)) else {
+        return Err("set_gyro takes a sink id, a setting and its value".to_string());
+    };
+    let gyro = gyro_of(sinks, args.first())?;
+    let res = gyro.lock().unwrap().set(setting, value);
+    res.map(|()| "OK")
+}
+
+pub fn reset_gyro(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let gyro = gyro_of(sinks, args.first())?;
+    let res = gyro.lock().unwrap().reset();
+    res.map(|()| "OK")
+}
+
+/// `<name>\t<applications>` per profile, applications separated by commas.
+pub fn list_profiles(_: &Sinks, _: Vec<String>) -> String {
+    profile::list()
+}
+
+/// The profile as it's stored.
+pub fn get_profile(_: &Sinks, args: Vec<String>) -> Result<String, String> {
+    let Some(name) = args.first() else {
+        return Err("get_profile takes a profile name, see list_profiles".to_string());
+    };
+    profile::describe(name)
+}
+
+/// Remaps a key or axis in a profile, `none` removes the remap. Profiles are
+/// made on first use.
+pub fn set_profile_remap(_: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let (Some(name), Some(from), Some(to)) = (args.first(), args.get(1), args.get(2)) else {
+        return Err("set_profile_remap takes a profile name, a key or axis and what it becomes".to_string());
+    };
+    let to = Some(to.as_str()).filter(|to| *to != "none");
+    profile::set_remap(name, from, to).map(|()| "OK")
+}
+
+/// Makes a key a turbo button pressing that many times a second, 0 turns it off.
+pub fn set_profile_turbo(_: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let (Some(name), Some(key), Some(rate)) = (args.first(), args.get(1), args.get(2)) else {
+        return Err("set_profile_turbo takes a profile name, a key and presses per second".to_string());
+    };
+    let rate = rate.parse::<u32>().map_err(|_| format!("invalid number of presses per second: {}", rate))?;
+    profile::set_turbo(name, key, rate).map(|()| "OK")
+}
+
+/// Applications the profile is used for while they're focused, replacing the old ones.
+pub fn set_profile_apps(_: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let Some((name, apps)) = args.split_first() else {
+        return Err("set_profile_apps takes a profile name and application ids".to_string());
+    };
+    profile::set_apps(name, apps).map(|()| "OK")
+}
+
+pub fn del_profile(_: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let Some(name) = args.first() else {
+        return Err("del_profile takes a profile name, see list_profiles".to_string());
+    };
+    profile::delete(name).map(|()| "OK")
+}
+
+/// Picks the profile a sink uses while the focused application has none, `none` for no profile.
+pub fn use_profile(sinks: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let (Some(arg), Some(name)) = (args.first(), args.get(1)) else {
+        return Err("use_profile takes a sink id and a profile name or none".to_string());
+    };
+    let selection = sink_of(sinks, Some(arg), |sink| sink.profile(), "doesn't take profiles")?;
+    let name = Some(name.as_str()).filter(|name| *name != "none");
+    profile::choose(&selection, name).map(|()| "OK")
+}
+
+/// `<button>\t<key>+<key>...` the keyboard and mouse sinks bring up the
+/// on-screen keyboard with, or `none`.
+pub fn get_osk_trigger(_: &Sinks, _: Vec<String>) -> String {
+    desktop::osk_trigger()
+}
+
+/// Makes a gamepad button press a shortcut the desktop opens its on-screen
+/// keyboard with, `none` turns it off.
+pub fn set_osk_trigger(_: &Sinks, args: Vec<String>) -> Result<&'static str, String> {
+    let Some((button, keys)) = args.split_first() else {
+        return Err("set_osk_trigger takes a button and the keys it presses, or none".to_string());
+    };
+    let keys = keys.iter().map(String::as_str).collect::<Vec<&str>>();
+    let button = Some(button.as_str()).filter(|b| *b != "none");
+    desktop::set_osk_trigger(button, &keys).map(|()| "OK")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waiting_calls_leave_workers_free() {
+        let sinks = Sinks::default();
+        let taken = (0..MAX_WAITING).map(|_| Waiting::take(&sinks.waiting).unwrap()).collect::<Vec<Waiting>>();
+        assert!(Waiting::take(&sinks.waiting).is_err());
+        drop(taken);
+        assert!(Waiting::take(&sinks.waiting).is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+};
+use anyhow::Result;
+use rservice::client;
+
+use crate::control::SERVICE_NAME;
+
+static HELP_TEXT: &[u8] = b"Available commands are:
+list_sinks: Lists all sinks in use with sources attached to them
+add_sink <type>: Adds a sink and autobinds the first source L+R is pressed on, gives up after a minute
+del_sink <id>: Removes a sink
+list_sink_types: Lists sink types that can be added with add_sink and the devices they create
+get_calibration <id>: Lists min, center, max, flat and inversion of every axis of a sink's source
+calibrate <id> [seconds]: Recalibrates a sink's source, start with the sticks resting and move them to their limits, for up to 60 seconds
+start_calibration <id>: Starts recalibrating a sink's source, with the sticks resting
+finish_calibration <id>: Stores the limits the sticks and triggers went to since start_calibration
+set_axis_inverted <id> <axis> <true|false>: Inverts an axis, e.g. ABS_Y
+reset_calibration <id>: Goes back to the ranges the device reports
+get_response <id>: Lists deadzone, deadzone mode, anti-deadzone, saturation and curve of every stick and trigger of a sink's source
+set_response <id> <control> <setting> <value>: Changes one of those, e.g. set_response 0 left_stick deadzone 0.1
+reset_response <id>: Goes back to no deadzones and linear response
+get_gyro <id>: Lists gyro aiming mode, mouse and stick speed, smoothing, deadzone and ratchet button of a sink's source
+set_gyro <id> <setting> <value>: Changes one of those, e.g. set_gyro 0 mode mouse or set_gyro 0 ratchet BTN_TL
+reset_gyro <id>: Turns gyro aiming off and forgets its settings
+list_profiles: Lists remap profiles and the applications they're used for
+get_profile <name>: Shows a profile
+set_profile_remap <name> <from> <to|none>: Remaps a key or axis in a profile, e.g. set_profile_remap swap BTN_SOUTH BTN_EAST
+set_profile_turbo <name> <key> <rate>: Makes a key a turbo button pressing rate times a second, 0 turns it off
+set_profile_apps <name> [app...]: Uses the profile whenever one of these applications is focused
+del_profile <name>: Removes a profile
+use_profile <id> <name|none>: Picks the profile a sink uses while the focused application has none
+get_osk_trigger: Shows the button keyboard and mouse sinks bring up the on-screen keyboard with and the keys it presses
+set_osk_trigger <button|none> [key...]: Changes it, e.g. set_osk_trigger BTN_NORTH KEY_LEFTCTRL KEY_LEFTMETA KEY_O
+help: Displays this message
+Answers are OK:<line> for every line of the reply followed by END_MULTILINE, or ERR:<message>
+";
+
+// every command goes through the service, so both interfaces behave the same
+fn handle_client(stream: TcpStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let Some(mut input) = client::get_service(SERVICE_NAME) else {
+        writer.write_all(b"ERR:input service isn't running\n")?;
+        return Ok(());
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let mut args = line.split_whitespace().map(str::to_string);
+        let Some(cmd) = args.next() else {
+            continue;
+        };
+        if cmd == "help" {
+            writer.write_all(HELP_TEXT)?;
+            continue;
+        }
+
+        match input.call(&cmd, args) {
+            Ok(reply) => {
+                for line in reply.lines() {
+                    writeln!(writer, "OK:{}", line)?;
+                }
+                writer.write_all(b"END_MULTILINE\n")?;
+            },
+            Err(e) => writeln!(writer, "ERR:{}", e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Serves the line protocol rinputer4 used to speak, for poking at it remotely.
+pub fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Debug interface listening on {}", listener.local_addr()?);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            thread::spawn(move || {
+                if let Err(e) = handle_client(stream) {
+                    eprintln!("Debug client error: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
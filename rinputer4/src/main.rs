@@ -1,120 +1,129 @@
 mod source;
 mod sink;
-
-use std::net::{
-    TcpListener,
-    TcpStream,
-};
-use std::io::{
-    self,
-    BufReader,
-    BufRead,
-    Write,
+mod control;
+mod debug_tcp;
+mod profile;
+mod toml_tables;
+
+use std::{
+    env,
+    net::SocketAddr,
 };
-use std::sync::{
-    Arc,
-    Mutex,
-};
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use rservice::server::{srv_fn, ServiceServer};
 
 use crate::{
-    sink::{Sink, uinput::UinputSink},
+    control::{
+        Sinks, add_sink, calibrate, del_profile, del_sink, finish_calibration, get_calibration, get_gyro, get_osk_trigger,
+        get_profile, get_response, list_profiles, list_sink_types, list_sinks,
+        reset_calibration, reset_gyro, reset_response, set_axis_inverted, set_gyro, set_osk_trigger,
+        set_profile_apps, set_profile_remap, set_profile_turbo, set_response, start_calibration, use_profile,
+    },
     source::OpenedEventSource,
 };
 
-static HELP_TEXT: &[u8] = b"Available commands are:
-list_sinks: Lists all sinks in use with sources attached to them
-add_sink: Adds a sink and autobinds a source
-del_sink: Removes a sink
-list_sink_types: Lists sink types that can be added with add_sink
-help: Displays this message
-";
+static HELP_TEXT: &str = "Usage: rinputer4 [--debug-tcp <addr>]
 
-fn handle_client(mut stream: TcpStream, all_sinks_mutex: Arc<Mutex<Vec<Box<dyn Sink>>>>) -> Result<()> {
-    let sink_types = sink::list_names();
+Serves the input service on the bus picked by RSERVICE_BUS.
 
-    loop {
-        let mut buf = String::new();
-        let mut buf_reader = BufReader::new(&mut stream);
-        buf_reader.read_line(&mut buf)?;
-
-        let args: Vec<&str> = buf.trim().split(' ').collect();
+--debug-tcp  also speak the old line protocol on a TCP address, for remote debugging
+";
 
-        match args[0] {
-            "add_sink" => {
-                let snk_type = args[1].parse::<usize>()?;
-                let mut all_sinks = all_sinks_mutex.lock().unwrap();
-                let (_, new_fn) = sink_types[snk_type];
+fn parse_args() -> Result<Option<SocketAddr>> {
+    let mut debug_tcp = None;
 
-                let cur_sources = source::enumerate().into_iter()
-                    .map(|v| source::into_opened(v))
-                    .collect::<Vec<OpenedEventSource>>();
-                let new_source = source::wait_for_lr(cur_sources);
-                match new_fn(new_source) {
-                    Ok(sink) => {
-                        all_sinks.push(sink);
-                        stream.write_all(b"OK\n")?;
-                    }
-                    Err(e) => {
-                        eprintln!("Failed making a new sink:");
-                        eprintln!("{}", e);
-                        stream.write_all(b"ERR\n")?;
-                    }
-                };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug-tcp" => {
+                let addr = args.next().context("--debug-tcp needs an address")?;
+                debug_tcp = Some(addr.parse().context("Invalid TCP address")?);
             },
-            "del_sink" => {
-                let mut all_sinks = all_sinks_mutex.lock().unwrap();
-                let victim = args[1].parse::<usize>()?;
-                all_sinks.remove(victim);
-                stream.write_all(b"OK\n")?;
-            }
-            "list_sink_types" => {
-                for (i, (name, _)) in sink_types.iter().enumerate() {
-                    let tmp = format!("OK:{}:{}", i, name);
-                    stream.write_all(tmp.as_str().as_bytes())?;
-                    stream.write_all(b"\n")?;
-                }
-                stream.write_all(b"END_MULTILINE\n")?;
+            "--help" => {
+                print!("{}", HELP_TEXT);
+                std::process::exit(0);
             },
-            "list_sinks" => {
-                let all_sinks = all_sinks_mutex.lock().unwrap();
-                for (i, sink) in all_sinks.iter().enumerate() {
-                    let response = format!("OK:{}:{}:{}\n", i, sink.name(), sink.source_name());
-                    stream.write_all(response.as_str().as_bytes())?;
-                }
-                stream.write_all(b"END_MULTILINE\n")?;
-            }
-            "help" => stream.write_all(HELP_TEXT)?,
-            _ => stream.write_all(b"ERR:Invalid command\n")?,
+            _ => bail!("Unknown argument {}\n\n{}", arg, HELP_TEXT),
         }
-
-        println!("{:#?}", args);
     }
+    Ok(debug_tcp)
 }
 
-fn main() -> io::Result<()> {
-
-
-    let mutex = Arc::new(Mutex::new(Vec::new()));
-    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-    println!("Listening on {}", listener.local_addr().unwrap());
-    for stream in listener.incoming() {
-        let ptr = Arc::clone(&mutex);
-        std::thread::spawn(move || handle_client(stream.unwrap(), ptr));
-    };
-
-    Ok(())
-    /*
-    let devices = source::enumerate();
-    for (i, device) in devices.iter().enumerate() {
-        println!("Device {}: {}", i, device.name())
+fn main() -> Result<()> {
+    let debug_tcp = parse_args()?;
+
+    let srv = ServiceServer::builder(control::SERVICE_NAME, Sinks::default())
+        .implements(control::INTERFACE, 1)
+        .workers(control::WORKERS)
+        .methods([
+            srv_fn!(shared list_sink_types),
+            srv_fn!(shared list_sinks),
+            srv_fn!(shared add_sink),
+            srv_fn!(shared del_sink),
+            srv_fn!(shared get_calibration),
+            srv_fn!(shared calibrate),
+            srv_fn!(shared start_calibration),
+            srv_fn!(shared finish_calibration),
+            srv_fn!(shared set_axis_inverted),
+            srv_fn!(shared reset_calibration),
+            srv_fn!(shared get_response),
+            srv_fn!(shared set_response),
+            srv_fn!(shared reset_response),
+            srv_fn!(shared get_gyro),
+            srv_fn!(shared set_gyro),
+            srv_fn!(shared reset_gyro),
+            srv_fn!(shared list_profiles),
+            srv_fn!(shared get_profile),
+            srv_fn!(shared set_profile_remap),
+            srv_fn!(shared set_profile_turbo),
+            srv_fn!(shared set_profile_apps),
+            srv_fn!(shared del_profile),
+            srv_fn!(shared use_profile),
+            srv_fn!(shared get_osk_trigger),
+            srv_fn!(shared set_osk_trigger),
+        ])
+        .method_args("list_sink_types", [""; 0])
+        .method_args("list_sinks", [""; 0])
+        .method_args("add_sink", ["type"])
+        .method_args("del_sink", ["id"])
+        .method_args("get_calibration", ["id"])
+        .method_args("calibrate", ["id", "seconds?"])
+        .method_args("start_calibration", ["id"])
+        .method_args("finish_calibration", ["id"])
+        .method_args("set_axis_inverted", ["id", "axis", "inverted"])
+        .method_args("reset_calibration", ["id"])
+        .method_args("get_response", ["id"])
+        .method_args("set_response", ["id", "control", "setting", "value"])
+        .method_args("reset_response", ["id"])
+        .method_args("get_gyro", ["id"])
+        .method_args("set_gyro", ["id", "setting", "value"])
+        .method_args("reset_gyro", ["id"])
+        .method_args("list_profiles", [""; 0])
+        .method_args("get_profile", ["name"])
+        .method_args("set_profile_remap", ["name", "from", "to"])
+        .method_args("set_profile_turbo", ["name", "key", "rate"])
+        .method_args("set_profile_apps", ["name", "apps..."])
+        .method_args("del_profile", ["name"])
+        .method_args("use_profile", ["id", "name"])
+        .method_args("get_osk_trigger", [""; 0])
+        .method_args("set_osk_trigger", ["button", "keys..."])
+        // lines of tab-separated fields, see the methods in control.rs
+        .method_reply("list_sink_types", ["index", "name", "nodes"])
+        .method_reply("list_sinks", ["id", "sink_type", "source_name", "profile", "nodes"])
+        .method_reply("get_calibration", ["axis", "min", "center", "max", "flat", "inverted"])
+        .method_reply("get_response", ["control", "deadzone", "shape", "anti_deadzone", "saturation", "curve"])
+        .method_reply("get_gyro", ["setting", "value"])
+        .method_reply("list_profiles", ["name", "applications"])
+        .method_reply("get_osk_trigger", ["button", "keys"])
+        .build()
+        .context("Failed to register the input service")?;
+
+    profile::load();
+    sink::desktop::load();
+    profile::watch_focus();
+    source::hotplug::watch();
+    if let Some(addr) = debug_tcp {
+        debug_tcp::serve(addr)?;
     }
-    println!("Which device should be used for 1st gamepad?");
-
-    let mut buf = String::new();
-    io::stdin().read_line(&mut buf).unwrap();
-
-    let dev_idx = buf.trim().parse::<usize>().unwrap();
-
-    println!("Using device {}", devices[dev_idx].name());*/
+    srv.run();
 }
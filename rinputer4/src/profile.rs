@@ -0,0 +1,550 @@
+use evdev::{
+    AbsoluteAxisType,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    Key,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+use rservice::client;
+use toml_edit::{Document, Item};
+
+use crate::toml_tables::tables;
+
+/// Where profiles are kept, `<name>.toml` each.
+static PROFILE_DIR: &str = "/var/lib/rinputer/profiles";
+/// Interface of the service telling which application is focused, through its `app` property.
+pub static FOCUS_INTERFACE: &str = "org.rsystem.Focus";
+static FOCUS_PROPERTY: &str = "app";
+/// How long to wait before looking for the focus service again.
+const FOCUS_RETRY: Duration = Duration::from_secs(5);
+/// Turbo presses per second, at most.
+const MAX_TURBO_RATE: u32 = 30;
+
+// axes only go to axes of the same kind, their ranges differ
+static AXIS_KINDS: [&[AbsoluteAxisType]; 3] = [
+    &[AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y, AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY],
+    &[AbsoluteAxisType::ABS_Z, AbsoluteAxisType::ABS_RZ],
+    &[AbsoluteAxisType::ABS_HAT0X, AbsoluteAxisType::ABS_HAT0Y],
+];
+
+/// Remaps and turbo buttons applied to what a sink sends, on top of the quirks.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    keys: BTreeMap<u16, Key>,
+    axes: BTreeMap<u16, AbsoluteAxisType>,
+    // presses per second, of the key after remapping
+    turbo: BTreeMap<u16, u32>,
+    // used whenever one of these is focused
+    apps: Vec<String>,
+}
+
+fn valid_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("invalid profile name {:?}, use letters, digits, - and _", name));
+    }
+    Ok(())
+}
+
+fn valid_app(app: &str) -> Result<(), String> {
+    if app.is_empty() || app.chars().any(|c| c.is_control() || c == '"' || c == '\\' || c == ',') {
+        return Err(format!("invalid application id {:?}", app));
+    }
+    Ok(())
+}
+
+fn string<'a>(item: &'a Item, what: &str) -> Result<&'a str, String> {
+    item.as_str().ok_or_else(|| format!("{} has to be a string", what))
+}
+
+impl Profile {
+    fn set_remap(&mut self, from: &str, to: Option<&str>) -> Result<(), String> {
+        if let Ok(from) = from.parse::<Key>() {
+            let Some(to) = to else {
+                self.keys.remove(&from.code());
+                return Ok(());
+            };
+            let to = to.parse::<Key>().map_err(|_| format!("{:?} only goes to keys, not {}", from, to))?;
+            self.keys.insert(from.code(), to);
+            return Ok(());
+        }
+        let Ok(from) = from.parse::<AbsoluteAxisType>() else {
+            return Err(format!("unknown key or axis {}", from));
+        };
+        let Some(to) = to else {
+            self.axes.remove(&from.0);
+            return Ok(());
+        };
+        let kind = AXIS_KINDS.iter().find(|kind| kind.contains(&from))
+            .ok_or_else(|| format!("{:?} can't be remapped", from))?;
+        match to.parse::<AbsoluteAxisType>() {
+            Ok(to) if kind.contains(&to) => {
+                self.axes.insert(from.0, to);
+                Ok(())
+            },
+            _ => Err(format!("{:?} only goes to one of {:?}", from, kind)),
+        }
+    }
+
+    fn set_turbo(&mut self, key: &str, rate: u32) -> Result<(), String> {
+        let key = key.parse::<Key>().map_err(|_| format!("unknown key {}", key))?;
+        if rate > MAX_TURBO_RATE {
+            return Err(format!("turbo goes up to {} presses per second", MAX_TURBO_RATE));
+        }
+        if rate == 0 {
+            self.turbo.remove(&key.code());
+        } else {
+            self.turbo.insert(key.code(), rate);
+        }
+        Ok(())
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let doc = Document::parse(contents).map_err(|e| e.to_string())?;
+        let mut ret = Self::default();
+        for (key, item) in doc.as_table().iter() {
+            match key {
+                "apps" => {
+                    let apps = item.as_array().ok_or("apps has to be a list of strings")?;
+                    for app in apps.iter() {
+                        let app = app.as_str().ok_or("apps has to be a list of strings")?;
+                        valid_app(app)?;
+                        ret.apps.push(app.to_string());
+                    }
+                },
+                "remap" => {
+                    for remap in tables(item).ok_or("remap has to be a list of tables")? {
+                        let from = string(remap.get("from").ok_or("a remap needs a from")?, "from")?;
+                        let to = string(remap.get("to").ok_or("a remap needs a to")?, "to")?;
+                        ret.set_remap(from, Some(to))?;
+                    }
+                },
+                "turbo" => {
+                    for turbo in tables(item).ok_or("turbo has to be a list of tables")? {
+                        let key = string(turbo.get("key").ok_or("a turbo button needs a key")?, "key")?;
+                        let rate = turbo.get("rate").and_then(Item::as_integer)
+                            .and_then(|r| u32::try_from(r).ok())
+                            .ok_or("a turbo button needs a rate in presses per second")?;
+                        ret.set_turbo(key, rate)?;
+                    }
+                },
+                _ => return Err(format!("unknown key {}", key)),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The profile as it's stored.
+    fn to_toml(&self) -> String {
+        let mut ret = String::new();
+        let apps = self.apps.iter().map(|a| format!("\"{}\"", a)).collect::<Vec<String>>();
+        ret += &format!("apps = [{}]\n", apps.join(", "));
+        ret += "remap = [\n";
+        for (from, to) in &self.keys {
+            ret += &format!("    {{ from = \"{:?}\", to = \"{:?}\" }},\n", Key::new(*from), to);
+        }
+        for (from, to) in &self.axes {
+            ret += &format!("    {{ from = \"{:?}\", to = \"{:?}\" }},\n", AbsoluteAxisType(*from), to);
+        }
+        ret += "]\nturbo = [\n";
+        for (key, rate) in &self.turbo {
+            ret += &format!("    {{ key = \"{:?}\", rate = {} }},\n", Key::new(*key), rate);
+        }
+        ret += "]\n";
+        ret
+    }
+}
+
+/// Profile choice of one sink.
+#[derive(Default)]
+pub struct Selection {
+    // picked through use_profile, used while no focused application has one
+    chosen: Option<String>,
+    active: Option<(String, Arc<Profile>)>,
+}
+
+impl Selection {
+    pub fn active_name(&self) -> &str {
+        self.active.as_ref().map_or("", |(name, _)| name)
+    }
+}
+
+struct State {
+    profiles: BTreeMap<String, Arc<Profile>>,
+    focused: String,
+    selections: Vec<Weak<Mutex<Selection>>>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    profiles: BTreeMap::new(),
+    focused: String::new(),
+    selections: Vec::new(),
+});
+
+impl State {
+    // every sink gets the profile of the focused application, or its own choice
+    fn refresh(&mut self) {
+        let focused = self.profiles.iter()
+            .find(|(_, p)| p.apps.contains(&self.focused))
+            .map(|(name, p)| (name.clone(), Arc::clone(p)));
+        self.selections.retain(|s| s.strong_count() > 0);
+        for selection in self.selections.iter().filter_map(Weak::upgrade) {
+            let mut selection = selection.lock().unwrap();
+            let chosen = selection.chosen.as_ref()
+                .and_then(|name| self.profiles.get(name).map(|p| (name.clone(), Arc::clone(p))));
+            selection.active = focused.clone().or(chosen);
+        }
+    }
+
+    fn update(&mut self, name: &str, f: impl FnOnce(&mut Profile) -> Result<(), String>) -> Result<(), String> {
+        valid_name(name)?;
+        let mut profile = self.profiles.get(name).map(|p| (**p).clone()).unwrap_or_default();
+        f(&mut profile)?;
+
+        let file = Path::new(PROFILE_DIR).join(format!("{}.toml", name));
+        let write = || {
+            fs::create_dir_all(PROFILE_DIR)?;
+            fs::write(&file, profile.to_toml())
+        };
+        write().map_err(|e| format!("failed to store {}: {}", file.display(), e))?;
+        self.profiles.insert(name.to_string(), Arc::new(profile));
+        self.refresh();
+        Ok(())
+    }
+}
+
+/// Reads the stored profiles, broken ones are skipped with a complaint.
+pub fn load() {
+    let mut files = fs::read_dir(PROFILE_DIR)
+        .map(|dir| dir.filter_map(|e| e.ok())
+             .map(|e| e.path())
+             .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+             .collect::<Vec<PathBuf>>())
+        .unwrap_or_default();
+    files.sort();
+
+    let mut state = STATE.lock().unwrap();
+    for file in files {
+        let Some(name) = file.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+            continue;
+        };
+        let profile = valid_name(&name)
+            .and_then(|()| fs::read_to_string(&file).map_err(|e| e.to_string()))
+            .and_then(|contents| Profile::parse(&contents));
+        match profile {
+            Ok(profile) => {
+                state.profiles.insert(name, Arc::new(profile));
+            },
+            Err(e) => eprintln!("Ignoring profile {}: {}", file.display(), e),
+        }
+    }
+    state.refresh();
+}
+
+/// A profile choice for a new sink, kept up to date until it's dropped.
+pub fn register() -> Arc<Mutex<Selection>> {
+    let selection = Arc::new(Mutex::new(Selection::default()));
+    let mut state = STATE.lock().unwrap();
+    state.selections.push(Arc::downgrade(&selection));
+    state.refresh();
+    selection
+}
+
+/// Picks the profile of a sink, `None` for no profile.
+pub fn choose(selection: &Arc<Mutex<Selection>>, name: Option<&str>) -> Result<(), String> {
+    let mut state = STATE.lock().unwrap();
+    if let Some(name) = name {
+        if !state.profiles.contains_key(name) {
+            return Err(format!("no such profile: {}", name));
+        }
+    }
+    selection.lock().unwrap().chosen = name.map(str::to_string);
+    state.refresh();
+    Ok(())
+}
+
+/// `<name>\t<applications>` per profile, applications separated by commas.
+pub fn list() -> String {
+    let state = STATE.lock().unwrap();
+    state.profiles.iter()
+        .map(|(name, p)| format!("{}\t{}", name, p.apps.join(",")))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn describe(name: &str) -> Result<String, String> {
+    let state = STATE.lock().unwrap();
+    state.profiles.get(name)
+        .map(|p| p.to_toml())
+        .ok_or_else(|| format!("no such profile: {}", name))
+}
+
+/// Remaps a key or axis in a profile, making the profile if needed. `None` removes the remap.
+pub fn set_remap(name: &str, from: &str, to: Option<&str>) -> Result<(), String> {
+    STATE.lock().unwrap().update(name, |p| p.set_remap(from, to))
+}
+
+/// Makes a key a turbo button, a rate of 0 makes it a normal one again.
+pub fn set_turbo(name: &str, key: &str, rate: u32) -> Result<(), String> {
+    STATE.lock().unwrap().update(name, |p| p.set_turbo(key, rate))
+}
+
+pub fn set_apps(name: &str, apps: &[String]) -> Result<(), String> {
+    STATE.lock().unwrap().update(name, |p| {
+        for app in apps {
+            valid_app(app)?;
+        }
+        p.apps = apps.to_vec();
+        Ok(())
+    })
+}
+
+pub fn delete(name: &str) -> Result<(), String> {
+    let mut state = STATE.lock().unwrap();
+    if state.profiles.remove(name).is_none() {
+        return Err(format!("no such profile: {}", name));
+    }
+    state.refresh();
+    let file = Path::new(PROFILE_DIR).join(format!("{}.toml", name));
+    fs::remove_file(&file).map_err(|e| format!("failed to remove {}: {}", file.display(), e))
+}
+
+fn set_focused(app: String) {
+    let mut state = STATE.lock().unwrap();
+    if state.focused != app {
+        state.focused = app;
+        state.refresh();
+    }
+}
+
+/// Follows the focused application as reported by whatever implements [`FOCUS_INTERFACE`].
+pub fn watch_focus() {
+    thread::spawn(|| loop {
+        let watcher = client::find_service(FOCUS_INTERFACE, 1)
+            .and_then(|service| service.watch([FOCUS_PROPERTY]).ok());
+        if let Some(watcher) = watcher {
+            for change in watcher {
+                set_focused(change.value);
+            }
+            // gone, nothing is focused until it's back
+            set_focused(String::new());
+        }
+        thread::sleep(FOCUS_RETRY);
+    });
+}
+
+/// Applies the active profile of a sink to the events it sends.
+pub struct Mapper {
+    selection: Arc<Mutex<Selection>>,
+    profile: Option<Arc<Profile>>,
+    // keys held down and what they came out as, they're released as that
+    pressed: BTreeMap<u16, u16>,
+    // turbo keys held down, whether they're pressed right now and when that flips
+    turbo: BTreeMap<u16, (bool, Instant)>,
+}
+
+impl Mapper {
+    pub fn new(selection: Arc<Mutex<Selection>>) -> Self {
+        Self {
+            selection,
+            profile: None,
+            pressed: BTreeMap::new(),
+            turbo: BTreeMap::new(),
+        }
+    }
+
+    fn half_period(&self, key: u16) -> Option<Duration> {
+        let rate = *self.profile.as_ref()?.turbo.get(&key)?;
+        Some(Duration::from_secs(1) / (rate * 2))
+    }
+
+    pub fn map(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        let active = self.selection.lock().unwrap().active.as_ref().map(|(_, p)| Arc::clone(p));
+        let changed = match (&active, &self.profile) {
+            (Some(a), Some(b)) => !Arc::ptr_eq(a, b),
+            (a, b) => a.is_some() != b.is_some(),
+        };
+        if changed {
+            self.profile = active;
+            // what's held keeps going until it's let go of
+            self.turbo.clear();
+        }
+
+        match ev.kind() {
+            InputEventKind::Key(key) => {
+                let out = match ev.value() {
+                    0 => self.pressed.remove(&key.code()),
+                    _ => self.pressed.get(&key.code()).copied(),
+                };
+                let out = out.unwrap_or_else(|| {
+                    self.profile.as_ref().and_then(|p| p.keys.get(&key.code())).unwrap_or(&key).code()
+                });
+                match ev.value() {
+                    0 => {
+                        self.turbo.remove(&out);
+                    },
+                    1 => {
+                        self.pressed.insert(key.code(), out);
+                        if let Some(half) = self.half_period(out) {
+                            self.turbo.insert(out, (true, Instant::now() + half));
+                        }
+                    },
+                    _ => (),
+                }
+                vec![InputEvent::new(EventType::KEY, out, ev.value())]
+            },
+            InputEventKind::AbsAxis(axis) => {
+                let out = self.profile.as_ref().and_then(|p| p.axes.get(&axis.0)).unwrap_or(&axis);
+                vec![InputEvent::new(EventType::ABSOLUTE, out.0, ev.value())]
+            },
+            _ => vec![ev],
+        }
+    }
+
+    /// How long until a turbo button flips, `None` if none is held.
+    pub fn timeout(&self) -> Option<Duration> {
+        let next = self.turbo.values().map(|(_, at)| *at).min()?;
+        Some(next.saturating_duration_since(Instant::now()))
+    }
+
+    /// Presses and releases the turbo buttons that are due.
+    pub fn tick(&mut self) -> Vec<InputEvent> {
+        let now = Instant::now();
+        let mut ret = Vec::new();
+        let keys = self.turbo.keys().copied().collect::<Vec<u16>>();
+        for key in keys {
+            let Some(half) = self.half_period(key) else {
+                continue;
+            };
+            if let Some((down, at)) = self.turbo.get_mut(&key) {
+                if *at <= now {
+                    *down = !*down;
+                    *at = now + half;
+                    ret.push(InputEvent::new(EventType::KEY, key, *down as i32));
+                }
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SAMPLE: &str = r#"
+apps = ["org.example.Shooter", "steam_app_1234"]
+remap = [
+    { from = "BTN_SOUTH", to = "BTN_EAST" },
+    { from = "ABS_X", to = "ABS_RX" },
+]
+[[turbo]]
+key = "BTN_EAST"
+rate = 10
+"#;
+
+    fn mapper(profile: Profile) -> Mapper {
+        let selection = Selection { chosen: None, active: Some(("test".to_string(), Arc::new(profile))) };
+        Mapper::new(Arc::new(Mutex::new(selection)))
+    }
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.code(), value)
+    }
+
+    fn keys(events: &[InputEvent]) -> Vec<(u16, i32)> {
+        events.iter().map(|ev| (ev.code(), ev.value())).collect()
+    }
+
+    #[test]
+    fn profiles_parse() {
+        let profile = Profile::parse(SAMPLE).unwrap();
+        assert_eq!(profile.apps, vec!["org.example.Shooter", "steam_app_1234"]);
+        assert_eq!(profile.keys.get(&Key::BTN_SOUTH.code()), Some(&Key::BTN_EAST));
+        assert_eq!(profile.axes.get(&AbsoluteAxisType::ABS_X.0), Some(&AbsoluteAxisType::ABS_RX));
+        assert_eq!(profile.turbo.get(&Key::BTN_EAST.code()), Some(&10));
+    }
+
+    #[test]
+    fn stored_profiles_parse_back() {
+        let profile = Profile::parse(SAMPLE).unwrap();
+        let stored = profile.to_toml();
+        let parsed = Profile::parse(&stored).unwrap();
+        assert_eq!(parsed.to_toml(), stored);
+        assert_eq!((parsed.keys, parsed.axes, parsed.turbo, parsed.apps), (profile.keys, profile.axes, profile.turbo, profile.apps));
+
+        let empty = Profile::default().to_toml();
+        assert_eq!(Profile::parse(&empty).unwrap().to_toml(), empty);
+    }
+
+    #[test]
+    fn broken_profiles_are_refused() {
+        for bad in [
+            "apps = \"one\"",
+            "apps = [\"has,comma\"]",
+            "remap = [{ from = \"BTN_SOUTH\" }]",
+            "remap = [{ from = \"BTN_SOUTH\", to = \"ABS_X\" }]",
+            "remap = [{ from = \"ABS_X\", to = \"ABS_Z\" }]",
+            "remap = [{ from = \"ABS_MISC\", to = \"ABS_X\" }]",
+            "turbo = [{ key = \"BTN_SOUTH\", rate = 31 }]",
+            "turbo = [{ key = \"BTN_SOUTH\", rate = -1 }]",
+            "turbo = [{ key = \"BTN_SOUTH\" }]",
+            "colour = \"red\"",
+            "apps = [",
+        ] {
+            assert!(Profile::parse(bad).is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert!(valid_name("fps-games_2").is_ok());
+        for bad in ["", "../etc", "with space", "dot.toml"] {
+            assert!(valid_name(bad).is_err(), "{} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn remapped_keys_are_released_as_they_were_pressed() {
+        let mut profile = Profile::default();
+        profile.set_remap("BTN_SOUTH", Some("BTN_EAST")).unwrap();
+        let mut mapper = mapper(profile);
+
+        assert_eq!(keys(&mapper.map(key(Key::BTN_SOUTH, 1))), vec![(Key::BTN_EAST.code(), 1)]);
+        // the profile goes away while it's held
+        mapper.selection.lock().unwrap().active = None;
+        assert_eq!(keys(&mapper.map(key(Key::BTN_SOUTH, 0))), vec![(Key::BTN_EAST.code(), 0)]);
+        assert_eq!(keys(&mapper.map(key(Key::BTN_SOUTH, 1))), vec![(Key::BTN_SOUTH.code(), 1)]);
+    }
+
+    #[test]
+    fn turbo_keys_flip_while_held() {
+        let mut profile = Profile::default();
+        profile.set_turbo("BTN_EAST", MAX_TURBO_RATE).unwrap();
+        let mut mapper = mapper(profile);
+        assert!(mapper.timeout().is_none());
+
+        assert_eq!(keys(&mapper.map(key(Key::BTN_EAST, 1))), vec![(Key::BTN_EAST.code(), 1)]);
+        assert!(mapper.tick().is_empty());
+        let half = mapper.timeout().unwrap();
+        assert!(half <= Duration::from_secs(1) / (MAX_TURBO_RATE * 2));
+
+        thread::sleep(half);
+        assert_eq!(keys(&mapper.tick()), vec![(Key::BTN_EAST.code(), 0)]);
+        thread::sleep(mapper.timeout().unwrap());
+        assert_eq!(keys(&mapper.tick()), vec![(Key::BTN_EAST.code(), 1)]);
+
+        assert_eq!(keys(&mapper.map(key(Key::BTN_EAST, 0))), vec![(Key::BTN_EAST.code(), 0)]);
+        assert!(mapper.timeout().is_none());
+        // keys without turbo don't start it
+        mapper.map(key(Key::BTN_SOUTH, 1));
+        assert!(mapper.timeout().is_none());
+    }
+}
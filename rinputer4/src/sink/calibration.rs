@@ -0,0 +1,293 @@
+use evdev::{AbsInfo, AbsoluteAxisType};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+};
+
+use crate::{
+    sink::{forget, store},
+    source::OpenedEventSource,
+};
+
+/// Where calibrations are kept, one file per source.
+static CALIBRATION_DIR: &str = "/var/lib/rinputer/calibration";
+
+/// Raw range of one source axis.
+#[derive(Clone, Copy, Debug)]
+struct AxisCalibration {
+    min: i32,
+    center: i32,
+    max: i32,
+    // raw values this close to the center count as centered
+    flat: i32,
+    inverted: bool,
+}
+
+impl AxisCalibration {
+    // fuzz isn't kept, the kernel drops changes within it before they're read
+    fn from_info(info: &AbsInfo) -> Self {
+        Self {
+            min: info.minimum(),
+            center: (info.minimum() + info.maximum()) / 2,
+            max: info.maximum(),
+            flat: info.flat(),
+            inverted: false,
+        }
+    }
+
+    /// Maps a raw value onto `out`. Outputs going below zero (sticks, hats)
+    /// put the center at 0 and scale each side on its own, others (triggers)
+    /// scale the whole range.
+    fn map(&self, raw: i32, (out_min, out_max): (i32, i32)) -> i32 {
+        let (raw, min, center, max) = (raw as i64, self.min as i64, self.center as i64, self.max as i64);
+        let (out_min, out_max) = (out_min as i64, out_max as i64);
+
+        let out = if out_min < 0 {
+            let off = raw - center;
+            let out = if off.abs() <= self.flat as i64 {
+                0
+            } else if off > 0 {
+                if max > center { off * out_max / (max - center) } else { 0 }
+            } else if center > min {
+                off * -out_min / (center - min)
+            } else {
+                0
+            };
+            if self.inverted { -out } else { out }
+        } else {
+            let out = if max > min { out_min + (raw - min) * (out_max - out_min) / (max - min) } else { out_min };
+            if self.inverted { out_max - (out - out_min) } else { out }
+        };
+        out.clamp(out_min, out_max) as i32
+    }
+}
+
+// `<axis>\t<min>\t<center>\t<max>\t<flat>\t<inverted>`, both on disk and in replies
+fn parse_line(line: &str) -> Option<(&str, AxisCalibration)> {
+    let mut fields = line.split('\t');
+    let name = fields.next()?;
+    let mut num = || fields.next()?.parse::<i32>().ok();
+    let (min, center, max, flat) = (num()?, num()?, num()?, num()?);
+    let inverted = fields.next()?.parse().ok()?;
+    if min > center || center > max {
+        return None;
+    }
+    Some((name, AxisCalibration { min, center, max, flat, inverted }))
+}
+
+/// Axis calibration of a sink's source, loaded from disk if it was calibrated before.
+pub struct Calibration {
+    file: PathBuf,
+    // what the device says about itself
+    defaults: BTreeMap<u16, AxisCalibration>,
+    axes: BTreeMap<u16, AxisCalibration>,
+    last_raw: BTreeMap<u16, i32>,
+    // resting value and lowest/highest value seen per axis while calibrating
+    recording: Option<BTreeMap<u16, (i32, i32, i32)>>,
+}
+
+impl Calibration {
+    fn new(file: PathBuf, axes: &[(AbsoluteAxisType, AbsInfo)]) -> Self {
+        let defaults = axes.iter()
+            .map(|(axis, info)| (axis.0, AxisCalibration::from_info(info)))
+            .collect::<BTreeMap<u16, AxisCalibration>>();
+        Self {
+            file,
+            axes: defaults.clone(),
+            defaults,
+            last_raw: axes.iter().map(|(axis, info)| (axis.0, info.value())).collect(),
+            recording: None,
+        }
+    }
+
+    pub fn load(source: &OpenedEventSource) -> Self {
+        let mut ret = Self::new(PathBuf::from(CALIBRATION_DIR).join(source.storage_key()), &source.axes);
+        if let Ok(stored) = fs::read_to_string(&ret.file) {
+            for (name, cal) in stored.lines().filter_map(parse_line) {
+                if let Some(code) = ret.code_of(name) {
+                    ret.axes.insert(code, cal);
+                }
+            }
+        }
+        ret
+    }
+
+    /// The device's own ranges, stored nowhere.
+    #[cfg(test)]
+    fn unsaved(axes: &[(AbsoluteAxisType, AbsInfo)]) -> Self {
+        Self::new(PathBuf::new(), axes)
+    }
+
+    fn code_of(&self, name: &str) -> Option<u16> {
+        self.defaults.keys().copied().find(|code| format!("{:?}", AbsoluteAxisType(*code)) == name)
+    }
+
+    /// Maps a raw value of a source axis onto `out`, axes the source didn't
+    /// declare are in sink units already and stay as they are.
+    pub fn map(&mut self, axis: AbsoluteAxisType, raw: i32, out: (i32, i32)) -> i32 {
+        let Some(cal) = self.axes.get(&axis.0) else {
+            return raw;
+        };
+        self.last_raw.insert(axis.0, raw);
+        if let Some((_, lo, hi)) = self.recording.as_mut().and_then(|r| r.get_mut(&axis.0)) {
+            *lo = (*lo).min(raw);
+            *hi = (*hi).max(raw);
+        }
+        cal.map(raw, out)
+    }
+
+    /// Starts recording, axes are expected to rest right now.
+    pub fn start(&mut self) {
+        let recording = self.axes.keys()
+            .map(|code| {
+                let rest = self.last_raw.get(code).copied().unwrap_or(self.axes[code].center);
+                (*code, (rest, rest, rest))
+            })
+            .collect();
+        self.recording = Some(recording);
+    }
+
+    /// Takes the ranges seen since `start` and stores them. Axes that barely
+    /// moved keep their range and only get a new center.
+    pub fn finish(&mut self) -> Result<(), String> {
+        let Some(recording) = self.recording.take() else {
+            return Err("not calibrating".to_string());
+        };
+        for (code, (rest, lo, hi)) in recording {
+            let (Some(cal), Some(default)) = (self.axes.get_mut(&code), self.defaults.get(&code)) else {
+                continue;
+            };
+            if (hi as i64 - lo as i64) * 2 >= default.max as i64 - default.min as i64 {
+                cal.min = lo;
+                cal.max = hi;
+            }
+            cal.center = rest.clamp(cal.min, cal.max);
+        }
+        self.save()
+    }
+
+    /// Stops recording, keeping the calibration as it was.
+    pub fn cancel(&mut self) {
+        self.recording = None;
+    }
+
+    pub fn set_inverted(&mut self, name: &str, inverted: bool) -> Result<(), String> {
+        let Some(code) = self.code_of(name) else {
+            return Err(format!("no such axis: {}", name));
+        };
+        if let Some(cal) = self.axes.get_mut(&code) {
+            cal.inverted = inverted;
+        }
+        self.save()
+    }
+
+    /// Goes back to what the device reports, forgetting the stored calibration.
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.axes = self.defaults.clone();
+        self.recording = None;
+        forget(&self.file)
+    }
+
+    /// One `<axis>\t<min>\t<center>\t<max>\t<flat>\t<inverted>` line per axis.
+    pub fn describe(&self) -> String {
+        self.axes.iter()
+            .map(|(code, cal)| format!("{:?}\t{}\t{}\t{}\t{}\t{}",
+                AbsoluteAxisType(*code), cal.min, cal.center, cal.max, cal.flat, cal.inverted))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn save(&self) -> Result<(), String> {
+        store(CALIBRATION_DIR, &self.file, &(self.describe() + "\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STICK: (i32, i32) = (-32768, 32767);
+    const TRIGGER: (i32, i32) = (0, 255);
+
+    fn axis(min: i32, center: i32, max: i32, flat: i32) -> AxisCalibration {
+        AxisCalibration { min, center, max, flat, inverted: false }
+    }
+
+    #[test]
+    fn sticks_scale_each_side_from_the_center() {
+        // off-center rest, as worn sticks have
+        let cal = axis(0, 100, 1000, 0);
+        assert_eq!(cal.map(100, STICK), 0);
+        assert_eq!(cal.map(0, STICK), -32768);
+        assert_eq!(cal.map(50, STICK), -16384);
+        assert_eq!(cal.map(1000, STICK), 32767);
+        assert_eq!(cal.map(550, STICK), 16383);
+        // past the calibrated range
+        assert_eq!(cal.map(-50, STICK), -32768);
+        assert_eq!(cal.map(2000, STICK), 32767);
+    }
+
+    #[test]
+    fn flat_values_are_centered() {
+        let cal = axis(-100, 0, 100, 10);
+        assert_eq!(cal.map(10, STICK), 0);
+        assert_eq!(cal.map(-10, STICK), 0);
+        assert!(cal.map(11, STICK) > 0);
+    }
+
+    #[test]
+    fn triggers_scale_the_whole_range() {
+        let cal = axis(0, 512, 1023, 0);
+        assert_eq!(cal.map(0, TRIGGER), 0);
+        assert_eq!(cal.map(1023, TRIGGER), 255);
+        assert_eq!(cal.map(2000, TRIGGER), 255);
+
+        let inverted = AxisCalibration { inverted: true, ..cal };
+        assert_eq!(inverted.map(0, TRIGGER), 255);
+        assert_eq!(inverted.map(1023, TRIGGER), 0);
+    }
+
+    #[test]
+    fn inverted_sticks_flip_around_the_center() {
+        let cal = AxisCalibration { inverted: true, ..axis(-100, 0, 100, 0) };
+        assert_eq!(cal.map(100, STICK), -32767);
+        assert_eq!(cal.map(-100, STICK), 32767);
+    }
+
+    #[test]
+    fn empty_ranges_dont_divide_by_zero() {
+        let cal = axis(5, 5, 5, 0);
+        assert_eq!(cal.map(10, STICK), 0);
+        assert_eq!(cal.map(0, STICK), 0);
+        assert_eq!(cal.map(10, TRIGGER), 0);
+    }
+
+    #[test]
+    fn lines_parse() {
+        let (name, cal) = parse_line("ABS_X\t-100\t3\t120\t8\ttrue").unwrap();
+        assert_eq!(name, "ABS_X");
+        assert_eq!((cal.min, cal.center, cal.max, cal.flat, cal.inverted), (-100, 3, 120, 8, true));
+
+        for bad in ["ABS_X\t-100\t3\t120\t8", "ABS_X\t-100\t3\t120\t8\tyes", "ABS_X\tlow\t3\t120\t8\tfalse",
+                    "ABS_X\t0\t200\t100\t8\tfalse", "ABS_X\t50\t0\t100\t8\tfalse", ""] {
+            assert!(parse_line(bad).is_none(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn recording_tracks_the_range() {
+        let mut cal = Calibration::unsaved(&[(AbsoluteAxisType::ABS_X, AbsInfo::new(4, -100, 100, 0, 0, 0))]);
+        cal.start();
+        for raw in [-80, 90, 10] {
+            cal.map(AbsoluteAxisType::ABS_X, raw, STICK);
+        }
+        // an axis the source didn't declare goes through untouched
+        assert_eq!(cal.map(AbsoluteAxisType::ABS_Z, 77, TRIGGER), 77);
+        assert_eq!(cal.recording.as_ref().unwrap()[&AbsoluteAxisType::ABS_X.0], (4, -80, 90));
+
+        cal.cancel();
+        assert!(cal.finish().is_err());
+        assert_eq!(cal.map(AbsoluteAxisType::ABS_X, 100, STICK), 32767);
+    }
+}
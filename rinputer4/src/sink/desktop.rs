@@ -0,0 +1,456 @@
+use crate::{
+    profile::{self, Mapper, Selection},
+    sink::{
+        calibration::Calibration,
+        response::Response,
+        uinput::{dev_node, Pipeline, OWN_VERSION},
+        Node,
+        Sink,
+    },
+    source::OpenedEventSource,
+};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{
+        mpsc::RecvTimeoutError,
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use evdev::{
+    uinput::{
+        VirtualDeviceBuilder,
+        VirtualDevice,
+    },
+    AttributeSet,
+    AbsoluteAxisType,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    InputId,
+    Key,
+    RelativeAxisType,
+};
+use anyhow::Result;
+
+/// Where the on-screen keyboard trigger is kept.
+static OSK_FILE: &str = "/var/lib/rinputer/osk_trigger";
+/// Keys a trigger presses at most.
+const MAX_OSK_KEYS: usize = 4;
+
+/// How often the pointer moves while a stick is held.
+const TICK: Duration = Duration::from_millis(8);
+/// Pixels a second with the right stick all the way out, before acceleration.
+const POINTER_SPEED: f64 = 800.0;
+/// How much faster the pointer gets when the stick is held out...
+const POINTER_ACCEL: f64 = 2.5;
+/// ...for this long.
+const ACCEL_TIME: Duration = Duration::from_secs(1);
+/// Wheel steps a second with the left stick all the way out.
+const SCROLL_SPEED: f64 = 12.0;
+// sticks hardly ever rest at exactly zero, the pointer mustn't drift
+const STICK_DEADZONE: f64 = 0.12;
+// triggers click past the press point and let go below the release point
+const TRIGGER_PRESS: i32 = 160;
+const TRIGGER_RELEASE: i32 = 96;
+
+/// Gamepad button that brings up the on-screen keyboard, by pressing a
+/// shortcut the desktop has it bound to for as long as the button is held.
+#[derive(Clone, Debug)]
+struct OskTrigger {
+    button: Key,
+    keys: Vec<Key>,
+}
+
+// `None` once it's turned off
+static OSK_TRIGGER: Mutex<Option<OskTrigger>> = Mutex::new(None);
+
+impl OskTrigger {
+    fn parse(button: &str, keys: &[&str]) -> Result<Self, String> {
+        let button = button.parse::<Key>().map_err(|_| format!("unknown button {}", button))?;
+        if keys.is_empty() || keys.len() > MAX_OSK_KEYS {
+            return Err(format!("the trigger presses 1 to {} keys", MAX_OSK_KEYS));
+        }
+        let keys = keys.iter()
+            .map(|key| match key.parse::<Key>() {
+                Ok(key) if is_keyboard_key(key) => Ok(key),
+                _ => Err(format!("not a keyboard key: {}", key)),
+            })
+            .collect::<Result<Vec<Key>, String>>()?;
+        Ok(Self { button, keys })
+    }
+}
+
+// Ctrl+Win+O, what Windows opens its on-screen keyboard with
+impl Default for OskTrigger {
+    fn default() -> Self {
+        Self {
+            button: Key::BTN_NORTH,
+            keys: vec![Key::KEY_LEFTCTRL, Key::KEY_LEFTMETA, Key::KEY_O],
+        }
+    }
+}
+
+/// `<button>\t<key>+<key>...`, or `none`, both on disk and in replies.
+fn describe_osk(trigger: &Option<OskTrigger>) -> String {
+    match trigger {
+        Some(trigger) => {
+            let keys = trigger.keys.iter().map(|k| format!("{:?}", k)).collect::<Vec<String>>();
+            format!("{:?}\t{}", trigger.button, keys.join("+"))
+        },
+        None => "none".to_string(),
+    }
+}
+
+/// Reads the stored on-screen keyboard trigger, the default one if there's none.
+pub fn load() {
+    let trigger = match fs::read_to_string(OSK_FILE) {
+        Ok(stored) if stored.trim() == "none" => None,
+        Ok(stored) => {
+            let parsed = stored.trim().split_once('\t')
+                .ok_or_else(|| "expected <button>\t<keys>".to_string())
+                .and_then(|(button, keys)| OskTrigger::parse(button, &keys.split('+').collect::<Vec<&str>>()));
+            match parsed {
+                Ok(trigger) => Some(trigger),
+                Err(e) => {
+                    eprintln!("Ignoring {}: {}", OSK_FILE, e);
+                    Some(OskTrigger::default())
+                },
+            }
+        },
+        Err(_) => Some(OskTrigger::default()),
+    };
+    *OSK_TRIGGER.lock().unwrap() = trigger;
+}
+
+pub fn osk_trigger() -> String {
+    describe_osk(&OSK_TRIGGER.lock().unwrap())
+}
+
+/// Changes the on-screen keyboard trigger of every keyboard and mouse sink,
+/// `None` turns it off.
+pub fn set_osk_trigger(button: Option<&str>, keys: &[&str]) -> Result<(), String> {
+    let trigger = button.map(|button| OskTrigger::parse(button, keys)).transpose()?;
+    let write = || {
+        fs::create_dir_all(PathBuf::from(OSK_FILE).parent().unwrap())?;
+        fs::write(OSK_FILE, describe_osk(&trigger) + "\n")
+    };
+    write().map_err(|e| format!("failed to store the trigger in {}: {}", OSK_FILE, e))?;
+    *OSK_TRIGGER.lock().unwrap() = trigger;
+    Ok(())
+}
+
+// everything a keyboard has, from KEY_ESC to KEY_MICMUTE
+fn is_keyboard_key(key: Key) -> bool {
+    (Key::KEY_ESC.code()..=Key::KEY_MICMUTE.code()).contains(&key.code())
+}
+
+fn key(key: Key, value: i32) -> InputEvent {
+    InputEvent::new(EventType::KEY, key.code(), value)
+}
+
+fn rel(axis: RelativeAxisType, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE, axis.0, value)
+}
+
+// -1..1 past the deadzone, from Xbox 360 stick units
+fn stick(value: i32) -> f64 {
+    let value = (value as f64 / 32767.0).clamp(-1.0, 1.0);
+    if value.abs() <= STICK_DEADZONE {
+        return 0.0;
+    }
+    value.signum() * (value.abs() - STICK_DEADZONE) / (1.0 - STICK_DEADZONE)
+}
+
+/// Turns what comes out of the pipeline into keyboard and mouse events.
+#[derive(Default)]
+struct Desktop {
+    // right stick moves the pointer, left one scrolls
+    pointer: (f64, f64),
+    scroll: (f64, f64),
+    // when the pointer started moving, for acceleration
+    moving_since: Option<Instant>,
+    last_tick: Option<Instant>,
+    // fractions of pixels and wheel steps not sent yet
+    remainder: [f64; 4],
+    clicks: [bool; 2],
+}
+
+impl Desktop {
+    fn translate(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        match ev.kind() {
+            InputEventKind::Key(button) => {
+                let osk = OSK_TRIGGER.lock().unwrap().clone().filter(|t| t.button == button);
+                if let Some(osk) = osk {
+                    return match ev.value() {
+                        0 => osk.keys.iter().rev().map(|k| key(*k, 0)).collect(),
+                        1 => osk.keys.iter().map(|k| key(*k, 1)).collect(),
+                        // no repeats, the desktop would toggle it on and off
+                        _ => Vec::new(),
+                    };
+                }
+                let to = match button {
+                    Key::BTN_SOUTH => Key::KEY_ENTER,
+                    Key::BTN_EAST => Key::KEY_ESC,
+                    Key::BTN_DPAD_UP => Key::KEY_UP,
+                    Key::BTN_DPAD_DOWN => Key::KEY_DOWN,
+                    Key::BTN_DPAD_LEFT => Key::KEY_LEFT,
+                    Key::BTN_DPAD_RIGHT => Key::KEY_RIGHT,
+                    _ => return Vec::new(),
+                };
+                vec![key(to, ev.value())]
+            },
+            InputEventKind::AbsAxis(axis) => match axis {
+                // the kernel drops the releases of keys that weren't pressed
+                AbsoluteAxisType::ABS_HAT0X => vec![key(Key::KEY_LEFT, (ev.value() < 0) as i32), key(Key::KEY_RIGHT, (ev.value() > 0) as i32)],
+                AbsoluteAxisType::ABS_HAT0Y => vec![key(Key::KEY_UP, (ev.value() < 0) as i32), key(Key::KEY_DOWN, (ev.value() > 0) as i32)],
+                AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ => {
+                    let (i, button) = if axis == AbsoluteAxisType::ABS_RZ { (0, Key::BTN_LEFT) } else { (1, Key::BTN_RIGHT) };
+                    let pressed = if self.clicks[i] { ev.value() > TRIGGER_RELEASE } else { ev.value() >= TRIGGER_PRESS };
+                    if pressed == self.clicks[i] {
+                        return Vec::new();
+                    }
+                    self.clicks[i] = pressed;
+                    vec![key(button, pressed as i32)]
+                },
+                AbsoluteAxisType::ABS_RX => { self.pointer.0 = stick(ev.value()); Vec::new() },
+                AbsoluteAxisType::ABS_RY => { self.pointer.1 = stick(ev.value()); Vec::new() },
+                AbsoluteAxisType::ABS_X => { self.scroll.0 = stick(ev.value()); Vec::new() },
+                AbsoluteAxisType::ABS_Y => { self.scroll.1 = stick(ev.value()); Vec::new() },
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn moving(&self) -> bool {
+        self.pointer != (0.0, 0.0) || self.scroll != (0.0, 0.0)
+    }
+
+    /// Moves the pointer and scrolls for the time since the last tick.
+    fn tick(&mut self) -> Vec<InputEvent> {
+        let now = Instant::now();
+        let dt = self.last_tick.map_or(TICK, |last| now - last).min(TICK * 4).as_secs_f64();
+        if !self.moving() {
+            self.moving_since = None;
+            self.last_tick = None;
+            return Vec::new();
+        }
+        self.last_tick = Some(now);
+
+        let mut speed = 0.0;
+        if self.pointer != (0.0, 0.0) {
+            let since = *self.moving_since.get_or_insert(now);
+            let ramp = ((now - since).as_secs_f64() / ACCEL_TIME.as_secs_f64()).min(1.0);
+            // slow and precise near the center, faster the longer it's held out
+            speed = POINTER_SPEED * self.pointer.0.hypot(self.pointer.1) * (1.0 + ramp * (POINTER_ACCEL - 1.0));
+        } else {
+            self.moving_since = None;
+        }
+
+        let moves = [
+            (RelativeAxisType::REL_X, self.pointer.0 * speed),
+            (RelativeAxisType::REL_Y, self.pointer.1 * speed),
+            (RelativeAxisType::REL_HWHEEL, self.scroll.0 * SCROLL_SPEED),
+            // the wheel goes up when the stick goes down
+            (RelativeAxisType::REL_WHEEL, -self.scroll.1 * SCROLL_SPEED),
+        ];
+        let mut ret = Vec::new();
+        for (i, (axis, per_sec)) in moves.into_iter().enumerate() {
+            self.remainder[i] += per_sec * dt;
+            let whole = self.remainder[i].trunc();
+            if whole != 0.0 {
+                self.remainder[i] -= whole;
+                ret.push(rel(axis, whole as i32));
+            }
+        }
+        ret
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.moving().then_some(TICK)
+    }
+}
+
+pub struct DesktopSink {
+    source_name: String,
+    calibration: Arc<Mutex<Calibration>>,
+    response: Arc<Mutex<Response>>,
+    profile: Arc<Mutex<Selection>>,
+    nodes: Vec<(Node, PathBuf)>,
+    _ptr: Arc<()>,
+}
+
+fn sink_worker(src: OpenedEventSource, mut dst: VirtualDevice, mut pipeline: Pipeline, ptr: Arc<()>) {
+    let mut desktop = Desktop::default();
+    loop {
+        // if the DesktopSink was dropped quit
+        if Arc::strong_count(&ptr) < 2 {
+            return;
+        }
+        // a held stick and turbo buttons need waking up without events coming in
+        let timeout = [desktop.timeout(), pipeline.mapper.timeout()].into_iter().flatten().min();
+        let ev = match timeout {
+            Some(timeout) => match src.chan.recv_timeout(timeout) {
+                Ok(ev) => Some(ev),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match src.chan.recv() {
+                Ok(ev) => Some(ev),
+                Err(_) => return, // assume we got dropped
+            },
+        };
+
+        let mut events = Vec::new();
+        if let Some(ev) = ev {
+            events.extend(pipeline.process(ev).into_iter().flat_map(|ev| desktop.translate(ev)));
+        }
+        events.extend(pipeline.mapper.tick().into_iter().flat_map(|ev| desktop.translate(ev)));
+        events.extend(desktop.tick());
+        if !events.is_empty() {
+            dst.emit(&events).unwrap();
+        }
+    }
+}
+
+impl Sink for DesktopSink {
+    fn name(&self) -> &'static str {
+        "Keyboard and mouse device"
+    }
+    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+        let mut keys = AttributeSet::<Key>::new();
+        for code in Key::KEY_ESC.code()..=Key::KEY_MICMUTE.code() {
+            keys.insert(Key::new(code));
+        }
+        keys.insert(Key::BTN_LEFT);
+        keys.insert(Key::BTN_RIGHT);
+
+        let mut rel_axes = AttributeSet::<RelativeAxisType>::new();
+        rel_axes.insert(RelativeAxisType::REL_X);
+        rel_axes.insert(RelativeAxisType::REL_Y);
+        rel_axes.insert(RelativeAxisType::REL_WHEEL);
+        rel_axes.insert(RelativeAxisType::REL_HWHEEL);
+
+        let input_id = InputId::new(evdev::BusType::BUS_VIRTUAL, 0, 0, OWN_VERSION);
+
+        let mut uinput_handle = VirtualDeviceBuilder::new()?
+            .name(format!("{} Keyboard and Mouse", source.name).as_bytes())
+            .input_id(input_id)
+            .with_keys(&keys)?
+            .with_relative_axes(&rel_axes)?
+            .build()?;
+        let node = dev_node(&mut uinput_handle);
+
+        let calibration = Arc::new(Mutex::new(Calibration::load(&source)));
+        let response = Arc::new(Mutex::new(Response::load(&source)));
+        let profile = profile::register();
+        let pipeline = Pipeline {
+            calibration: Arc::clone(&calibration),
+            response: Arc::clone(&response),
+            mapper: Mapper::new(Arc::clone(&profile)),
+        };
+
+        let ptr = Arc::new(());
+        let ptr2 = Arc::clone(&ptr);
+
+        let out = Box::new(DesktopSink {
+            source_name: source.name.clone(),
+            calibration,
+            response,
+            profile,
+            nodes: vec![(Node::KeyboardMouse, node)],
+            _ptr: ptr,
+        });
+
+        std::thread::spawn(|| sink_worker(source, uinput_handle, pipeline, ptr2));
+        Ok(out)
+    }
+    fn source_name(&self) -> String {
+        self.source_name.clone()
+    }
+    fn calibration(&self) -> Option<Arc<Mutex<Calibration>>> {
+        Some(Arc::clone(&self.calibration))
+    }
+    fn response(&self) -> Option<Arc<Mutex<Response>>> {
+        Some(Arc::clone(&self.response))
+    }
+    fn profile(&self) -> Option<Arc<Mutex<Selection>>> {
+        Some(Arc::clone(&self.profile))
+    }
+    fn nodes(&self) -> Vec<(Node, PathBuf)> {
+        self.nodes.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abs(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    fn sent(events: Vec<InputEvent>) -> Vec<(InputEventKind, i32)> {
+        events.iter().map(|ev| (ev.kind(), ev.value())).collect()
+    }
+
+    #[test]
+    fn triggers_click_past_the_press_point_and_let_go_below_the_release_point() {
+        let mut desktop = Desktop::default();
+        let left = |value| vec![(InputEventKind::Key(Key::BTN_LEFT), value)];
+
+        assert!(desktop.translate(abs(AbsoluteAxisType::ABS_RZ, TRIGGER_PRESS - 1)).is_empty());
+        assert_eq!(sent(desktop.translate(abs(AbsoluteAxisType::ABS_RZ, TRIGGER_PRESS))), left(1));
+        // between the two points it stays down
+        assert!(desktop.translate(abs(AbsoluteAxisType::ABS_RZ, TRIGGER_RELEASE + 1)).is_empty());
+        assert_eq!(sent(desktop.translate(abs(AbsoluteAxisType::ABS_RZ, TRIGGER_RELEASE))), left(0));
+        assert!(desktop.translate(abs(AbsoluteAxisType::ABS_RZ, TRIGGER_PRESS - 1)).is_empty());
+
+        assert_eq!(sent(desktop.translate(abs(AbsoluteAxisType::ABS_Z, 255))), vec![(InputEventKind::Key(Key::BTN_RIGHT), 1)]);
+    }
+
+    #[test]
+    fn hats_press_arrow_keys() {
+        let mut desktop = Desktop::default();
+        let arrows = |(a, a_value), (b, b_value)| vec![(InputEventKind::Key(a), a_value), (InputEventKind::Key(b), b_value)];
+
+        assert_eq!(sent(desktop.translate(abs(AbsoluteAxisType::ABS_HAT0X, -1))), arrows((Key::KEY_LEFT, 1), (Key::KEY_RIGHT, 0)));
+        assert_eq!(sent(desktop.translate(abs(AbsoluteAxisType::ABS_HAT0X, 0))), arrows((Key::KEY_LEFT, 0), (Key::KEY_RIGHT, 0)));
+        assert_eq!(sent(desktop.translate(abs(AbsoluteAxisType::ABS_HAT0Y, 1))), arrows((Key::KEY_UP, 0), (Key::KEY_DOWN, 1)));
+        assert_eq!(sent(desktop.translate(key(Key::BTN_DPAD_RIGHT, 1))), vec![(InputEventKind::Key(Key::KEY_RIGHT), 1)]);
+    }
+
+    #[test]
+    fn the_osk_trigger_presses_its_shortcut_while_held() {
+        let mut desktop = Desktop::default();
+        let keys = |keys: &[Key], value| keys.iter().map(|k| (InputEventKind::Key(*k), value)).collect::<Vec<(InputEventKind, i32)>>();
+
+        *OSK_TRIGGER.lock().unwrap() = Some(OskTrigger::default());
+        assert_eq!(sent(desktop.translate(key(Key::BTN_NORTH, 1))), keys(&[Key::KEY_LEFTCTRL, Key::KEY_LEFTMETA, Key::KEY_O], 1));
+        assert!(desktop.translate(key(Key::BTN_NORTH, 2)).is_empty());
+        // let go in reverse
+        assert_eq!(sent(desktop.translate(key(Key::BTN_NORTH, 0))), keys(&[Key::KEY_O, Key::KEY_LEFTMETA, Key::KEY_LEFTCTRL], 0));
+
+        *OSK_TRIGGER.lock().unwrap() = None;
+        assert!(desktop.translate(key(Key::BTN_NORTH, 1)).is_empty());
+    }
+
+    #[test]
+    fn held_sticks_move_the_pointer_every_tick() {
+        let mut desktop = Desktop::default();
+        assert!(desktop.translate(abs(AbsoluteAxisType::ABS_RX, 32767)).is_empty());
+        assert_eq!(desktop.timeout(), Some(TICK));
+
+        // the first tick goes a whole TICK at the slowest speed
+        let expected = (POINTER_SPEED * TICK.as_secs_f64()) as i32;
+        assert_eq!(sent(desktop.tick()), vec![(InputEventKind::RelAxis(RelativeAxisType::REL_X), expected)]);
+
+        // a stick inside the deadzone stops it
+        desktop.translate(abs(AbsoluteAxisType::ABS_RX, 1000));
+        assert!(desktop.tick().is_empty());
+        assert_eq!(desktop.timeout(), None);
+    }
+}
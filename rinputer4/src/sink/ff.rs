@@ -0,0 +1,199 @@
+use evdev::{
+    uinput::VirtualDevice,
+    AttributeSet,
+    Device,
+    FFEffect,
+    FFEffectData,
+    FFEffectType,
+    InputEventKind,
+    UInputEventType,
+};
+use std::{
+    collections::BTreeMap,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Effects a game can upload at once.
+pub const FF_EFFECTS_MAX: u32 = 16;
+
+// effect kinds and waveforms passed on when a source device has them
+static PASSED_THROUGH: [FFEffectType; 7] = [
+    FFEffectType::FF_PERIODIC,
+    FFEffectType::FF_CONSTANT,
+    FFEffectType::FF_SINE,
+    FFEffectType::FF_SQUARE,
+    FFEffectType::FF_TRIANGLE,
+    FFEffectType::FF_SAW_UP,
+    FFEffectType::FF_SAW_DOWN,
+];
+
+/// Force feedback the sink declares: rumble, which every controller that
+/// rumbles at all takes, and whatever else one of the source devices does.
+pub fn supported(nodes: &[Arc<Mutex<PathBuf>>]) -> AttributeSet<FFEffectType> {
+    let mut ret = AttributeSet::new();
+    ret.insert(FFEffectType::FF_RUMBLE);
+    ret.insert(FFEffectType::FF_GAIN);
+    for node in nodes {
+        let path = node.lock().unwrap().clone();
+        let Ok(device) = Device::open(&path) else {
+            continue;
+        };
+        if let Some(ff) = device.supported_ff() {
+            for effect in PASSED_THROUGH.iter().filter(|e| ff.contains(**e)) {
+                ret.insert(*effect);
+            }
+        }
+    }
+    ret
+}
+
+/// One physical device effects are played on, through a handle of its own
+/// so its worker can keep reading events.
+struct Target {
+    node: Arc<Mutex<PathBuf>>,
+    device: Option<Device>,
+    // by the id the game knows them by
+    effects: BTreeMap<i16, FFEffect>,
+}
+
+impl Target {
+    /// Uploads the effect, on a freshly opened device if the old one went away.
+    fn upload(&mut self, id: i16, data: FFEffectData) {
+        if let Some(effect) = self.effects.get_mut(&id) {
+            if effect.update(data).is_ok() {
+                return;
+            }
+        }
+        self.effects.remove(&id);
+        if self.device.is_none() {
+            let path = self.node.lock().unwrap().clone();
+            self.device = Device::open(path).ok();
+        }
+        let Some(device) = self.device.as_mut() else {
+            return;
+        };
+        match device.upload_ff_effect(data) {
+            Ok(effect) => {
+                self.effects.insert(id, effect);
+            },
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => self.device = None,
+            // it doesn't do this kind of effect
+            Err(_) => (),
+        }
+    }
+}
+
+/// Force feedback of a sink, played on every device of its source.
+pub struct Rumble {
+    targets: Vec<Target>,
+    // kept to upload again to devices that come back
+    effects: BTreeMap<i16, FFEffectData>,
+}
+
+impl Rumble {
+    pub fn new(nodes: &[Arc<Mutex<PathBuf>>]) -> Self {
+        Self {
+            targets: nodes.iter()
+                .map(|node| Target { node: Arc::clone(node), device: None, effects: BTreeMap::new() })
+                .collect(),
+            effects: BTreeMap::new(),
+        }
+    }
+
+    fn upload(&mut self, id: i16, data: FFEffectData) {
+        self.effects.insert(id, data);
+        for target in &mut self.targets {
+            target.upload(id, data);
+        }
+    }
+
+    fn erase(&mut self, id: i16) {
+        self.effects.remove(&id);
+        for target in &mut self.targets {
+            // dropping erases it from the device
+            target.effects.remove(&id);
+        }
+    }
+
+    fn play(&mut self, id: i16, count: i32) {
+        for target in &mut self.targets {
+            let played = match target.effects.get_mut(&id) {
+                Some(effect) => effect.play(count).is_ok(),
+                // it doesn't do this kind of effect, or it's gone
+                None => target.device.is_some(),
+            };
+            if played {
+                continue;
+            }
+            // maybe it came back under a new node, everything goes there again
+            target.device = None;
+            target.effects.clear();
+            for (id, data) in &self.effects {
+                target.upload(*id, *data);
+            }
+            if let Some(effect) = target.effects.get_mut(&id) {
+                let _ = effect.play(count);
+            }
+        }
+    }
+
+    fn set_gain(&mut self, gain: u16) {
+        for target in &mut self.targets {
+            if let Some(device) = target.device.as_mut() {
+                let _ = device.set_ff_gain(gain);
+            }
+        }
+    }
+}
+
+/// Answers the force feedback requests of games until the sink goes away.
+pub fn worker(dst: Arc<Mutex<VirtualDevice>>, mut rumble: Rumble, ptr: Weak<()>) {
+    let fd = dst.lock().unwrap().as_raw_fd();
+    // the sink worker emits through the same device, reads mustn't block it
+    // SAFETY: fcntl on an fd that stays open as long as dst
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+
+    loop {
+        // if the UinputSink was dropped quit
+        if ptr.strong_count() < 2 {
+            return;
+        }
+        let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        // SAFETY: one valid pollfd, woken up every second to notice the sink going away
+        if unsafe { libc::poll(&mut pollfd, 1, 1000) } <= 0 {
+            continue;
+        }
+
+        // the device stays locked only for the ioctls, the sink worker emits through it meanwhile
+        let Ok(events) = dst.lock().unwrap().fetch_events().map(|events| events.collect::<Vec<_>>()) else {
+            continue;
+        };
+        for ev in events {
+            match ev.kind() {
+                InputEventKind::UInput(code) if code == UInputEventType::UI_FF_UPLOAD.0 => {
+                    let upload = dst.lock().unwrap().process_ff_upload(ev);
+                    if let Ok(upload) = upload {
+                        rumble.upload(upload.effect_id(), upload.effect());
+                    }
+                },
+                InputEventKind::UInput(code) if code == UInputEventType::UI_FF_ERASE.0 => {
+                    let erase = dst.lock().unwrap().process_ff_erase(ev);
+                    if let Ok(erase) = erase {
+                        rumble.erase(erase.effect_id() as i16);
+                    }
+                },
+                InputEventKind::ForceFeedback(code) if code == FFEffectType::FF_GAIN.0 => {
+                    rumble.set_gain(ev.value().clamp(0, u16::MAX as i32) as u16);
+                },
+                // codes past the effect ids are settings like FF_AUTOCENTER, those are left alone
+                InputEventKind::ForceFeedback(code) if (code as u32) < FF_EFFECTS_MAX => rumble.play(code as i16, ev.value()),
+                _ => (),
+            }
+        }
+    }
+}
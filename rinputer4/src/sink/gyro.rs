@@ -0,0 +1,427 @@
+use evdev::{AbsoluteAxisType, EventType, InputEvent, InputEventKind, Key, Synchronization};
+use std::{
+    fmt,
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    sink::{forget, store},
+    source::{motion::GYRO_RES_PER_DEG_S, OpenedEventSource},
+};
+
+/// Where gyro aiming settings are kept, one file per source.
+static GYRO_DIR: &str = "/var/lib/rinputer/gyro";
+
+static MAX_STICK: i32 = 32767;
+static MIN_STICK: i32 = -32768;
+
+/// Frames further apart than that don't move the mouse further, the sensor
+/// was gone or the machine asleep.
+const MAX_FRAME_GAP: Duration = Duration::from_millis(50);
+
+/// What turning the controller does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Off,
+    /// Adds to the right stick, the turning speed sets how far.
+    Stick,
+    /// Moves a mouse, the angle turned sets how far.
+    Mouse,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Off => write!(f, "off"),
+            Mode::Stick => write!(f, "stick"),
+            Mode::Mouse => write!(f, "mouse"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "off" => Ok(Mode::Off),
+            "stick" => Ok(Mode::Stick),
+            "mouse" => Ok(Mode::Mouse),
+            _ => Err(format!("mode is off, stick or mouse, got {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Settings {
+    mode: Mode,
+    // pixels per degree turned
+    mouse_speed: f64,
+    // degrees a second that tilt the stick all the way
+    stick_speed: f64,
+    // 0 for none, up to 1 for a lot; only slow turns are smoothed so flicks stay sharp
+    smoothing: f64,
+    // degrees a second that count as holding still
+    deadzone: f64,
+    // held to move the controller without aiming, like lifting a mouse
+    ratchet: Option<Key>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Off,
+            mouse_speed: 15.0,
+            stick_speed: 150.0,
+            smoothing: 0.0,
+            deadzone: 1.0,
+            ratchet: None,
+        }
+    }
+}
+
+/// Turning speed below which smoothing kicks in, in degrees a second.
+const SMOOTHING_BELOW: f64 = 8.0;
+/// Slowest settings, anything below turns every twitch into a full flick.
+const MIN_MOUSE_SPEED: f64 = 0.1;
+const MIN_STICK_SPEED: f64 = 1.0;
+
+static SETTINGS: [&str; 6] = ["mode", "mouse_speed", "stick_speed", "smoothing", "deadzone", "ratchet"];
+
+impl Settings {
+    fn set(&mut self, setting: &str, value: &str) -> Result<(), String> {
+        let at_least = |min: f64| match value.parse::<f64>() {
+            Ok(v) if v >= min && v.is_finite() => Ok(v),
+            _ => Err(format!("{} takes a number from {} up, got {}", setting, min, value)),
+        };
+        match setting {
+            "mode" => self.mode = value.parse()?,
+            "mouse_speed" => self.mouse_speed = at_least(MIN_MOUSE_SPEED)?,
+            "stick_speed" => self.stick_speed = at_least(MIN_STICK_SPEED)?,
+            "smoothing" => self.smoothing = match value.parse::<f64>() {
+                Ok(v) if (0.0..1.0).contains(&v) => v,
+                _ => return Err(format!("smoothing takes a number within 0..1, below 1, got {}", value)),
+            },
+            "deadzone" => self.deadzone = match value.parse::<f64>() {
+                Ok(v) if v >= 0.0 && v.is_finite() => v,
+                _ => return Err(format!("deadzone takes degrees a second, got {}", value)),
+            },
+            "ratchet" => self.ratchet = match value {
+                "none" => None,
+                _ => Some(value.parse::<Key>().map_err(|_| format!("unknown button {}", value))?),
+            },
+            _ => return Err(format!("no such setting: {}, expected one of {}", setting, SETTINGS.join(", "))),
+        }
+        Ok(())
+    }
+
+    fn get(&self, setting: &str) -> Option<String> {
+        Some(match setting {
+            "mode" => self.mode.to_string(),
+            "mouse_speed" => self.mouse_speed.to_string(),
+            "stick_speed" => self.stick_speed.to_string(),
+            "smoothing" => self.smoothing.to_string(),
+            "deadzone" => self.deadzone.to_string(),
+            "ratchet" => self.ratchet.map(|key| format!("{:?}", key)).unwrap_or_else(|| "none".to_string()),
+            _ => return None,
+        })
+    }
+}
+
+/// What a motion frame does to the sink's devices.
+pub enum Aim {
+    /// Right stick values in sink units, the real stick included.
+    Stick(i32, i32),
+    /// Pixels to move the mouse by.
+    Mouse(i32, i32),
+}
+
+/// Gyro aiming of a sink's source, fed with motion frames in the units of `Motion::start`.
+pub struct GyroAim {
+    file: PathBuf,
+    settings: Settings,
+    ratchet_held: bool,
+    // right stick as the pipeline left it
+    stick: (i32, i32),
+    // what the gyro adds to it
+    aim: (i32, i32),
+    // degrees a second turning right and up
+    rate: (f64, f64),
+    smoothed: (f64, f64),
+    last_frame: Option<Instant>,
+    // mouse movement below a pixel, kept for the next frame
+    rest: (f64, f64),
+}
+
+impl GyroAim {
+    fn new(file: PathBuf) -> Self {
+        Self {
+            file,
+            settings: Settings::default(),
+            ratchet_held: false,
+            stick: (0, 0),
+            aim: (0, 0),
+            rate: (0.0, 0.0),
+            smoothed: (0.0, 0.0),
+            last_frame: None,
+            rest: (0.0, 0.0),
+        }
+    }
+
+    pub fn load(source: &OpenedEventSource) -> Self {
+        let mut ret = Self::new(PathBuf::from(GYRO_DIR).join(source.storage_key()));
+        if let Ok(stored) = fs::read_to_string(&ret.file) {
+            for (setting, value) in stored.lines().filter_map(|line| line.split_once('\t')) {
+                if let Err(e) = ret.settings.set(setting, value) {
+                    eprintln!("Skipping gyro setting in {}: {}", ret.file.display(), e);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Default settings, stored nowhere.
+    #[cfg(test)]
+    fn unsaved() -> Self {
+        Self::new(PathBuf::new())
+    }
+
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), String> {
+        self.settings.set(setting, value)?;
+        self.stop();
+        self.save()
+    }
+
+    /// Back to no gyro aiming, forgetting the stored settings.
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.settings = Settings::default();
+        self.stop();
+        forget(&self.file)
+    }
+
+    /// One `<setting>\t<value>` line per setting.
+    pub fn describe(&self) -> String {
+        SETTINGS.iter()
+            .filter_map(|setting| Some(format!("{}\t{}", setting, self.settings.get(setting)?)))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Takes an event the sink is about to emit, the right stick gets the
+    /// gyro added to it.
+    pub fn mix(&mut self, ev: InputEvent) -> InputEvent {
+        match ev.kind() {
+            InputEventKind::Key(key) if Some(key) == self.settings.ratchet => {
+                self.ratchet_held = ev.value() != 0;
+                if self.ratchet_held {
+                    self.stop();
+                }
+            },
+            // kept track of whatever the mode, it may change any time
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_RX) => {
+                self.stick.0 = ev.value();
+                if self.settings.mode == Mode::Stick {
+                    return InputEvent::new(EventType::ABSOLUTE, ev.code(), self.stick_out().0);
+                }
+            },
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_RY) => {
+                self.stick.1 = ev.value();
+                if self.settings.mode == Mode::Stick {
+                    return InputEvent::new(EventType::ABSOLUTE, ev.code(), self.stick_out().1);
+                }
+            },
+            _ => (),
+        }
+        ev
+    }
+
+    /// Takes a motion event, what it does comes with the SYN_REPORT ending the frame.
+    pub fn feed(&mut self, ev: InputEvent) -> Option<Aim> {
+        // gyro y turns around the vertical axis, gyro x around the sideways one
+        match ev.kind() {
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_RY) => self.rate.0 = -ev.value() as f64 / GYRO_RES_PER_DEG_S as f64,
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_RX) => self.rate.1 = ev.value() as f64 / GYRO_RES_PER_DEG_S as f64,
+            InputEventKind::Synchronization(Synchronization::SYN_REPORT) => return self.frame(),
+            _ => (),
+        }
+        None
+    }
+
+    fn frame(&mut self) -> Option<Aim> {
+        let now = Instant::now();
+        let dt = self.last_frame.map(|last| now.duration_since(last).min(MAX_FRAME_GAP)).unwrap_or_default();
+        self.last_frame = Some(now);
+        if self.settings.mode != Mode::Stick {
+            // just switched away, the right stick goes back to the real one
+            if self.aim != (0, 0) {
+                self.aim = (0, 0);
+                return Some(Aim::Stick(self.stick.0, self.stick.1));
+            }
+        }
+        if self.settings.mode == Mode::Off {
+            return None;
+        }
+
+        let (right, up) = if self.ratchet_held { (0.0, 0.0) } else { self.turn() };
+        match self.settings.mode {
+            Mode::Stick => {
+                // fast turns go past full deflection, and a float too large for an i32 saturates
+                let deflect = |rate: f64| (rate / self.settings.stick_speed * MAX_STICK as f64).round().clamp(MIN_STICK as f64, MAX_STICK as f64) as i32;
+                // stick y goes down
+                self.aim = (deflect(right), deflect(-up));
+                let (x, y) = self.stick_out();
+                Some(Aim::Stick(x, y))
+            },
+            Mode::Mouse => {
+                let secs = dt.as_secs_f64();
+                let x = right * secs * self.settings.mouse_speed + self.rest.0;
+                let y = -up * secs * self.settings.mouse_speed + self.rest.1;
+                self.rest = (x.fract(), y.fract());
+                Some(Aim::Mouse(x.trunc() as i32, y.trunc() as i32))
+            },
+            Mode::Off => None,
+        }
+    }
+
+    // turning speed past the deadzone and smoothing
+    fn turn(&mut self) -> (f64, f64) {
+        let (x, y) = self.rate;
+        let speed = x.hypot(y);
+        if speed <= self.settings.deadzone {
+            self.smoothed = (0.0, 0.0);
+            return (0.0, 0.0);
+        }
+        let keep = self.settings.smoothing;
+        self.smoothed = (self.smoothed.0 * keep + x * (1.0 - keep), self.smoothed.1 * keep + y * (1.0 - keep));
+        // fast turns go straight through, slow ones get more of the smoothed value
+        let direct = (speed / SMOOTHING_BELOW).min(1.0);
+        (x * direct + self.smoothed.0 * (1.0 - direct), y * direct + self.smoothed.1 * (1.0 - direct))
+    }
+
+    fn stick_out(&self) -> (i32, i32) {
+        (
+            self.stick.0.saturating_add(self.aim.0).clamp(MIN_STICK, MAX_STICK),
+            self.stick.1.saturating_add(self.aim.1).clamp(MIN_STICK, MAX_STICK),
+        )
+    }
+
+    // forgets past movement, the stick catches up on the next frame
+    fn stop(&mut self) {
+        self.smoothed = (0.0, 0.0);
+        self.rest = (0.0, 0.0);
+    }
+
+    fn save(&self) -> Result<(), String> {
+        store(GYRO_DIR, &self.file, &(self.describe() + "\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn aim(settings: &[(&str, &str)]) -> GyroAim {
+        let mut ret = GyroAim::unsaved();
+        for (setting, value) in settings {
+            ret.settings.set(setting, value).unwrap();
+        }
+        ret
+    }
+
+    // degrees a second turning right and up
+    fn turn(gyro: &mut GyroAim, right: f64, up: f64) -> Option<Aim> {
+        let value = |rate: f64| (rate * GYRO_RES_PER_DEG_S as f64) as i32;
+        assert!(gyro.feed(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, value(-right))).is_none());
+        assert!(gyro.feed(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, value(up))).is_none());
+        gyro.feed(InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0))
+    }
+
+    fn stick(gyro: &mut GyroAim, axis: AbsoluteAxisType, value: i32) -> i32 {
+        gyro.mix(InputEvent::new(EventType::ABSOLUTE, axis.0, value)).value()
+    }
+
+    #[test]
+    fn off_does_nothing() {
+        let mut gyro = aim(&[]);
+        assert!(turn(&mut gyro, 100.0, 0.0).is_none());
+        assert_eq!(stick(&mut gyro, AbsoluteAxisType::ABS_RX, 1000), 1000);
+    }
+
+    #[test]
+    fn stick_mode_deflects_by_turning_speed() {
+        let mut gyro = aim(&[("mode", "stick"), ("stick_speed", "100"), ("deadzone", "0")]);
+        let Some(Aim::Stick(x, y)) = turn(&mut gyro, 50.0, 25.0) else {
+            panic!("no stick movement");
+        };
+        assert_eq!((x, y), (16384, -8192));
+
+        // the real stick adds to it
+        assert_eq!(stick(&mut gyro, AbsoluteAxisType::ABS_RX, 1000), 17384);
+        assert_eq!(stick(&mut gyro, AbsoluteAxisType::ABS_RY, 0), -8192);
+    }
+
+    #[test]
+    fn fast_turns_and_full_sticks_stay_in_range() {
+        let mut gyro = aim(&[("mode", "stick"), ("stick_speed", "1")]);
+        stick(&mut gyro, AbsoluteAxisType::ABS_RX, MAX_STICK);
+        stick(&mut gyro, AbsoluteAxisType::ABS_RY, MIN_STICK);
+        let Some(Aim::Stick(x, y)) = turn(&mut gyro, 2_000_000.0, -2_000_000.0) else {
+            panic!("no stick movement");
+        };
+        assert_eq!((x, y), (MAX_STICK, MIN_STICK + MAX_STICK));
+        assert_eq!(stick(&mut gyro, AbsoluteAxisType::ABS_RX, MAX_STICK), MAX_STICK);
+        assert_eq!(stick(&mut gyro, AbsoluteAxisType::ABS_RY, MIN_STICK), MIN_STICK + MAX_STICK);
+
+        gyro.stick = (i32::MAX, i32::MIN);
+        assert_eq!(gyro.stick_out(), (MAX_STICK, MIN_STICK));
+    }
+
+    #[test]
+    fn speeds_have_minimums() {
+        let mut gyro = aim(&[]);
+        for (setting, value) in [("stick_speed", "0"), ("stick_speed", "0.5"), ("mouse_speed", "0.01"), ("mouse_speed", "inf"), ("mouse_speed", "-1")] {
+            assert!(gyro.settings.set(setting, value).is_err(), "{} {} accepted", setting, value);
+        }
+        assert!(gyro.settings.set("stick_speed", "1").is_ok());
+        assert!(gyro.settings.set("mouse_speed", "0.1").is_ok());
+    }
+
+    #[test]
+    fn deadzone_and_ratchet_hold_still() {
+        let mut gyro = aim(&[("mode", "stick"), ("deadzone", "5"), ("ratchet", "BTN_TL")]);
+        assert!(matches!(turn(&mut gyro, 3.0, 3.0), Some(Aim::Stick(0, 0))));
+
+        gyro.mix(InputEvent::new(EventType::KEY, Key::BTN_TL.code(), 1));
+        assert!(matches!(turn(&mut gyro, 100.0, 0.0), Some(Aim::Stick(0, 0))));
+        gyro.mix(InputEvent::new(EventType::KEY, Key::BTN_TL.code(), 0));
+        assert!(matches!(turn(&mut gyro, 100.0, 0.0), Some(Aim::Stick(x, 0)) if x > 0));
+    }
+
+    #[test]
+    fn switching_away_from_stick_gives_the_stick_back() {
+        let mut gyro = aim(&[("mode", "stick")]);
+        stick(&mut gyro, AbsoluteAxisType::ABS_RX, 500);
+        turn(&mut gyro, 100.0, 0.0);
+        gyro.settings.mode = Mode::Off;
+        assert!(matches!(turn(&mut gyro, 100.0, 0.0), Some(Aim::Stick(500, 0))));
+        assert!(turn(&mut gyro, 100.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn mouse_mode_moves_by_angle() {
+        let mut gyro = aim(&[("mode", "mouse"), ("mouse_speed", "10"), ("deadzone", "0")]);
+        // the first frame has nothing to go by
+        assert!(matches!(turn(&mut gyro, 1000.0, 0.0), Some(Aim::Mouse(0, 0))));
+        thread::sleep(Duration::from_millis(20));
+        // 1000 degrees a second for at least 20ms, at 10 pixels a degree
+        let Some(Aim::Mouse(x, y)) = turn(&mut gyro, 1000.0, 0.0) else {
+            panic!("no mouse movement");
+        };
+        assert!((200..=500).contains(&x), "moved {}", x);
+        assert_eq!(y, 0);
+    }
+}
@@ -1,19 +1,116 @@
-use crate::{OpenedEventSource, source::SourceCaps};
+use crate::{OpenedEventSource, profile::Selection};
 
 use anyhow::Result;
+use std::{
+    fmt,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
+pub mod calibration;
+pub mod desktop;
+pub mod ff;
+pub mod gyro;
+pub mod model;
+pub mod response;
 pub mod uinput;
+use calibration::Calibration;
+use desktop::DesktopSink;
+use gyro::GyroAim;
+use model::Model;
+use response::Response;
 use uinput::UinputSink;
 
+/// One of the devices a sink creates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Node {
+    Gamepad,
+    Touchpad,
+    Motion,
+    KeyboardMouse,
+    /// Moved by gyro aiming, only there when the source has motion sensors.
+    Mouse,
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Gamepad => write!(f, "gamepad"),
+            Node::Touchpad => write!(f, "touchpad"),
+            Node::Motion => write!(f, "motion"),
+            Node::KeyboardMouse => write!(f, "keyboard_mouse"),
+            Node::Mouse => write!(f, "mouse"),
+        }
+    }
+}
+
 pub trait Sink: Send + Sync {
     fn name(&self) -> &str;
     fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> where Self: Sized;
     fn source_name(&self) -> String;
-    fn source_caps(&self) -> SourceCaps;
+    /// Calibration of the source's axes, for sinks that have any.
+    fn calibration(&self) -> Option<Arc<Mutex<Calibration>>> {
+        None
+    }
+    /// Deadzones and curves of the source's sticks and triggers, for sinks that have any.
+    fn response(&self) -> Option<Arc<Mutex<Response>>> {
+        None
+    }
+    /// Which remap profile the sink uses.
+    fn profile(&self) -> Option<Arc<Mutex<Selection>>> {
+        None
+    }
+    /// Gyro aiming, for sinks whose source has motion sensors.
+    fn gyro(&self) -> Option<Arc<Mutex<GyroAim>>> {
+        None
+    }
+    /// Devices it created and their nodes in /dev/input.
+    fn nodes(&self) -> Vec<(Node, PathBuf)> {
+        Vec::new()
+    }
+}
+
+/// A kind of sink `add_sink` can make.
+pub struct SinkType {
+    pub name: String,
+    pub nodes: Vec<Node>,
+    pub new: fn(OpenedEventSource) -> Result<Box<dyn Sink>>,
 }
 
-pub fn list_names() -> Vec<(String, fn(OpenedEventSource) -> Result<Box<dyn Sink>>)> {
+impl SinkType {
+    fn uinput(model: &'static Model, new: fn(OpenedEventSource) -> Result<Box<dyn Sink>>) -> Self {
+        Self { name: model.name.to_string(), nodes: model.nodes(), new }
+    }
+}
+
+pub fn list_names() -> Vec<SinkType> {
     vec![
-        ("Gamepad device".to_string(), UinputSink::new),
+        SinkType::uinput(&model::XBOX_360, UinputSink::new),
+        SinkType::uinput(&model::DUALSHOCK_4, |source| UinputSink::with_model(&model::DUALSHOCK_4, source)),
+        SinkType::uinput(&model::DUALSENSE, |source| UinputSink::with_model(&model::DUALSENSE, source)),
+        SinkType {
+            name: "Keyboard and mouse device".to_string(),
+            nodes: vec![Node::KeyboardMouse],
+            new: DesktopSink::new,
+        },
     ]
 }
+
+/// Writes settings of a sink to `file`, making `dir` first if it isn't there yet.
+fn store(dir: &str, file: &Path, contents: &str) -> Result<(), String> {
+    let write = || {
+        fs::create_dir_all(dir)?;
+        fs::write(file, contents)
+    };
+    write().map_err(|e| format!("failed to store {}: {}", file.display(), e))
+}
+
+/// Removes stored settings, fine if there were none.
+fn forget(file: &Path) -> Result<(), String> {
+    match fs::remove_file(file) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(format!("failed to remove {}: {}", file.display(), e)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,154 @@
+use evdev::{AbsoluteAxisType, EventType, InputEvent, InputEventKind, Key};
+
+use crate::sink::Node;
+
+/// A controller the uinput sink pretends to be. The pipeline works in the
+/// units of the Xbox 360 pad, other models convert right before emitting.
+pub struct Model {
+    /// What `list_sink_types` calls it.
+    pub name: &'static str,
+    // name of the gamepad device, the source's name if there's none
+    pub device_name: Option<&'static str>,
+    pub vendor: u16,
+    pub product: u16,
+    pub keys: &'static [Key],
+    // ranges of sticks and triggers, hats always go -1..1
+    pub stick: (i32, i32),
+    pub trigger: (i32, i32),
+    // size of the touchpad, if it has one
+    pub touchpad: Option<(i32, i32)>,
+    pub motion: bool,
+}
+
+static XBOX_KEYS: [Key; 11] = [
+    Key::BTN_SOUTH, Key::BTN_EAST, Key::BTN_NORTH, Key::BTN_WEST,
+    Key::BTN_TL, Key::BTN_TR,
+    Key::BTN_SELECT, Key::BTN_START, Key::BTN_MODE,
+    Key::BTN_THUMBL, Key::BTN_THUMBR,
+];
+
+// what hid-playstation declares, L2 and R2 are buttons too
+static SONY_KEYS: [Key; 13] = [
+    Key::BTN_SOUTH, Key::BTN_EAST, Key::BTN_NORTH, Key::BTN_WEST,
+    Key::BTN_TL, Key::BTN_TR, Key::BTN_TL2, Key::BTN_TR2,
+    Key::BTN_SELECT, Key::BTN_START, Key::BTN_MODE,
+    Key::BTN_THUMBL, Key::BTN_THUMBR,
+];
+
+pub static XBOX_360: Model = Model {
+    name: "Gamepad device",
+    device_name: None,
+    vendor: 0x045e,
+    product: 0x028e,
+    keys: &XBOX_KEYS,
+    stick: (-32768, 32767),
+    trigger: (0, 255),
+    touchpad: None,
+    motion: false,
+};
+
+pub static DUALSHOCK_4: Model = Model {
+    name: "DualShock 4 device",
+    device_name: Some("Sony Interactive Entertainment Wireless Controller"),
+    vendor: 0x054c,
+    product: 0x09cc,
+    keys: &SONY_KEYS,
+    stick: (0, 255),
+    trigger: (0, 255),
+    touchpad: Some((1920, 942)),
+    motion: true,
+};
+
+pub static DUALSENSE: Model = Model {
+    name: "DualSense device",
+    device_name: Some("Sony Interactive Entertainment DualSense Wireless Controller"),
+    vendor: 0x054c,
+    product: 0x0ce6,
+    keys: &SONY_KEYS,
+    stick: (0, 255),
+    trigger: (0, 255),
+    touchpad: Some((1920, 1080)),
+    motion: true,
+};
+
+/// Maps a value linearly from one range onto another.
+pub fn rescale(value: i32, (from_min, from_max): (i32, i32), (to_min, to_max): (i32, i32)) -> i32 {
+    if from_max <= from_min {
+        return to_min;
+    }
+    let off = (value as i64 - from_min as i64) * (to_max as i64 - to_min as i64);
+    let half = (from_max as i64 - from_min as i64) / 2;
+    (to_min as i64 + (off + half) / (from_max as i64 - from_min as i64)).clamp(to_min as i64, to_max as i64) as i32
+}
+
+impl Model {
+    /// Devices a sink of this model creates.
+    pub fn nodes(&self) -> Vec<Node> {
+        let mut ret = vec![Node::Gamepad];
+        if self.touchpad.is_some() {
+            ret.push(Node::Touchpad);
+        }
+        if self.motion {
+            ret.push(Node::Motion);
+        }
+        ret
+    }
+
+    pub fn has_key(&self, key: Key) -> bool {
+        self.keys.contains(&key)
+    }
+
+    /// Converts an event in Xbox 360 units into this model's.
+    pub fn convert(&self, ev: InputEvent) -> InputEvent {
+        let (out, to) = match ev.kind() {
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X | AbsoluteAxisType::ABS_Y
+                | AbsoluteAxisType::ABS_RX | AbsoluteAxisType::ABS_RY) => (XBOX_360.stick, self.stick),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ) => (XBOX_360.trigger, self.trigger),
+            _ => return ev,
+        };
+        if to == out {
+            return ev;
+        }
+        InputEvent::new(EventType::ABSOLUTE, ev.code(), rescale(ev.value(), out, to))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rescale_keeps_the_ends_and_the_middle() {
+        let (stick, sony) = (XBOX_360.stick, DUALSHOCK_4.stick);
+        assert_eq!(rescale(-32768, stick, sony), 0);
+        assert_eq!(rescale(32767, stick, sony), 255);
+        assert_eq!(rescale(0, stick, sony), 128);
+        assert_eq!(rescale(0, sony, stick), -32768);
+        assert_eq!(rescale(255, sony, stick), 32767);
+        assert_eq!(rescale(128, sony, stick), 128);
+    }
+
+    #[test]
+    fn rescale_rounds_and_clamps() {
+        assert_eq!(rescale(1, (0, 3), (0, 10)), 3);
+        assert_eq!(rescale(2, (0, 3), (0, 10)), 7);
+        assert_eq!(rescale(-5, (0, 255), (0, 1023)), 0);
+        assert_eq!(rescale(300, (0, 255), (0, 1023)), 1023);
+        // extremes don't overflow
+        assert_eq!(rescale(i32::MAX, (i32::MIN, i32::MAX), (0, 255)), 255);
+        // no range to map from
+        assert_eq!(rescale(7, (5, 5), (0, 255)), 0);
+    }
+
+    #[test]
+    fn convert_only_touches_axes_with_other_ranges() {
+        let stick = InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, 32767);
+        assert_eq!(DUALSENSE.convert(stick).value(), 255);
+        assert_eq!(XBOX_360.convert(stick).value(), 32767);
+
+        let trigger = InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, 200);
+        assert_eq!(DUALSENSE.convert(trigger).value(), 200);
+        let hat = InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, -1);
+        assert_eq!(DUALSENSE.convert(hat).value(), -1);
+    }
+}
@@ -0,0 +1,402 @@
+use evdev::AbsoluteAxisType;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::{
+    sink::{forget, store},
+    source::OpenedEventSource,
+};
+
+/// Where response settings are kept, one file per source.
+static RESPONSE_DIR: &str = "/var/lib/rinputer/response";
+
+/// Sticks and triggers that can be set up, and the sink axes they drive.
+static CONTROLS: [(&str, &[AbsoluteAxisType]); 4] = [
+    ("left_stick", &[AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y]),
+    ("right_stick", &[AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY]),
+    ("left_trigger", &[AbsoluteAxisType::ABS_Z]),
+    ("right_trigger", &[AbsoluteAxisType::ABS_RZ]),
+];
+
+/// How deflection past the deadzone turns into output, both going 0..1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// `out = in ^ exponent`
+    Exponential(f64),
+    /// Straight lines through (0, 0), the points and (1, 1).
+    Points(Vec<(f64, f64)>),
+}
+
+impl Curve {
+    fn apply(&self, t: f64) -> f64 {
+        match self {
+            Curve::Linear => t,
+            Curve::Exponential(exp) => t.powf(*exp),
+            Curve::Points(points) => {
+                let mut prev = (0.0, 0.0);
+                for &(x, y) in points.iter().chain([(1.0, 1.0)].iter()) {
+                    if t <= x {
+                        return if x > prev.0 { prev.1 + (t - prev.0) * (y - prev.1) / (x - prev.0) } else { y };
+                    }
+                    prev = (x, y);
+                }
+                1.0
+            },
+        }
+    }
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Curve::Linear => write!(f, "linear"),
+            Curve::Exponential(exp) => write!(f, "exp:{}", exp),
+            Curve::Points(points) => {
+                let points = points.iter()
+                    .map(|(x, y)| format!("{}:{}", x, y))
+                    .collect::<Vec<String>>();
+                write!(f, "points:{}", points.join(","))
+            },
+        }
+    }
+}
+
+/// `linear`, `exp:<exponent>` or `points:<x>:<y>,<x>:<y>...` with both going 0..1.
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "linear" {
+            return Ok(Curve::Linear);
+        }
+        if let Some(exp) = s.strip_prefix("exp:") {
+            return match exp.parse::<f64>() {
+                Ok(exp) if exp > 0.0 && exp.is_finite() => Ok(Curve::Exponential(exp)),
+                _ => Err(format!("invalid exponent: {}", exp)),
+            };
+        }
+        if let Some(points) = s.strip_prefix("points:") {
+            let mut ret: Vec<(f64, f64)> = Vec::new();
+            for point in points.split(',') {
+                let parsed = point.split_once(':')
+                    .and_then(|(x, y)| x.parse::<f64>().ok().zip(y.parse::<f64>().ok()))
+                    .filter(|(x, y)| (0.0..=1.0).contains(x) && (0.0..=1.0).contains(y));
+                let Some((x, y)) = parsed else {
+                    return Err(format!("invalid point, expected <x>:<y> within 0..1: {}", point));
+                };
+                if ret.last().is_some_and(|(last, _)| *last >= x) {
+                    return Err("points have to go left to right".to_string());
+                }
+                ret.push((x, y));
+            }
+            return Ok(Curve::Points(ret));
+        }
+        Err(format!("unknown curve: {}, expected linear, exp:<exponent> or points:<x>:<y>,...", s))
+    }
+}
+
+/// Response of one stick or trigger, distances go 0..1 from rest to full deflection.
+#[derive(Clone, Debug)]
+struct Settings {
+    deadzone: f64,
+    // the deadzone of sticks goes by distance from the center, not per axis
+    radial: bool,
+    // output right past the deadzone, games have deadzones of their own
+    anti_deadzone: f64,
+    // deflection that already counts as full, worn sticks don't reach the edge
+    saturation: f64,
+    curve: Curve,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.0,
+            radial: true,
+            anti_deadzone: 0.0,
+            saturation: 1.0,
+            curve: Curve::Linear,
+        }
+    }
+}
+
+impl Settings {
+    fn shape(&self, dist: f64) -> f64 {
+        if dist <= self.deadzone {
+            return 0.0;
+        }
+        let t = ((dist.min(self.saturation) - self.deadzone) / (self.saturation - self.deadzone)).clamp(0.0, 1.0);
+        self.anti_deadzone + (1.0 - self.anti_deadzone) * self.curve.apply(t)
+    }
+
+    fn set(&mut self, setting: &str, value: &str) -> Result<(), String> {
+        let fraction = || match value.parse::<f64>() {
+            Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+            _ => Err(format!("{} takes a number within 0..1, got {}", setting, value)),
+        };
+        let mut new = self.clone();
+        match setting {
+            "deadzone" => new.deadzone = fraction()?,
+            "deadzone_mode" => new.radial = match value {
+                "radial" => true,
+                "axial" => false,
+                _ => return Err(format!("deadzone_mode is radial or axial, got {}", value)),
+            },
+            "anti_deadzone" => new.anti_deadzone = fraction()?,
+            "saturation" => new.saturation = fraction()?,
+            "curve" => new.curve = value.parse()?,
+            _ => return Err(format!("no such setting: {}, expected deadzone, deadzone_mode, anti_deadzone, saturation or curve", setting)),
+        }
+        if new.saturation <= new.deadzone {
+            return Err("saturation has to be past the deadzone".to_string());
+        }
+        if new.anti_deadzone >= 1.0 {
+            return Err("anti_deadzone has to stay below 1".to_string());
+        }
+        *self = new;
+        Ok(())
+    }
+}
+
+// `<control>\t<deadzone>\t<radial|axial>\t<anti_deadzone>\t<saturation>\t<curve>`, both on disk and in replies
+fn parse_line(line: &str) -> Option<(usize, Settings)> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    let [name, deadzone, mode, anti_deadzone, saturation, curve] = fields[..] else {
+        return None;
+    };
+    let control = CONTROLS.iter().position(|(n, _)| *n == name)?;
+    let mut settings = Settings::default();
+    for (setting, value) in [("deadzone", deadzone), ("deadzone_mode", mode), ("anti_deadzone", anti_deadzone),
+                             ("saturation", saturation), ("curve", curve)] {
+        settings.set(setting, value).ok()?;
+    }
+    Some((control, settings))
+}
+
+// sink units to -1..1 (or 0..1 for outputs that don't go below zero) and back
+fn normalize(value: i32, (out_min, out_max): (i32, i32)) -> f64 {
+    if out_min >= 0 {
+        (value - out_min) as f64 / (out_max - out_min) as f64
+    } else if value >= 0 {
+        value as f64 / out_max as f64
+    } else {
+        value as f64 / -out_min as f64
+    }
+}
+
+fn denormalize(value: f64, (out_min, out_max): (i32, i32)) -> i32 {
+    let ret = if out_min >= 0 {
+        out_min as f64 + value * (out_max - out_min) as f64
+    } else if value >= 0.0 {
+        value * out_max as f64
+    } else {
+        value * -out_min as f64
+    };
+    (ret.round() as i32).clamp(out_min, out_max)
+}
+
+/// Deadzones and response curves of a sink's source, applied to calibrated values.
+pub struct Response {
+    file: PathBuf,
+    controls: [Settings; 4],
+    // last calibrated value per axis, radial deadzones need the other axis of the stick
+    last: BTreeMap<u16, f64>,
+}
+
+impl Response {
+    fn new(file: PathBuf) -> Self {
+        Self { file, controls: Default::default(), last: BTreeMap::new() }
+    }
+
+    pub fn load(source: &OpenedEventSource) -> Self {
+        let mut ret = Self::new(PathBuf::from(RESPONSE_DIR).join(source.storage_key()));
+        if let Ok(stored) = fs::read_to_string(&ret.file) {
+            for (control, settings) in stored.lines().filter_map(parse_line) {
+                ret.controls[control] = settings;
+            }
+        }
+        ret
+    }
+
+    /// No deadzones or curves, stored nowhere.
+    #[cfg(test)]
+    fn unsaved() -> Self {
+        Self::new(PathBuf::new())
+    }
+
+    /// Shapes a calibrated value in sink units, `out` being the range of the axis.
+    /// A stick with a radial deadzone changes both of its axes at once.
+    pub fn apply(&mut self, axis: AbsoluteAxisType, value: i32, out: (i32, i32)) -> Vec<(AbsoluteAxisType, i32)> {
+        let Some(control) = CONTROLS.iter().position(|(_, axes)| axes.contains(&axis)) else {
+            return vec![(axis, value)];
+        };
+        let settings = &self.controls[control];
+        let value = normalize(value, out);
+        self.last.insert(axis.0, value);
+
+        let axes = CONTROLS[control].1;
+        if settings.radial && axes.len() == 2 {
+            let (x, y) = (self.last.get(&axes[0].0).copied().unwrap_or(0.0), self.last.get(&axes[1].0).copied().unwrap_or(0.0));
+            let dist = x.hypot(y);
+            let scale = if dist > 0.0 { settings.shape(dist) / dist } else { 0.0 };
+            return vec![
+                (axes[0], denormalize((x * scale).clamp(-1.0, 1.0), out)),
+                (axes[1], denormalize((y * scale).clamp(-1.0, 1.0), out)),
+            ];
+        }
+        vec![(axis, denormalize(value.signum() * settings.shape(value.abs()), out))]
+    }
+
+    pub fn set(&mut self, control: &str, setting: &str, value: &str) -> Result<(), String> {
+        let Some(i) = CONTROLS.iter().position(|(name, _)| *name == control) else {
+            return Err(format!("no such control: {}, expected one of left_stick, right_stick, left_trigger, right_trigger", control));
+        };
+        self.controls[i].set(setting, value)?;
+        self.save()
+    }
+
+    /// Back to no deadzones and linear response, forgetting the stored settings.
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.controls = Default::default();
+        forget(&self.file)
+    }
+
+    /// One `<control>\t<deadzone>\t<radial|axial>\t<anti_deadzone>\t<saturation>\t<curve>` line per stick and trigger.
+    pub fn describe(&self) -> String {
+        CONTROLS.iter().zip(self.controls.iter())
+            .map(|((name, _), s)| format!("{}\t{}\t{}\t{}\t{}\t{}",
+                name, s.deadzone, if s.radial { "radial" } else { "axial" }, s.anti_deadzone, s.saturation, s.curve))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn save(&self) -> Result<(), String> {
+        store(RESPONSE_DIR, &self.file, &(self.describe() + "\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn curves_parse() {
+        assert_eq!("linear".parse(), Ok(Curve::Linear));
+        assert_eq!("exp:2.5".parse(), Ok(Curve::Exponential(2.5)));
+        assert_eq!("points:0.25:0.1,0.75:0.9".parse(), Ok(Curve::Points(vec![(0.25, 0.1), (0.75, 0.9)])));
+
+        for bad in ["", "cubic", "exp:", "exp:0", "exp:-1", "exp:inf", "exp:NaN", "points:", "points:0.5",
+                    "points:0.5:1.5", "points:-0.1:0.5", "points:0.5:0.5,0.5:0.6", "points:0.6:0.5,0.5:0.6"] {
+            assert!(bad.parse::<Curve>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn curves_display_as_they_parse() {
+        for curve in [Curve::Linear, Curve::Exponential(1.5), Curve::Points(vec![(0.2, 0.0), (0.5, 0.8)])] {
+            assert_eq!(curve.to_string().parse(), Ok(curve));
+        }
+    }
+
+    #[test]
+    fn points_are_joined_by_straight_lines() {
+        let curve: Curve = "points:0.5:0.2".parse().unwrap();
+        assert!(close(curve.apply(0.0), 0.0));
+        assert!(close(curve.apply(0.25), 0.1));
+        assert!(close(curve.apply(0.5), 0.2));
+        assert!(close(curve.apply(0.75), 0.6));
+        assert!(close(curve.apply(1.0), 1.0));
+
+        assert!(close(Curve::Exponential(2.0).apply(0.5), 0.25));
+    }
+
+    #[test]
+    fn shape_applies_deadzone_saturation_and_anti_deadzone() {
+        let mut settings = Settings::default();
+        assert!(close(settings.shape(0.3), 0.3));
+
+        settings.set("deadzone", "0.2").unwrap();
+        settings.set("saturation", "0.6").unwrap();
+        assert!(close(settings.shape(0.1), 0.0));
+        assert!(close(settings.shape(0.2), 0.0));
+        assert!(close(settings.shape(0.4), 0.5));
+        assert!(close(settings.shape(0.6), 1.0));
+        assert!(close(settings.shape(0.9), 1.0));
+
+        settings.set("anti_deadzone", "0.5").unwrap();
+        assert!(close(settings.shape(0.2), 0.0));
+        assert!(close(settings.shape(0.4), 0.75));
+        assert!(close(settings.shape(1.0), 1.0));
+    }
+
+    #[test]
+    fn contradicting_settings_are_refused() {
+        let mut settings = Settings::default();
+        settings.set("saturation", "0.5").unwrap();
+        assert!(settings.set("deadzone", "0.5").is_err());
+        assert!(settings.set("anti_deadzone", "1").is_err());
+        assert!(settings.set("deadzone", "2").is_err());
+        assert!(settings.set("deadzone_mode", "square").is_err());
+        assert!(settings.set("wobble", "1").is_err());
+        assert!(close(settings.deadzone, 0.0) && close(settings.anti_deadzone, 0.0));
+    }
+
+    #[test]
+    fn described_lines_parse_back() {
+        let mut response = Response::unsaved();
+        response.controls[1].set("deadzone", "0.1").unwrap();
+        response.controls[1].set("deadzone_mode", "axial").unwrap();
+        response.controls[1].set("curve", "exp:2").unwrap();
+
+        let parsed = response.describe().lines().map(|line| parse_line(line).unwrap()).collect::<Vec<(usize, Settings)>>();
+        assert_eq!(parsed.len(), CONTROLS.len());
+        let (control, settings) = &parsed[1];
+        assert_eq!(*control, 1);
+        assert!(close(settings.deadzone, 0.1) && !settings.radial && settings.curve == Curve::Exponential(2.0));
+
+        assert!(parse_line("left_stick\t0.1\tradial\t0\t1").is_none());
+        assert!(parse_line("left_paddle\t0.1\tradial\t0\t1\tlinear").is_none());
+        assert!(parse_line("left_stick\t0.9\tradial\t0\t0.5\tlinear").is_none());
+    }
+
+    #[test]
+    fn radial_deadzones_move_both_axes() {
+        let mut response = Response::unsaved();
+        response.controls[0].set("deadzone", "0.5").unwrap();
+        let out = (-32768, 32767);
+
+        // each axis alone is inside, together they're out
+        assert_eq!(response.apply(AbsoluteAxisType::ABS_X, 13107, out), vec![(AbsoluteAxisType::ABS_X, 0), (AbsoluteAxisType::ABS_Y, 0)]);
+        // past the edge, kept on the circle in the same direction
+        let moved = response.apply(AbsoluteAxisType::ABS_Y, -32768, out);
+        let (x, y) = (normalize(moved[0].1, out), normalize(moved[1].1, out));
+        assert!((x.hypot(y) - 1.0).abs() < 1e-3, "{:?}", moved);
+        assert!((x / y + 0.4).abs() < 1e-3, "{:?}", moved);
+
+        // triggers go 0..255 and only have the one axis
+        response.controls[2].set("deadzone", "0.5").unwrap();
+        assert_eq!(response.apply(AbsoluteAxisType::ABS_Z, 100, (0, 255)), vec![(AbsoluteAxisType::ABS_Z, 0)]);
+        assert_eq!(response.apply(AbsoluteAxisType::ABS_Z, 255, (0, 255)), vec![(AbsoluteAxisType::ABS_Z, 255)]);
+    }
+
+    #[test]
+    fn normalizing_keeps_the_ends() {
+        for out in [(-32768, 32767), (0, 255)] {
+            for value in [out.0, 0, out.1] {
+                assert_eq!(denormalize(normalize(value, out), out), value);
+            }
+        }
+        assert!(close(normalize(-32768, (-32768, 32767)), -1.0));
+        assert!(close(normalize(32767, (-32768, 32767)), 1.0));
+    }
+}
@@ -1,8 +1,29 @@
 use crate::{
-    sink::Sink,
-    source::{OpenedEventSource, SourceCaps},
+    profile::{self, Mapper, Selection},
+    sink::{
+        calibration::Calibration,
+        ff::{self, Rumble},
+        gyro::{Aim, GyroAim},
+        model::{self, Model},
+        response::Response,
+        Node,
+        Sink,
+    },
+    source::{
+        motion::{ACCEL_RES_PER_G, GYRO_RES_PER_DEG_S},
+        OpenedEventSource,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+        Mutex,
+        Weak,
+    },
 };
-use std::sync::Arc;
 use evdev::{
     uinput::{
         VirtualDeviceBuilder,
@@ -10,19 +31,37 @@ use evdev::{
     },
     UinputAbsSetup,
     AbsInfo,
+    AttributeSet,
     Key,
     InputId,
     AbsoluteAxisType,
+    RelativeAxisType,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    MiscType,
+    PropType,
+    Synchronization,
 };
 use anyhow::Result;
 
 pub struct UinputSink {
+    model: &'static Model,
     source_name: String,
-    source_caps: SourceCaps,
+    calibration: Arc<Mutex<Calibration>>,
+    response: Arc<Mutex<Response>>,
+    profile: Arc<Mutex<Selection>>,
+    gyro: Option<Arc<Mutex<GyroAim>>>,
+    nodes: Vec<(Node, PathBuf)>,
+    // motion node of a source without motion sensors, nothing feeds it
+    _motion: Option<VirtualDevice>,
     _ptr: Arc<()>,
     //todo
 }
 
+/// Version of every device rinputer4 creates, so it doesn't take them as sources.
+pub static OWN_VERSION: u16 = 0x2137;
+
 static MAX_OUT_ANALOG: i32 = 32767;
 static MIN_OUT_ANALOG: i32 = -32768;
 
@@ -32,46 +71,339 @@ static MAX_OUT_HAT: i32 = 1;
 static MIN_OUT_TRIG: i32 = 0;
 static MAX_OUT_TRIG: i32 = 255;
 
-fn sink_worker(src: OpenedEventSource, mut dst: VirtualDevice, ptr: Arc<()>) {
+fn out_range(axis: AbsoluteAxisType) -> Option<(i32, i32)> {
+    match axis {
+        AbsoluteAxisType::ABS_X | AbsoluteAxisType::ABS_Y
+        | AbsoluteAxisType::ABS_RX | AbsoluteAxisType::ABS_RY => Some((MIN_OUT_ANALOG, MAX_OUT_ANALOG)),
+        AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ => Some((MIN_OUT_TRIG, MAX_OUT_TRIG)),
+        AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y => Some((MIN_OUT_HAT, MAX_OUT_HAT)),
+        _ => None,
+    }
+}
+
+// what hid-playstation declares for the motion sensors
+static ACCEL_RANGE: i32 = 32768;
+static GYRO_RANGE: i32 = 2097152;
+
+pub(super) struct Pipeline {
+    pub(super) calibration: Arc<Mutex<Calibration>>,
+    pub(super) response: Arc<Mutex<Response>>,
+    pub(super) mapper: Mapper,
+}
+
+fn is_touch(ev: InputEvent) -> bool {
+    match ev.kind() {
+        InputEventKind::AbsAxis(axis) => (AbsoluteAxisType::ABS_MT_SLOT.0..=AbsoluteAxisType::ABS_MT_TOOL_Y.0).contains(&axis.0),
+        InputEventKind::Key(key) => matches!(key, Key::BTN_TOUCH | Key::BTN_TOOL_FINGER | Key::BTN_TOOL_DOUBLETAP | Key::BTN_LEFT),
+        _ => false,
+    }
+}
+
+/// Touchpad node of a sink, fed by the source's touch events.
+struct Touchpad {
+    dev: VirtualDevice,
+    size: (i32, i32),
+    // raw ranges of the source's touch positions
+    ranges: BTreeMap<u16, (i32, i32)>,
+    slot: i32,
+    frame: Vec<InputEvent>,
+}
+
+impl Touchpad {
+    fn new(name: &str, size: (i32, i32), input_id: InputId, source: &OpenedEventSource) -> Result<Self> {
+        let mut keys = AttributeSet::<Key>::new();
+        for key in [Key::BTN_LEFT, Key::BTN_TOUCH, Key::BTN_TOOL_FINGER, Key::BTN_TOOL_DOUBLETAP] {
+            keys.insert(key);
+        }
+        let mut props = AttributeSet::<PropType>::new();
+        props.insert(PropType::POINTER);
+        props.insert(PropType::BUTTONPAD);
+
+        let x = AbsInfo::new(0, 0, size.0 - 1, 0, 0, 0);
+        let y = AbsInfo::new(0, 0, size.1 - 1, 0, 0, 0);
+        let dev = VirtualDeviceBuilder::new()?
+            .name(format!("{} Touchpad", name).as_bytes())
+            .input_id(input_id)
+            .with_keys(&keys)?
+            .with_properties(&props)?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_X, x))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, y))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_MT_SLOT, AbsInfo::new(0, 0, 1, 0, 0, 0)))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_MT_TRACKING_ID, AbsInfo::new(0, 0, 65535, 0, 0, 0)))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_MT_POSITION_X, x))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_MT_POSITION_Y, y))?
+            .build()?;
+
+        Ok(Self {
+            dev,
+            size,
+            ranges: source.axes.iter()
+                .filter(|(axis, _)| matches!(*axis, AbsoluteAxisType::ABS_MT_POSITION_X | AbsoluteAxisType::ABS_MT_POSITION_Y))
+                .map(|(axis, info)| (axis.0, (info.minimum(), info.maximum())))
+                .collect(),
+            slot: 0,
+            frame: Vec::new(),
+        })
+    }
+
+    /// Collects a touch event for the next frame, `false` for anything else.
+    fn take(&mut self, ev: InputEvent) -> bool {
+        if !is_touch(ev) {
+            return false;
+        }
+        match ev.kind() {
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_SLOT) => self.slot = ev.value(),
+            InputEventKind::AbsAxis(axis @ (AbsoluteAxisType::ABS_MT_POSITION_X | AbsoluteAxisType::ABS_MT_POSITION_Y)) => {
+                let (single, size) = match axis {
+                    AbsoluteAxisType::ABS_MT_POSITION_X => (AbsoluteAxisType::ABS_X, self.size.0),
+                    _ => (AbsoluteAxisType::ABS_Y, self.size.1),
+                };
+                let value = match self.ranges.get(&axis.0) {
+                    Some(range) => model::rescale(ev.value(), *range, (0, size - 1)),
+                    None => ev.value(),
+                };
+                self.frame.push(InputEvent::new(EventType::ABSOLUTE, axis.0, value));
+                // the pointer follows the first finger, as with real touchpads
+                if self.slot == 0 {
+                    self.frame.push(InputEvent::new(EventType::ABSOLUTE, single.0, value));
+                }
+                return true;
+            },
+            _ => (),
+        }
+        self.frame.push(ev);
+        true
+    }
+
+    fn flush(&mut self) {
+        if !self.frame.is_empty() {
+            if let Err(e) = self.dev.emit(&self.frame) {
+                eprintln!("Failed to emit touchpad events: {}", e);
+            }
+            self.frame.clear();
+        }
+    }
+}
+
+fn motion_node(name: &str, input_id: InputId) -> Result<VirtualDevice> {
+    let mut props = AttributeSet::<PropType>::new();
+    props.insert(PropType::ACCELEROMETER);
+    let mut msc = AttributeSet::<MiscType>::new();
+    msc.insert(MiscType::MSC_TIMESTAMP);
+
+    let accel = AbsInfo::new(0, -ACCEL_RANGE, ACCEL_RANGE - 1, 16, 0, ACCEL_RES_PER_G);
+    let gyro = AbsInfo::new(0, -GYRO_RANGE, GYRO_RANGE - 1, 16, 0, GYRO_RES_PER_DEG_S);
+    Ok(VirtualDeviceBuilder::new()?
+        .name(format!("{} Motion Sensors", name).as_bytes())
+        .input_id(input_id)
+        .with_properties(&props)?
+        .with_msc(&msc)?
+        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_X, accel))?
+        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, accel))?
+        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_Z, accel))?
+        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_RX, gyro))?
+        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_RY, gyro))?
+        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_RZ, gyro))?
+        .build()?)
+}
+
+// moved by gyro aiming
+fn mouse_node(name: &str, input_id: InputId) -> Result<VirtualDevice> {
+    // without buttons it isn't taken for a mouse
+    let mut keys = AttributeSet::<Key>::new();
+    keys.insert(Key::BTN_LEFT);
+    keys.insert(Key::BTN_RIGHT);
+    let mut rel = AttributeSet::<RelativeAxisType>::new();
+    rel.insert(RelativeAxisType::REL_X);
+    rel.insert(RelativeAxisType::REL_Y);
+    Ok(VirtualDeviceBuilder::new()?
+        .name(format!("{} Gyro Mouse", name).as_bytes())
+        .input_id(input_id)
+        .with_keys(&keys)?
+        .with_relative_axes(&rel)?
+        .build()?)
+}
+
+// /dev/input/event* of a device, or its sysfs path if udev hasn't made one
+pub(super) fn dev_node(dev: &mut VirtualDevice) -> PathBuf {
+    if let Some(Ok(node)) = dev.enumerate_dev_nodes_blocking().ok().and_then(|mut nodes| nodes.next()) {
+        return node;
+    }
+    dev.get_syspath().unwrap_or_default()
+}
+
+/// Devices of a sink events go out through.
+struct Outputs {
+    model: &'static Model,
+    gamepad: Arc<Mutex<VirtualDevice>>,
+    touchpad: Option<Touchpad>,
+    gyro: Option<Arc<Mutex<GyroAim>>>,
+}
+
+impl Outputs {
+    /// Sends events in Xbox 360 units to the device they belong to.
+    fn emit(&mut self, events: &[InputEvent]) {
+        let mut gamepad = Vec::new();
+        let mut gyro = self.gyro.as_ref().map(|gyro| gyro.lock().unwrap());
+        for ev in events.iter().copied() {
+            let ev = match gyro.as_mut() {
+                Some(gyro) => gyro.mix(ev),
+                None => ev,
+            };
+            if let Some(touchpad) = self.touchpad.as_mut() {
+                if touchpad.take(ev) {
+                    continue;
+                }
+                if ev.kind() == InputEventKind::Synchronization(Synchronization::SYN_REPORT) {
+                    touchpad.flush();
+                }
+            }
+            // models with L2 and R2 buttons press them as soon as the trigger moves,
+            // the kernel drops the ones that don't change anything
+            let trigger_key = match ev.kind() {
+                InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Z) => Some(Key::BTN_TL2),
+                InputEventKind::AbsAxis(AbsoluteAxisType::ABS_RZ) => Some(Key::BTN_TR2),
+                _ => None,
+            };
+            if let Some(key) = trigger_key.filter(|key| self.model.has_key(*key)) {
+                gamepad.push(InputEvent::new(EventType::KEY, key.code(), (ev.value() > 0) as i32));
+            }
+            gamepad.push(self.model.convert(ev));
+        }
+        if !gamepad.is_empty() {
+            if let Err(e) = self.gamepad.lock().unwrap().emit(&gamepad) {
+                eprintln!("Failed to emit gamepad events: {}", e);
+            }
+        }
+    }
+}
+
+/// Where a sink's motion frames go.
+struct MotionOutputs {
+    model: &'static Model,
+    // models with motion sensors pass them on
+    motion: Option<VirtualDevice>,
+    gamepad: Arc<Mutex<VirtualDevice>>,
+    mouse: VirtualDevice,
+    gyro: Arc<Mutex<GyroAim>>,
+}
+
+fn motion_worker(src: Receiver<InputEvent>, mut dst: MotionOutputs, ptr: Weak<()>) {
+    let mut frame = Vec::new();
+    for ev in src.iter() {
+        // if the UinputSink was dropped quit
+        if ptr.strong_count() == 0 {
+            return;
+        }
+        let aim = dst.gyro.lock().unwrap().feed(ev);
+        if ev.kind() != InputEventKind::Synchronization(Synchronization::SYN_REPORT) {
+            frame.push(ev);
+            continue;
+        }
+        if let Some(motion) = dst.motion.as_mut() {
+            if !frame.is_empty() {
+                if let Err(e) = motion.emit(&frame) {
+                    eprintln!("Failed to emit motion events: {}", e);
+                    return;
+                }
+            }
+        }
+        frame.clear();
+        match aim {
+            Some(Aim::Stick(x, y)) => {
+                let stick = [
+                    dst.model.convert(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, x)),
+                    dst.model.convert(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, y)),
+                ];
+                if let Err(e) = dst.gamepad.lock().unwrap().emit(&stick) {
+                    eprintln!("Failed to emit gyro stick events: {}", e);
+                    return;
+                }
+            },
+            Some(Aim::Mouse(x, y)) if x != 0 || y != 0 => {
+                let rel = [
+                    InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, x),
+                    InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, y),
+                ];
+                if let Err(e) = dst.mouse.emit(&rel) {
+                    eprintln!("Failed to emit gyro mouse events: {}", e);
+                    return;
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+fn sink_worker(src: OpenedEventSource, mut dst: Outputs, mut pipeline: Pipeline, ptr: Arc<()>) {
     loop {
         // if the UinputSink was dropped quit
         if Arc::strong_count(&ptr) < 2 {
             return;
         }
-        match src.chan.recv() {
-            Ok(ev) => dst.emit(&[ev]).unwrap(),
-            Err(_) => return, // assume we got dropped
+        // turbo buttons need waking up without events coming in
+        let ev = match pipeline.mapper.timeout() {
+            Some(timeout) => match src.chan.recv_timeout(timeout) {
+                Ok(ev) => Some(ev),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match src.chan.recv() {
+                Ok(ev) => Some(ev),
+                Err(_) => return, // assume we got dropped
+            },
+        };
+
+        if let Some(ev) = ev {
+            let events = pipeline.process(ev);
+            dst.emit(&events);
+        }
+        let turbo = pipeline.mapper.tick();
+        if !turbo.is_empty() {
+            dst.emit(&turbo);
         }
     }
 }
 
-impl Sink for UinputSink {
-    fn name(&self) -> &'static str {
-        "Gamepad device"
+impl Pipeline {
+    // calibration, then deadzones and curves, then the profile
+    pub(super) fn process(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        let events = match ev.kind() {
+            InputEventKind::AbsAxis(axis) => match out_range(axis) {
+                Some(out) => {
+                    let value = self.calibration.lock().unwrap().map(axis, ev.value(), out);
+                    self.response.lock().unwrap().apply(axis, value, out).into_iter()
+                        .map(|(axis, value)| InputEvent::new(EventType::ABSOLUTE, axis.0, value))
+                        .collect()
+                },
+                None => vec![ev],
+            },
+            _ => vec![ev],
+        };
+        events.into_iter().flat_map(|ev| self.mapper.map(ev)).collect()
     }
-    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
-        let mut keys = evdev::AttributeSet::<Key>::new();
-        keys.insert(Key::BTN_SOUTH);
-        keys.insert(Key::BTN_EAST);
-        keys.insert(Key::BTN_NORTH);
-        keys.insert(Key::BTN_WEST);
-        keys.insert(Key::BTN_TL);
-        keys.insert(Key::BTN_TR);
-        keys.insert(Key::BTN_SELECT);
-        keys.insert(Key::BTN_START);
-        keys.insert(Key::BTN_MODE);
-        keys.insert(Key::BTN_THUMBL);
-        keys.insert(Key::BTN_THUMBR);
-
-        let input_id = InputId::new(evdev::BusType::BUS_USB, 0x045e, 0x028e, 0x2137);
-
-        let abs_analogs = AbsInfo::new(0, MIN_OUT_ANALOG, MAX_OUT_ANALOG, 16, 256, 0);
+}
+
+impl UinputSink {
+    pub fn with_model(model: &'static Model, mut source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+        let mut keys = AttributeSet::<Key>::new();
+        for key in model.keys {
+            keys.insert(*key);
+        }
+
+        // every node has the same ids, games pair them up by that
+        let input_id = InputId::new(evdev::BusType::BUS_USB, model.vendor, model.product, OWN_VERSION);
+
+        let (stick_min, stick_max) = model.stick;
+        // same deadzone share of the range whatever the model
+        let stick_flat = (stick_max - stick_min + 1) / 256;
+        let abs_analogs = AbsInfo::new((stick_min + stick_max + 1) / 2, stick_min, stick_max, stick_flat / 16, stick_flat, 0);
         let abs_x = UinputAbsSetup::new(AbsoluteAxisType::ABS_X, abs_analogs);
         let abs_y = UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, abs_analogs);
         let abs_rx = UinputAbsSetup::new(AbsoluteAxisType::ABS_RX, abs_analogs);
         let abs_ry = UinputAbsSetup::new(AbsoluteAxisType::ABS_RY, abs_analogs);
 
-        let abs_triggers = AbsInfo::new(0, MIN_OUT_TRIG, MAX_OUT_TRIG, 0, 0, 0);
+        let abs_triggers = AbsInfo::new(model.trigger.0, model.trigger.0, model.trigger.1, 0, 0, 0);
         let abs_z = UinputAbsSetup::new(AbsoluteAxisType::ABS_Z, abs_triggers);
         let abs_rz = UinputAbsSetup::new(AbsoluteAxisType::ABS_RZ, abs_triggers);
 
@@ -79,9 +411,10 @@ impl Sink for UinputSink {
         let abs_hat_x = UinputAbsSetup::new(AbsoluteAxisType::ABS_HAT0X, abs_hat);
         let abs_hat_y = UinputAbsSetup::new(AbsoluteAxisType::ABS_HAT0Y, abs_hat);
 
-        let uinput_handle = VirtualDeviceBuilder::new().unwrap()
-            .name(source.name.as_str().as_bytes())
-            .input_id(input_id)
+        let name = model.device_name.unwrap_or(source.name.as_str());
+        let mut uinput_handle = VirtualDeviceBuilder::new()?
+            .name(name.as_bytes())
+            .input_id(input_id.clone())
             .with_keys(&keys)?
             .with_absolute_axis(&abs_x)?
             .with_absolute_axis(&abs_y)?
@@ -91,26 +424,111 @@ impl Sink for UinputSink {
             .with_absolute_axis(&abs_rz)?
             .with_absolute_axis(&abs_hat_x)?
             .with_absolute_axis(&abs_hat_y)?
-            .build().unwrap();
+            .with_ff(&ff::supported(&source.nodes))?
+            .with_ff_effects_max(ff::FF_EFFECTS_MAX)
+            .build()?;
+        let mut nodes = vec![(Node::Gamepad, dev_node(&mut uinput_handle))];
+        let uinput_handle = Arc::new(Mutex::new(uinput_handle));
 
-        // TODO: map abs axis values
+        let mut touchpad = match model.touchpad {
+            Some(size) => Some(Touchpad::new(name, size, input_id.clone(), &source)?),
+            None => None,
+        };
+        if let Some(touchpad) = touchpad.as_mut() {
+            nodes.push((Node::Touchpad, dev_node(&mut touchpad.dev)));
+        }
+        let mut motion = if model.motion { Some(motion_node(name, input_id.clone())?) } else { None };
+        if let Some(motion) = motion.as_mut() {
+            nodes.push((Node::Motion, dev_node(motion)));
+        }
+        // only opened now, sources that weren't picked leave them alone
+        let motion_src = source.motion.take().and_then(|open| open());
+        let mut mouse = match motion_src {
+            Some(_) => Some(mouse_node(name, input_id)?),
+            None => None,
+        };
+        if let Some(mouse) = mouse.as_mut() {
+            nodes.push((Node::Mouse, dev_node(mouse)));
+        }
+        let gyro = motion_src.as_ref().map(|_| Arc::new(Mutex::new(GyroAim::load(&source))));
+
+        let calibration = Arc::new(Mutex::new(Calibration::load(&source)));
+        let response = Arc::new(Mutex::new(Response::load(&source)));
+        let profile = profile::register();
+        let pipeline = Pipeline {
+            calibration: Arc::clone(&calibration),
+            response: Arc::clone(&response),
+            mapper: Mapper::new(Arc::clone(&profile)),
+        };
+        let outputs = Outputs {
+            model,
+            gamepad: Arc::clone(&uinput_handle),
+            touchpad,
+            gyro: gyro.clone(),
+        };
 
         let ptr = Arc::new(());
         let ptr2 = Arc::clone(&ptr);
 
+        // the motion node goes to the worker feeding it
+        let motion_outputs = match (mouse, gyro.as_ref()) {
+            (Some(mouse), Some(gyro)) => Some(MotionOutputs {
+                model,
+                motion: motion.take(),
+                gamepad: Arc::clone(&uinput_handle),
+                mouse,
+                gyro: Arc::clone(gyro),
+            }),
+            _ => None,
+        };
+
         let out = Box::new(UinputSink{
+            model,
             source_name: source.name.clone(),
-            source_caps: source.caps,
+            calibration,
+            response,
+            profile,
+            gyro,
+            nodes,
+            _motion: motion,
             _ptr: ptr,
         });
 
-        std::thread::spawn(|| sink_worker(source, uinput_handle, ptr2));
+        let rumble = Rumble::new(&source.nodes);
+        let ptr3 = Arc::downgrade(&ptr2);
+        std::thread::spawn(move || ff::worker(uinput_handle, rumble, ptr3));
+        if let (Some(src), Some(dst)) = (motion_src, motion_outputs) {
+            let ptr4 = Arc::downgrade(&ptr2);
+            std::thread::spawn(move || motion_worker(src, dst, ptr4));
+        }
+        std::thread::spawn(|| sink_worker(source, outputs, pipeline, ptr2));
         Ok(out)
     }
+}
+
+impl Sink for UinputSink {
+    fn name(&self) -> &'static str {
+        self.model.name
+    }
+    fn new(source: OpenedEventSource) -> Result<Box<dyn Sink>> {
+        Self::with_model(&model::XBOX_360, source)
+    }
     fn source_name(&self) -> String {
         self.source_name.clone()
     }
-    fn source_caps(&self) -> SourceCaps {
-        self.source_caps
+    fn calibration(&self) -> Option<Arc<Mutex<Calibration>>> {
+        Some(Arc::clone(&self.calibration))
+    }
+    fn response(&self) -> Option<Arc<Mutex<Response>>> {
+        Some(Arc::clone(&self.response))
+    }
+    fn profile(&self) -> Option<Arc<Mutex<Selection>>> {
+        Some(Arc::clone(&self.profile))
+    }
+    fn gyro(&self) -> Option<Arc<Mutex<GyroAim>>> {
+        self.gyro.clone()
+    }
+    fn nodes(&self) -> Vec<(Node, PathBuf)> {
+        self.nodes.clone()
     }
 }
@@ -1,23 +1,43 @@
 use evdev::{
+    AbsInfo,
     Device,
     InputEvent,
     Key,
     AbsoluteAxisType,
+    EventType,
+    InputEventKind,
+    InputId,
+    Synchronization,
 };
+use crate::sink::uinput::OWN_VERSION;
 use crate::source::{
+    hotplug,
+    motion::{self, Motion, OpenMotion},
     EventSource,
     SourceCaps,
+    SourceId,
     quirks_db::{
         self,
+        DeviceMatch,
         InputRemap,
     },
 };
 use std::{
-    sync::mpsc::{channel, Sender, Receiver},
-    path::{Path, PathBuf},
+    io,
+    os::fd::AsRawFd,
+    sync::{
+        mpsc::{channel, Sender, Receiver},
+        Arc,
+        Mutex,
+    },
+    path::PathBuf,
     fs,
 };
 
+/// How often a worker checks whether it got bound and looks for nodes of
+/// its controller that showed up late, in milliseconds.
+const SIBLING_POLL_MS: i32 = 1000;
+
 fn usb_manufacturer_product(input: String) -> Option<String> {
     // input: usb-0000:09:00.3-3/input0
     if let Some(path_with_input) = input.strip_prefix("usb-") {
@@ -75,60 +95,137 @@ fn usb_manufacturer_product(input: String) -> Option<String> {
 #[derive(Debug)]
 enum EvdevQuirks {
     RemapCodes(InputRemap),
-    //MergeWithDevice(Device),
+    MergeWithDevice(DeviceMatch),
     OverrideName(String),
+    IioMotion,
 }
 
-fn get_device_quirks(dev: &Device, path: &Path) -> Vec<EvdevQuirks> {
+fn get_device_quirks(dev: &Device) -> Vec<EvdevQuirks> {
     let mut ret = Vec::new();
-    let dmi_quirk = quirks_db::get_dmi_quirk(path);
+    let quirks = quirks_db::get_quirks(dev);
 
-    if let Some(phys_path) = dev.physical_path() {
-        if dmi_quirk.is_some() {
-            let quirk = EvdevQuirks::OverrideName("Built-in Controller".to_string());
-            ret.push(quirk);
-        } else {
-            if let Some(name) = usb_manufacturer_product(phys_path.to_string()) {
-                let quirk = EvdevQuirks::OverrideName(name);
-                ret.push(quirk);
-            }
+    if let Some(name) = quirks.iter().find_map(|q| q.override_name.clone()) {
+        ret.push(EvdevQuirks::OverrideName(name));
+    } else if let Some(phys_path) = dev.physical_path() {
+        if let Some(name) = usb_manufacturer_product(phys_path.to_string()) {
+            ret.push(EvdevQuirks::OverrideName(name));
         }
     }
 
-    if let Some(actual_dmi_quirk) = dmi_quirk {
-        ret.extend(actual_dmi_quirk.remap_codes.into_iter()
-                   .map(|v| EvdevQuirks::RemapCodes(v)));
+    if quirks.iter().any(|q| q.iio_motion) {
+        ret.push(EvdevQuirks::IioMotion);
     }
 
-    if let Some(name) = dev.name() {
-        if name.contains("Left Joy-Con") {
-            let remaps = vec![
-                InputRemap::KeyToAbs(Key::BTN_TL2, AbsoluteAxisType::ABS_Z),
-                InputRemap::KeyToKey(Key::BTN_Z, Key::BTN_START),
+    for quirk in quirks {
+        ret.extend(quirk.remap_codes.into_iter().map(EvdevQuirks::RemapCodes));
+        ret.extend(quirk.merge_with.map(EvdevQuirks::MergeWithDevice));
+    }
 
-                InputRemap::KeyToAbs(Key::BTN_DPAD_LEFT, AbsoluteAxisType::ABS_HAT0X),
-                InputRemap::KeyToAbs(Key::BTN_DPAD_RIGHT, AbsoluteAxisType::ABS_HAT0X),
-                InputRemap::KeyToAbs(Key::BTN_DPAD_DOWN, AbsoluteAxisType::ABS_HAT0Y),
-                InputRemap::KeyToAbs(Key::BTN_DPAD_UP, AbsoluteAxisType::ABS_HAT0Y),
-            ];
-            ret.extend(remaps.into_iter().map(|v| EvdevQuirks::RemapCodes(v)));
-        } else {
-            let remaps = vec![
-                InputRemap::KeyToAbs(Key::BTN_TR2, AbsoluteAxisType::ABS_RZ),
-            ];
-            ret.extend(remaps.into_iter().map(|v| EvdevQuirks::RemapCodes(v)));
+    ret
+}
+
+// `usb-0000:09:00.3-3/input0` to `usb-0000:09:00.3-3`, the interfaces of one USB device
+fn phys_device(phys: &str) -> &str {
+    match phys.rsplit_once('/') {
+        Some((dev, input)) if input.starts_with("input") => dev,
+        _ => phys,
+    }
+}
+
+/// What a sibling rule looks at in a node.
+struct NodeInfo<'a> {
+    name: &'a str,
+    phys: &'a str,
+    uniq: &'a str,
+    id: InputId,
+    gamepad: bool,
+    motion: bool,
+}
+
+impl<'a> NodeInfo<'a> {
+    fn of(device: &'a Device) -> Self {
+        Self {
+            name: device.name().unwrap_or_default(),
+            phys: device.physical_path().unwrap_or_default(),
+            uniq: device.unique_name().unwrap_or_default(),
+            id: device.input_id(),
+            gamepad: is_gamepad(device),
+            motion: motion::is_motion(device),
         }
     }
+}
 
-    ret
+/// What makes another node part of the same controller: a quirk's
+/// merge_with, or being another interface of the same physical device,
+/// like the touchpad or the keyboard of a USB controller.
+#[derive(Clone)]
+pub(crate) struct SiblingRule {
+    phys: String,
+    uniq: String,
+    merge_with: Vec<DeviceMatch>,
+}
+
+impl SiblingRule {
+    fn of(device: &Device, merge_with: Vec<DeviceMatch>) -> Self {
+        Self {
+            phys: phys_device(device.physical_path().unwrap_or_default()).to_string(),
+            uniq: device.unique_name().unwrap_or_default().to_string(),
+            merge_with: quirks_db::on_this_machine(merge_with),
+        }
+    }
+
+    /// Controllers are sources of their own and motion nodes go with `Motion`.
+    pub(crate) fn matches(&self, device: &Device) -> bool {
+        self.fits(&NodeInfo::of(device))
+    }
+
+    fn fits(&self, node: &NodeInfo) -> bool {
+        if node.gamepad || node.motion || node.id.version() == OWN_VERSION {
+            return false;
+        }
+        if self.merge_with.iter().any(|m| m.fits_node(node.name, node.phys, &node.id)) {
+            return true;
+        }
+        !self.phys.is_empty() && phys_device(node.phys) == self.phys && node.uniq == self.uniq
+    }
+}
+
+/// Another node of the same controller, merged into its source.
+struct Sibling {
+    device: Device,
+    path: PathBuf,
+    // events of a frame that isn't complete yet
+    pending: Vec<InputEvent>,
+}
+
+impl Sibling {
+    fn new(path: PathBuf, device: Device) -> Self {
+        Self { device, path, pending: Vec::new() }
+    }
+}
+
+fn find_siblings(rule: &SiblingRule) -> Vec<Sibling> {
+    evdev::enumerate()
+        .filter(|(_, device)| rule.matches(device))
+        // fails if someone else merged it already
+        .filter_map(|(path, mut device)| device.grab().ok().map(|()| Sibling::new(path, device)))
+        .collect()
 }
 
 #[allow(dead_code)]
 pub struct Evdev {
     device: Device,
+    node: Arc<Mutex<PathBuf>>,
+    id: SourceId,
+    axes: Vec<(AbsoluteAxisType, AbsInfo)>,
     override_name: Option<String>,
     remap_events: Vec<InputRemap>,
-    sibling_device: Option<Device>,
+    // built into the machine, its motion comes from there
+    iio_motion: bool,
+    sibling_rule: SiblingRule,
+    siblings: Vec<Sibling>,
+    // events of a frame that isn't complete yet
+    pending: Vec<InputEvent>,
     tx: Sender<InputEvent>,
     rx: Option<Receiver<InputEvent>>,
 }
@@ -139,19 +236,33 @@ unsafe impl Sync for Evdev{}
 impl Drop for Evdev {
     fn drop(&mut self) {
         println!("Ungrabbing device");
-        self.device.ungrab().unwrap();
+        // a device that's gone can't be ungrabbed, and doesn't need to be
+        let _ = self.device.ungrab();
     }
 }
 
-impl Evdev {
-    fn new(path: PathBuf, mut device: Device) -> Option<Self> {
-        // check for gamepads
-        if !device.supported_keys().map_or(false, |k| k.contains(Key::BTN_SOUTH)) 
-        && !device.supported_keys().map_or(false, |k| k.contains(Key::BTN_THUMBL)) {
-            return None;
-        }
+/// Whether the device is a controller, and not one of our own virtual ones.
+pub(crate) fn is_gamepad(device: &Device) -> bool {
+    device.supported_keys().is_some_and(|k| k.contains(Key::BTN_SOUTH) || k.contains(Key::BTN_THUMBL))
+        && device.input_id().version() != OWN_VERSION
+}
+
+// the cached state only learns the ranges once it resyncs, ask the kernel
+fn axis_ranges(device: &Device) -> Vec<(AbsoluteAxisType, AbsInfo)> {
+    let (Some(axes), Ok(vals)) = (device.supported_absolute_axes(), device.get_abs_state()) else {
+        return Vec::new();
+    };
+    axes.iter()
+        .map(|axis| {
+            let info = &vals[axis.0 as usize];
+            (axis, AbsInfo::new(info.value, info.minimum, info.maximum, info.fuzz, info.flat, info.resolution))
+        })
+        .collect()
+}
 
-        if device.input_id().version() == 0x2137 {
+impl Evdev {
+    pub(crate) fn new(path: PathBuf, mut device: Device) -> Option<Self> {
+        if !is_gamepad(&device) {
             return None;
         }
 
@@ -160,23 +271,34 @@ impl Evdev {
 
         let mut override_name = None;
         let mut remap_events = Vec::new();
+        let mut iio_motion = false;
+        let mut merge_with = Vec::new();
 
-        let quirks = get_device_quirks(&device, &path);
+        let quirks = get_device_quirks(&device);
 
         for quirk in quirks {
             match quirk {
                 EvdevQuirks::RemapCodes(v)          => remap_events.push(v),
-                //EvdevQuirks::MergeWithDevice(_)   => todo!("merging with other input device"),
+                EvdevQuirks::MergeWithDevice(m)     => merge_with.push(m),
                 EvdevQuirks::OverrideName(new)      => override_name = Some(new),
+                EvdevQuirks::IioMotion              => iio_motion = true,
             };
         }
 
+        let sibling_rule = SiblingRule::of(&device, merge_with);
+
         let (tx, rx) = channel();
         Some(Self {
+            id: SourceId::of(&device),
+            axes: axis_ranges(&device),
             device,
+            node: Arc::new(Mutex::new(path)),
             override_name,
             remap_events,
-            sibling_device: None,
+            iio_motion,
+            sibling_rule,
+            siblings: Vec::new(),
+            pending: Vec::new(),
             tx,
             rx: Some(rx),
         })
@@ -198,21 +320,130 @@ pub fn enumerate() -> (Vec<Box<dyn EventSource>>, Receiver<Evdev>) {
     (ret, rx)
 }
 
+impl Evdev {
+    /// `false` once nobody listens anymore.
+    fn send(&self, ev: InputEvent) -> bool {
+        if let Some(new) = self.remap_events.iter().find_map(|v| v.apply_quirk(ev)) {
+            return self.tx.send(new).is_ok();
+        }
+        self.tx.send(ev).is_ok()
+    }
+
+    /// Lets go of every button and centers the axes, so nothing stays held
+    /// while the device is gone.
+    fn release_all(&self) -> bool {
+        let mut events = Vec::new();
+        for device in [&self.device].into_iter().chain(self.siblings.iter().map(|s| &s.device)) {
+            if let Some(keys) = device.cached_state().key_vals() {
+                events.extend(keys.iter().map(|k| InputEvent::new(EventType::KEY, k.code(), 0)));
+            }
+        }
+        for (axis, info) in &self.axes {
+            let rest = match *axis {
+                AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ => info.minimum(),
+                _ => (info.minimum() + info.maximum()) / 2,
+            };
+            events.push(InputEvent::new(EventType::ABSOLUTE, axis.0, rest));
+        }
+        events.push(InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0));
+        events.into_iter().all(|ev| self.send(ev))
+    }
+}
+
+// complete frames read from a device, the rest waits for the next read
+fn read_frames(device: &mut Device, pending: &mut Vec<InputEvent>) -> io::Result<Vec<Vec<InputEvent>>> {
+    pending.extend(device.fetch_events()?);
+    let mut frames = Vec::new();
+    while let Some(end) = pending.iter().position(|ev| ev.kind() == InputEventKind::Synchronization(Synchronization::SYN_REPORT)) {
+        frames.push(pending.drain(..=end).collect());
+    }
+    Ok(frames)
+}
+
+// blocks until one of the fds can be read or the timeout passes, which ones can is in revents
+fn poll(fds: &mut [libc::pollfd], timeout_ms: i32) -> io::Result<()> {
+    // SAFETY: fds is a valid array of fds.len() pollfds
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
 fn worker(mut dev: Evdev) {
-    let raw_dev = &mut dev.device;
-    let skip_remap = dev.remap_events.is_empty();
-    //let skip_mult = true; // TODO
+    // siblings are only grabbed once a sink has it, not while add_sink waits for L+R
+    let mut new_siblings = None;
     loop {
-        for ev in raw_dev.fetch_events().unwrap() {
-            if !skip_remap {
-                if let Some(new) = dev.remap_events.iter().find_map(|v| v.apply_quirk(ev)) {
-                    if dev.tx.send(new).is_err() {
+        if new_siblings.is_none() && hotplug::is_bound(&dev.id) {
+            // watched first, so nothing plugged in while looking is missed
+            new_siblings = Some(hotplug::watch_siblings(dev.id.clone(), dev.sibling_rule.clone()));
+            dev.siblings = find_siblings(&dev.sibling_rule);
+        }
+        for (path, device) in new_siblings.iter().flat_map(Receiver::try_iter) {
+            dev.siblings.push(Sibling::new(path, device));
+        }
+
+        let mut fds = [&dev.device].into_iter()
+            .chain(dev.siblings.iter().map(|s| &s.device))
+            .map(|device| libc::pollfd { fd: device.as_raw_fd(), events: libc::POLLIN, revents: 0 })
+            .collect::<Vec<libc::pollfd>>();
+        if let Err(e) = poll(&mut fds, SIBLING_POLL_MS) {
+            eprintln!("Failed to wait for events of {}: {}", dev.name(), e);
+            return;
+        }
+
+        let mut frames = Vec::new();
+        if fds[0].revents != 0 {
+            match read_frames(&mut dev.device, &mut dev.pending) {
+                Ok(read) => frames.extend(read),
+                Err(e) => {
+                    eprintln!("Lost {}: {}", dev.name(), e);
+                    if !dev.release_all() {
                         return;
                     }
-                    continue;
-                }
+                    // they're looked for again with the controller
+                    dev.siblings.clear();
+                    dev.pending.clear();
+                    // the sink keeps going meanwhile, games don't notice a short disconnect
+                    match hotplug::wait_for_return(&dev.id) {
+                        Some((path, device)) => {
+                            dev.device = device;
+                            *dev.node.lock().unwrap() = path;
+                            dev.siblings = find_siblings(&dev.sibling_rule);
+                            continue;
+                        },
+                        None => return,
+                    }
+                },
+            }
+        }
+        let mut i = 0;
+        dev.siblings.retain_mut(|sibling| {
+            i += 1;
+            if fds[i].revents == 0 {
+                return true;
+            }
+            match read_frames(&mut sibling.device, &mut sibling.pending) {
+                Ok(read) => {
+                    // the controller's own axes win, a touchpad's ABS_X isn't the left stick
+                    frames.extend(read.into_iter().map(|frame| frame.into_iter()
+                        .filter(|ev| !matches!(ev.kind(), InputEventKind::AbsAxis(axis) if dev.axes.iter().any(|(a, _)| *a == axis)))
+                        .collect::<Vec<InputEvent>>()));
+                    true
+                },
+                Err(e) => {
+                    eprintln!("Lost {}: {}", sibling.path.display(), e);
+                    false
+                },
             }
-            if dev.tx.send(ev).is_err() {
+        });
+
+        // in the order they happened whichever node they came from
+        frames.sort_by_key(|frame| frame.first().map(InputEvent::timestamp));
+        for ev in frames.into_iter().flatten() {
+            if !dev.send(ev) {
                 return;
             }
         }
@@ -240,6 +471,34 @@ impl EventSource for Evdev {
     fn path(self: &Evdev) -> String {
         self.device.physical_path().unwrap_or("Unknown").to_string()
     }
+    fn id(&self) -> SourceId {
+        self.id.clone()
+    }
+    fn node(&self) -> Arc<Mutex<PathBuf>> {
+        Arc::clone(&self.node)
+    }
+    fn motion(&self) -> OpenMotion {
+        let (iio_motion, id) = (self.iio_motion, self.id.clone());
+        Box::new(move || {
+            let motion = if iio_motion { Motion::iio() } else { Motion::evdev(&id) };
+            motion.map(Motion::start)
+        })
+    }
+    fn axes(&self) -> Vec<(AbsoluteAxisType, AbsInfo)> {
+        // the controller's own axes win, see worker; siblings aren't grabbed
+        // before it's bound, so they're looked at without
+        let siblings = evdev::enumerate()
+            .filter(|(_, device)| self.sibling_rule.matches(device))
+            .flat_map(|(_, device)| axis_ranges(&device))
+            .filter(|(axis, _)| !self.axes.iter().any(|(a, _)| a == axis))
+            .collect::<Vec<(AbsoluteAxisType, AbsInfo)>>();
+        self.axes.iter()
+            .chain(siblings.iter())
+            // remapped buttons already send what sinks expect
+            .filter(|(axis, _)| !self.remap_events.iter().any(|r| matches!(r, InputRemap::KeyToAbs(_, to) if to == axis)))
+            .copied()
+            .collect()
+    }
     fn get_capabilities(&self) -> SourceCaps {
         if let Some(keys) = self.device.supported_keys() {
             if keys.contains(Key::BTN_SOUTH) {
@@ -260,3 +519,63 @@ impl EventSource for Evdev {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::BusType;
+
+    const PHYS: &str = "usb-0000:09:00.3-3/input0";
+
+    fn node(phys: &str, version: u16) -> NodeInfo<'_> {
+        NodeInfo {
+            name: "Pad Touchpad",
+            phys,
+            uniq: "aa:bb",
+            id: InputId::new(BusType::BUS_USB, 0x054c, 0x0ce6, version),
+            gamepad: false,
+            motion: false,
+        }
+    }
+
+    fn rule(merge_with: Vec<DeviceMatch>) -> SiblingRule {
+        SiblingRule { phys: phys_device(PHYS).to_string(), uniq: "aa:bb".to_string(), merge_with }
+    }
+
+    #[test]
+    fn interfaces_share_their_device() {
+        assert_eq!(phys_device("usb-0000:09:00.3-3/input0"), "usb-0000:09:00.3-3");
+        assert_eq!(phys_device("usb-0000:09:00.3-3/input3"), "usb-0000:09:00.3-3");
+        assert_eq!(phys_device("isa0060/serio0/input0"), "isa0060/serio0");
+        // no input node at the end, nothing to cut off
+        assert_eq!(phys_device("isa0060/serio0"), "isa0060/serio0");
+        assert_eq!(phys_device("b8:27:eb:00:00:01"), "b8:27:eb:00:00:01");
+        assert_eq!(phys_device(""), "");
+    }
+
+    #[test]
+    fn other_interfaces_of_the_controller_are_siblings() {
+        let rule = rule(Vec::new());
+        assert!(rule.fits(&node("usb-0000:09:00.3-3/input3", 0x8111)));
+        assert!(!rule.fits(&node("usb-0000:09:00.3-4/input3", 0x8111)));
+        assert!(!rule.fits(&NodeInfo { uniq: "cc:dd", ..node(PHYS, 0x8111) }));
+        // without a physical path nothing is known to belong together
+        assert!(!SiblingRule { phys: String::new(), ..rule }.fits(&node("", 0x8111)));
+    }
+
+    #[test]
+    fn gamepads_motion_and_our_own_nodes_are_no_siblings() {
+        // even when a quirk would merge anything
+        for rule in [rule(Vec::new()), rule(vec![DeviceMatch::default()])] {
+            assert!(!rule.fits(&NodeInfo { gamepad: true, ..node(PHYS, 0x8111) }));
+            assert!(!rule.fits(&NodeInfo { motion: true, ..node(PHYS, 0x8111) }));
+            assert!(!rule.fits(&node(PHYS, OWN_VERSION)));
+        }
+    }
+
+    #[test]
+    fn quirks_merge_nodes_from_elsewhere() {
+        assert!(rule(vec![DeviceMatch::default()]).fits(&node("isa0060/serio0/input0", 1)));
+        assert!(!rule(Vec::new()).fits(&node("isa0060/serio0/input0", 1)));
+    }
+}
@@ -0,0 +1,220 @@
+use evdev::Device;
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fs::File,
+    io::Read,
+    os::fd::{FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex,
+    },
+};
+
+use crate::source::{
+    event::{self, Evdev, SiblingRule},
+    EventSource,
+    SourceId,
+};
+
+static INPUT_DIR: &str = "/dev/input";
+
+// hands a grabbed node over to the worker of its source
+type SiblingTx = Sender<(PathBuf, Device)>;
+
+struct State {
+    // sources sinks use, they're waited for when they go away
+    bound: Vec<SourceId>,
+    // workers of bound sources that went away, waiting for their device to come back
+    lost: Vec<(SourceId, SiblingTx)>,
+    // add_sink calls that want to hear about new controllers
+    subscribers: Vec<Sender<Box<dyn EventSource>>>,
+    // workers that merge nodes of their controller showing up later
+    siblings: Vec<(SourceId, SiblingRule, SiblingTx)>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    bound: Vec::new(),
+    lost: Vec::new(),
+    subscribers: Vec::new(),
+    siblings: Vec::new(),
+});
+
+/// Keeps the sources of a sink around across disconnects.
+pub fn bind(ids: &[SourceId]) {
+    STATE.lock().unwrap().bound.extend(ids.iter().cloned());
+}
+
+/// Lets the sources go, workers still waiting for them give up.
+pub fn unbind(ids: &[SourceId]) {
+    let mut state = STATE.lock().unwrap();
+    for id in ids {
+        if let Some(pos) = state.bound.iter().position(|b| b == id) {
+            state.bound.remove(pos);
+        }
+        state.lost.retain(|(lost, _)| lost != id);
+        state.siblings.retain(|(watcher, _, _)| watcher != id);
+    }
+}
+
+/// Whether a sink uses the source.
+pub(crate) fn is_bound(id: &SourceId) -> bool {
+    STATE.lock().unwrap().bound.iter().any(|b| b == id)
+}
+
+/// Blocks until a device with the same id is plugged in again, `None` if
+/// nobody cares about this source or it got unbound in the meantime.
+pub(crate) fn wait_for_return(id: &SourceId) -> Option<(PathBuf, Device)> {
+    let rx = {
+        let mut state = STATE.lock().unwrap();
+        if !state.bound.iter().any(|b| b == id) {
+            return None;
+        }
+        let (tx, rx) = channel();
+        state.lost.push((id.clone(), tx));
+        rx
+    };
+    rx.recv().ok()
+}
+
+/// Controllers plugged in from now on, until the receiver is dropped.
+pub fn subscribe() -> Receiver<Box<dyn EventSource>> {
+    let (tx, rx) = channel();
+    STATE.lock().unwrap().subscribers.push(tx);
+    rx
+}
+
+/// Nodes of the controller plugged in from now on, grabbed already, until
+/// the receiver is dropped or the source is unbound.
+pub(crate) fn watch_siblings(id: SourceId, rule: SiblingRule) -> Receiver<(PathBuf, Device)> {
+    let (tx, rx) = channel();
+    STATE.lock().unwrap().siblings.push((id, rule, tx));
+    rx
+}
+
+fn sibling_added(path: &Path, mut device: Device) {
+    let mut state = STATE.lock().unwrap();
+    while let Some(pos) = state.siblings.iter().position(|(_, rule, _)| rule.matches(&device)) {
+        // fails if it's already merged
+        if device.grab().is_err() {
+            return;
+        }
+        match state.siblings[pos].2.send((path.to_path_buf(), device)) {
+            Ok(()) => return,
+            // that worker is gone, maybe there's another one
+            Err(e) => {
+                device = e.0.1;
+                let _ = device.ungrab();
+                state.siblings.remove(pos);
+            },
+        }
+    }
+}
+
+/// `false` if the node couldn't be opened yet.
+fn device_added(path: &Path) -> bool {
+    let Ok(mut device) = Device::open(path) else {
+        // udev may not have set the permissions yet, IN_ATTRIB comes once it has
+        return false;
+    };
+    if !event::is_gamepad(&device) {
+        sibling_added(path, device);
+        return true;
+    }
+    let id = SourceId::of(&device);
+
+    let mut state = STATE.lock().unwrap();
+    while let Some(pos) = state.lost.iter().position(|(lost, _)| lost.matches(&id)) {
+        // fails if it's already ours
+        if device.grab().is_err() {
+            return true;
+        }
+        let (_, worker) = state.lost.remove(pos);
+        match worker.send((path.to_path_buf(), device)) {
+            Ok(()) => return true,
+            // that worker is gone, maybe there's another one
+            Err(e) => {
+                device = e.0.1;
+                let _ = device.ungrab();
+            },
+        }
+    }
+
+    if state.subscribers.is_empty() {
+        return true;
+    }
+    // opening it takes a while, others shouldn't wait on that
+    drop(state);
+    let Some(source) = Evdev::new(path.to_path_buf(), device) else {
+        return true;
+    };
+    // the latest add_sink gets it, subscribers that went away are dropped on the way
+    let mut source: Box<dyn EventSource> = Box::new(source);
+    let mut state = STATE.lock().unwrap();
+    while let Some(subscriber) = state.subscribers.last() {
+        match subscriber.send(source) {
+            Ok(()) => return true,
+            Err(e) => {
+                source = e.0;
+                state.subscribers.pop();
+            },
+        }
+    }
+    true
+}
+
+fn watch_loop(mut inotify: File) {
+    let mut buf = [0u8; 4096];
+    // nodes handled already, IN_ATTRIB follows IN_CREATE for the same one
+    let mut opened = HashSet::new();
+    loop {
+        let len = match inotify.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                eprintln!("Failed to watch {}: {}", INPUT_DIR, e);
+                return;
+            },
+        };
+
+        // struct inotify_event { int wd; uint32_t mask, cookie, len; char name[]; }
+        let mut off = 0;
+        while off + 16 <= len {
+            let mask = u32::from_ne_bytes(buf[off + 4..off + 8].try_into().unwrap());
+            let name_len = u32::from_ne_bytes(buf[off + 12..off + 16].try_into().unwrap()) as usize;
+            let name = &buf[off + 16..(off + 16 + name_len).min(len)];
+            off += 16 + name_len;
+
+            let name = String::from_utf8_lossy(name.split(|b| *b == 0).next().unwrap_or_default()).into_owned();
+            if !name.starts_with("event") {
+                continue;
+            }
+            let path = Path::new(INPUT_DIR).join(name);
+            if mask & libc::IN_DELETE != 0 {
+                // the name gets used again by the next device
+                opened.remove(&path);
+            } else if !opened.contains(&path) && device_added(&path) {
+                opened.insert(path);
+            }
+        }
+    }
+}
+
+/// Watches `/dev/input` for controllers being plugged in.
+pub fn watch() {
+    // SAFETY: no pointers involved
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        eprintln!("Hotplug won't work: {}", std::io::Error::last_os_error());
+        return;
+    }
+    // SAFETY: a fresh fd nothing else owns
+    let inotify = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    let dir = CString::new(INPUT_DIR).unwrap();
+    // SAFETY: dir is a valid C string for the duration of the call
+    if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_DELETE) } < 0 {
+        eprintln!("Hotplug won't work: {}", std::io::Error::last_os_error());
+        return;
+    }
+    std::thread::spawn(move || watch_loop(inotify));
+}
@@ -1,4 +1,6 @@
 use evdev::{
+    AbsInfo,
+    Device,
     Key,
     InputEvent,
     InputEventKind,
@@ -7,13 +9,18 @@ use evdev::{
 };
 use std::{
     fmt,
-    sync::mpsc,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
 };
 
 use anyhow::Result;
 
 mod quirks_db;
 pub mod event;
+pub mod hotplug;
+pub mod motion;
+use motion::OpenMotion;
 
 #[derive(Debug, Copy, Clone)]
 pub enum SourceCaps {
@@ -21,12 +28,43 @@ pub enum SourceCaps {
     DpadAndAB,
 }
 
+/// What a physical controller is recognized by when it's plugged in again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceId {
+    pub phys: String,
+    pub uniq: String,
+}
+
+impl SourceId {
+    pub fn of(device: &Device) -> Self {
+        Self {
+            phys: device.physical_path().unwrap_or_default().to_string(),
+            uniq: device.unique_name().unwrap_or_default().to_string(),
+        }
+    }
+    /// Same unique id, or same physical path for devices without one.
+    /// Bluetooth devices all share the adapter's physical path, they always have a unique id.
+    pub fn matches(&self, other: &SourceId) -> bool {
+        if !self.uniq.is_empty() || !other.uniq.is_empty() {
+            return self.uniq == other.uniq;
+        }
+        !self.phys.is_empty() && self.phys == other.phys
+    }
+}
+
 pub trait EventSource: Send + Sync {
     fn start_ev(self: Box<Self>) -> mpsc::Receiver<InputEvent>;
     fn make_tx(&self) -> mpsc::Sender<InputEvent>;
     
     fn name(&self) -> String;
     fn path(&self) -> String;
+    fn id(&self) -> SourceId;
+    /// Raw ranges of the axes it reports as they come from the hardware.
+    fn axes(&self) -> Vec<(AbsoluteAxisType, AbsInfo)>;
+    /// Device node force feedback goes to, it changes when the device is plugged in again.
+    fn node(&self) -> Arc<Mutex<PathBuf>>;
+    /// Accelerometer and gyro that go with it, looked for when it's called.
+    fn motion(&self) -> OpenMotion;
     
     fn get_capabilities(&self) -> SourceCaps;
 }
@@ -35,15 +73,45 @@ pub struct OpenedEventSource {
     pub name: String,
     pub path: String,
     pub caps: SourceCaps,
+    /// Every physical device feeding it.
+    pub ids: Vec<SourceId>,
+    /// Axes whose values still need mapping onto what a sink declares,
+    /// anything else on the channel is sent in sink units already.
+    pub axes: Vec<(AbsoluteAxisType, AbsInfo)>,
+    /// Device nodes of every physical device, for force feedback.
+    pub nodes: Vec<Arc<Mutex<PathBuf>>>,
+    /// Opens the motion sensors, taken by the sink it's bound to.
+    pub motion: Option<OpenMotion>,
     pub chan: mpsc::Receiver<InputEvent>,
     pub chan_tx: mpsc::Sender<InputEvent>,
 }
 
+impl OpenedEventSource {
+    /// File name for what's stored about this source, the same controller
+    /// gets the same one wherever it's plugged in.
+    pub fn storage_key(&self) -> String {
+        let key = self.ids.iter()
+            .map(|id| if id.uniq.is_empty() { &id.phys } else { &id.uniq })
+            .filter(|s| !s.is_empty())
+            .cloned()
+            .collect::<Vec<String>>()
+            .join("+");
+        let key = if key.is_empty() { self.name.clone() } else { key };
+        key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '+' { c } else { '_' })
+            .collect()
+    }
+}
+
 pub fn into_opened(input: Box<dyn EventSource>) -> OpenedEventSource {
     OpenedEventSource {
         name: input.name(),
         path: input.path(),
         caps: input.get_capabilities(),
+        ids: vec![input.id()],
+        axes: input.axes(),
+        nodes: vec![input.node()],
+        motion: Some(input.motion()),
         chan_tx: input.make_tx(),
         chan: input.start_ev(),
     }
@@ -71,7 +139,8 @@ fn actually_wait(dev: OpenedEventSource, out: mpsc::Sender<Option<OpenedEventSou
         }
 
         if pressed_l && pressed_r {
-            out.send(Some(dev)).unwrap();
+            // whoever waited may have given up meanwhile
+            let _ = out.send(Some(dev));
             return;
         }
     }
@@ -116,7 +185,7 @@ fn joycon_ev_middleman(dev: OpenedEventSource, out: mpsc::Sender<InputEvent>) ->
                             Key::BTN_TR2 => out.send(InputEvent::new(EventType::KEY, Key::BTN_TR.0, ev.value())),
                             Key::BTN_TL => continue,
                             _ => out.send(ev),
-                        }.unwrap();
+                        }?;
                     }
                 },
                 InputEventKind::AbsAxis(abs) => {
@@ -171,14 +240,14 @@ fn joycon_ev_middleman(dev: OpenedEventSource, out: mpsc::Sender<InputEvent>) ->
                                 0
                             };
                             if *last != val {
-                                out.send(InputEvent::new(EventType::ABSOLUTE, code, val)).unwrap();
+                                out.send(InputEvent::new(EventType::ABSOLUTE, code, val))?;
                                 *last = val;
                             }
                         }
                         _ => continue,
                     }
                 },
-                _ => out.send(ev).unwrap(),
+                _ => out.send(ev)?,
             }
         }
     }
@@ -227,10 +296,18 @@ fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Opti
         if left_tl && right_tr {
             // combine both devices
             let mut left = maybe_left.unwrap();
-            let right = maybe_right.unwrap();
+            let mut right = maybe_right.unwrap();
 
             left.caps = SourceCaps::FullX360;
             left.name = String::from("Nintendo Switch Both Joy-Cons");
+            left.ids.extend(right.ids.iter().cloned());
+            left.axes.extend(right.axes.iter().cloned());
+            left.nodes.extend(right.nodes.iter().cloned());
+            // the right hand aims, the left one only if the right can't
+            let (left_motion, right_motion) = (left.motion.take(), right.motion.take());
+            left.motion = Some(Box::new(move || {
+                right_motion.and_then(|open| open()).or_else(|| left_motion.and_then(|open| open()))
+            }));
 
             let to_left = left.chan_tx.clone();
             std::thread::spawn(move || {
@@ -243,11 +320,11 @@ fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Opti
                 }
             });
             
-            out.send(Some(left)).unwrap();
+            let _ = out.send(Some(left));
             return;
         }
         if left_tr && left_tr2 {
-            let left = maybe_left.unwrap();
+            let mut left = maybe_left.unwrap();
             
             let (tx, rx) = mpsc::channel();
             let tx_2 = tx.clone();
@@ -255,17 +332,22 @@ fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Opti
                 name: left.name.clone(),
                 path: left.path.clone(),
                 caps: left.caps,
+                ids: left.ids.clone(),
+                // the middleman only makes hats and buttons
+                axes: Vec::new(),
+                nodes: left.nodes.clone(),
+                motion: left.motion.take().map(|open| Box::new(move || open().map(|rx| motion::sideways(rx, true))) as OpenMotion),
                 chan: rx,
                 chan_tx: tx,
             };
 
             std::thread::spawn(move || joycon_ev_middleman(left, tx_2));
 
-            out.send(Some(new_left)).unwrap();
+            let _ = out.send(Some(new_left));
             return;
         }
         if right_tl && right_tl2 {
-            let right = maybe_right.unwrap();
+            let mut right = maybe_right.unwrap();
             
             let (tx, rx) = mpsc::channel();
             let tx_2 = tx.clone();
@@ -273,13 +355,17 @@ fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Opti
                 name: right.name.clone(),
                 path: right.path.clone(),
                 caps: right.caps,
+                ids: right.ids.clone(),
+                axes: Vec::new(),
+                nodes: right.nodes.clone(),
+                motion: right.motion.take().map(|open| Box::new(move || open().map(|rx| motion::sideways(rx, false))) as OpenMotion),
                 chan: rx,
                 chan_tx: tx,
             };
 
             std::thread::spawn(move || joycon_ev_middleman(right, tx_2));
 
-            out.send(Some(new_right)).unwrap();
+            let _ = out.send(Some(new_right));
             return;
         }
         if out.send(None).is_err() {
@@ -288,7 +374,11 @@ fn actually_wait_joycon(maybe_left: Option<OpenedEventSource>, maybe_right: Opti
     }
 }
 
-pub fn wait_for_lr(input: Vec<OpenedEventSource>) -> OpenedEventSource {
+/// Waits for L+R on any of `input` or the sources coming from `hotplugged`,
+/// `None` once `give_up` says so. It's asked about every 100ms.
+///
+/// Only Joy-Cons already connected get paired up, later ones are used on their own.
+pub fn wait_for_lr(input: Vec<OpenedEventSource>, hotplugged: &mpsc::Receiver<Box<dyn EventSource>>, give_up: impl Fn() -> bool) -> Option<OpenedEventSource> {
     let (tx, rx) = mpsc::channel();
     let mut joycons = TwoJoycons { left: None, right: None };
 
@@ -316,12 +406,27 @@ pub fn wait_for_lr(input: Vec<OpenedEventSource>) -> OpenedEventSource {
         std::thread::spawn(move || actually_wait_joycon(joycons.left.take(), joycons.right.take(), new_tx));
     }
 
-    let mut recv = None;
-    while recv.is_none() {
-        recv = rx.recv().unwrap();
-    }
+    loop {
+        if let Ok(Some(dev)) = rx.recv_timeout(Duration::from_millis(100)) {
+            return Some(dev);
+        }
+        // the waiting threads notice on their next event
+        if give_up() {
+            return None;
+        }
 
-    recv.unwrap()
+        while let Ok(dev) = hotplugged.try_recv() {
+            let dev = into_opened(dev);
+            let new_tx = tx.clone();
+            if !dev.name.contains("Joy-Con") {
+                std::thread::spawn(|| actually_wait(dev, new_tx));
+            } else if dev.name.contains("Left") {
+                std::thread::spawn(move || actually_wait_joycon(Some(dev), None, new_tx));
+            } else {
+                std::thread::spawn(move || actually_wait_joycon(None, Some(dev), new_tx));
+            }
+        }
+    }
 }
 
 pub fn enumerate() -> Vec<Box<dyn EventSource>> {
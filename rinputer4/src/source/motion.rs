@@ -0,0 +1,272 @@
+use evdev::{
+    AbsoluteAxisType,
+    Device,
+    EventType,
+    InputEvent,
+    InputEventKind,
+    MiscType,
+    PropType,
+    Synchronization,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{sink::uinput::OWN_VERSION, source::SourceId};
+
+/// Accelerometer units per g in motion events, what hid-playstation reports in.
+pub const ACCEL_RES_PER_G: i32 = 8192;
+/// Gyro units per degree a second in motion events.
+pub const GYRO_RES_PER_DEG_S: i32 = 1024;
+
+static IIO_DIR: &str = "/sys/bus/iio/devices";
+/// How often IIO sensors are read, there are no events to wait for.
+const IIO_POLL: Duration = Duration::from_millis(10);
+/// How often a motion node that went away is looked for.
+const RETRY: Duration = Duration::from_secs(1);
+const STANDARD_GRAVITY: f64 = 9.80665;
+
+// accelerometer, then gyro
+static AXES: [AbsoluteAxisType; 6] = [
+    AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y, AbsoluteAxisType::ABS_Z,
+    AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY, AbsoluteAxisType::ABS_RZ,
+];
+
+/// Whether the device is the accelerometer and gyro of a controller, and not one of our own.
+pub(crate) fn is_motion(device: &Device) -> bool {
+    device.properties().contains(PropType::ACCELEROMETER) && device.input_id().version() != OWN_VERSION
+}
+
+fn syn() -> InputEvent {
+    InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0)
+}
+
+fn axis_index(ev: InputEvent) -> Option<usize> {
+    match ev.kind() {
+        InputEventKind::AbsAxis(axis) => AXES.iter().position(|a| *a == axis),
+        _ => None,
+    }
+}
+
+/// One accelerometer or gyro of the machine, read through sysfs.
+pub struct IioSensor {
+    dir: PathBuf,
+    // `accel` or `anglvel`
+    kind: &'static str,
+    scale: [f64; 3],
+    offset: [f64; 3],
+    mount: [[f64; 3]; 3],
+}
+
+fn read_f64(path: &Path) -> Option<f64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// `x1, y1, z1; x2, y2, z2; x3, y3, z3`
+fn parse_mount_matrix(s: &str) -> Option<[[f64; 3]; 3]> {
+    let rows = s.trim().split(';')
+        .map(|row| {
+            let row = row.split(',').map(|v| v.trim().parse::<f64>().ok()).collect::<Option<Vec<f64>>>()?;
+            row.try_into().ok()
+        })
+        .collect::<Option<Vec<[f64; 3]>>>()?;
+    rows.try_into().ok()
+}
+
+impl IioSensor {
+    fn find(kind: &'static str) -> Option<Self> {
+        let mut dirs = fs::read_dir(IIO_DIR).ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect::<Vec<PathBuf>>();
+        dirs.sort();
+        let dir = dirs.into_iter().find(|dir| dir.join(format!("in_{}_x_raw", kind)).exists())?;
+
+        // shared by the axes or one per axis
+        let per_axis = |what: &str, default: f64| ["x", "y", "z"].map(|axis| {
+            read_f64(&dir.join(format!("in_{}_{}_{}", kind, axis, what)))
+                .or_else(|| read_f64(&dir.join(format!("in_{}_{}", kind, what))))
+                .unwrap_or(default)
+        });
+        let mount = fs::read_to_string(dir.join(format!("in_{}_mount_matrix", kind)))
+            .or_else(|_| fs::read_to_string(dir.join("mount_matrix")))
+            .ok()
+            .and_then(|m| parse_mount_matrix(&m))
+            .unwrap_or([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+        Some(Self {
+            scale: per_axis("scale", 1.0),
+            offset: per_axis("offset", 0.0),
+            mount,
+            dir,
+            kind,
+        })
+    }
+
+    /// Current reading in m/s² or rad/s, turned the way the machine is.
+    fn read(&self) -> Option<[f64; 3]> {
+        let mut raw = [0.0; 3];
+        for (i, axis) in ["x", "y", "z"].iter().enumerate() {
+            let value = read_f64(&self.dir.join(format!("in_{}_{}_raw", self.kind, axis)))?;
+            raw[i] = (value + self.offset[i]) * self.scale[i];
+        }
+        Some(self.mount.map(|row| row[0] * raw[0] + row[1] * raw[1] + row[2] * raw[2]))
+    }
+}
+
+/// Opens the motion of a source once it's bound to a sink, see `Motion::start`.
+/// Sources that are only looked at don't hold on to any sensors that way.
+pub type OpenMotion = Box<dyn FnOnce() -> Option<Receiver<InputEvent>> + Send>;
+
+/// Where the motion of a controller comes from.
+pub enum Motion {
+    /// Motion node of the controller itself, grabbed.
+    Evdev(SourceId, Device),
+    /// Accelerometer and gyro of the machine a controller is built into.
+    Iio(Option<IioSensor>, Option<IioSensor>),
+}
+
+fn find_node(id: &SourceId) -> Option<Device> {
+    let mut device = evdev::enumerate()
+        .map(|(_, device)| device)
+        .find(|device| is_motion(device) && SourceId::of(device).matches(id))?;
+    device.grab().ok()?;
+    Some(device)
+}
+
+// units per g and per degree a second, by index into AXES
+fn resolutions(device: &Device) -> [i32; 6] {
+    let Ok(vals) = device.get_abs_state() else {
+        return [0; 6];
+    };
+    AXES.map(|axis| vals[axis.0 as usize].resolution)
+}
+
+fn evdev_worker(id: SourceId, mut device: Device, tx: Sender<InputEvent>) {
+    loop {
+        let res = resolutions(&device);
+        while let Ok(events) = device.fetch_events().map(|events| events.collect::<Vec<InputEvent>>()) {
+            for ev in events {
+                let ev = match axis_index(ev) {
+                    Some(i) if res[i] > 0 => {
+                        let per = if i < 3 { ACCEL_RES_PER_G } else { GYRO_RES_PER_DEG_S };
+                        InputEvent::new(EventType::ABSOLUTE, ev.code(), (ev.value() as i64 * per as i64 / res[i] as i64) as i32)
+                    },
+                    _ => ev,
+                };
+                if tx.send(ev).is_err() {
+                    return;
+                }
+            }
+        }
+        // a controller that's gone lies still
+        for axis in AXES {
+            if tx.send(InputEvent::new(EventType::ABSOLUTE, axis.0, 0)).is_err() {
+                return;
+            }
+        }
+        // it comes back with its controller, if at all
+        device = loop {
+            thread::sleep(RETRY);
+            // nothing to send meanwhile, an empty frame tells whether anyone still listens
+            if tx.send(syn()).is_err() {
+                return;
+            }
+            if let Some(device) = find_node(&id) {
+                break device;
+            }
+        };
+    }
+}
+
+fn iio_worker(accel: Option<IioSensor>, gyro: Option<IioSensor>, tx: Sender<InputEvent>) {
+    let start = Instant::now();
+    let mut next = start;
+    loop {
+        let mut events = Vec::new();
+        if let Some(accel) = accel.as_ref().and_then(IioSensor::read) {
+            for (axis, value) in AXES[..3].iter().zip(accel) {
+                events.push(InputEvent::new(EventType::ABSOLUTE, axis.0, (value / STANDARD_GRAVITY * ACCEL_RES_PER_G as f64) as i32));
+            }
+        }
+        if let Some(gyro) = gyro.as_ref().and_then(IioSensor::read) {
+            for (axis, value) in AXES[3..].iter().zip(gyro) {
+                events.push(InputEvent::new(EventType::ABSOLUTE, axis.0, (value.to_degrees() * GYRO_RES_PER_DEG_S as f64) as i32));
+            }
+        }
+        // microseconds, wrapping around like the ones of controllers
+        let timestamp = start.elapsed().as_micros() as u32 as i32;
+        events.push(InputEvent::new(EventType::MISC, MiscType::MSC_TIMESTAMP.0, timestamp));
+        events.push(syn());
+        if events.into_iter().any(|ev| tx.send(ev).is_err()) {
+            return;
+        }
+
+        next += IIO_POLL;
+        match next.checked_duration_since(Instant::now()) {
+            Some(wait) => thread::sleep(wait),
+            // fell behind, don't try to catch up
+            None => next = Instant::now(),
+        }
+    }
+}
+
+impl Motion {
+    /// The motion node of the controller with this id, if it has one nobody else uses.
+    pub fn evdev(id: &SourceId) -> Option<Self> {
+        find_node(id).map(|device| Motion::Evdev(id.clone(), device))
+    }
+
+    /// The machine's accelerometer and gyro, if it has either.
+    pub fn iio() -> Option<Self> {
+        let (accel, gyro) = (IioSensor::find("accel"), IioSensor::find("anglvel"));
+        if accel.is_none() && gyro.is_none() {
+            return None;
+        }
+        Some(Motion::Iio(accel, gyro))
+    }
+
+    /// Sends motion frames until the receiver is dropped: ABS_X/Y/Z in
+    /// ACCEL_RES_PER_G, ABS_RX/RY/RZ in GYRO_RES_PER_DEG_S, MSC_TIMESTAMP
+    /// and a SYN_REPORT each.
+    pub fn start(self) -> Receiver<InputEvent> {
+        let (tx, rx) = channel();
+        match self {
+            Motion::Evdev(id, device) => thread::spawn(move || evdev_worker(id, device, tx)),
+            Motion::Iio(accel, gyro) => thread::spawn(move || iio_worker(accel, gyro, tx)),
+        };
+        rx
+    }
+}
+
+/// Motion of a Joy-Con held sideways, turned to how it's held. The stick
+/// is on the left either way, so the left one is turned the other way round.
+pub fn sideways(rx: Receiver<InputEvent>, left: bool) -> Receiver<InputEvent> {
+    let (tx, out) = channel();
+    thread::spawn(move || {
+        let mut values = [0; 6];
+        for ev in rx.iter() {
+            if let Some(i) = axis_index(ev) {
+                values[i] = ev.value();
+                continue;
+            }
+            if ev.kind() == InputEventKind::Synchronization(Synchronization::SYN_REPORT) {
+                let turn = |[x, y, z]: [i32; 3]| if left { [z, y, -x] } else { [-z, y, x] };
+                let accel = turn([values[0], values[1], values[2]]);
+                let gyro = turn([values[3], values[4], values[5]]);
+                for (axis, value) in AXES.iter().zip(accel.into_iter().chain(gyro)) {
+                    if tx.send(InputEvent::new(EventType::ABSOLUTE, axis.0, value)).is_err() {
+                        return;
+                    }
+                }
+            }
+            if tx.send(ev).is_err() {
+                return;
+            }
+        }
+    });
+    out
+}
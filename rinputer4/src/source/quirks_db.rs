@@ -1,23 +1,24 @@
 use evdev::{
+    Device,
     InputEvent,
     Key,
     AbsoluteAxisType,
     EventType,
     InputEventKind,
+    InputId,
 };
-use std::path::Path;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use toml_edit::{Document, Item, TableLike};
 
-#[derive(Clone, Debug)]
-pub struct DmiQuirk {
-    pub board_vendor: &'static str,
-    pub board_name: &'static str,
-    pub product_vendor: &'static str,
-    pub product_name: &'static str,
-    pub relaxed_name: bool,
-    pub relaxed_vendor: bool,
-    pub phys_path: &'static str,
-    pub remap_codes: Vec<InputRemap>, 
-}
+use crate::toml_tables::tables;
+
+/// Quirks shipped with rinputer4, see the file for the format.
+static BUILTIN_QUIRKS: &str = include_str!("quirks.toml");
+/// Quirks of the system, they go before the built-in ones.
+static QUIRKS_DIR: &str = "/etc/rinputer/quirks.d";
 
 #[derive(Copy, Clone, Debug)]
 pub enum InputRemap {
@@ -91,53 +92,310 @@ fn match_str(inp: &str, x: &str, relaxed: bool) -> bool {
     }
 }
 
-pub fn get_dmi_quirk(phys_path: &Path) -> Option<DmiQuirk> {
-    let quirks_vec = vec![
-        DmiQuirk {
-            board_vendor: "AYANEO",
-            board_name: "AIR",
-            product_vendor: "",
-            product_name: "",
-            relaxed_name: true,
-            relaxed_vendor: false,
-            remap_codes: vec![
-                InputRemap::KeyToKey(Key::KEY_F12, Key::BTN_MODE),
-                InputRemap::KeyToQuickAccessMenu(Key::KEY_D),
-            ],
-            phys_path: "", // TODO
-        },
-        DmiQuirk {
-            board_vendor: "AYANEO",
-            board_name: "NEXT",
-            product_vendor: "",
-            product_name: "",
-            relaxed_name: true,
-            relaxed_vendor: false,
-            remap_codes: vec![
-                InputRemap::KeyToKey(Key::KEY_F12, Key::BTN_MODE),
-                InputRemap::KeyToQuickAccessMenu(Key::KEY_D),
-            ],
-            phys_path: "", // TODO
+struct Dmi {
+    product_name: String,
+    product_vendor: String,
+    board_name: String,
+    board_vendor: String,
+}
+
+impl Dmi {
+    fn read() -> Self {
+        Self {
+            product_name: get_dmi("product_name"),
+            product_vendor: get_dmi("product_vendor"),
+            board_name: get_dmi("board_name"),
+            board_vendor: get_dmi("board_vendor"),
         }
-    ];
-
-    let product_name = get_dmi("product_name");
-    let product_vendor = get_dmi("product_vendor");
-    let board_name = get_dmi("board_name");
-    let board_vendor = get_dmi("board_vendor");
-
-    for quirk in quirks_vec.into_iter() {
-        let pn_match = match_str(&quirk.product_name, &product_name, quirk.relaxed_name);
-        let pv_match = match_str(&quirk.product_vendor, &product_vendor, quirk.relaxed_vendor);
-        let bn_match = match_str(&quirk.board_name, &board_name, quirk.relaxed_name);
-        let bv_match = match_str(&quirk.board_vendor, &board_vendor, quirk.relaxed_vendor);
-        if pn_match && pv_match && bn_match && bv_match {
-            if quirk.phys_path.is_empty() {
-                eprintln!("Note: Matched {} against empty path", phys_path.display());
+    }
+}
+
+/// What a quirk applies to, everything that's set has to match.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceMatch {
+    board_vendor: String,
+    board_name: String,
+    product_vendor: String,
+    product_name: String,
+    relaxed_name: bool,
+    relaxed_vendor: bool,
+    // from the input id, USB ids for USB devices
+    vendor: Option<u16>,
+    product: Option<u16>,
+    version: Option<u16>,
+    // contained in the device's name and physical path
+    name: String,
+    phys: String,
+}
+
+impl DeviceMatch {
+    fn parse(table: &dyn TableLike) -> Result<Self, String> {
+        let mut ret = Self::default();
+        for (key, item) in table.iter() {
+            match key {
+                "board_vendor" => ret.board_vendor = string(key, item)?,
+                "board_name" => ret.board_name = string(key, item)?,
+                "product_vendor" => ret.product_vendor = string(key, item)?,
+                "product_name" => ret.product_name = string(key, item)?,
+                "relaxed_name" => ret.relaxed_name = item.as_bool().ok_or("relaxed_name has to be true or false")?,
+                "relaxed_vendor" => ret.relaxed_vendor = item.as_bool().ok_or("relaxed_vendor has to be true or false")?,
+                "vendor" => ret.vendor = Some(id(key, item)?),
+                "product" => ret.product = Some(id(key, item)?),
+                "version" => ret.version = Some(id(key, item)?),
+                "name" => ret.name = string(key, item)?,
+                "phys" => ret.phys = string(key, item)?,
+                _ => return Err(format!("unknown match key {}", key)),
             }
-            return Some(quirk);
         }
+        Ok(ret)
+    }
+
+    fn matches(&self, dev: &Device, dmi: &Dmi) -> bool {
+        self.fits_machine(dmi)
+            && self.fits_node(dev.name().unwrap_or_default(), dev.physical_path().unwrap_or_default(), &dev.input_id())
+    }
+
+    fn fits_machine(&self, dmi: &Dmi) -> bool {
+        match_str(&self.product_name, &dmi.product_name, self.relaxed_name)
+            && match_str(&self.product_vendor, &dmi.product_vendor, self.relaxed_vendor)
+            && match_str(&self.board_name, &dmi.board_name, self.relaxed_name)
+            && match_str(&self.board_vendor, &dmi.board_vendor, self.relaxed_vendor)
+    }
+
+    /// Whether it fits a node with that name, physical path and id, whatever machine it's on.
+    pub fn fits_node(&self, name: &str, phys: &str, input_id: &InputId) -> bool {
+        self.vendor.is_none_or(|v| v == input_id.vendor())
+            && self.product.is_none_or(|p| p == input_id.product())
+            && self.version.is_none_or(|v| v == input_id.version())
+            && name.contains(&self.name)
+            && phys.contains(&self.phys)
     }
+}
+
+/// What to do about the devices a quirk matches.
+#[derive(Clone, Debug)]
+pub struct Quirk {
+    matches: DeviceMatch,
+    pub override_name: Option<String>,
+    pub remap_codes: Vec<InputRemap>,
+    /// Another device that's part of the same controller.
+    pub merge_with: Option<DeviceMatch>,
+    /// The controller is built into the machine, its accelerometer and gyro are the machine's.
+    pub iio_motion: bool,
+}
+
+fn string(key: &str, item: &Item) -> Result<String, String> {
+    item.as_str().map(str::to_string).ok_or_else(|| format!("{} has to be a string", key))
+}
+
+fn id(key: &str, item: &Item) -> Result<u16, String> {
+    item.as_integer()
+        .and_then(|v| u16::try_from(v).ok())
+        .ok_or_else(|| format!("{} has to be a number within 0..=0xffff", key))
+}
+
+/// `{ key = "KEY_F12", to = "BTN_MODE" }`, `to` being a key, an axis or `quick_access_menu`.
+fn parse_remap(table: &dyn TableLike) -> Result<InputRemap, String> {
+    let key = table.get("key").and_then(Item::as_str).ok_or("a remap needs a key")?;
+    let to = table.get("to").and_then(Item::as_str).ok_or("a remap needs a to")?;
+    if let Some((other, _)) = table.iter().find(|(k, _)| *k != "key" && *k != "to") {
+        return Err(format!("unknown remap key {}", other));
+    }
+    let key = key.parse::<Key>().map_err(|_| format!("unknown key {}", key))?;
 
-    None
+    if to == "quick_access_menu" {
+        return Ok(InputRemap::KeyToQuickAccessMenu(key));
+    }
+    if let Ok(to_key) = to.parse::<Key>() {
+        return Ok(InputRemap::KeyToKey(key, to_key));
+    }
+    match to.parse::<AbsoluteAxisType>() {
+        Ok(abs @ (AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ | AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y)) => {
+            Ok(InputRemap::KeyToAbs(key, abs))
+        },
+        Ok(_) => Err(format!("keys can only be remapped to triggers and the d-pad, not {}", to)),
+        Err(_) => Err(format!("unknown key or axis {}", to)),
+    }
+}
+
+fn parse_quirk(table: &dyn TableLike) -> Result<Quirk, String> {
+    let mut ret = Quirk {
+        matches: DeviceMatch::default(),
+        override_name: None,
+        remap_codes: Vec::new(),
+        merge_with: None,
+        iio_motion: false,
+    };
+    for (key, item) in table.iter() {
+        match key {
+            "match" => ret.matches = DeviceMatch::parse(item.as_table_like().ok_or("match has to be a table")?)?,
+            "override_name" => ret.override_name = Some(string(key, item)?),
+            "remap" => {
+                for remap in tables(item).ok_or("remap has to be a list of tables")? {
+                    ret.remap_codes.push(parse_remap(remap)?);
+                }
+            },
+            "merge_with" => ret.merge_with = Some(DeviceMatch::parse(item.as_table_like().ok_or("merge_with has to be a table")?)?),
+            "iio_motion" => ret.iio_motion = item.as_bool().ok_or("iio_motion has to be true or false")?,
+            _ => return Err(format!("unknown key {}", key)),
+        }
+    }
+    Ok(ret)
+}
+
+/// Quirks in one file, broken ones are skipped with a complaint.
+fn parse_file(path: &Path, contents: &str) -> Vec<Quirk> {
+    let doc = match Document::parse(contents) {
+        Ok(doc) => doc,
+        Err(e) => {
+            eprintln!("Ignoring quirks in {}: {}", path.display(), e);
+            return Vec::new();
+        },
+    };
+    let Some(quirks) = doc.as_table().get("quirk") else {
+        return Vec::new();
+    };
+    let Some(quirks) = tables(quirks) else {
+        eprintln!("Ignoring quirks in {}: quirk has to be a list of tables", path.display());
+        return Vec::new();
+    };
+    quirks.into_iter().enumerate()
+        .filter_map(|(i, quirk)| match parse_quirk(quirk) {
+            Ok(quirk) => Some(quirk),
+            Err(e) => {
+                eprintln!("Ignoring quirk {} in {}: {}", i + 1, path.display(), e);
+                None
+            },
+        })
+        .collect()
+}
+
+fn load() -> Vec<Quirk> {
+    let mut files = fs::read_dir(QUIRKS_DIR)
+        .map(|dir| dir.filter_map(|e| e.ok())
+             .map(|e| e.path())
+             .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+             .collect::<Vec<PathBuf>>())
+        .unwrap_or_default();
+    files.sort();
+
+    let mut ret = Vec::new();
+    for file in files {
+        match fs::read_to_string(&file) {
+            Ok(contents) => ret.extend(parse_file(&file, &contents)),
+            Err(e) => eprintln!("Failed to read {}: {}", file.display(), e),
+        }
+    }
+    ret.extend(parse_file(Path::new("<built-in quirks>"), BUILTIN_QUIRKS));
+    ret
+}
+
+/// The matches that fit this machine, only their devices are left to check.
+pub fn on_this_machine(matches: Vec<DeviceMatch>) -> Vec<DeviceMatch> {
+    let dmi = Dmi::read();
+    matches.into_iter().filter(|m| m.fits_machine(&dmi)).collect()
+}
+
+/// Every quirk that applies to the device, most important first. The files are
+/// read again each time, so changes apply the next time a device shows up.
+pub fn get_quirks(dev: &Device) -> Vec<Quirk> {
+    let dmi = Dmi::read();
+    load().into_iter()
+        .filter(|quirk| quirk.matches.matches(dev, &dmi))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Vec<Quirk> {
+        parse_file(Path::new("test.toml"), contents)
+    }
+
+    #[test]
+    fn builtin_quirks_parse() {
+        let quirks = parse(BUILTIN_QUIRKS);
+        assert_eq!(quirks.len(), BUILTIN_QUIRKS.lines().filter(|line| *line == "[[quirk]]").count());
+    }
+
+    #[test]
+    fn remaps_parse_inline_and_as_tables() {
+        let quirks = parse(r#"
+            [[quirk]]
+            match = { vendor = 0x045e, name = "Pad" }
+            override_name = "Some Pad"
+            remap = [
+                { key = "KEY_F12", to = "BTN_MODE" },
+                { key = "BTN_DPAD_UP", to = "ABS_HAT0Y" },
+            ]
+
+            [[quirk]]
+            iio_motion = true
+            [quirk.merge_with]
+            name = "AT Translated Set 2 keyboard"
+            [[quirk.remap]]
+            key = "KEY_D"
+            to = "quick_access_menu"
+        "#);
+        assert_eq!(quirks.len(), 2);
+
+        assert_eq!(quirks[0].override_name.as_deref(), Some("Some Pad"));
+        assert_eq!(quirks[0].matches.vendor, Some(0x045e));
+        assert_eq!(quirks[0].matches.name, "Pad");
+        assert!(matches!(quirks[0].remap_codes[..], [
+            InputRemap::KeyToKey(Key::KEY_F12, Key::BTN_MODE),
+            InputRemap::KeyToAbs(Key::BTN_DPAD_UP, AbsoluteAxisType::ABS_HAT0Y),
+        ]));
+        assert!(!quirks[0].iio_motion && quirks[0].merge_with.is_none());
+
+        assert!(quirks[1].iio_motion);
+        assert_eq!(quirks[1].merge_with.as_ref().unwrap().name, "AT Translated Set 2 keyboard");
+        assert!(matches!(quirks[1].remap_codes[..], [InputRemap::KeyToQuickAccessMenu(Key::KEY_D)]));
+    }
+
+    #[test]
+    fn broken_quirks_are_skipped() {
+        let quirks = parse(r#"
+            [[quirk]]
+            override_name = "kept"
+
+            [[quirk]]
+            remap = [{ key = "KEY_F12", to = "ABS_X" }]
+
+            [[quirk]]
+            remap = [{ key = "KEY_NOPE", to = "BTN_MODE" }]
+
+            [[quirk]]
+            match = { vendor = 0x10000 }
+
+            [[quirk]]
+            match = { colour = "red" }
+
+            [[quirk]]
+            iio_motion = "yes"
+
+            [[quirk]]
+            something_else = 1
+        "#);
+        assert_eq!(quirks.len(), 1);
+        assert_eq!(quirks[0].override_name.as_deref(), Some("kept"));
+    }
+
+    #[test]
+    fn broken_files_are_skipped() {
+        assert!(parse("[[quirk]\n").is_empty());
+        assert!(parse("quirk = 1").is_empty());
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn strings_match_exactly_unless_relaxed() {
+        assert!(match_str("", "anything", false));
+        assert!(match_str("AIR", "AIR", false));
+        assert!(!match_str("AIR", "AIR Pro", false));
+        assert!(match_str("AIR", "AIR Pro", true));
+        assert!(match_str("AIR Pro", "AIR", true));
+        assert!(!match_str("AIR", "NEXT", true));
+    }
 }
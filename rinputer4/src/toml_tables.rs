@@ -0,0 +1,12 @@
+use toml_edit::{Item, TableLike};
+
+/// The tables of a list, written inline as `key = [{ .. }]` or as `[[key]]`
+/// sections, `None` if it's something else.
+pub fn tables(item: &Item) -> Option<Vec<&dyn TableLike>> {
+    if let Some(array) = item.as_array() {
+        return array.iter()
+            .map(|v| v.as_inline_table().map(|t| t as &dyn TableLike))
+            .collect();
+    }
+    item.as_array_of_tables().map(|array| array.iter().map(|t| t as &dyn TableLike).collect())
+}
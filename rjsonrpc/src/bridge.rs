@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::FileTypeExt,
+    sync::Arc,
+    thread,
+};
+use rservice::{
+    client::{self, ServiceClient},
+    Bus,
+    Error,
+    Introspection,
+};
+use serde_json::{json, Map, Value};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// the rest are in the range JSON-RPC leaves to implementations
+const REMOTE_ERROR: i64 = -32000;
+const BUSY: i64 = -32001;
+const TIMEOUT: i64 = -32002;
+const UNAVAILABLE: i64 = -32003;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self { code, message: message.to_string() }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        let code = match e {
+            Error::Remote(_) => REMOTE_ERROR,
+            Error::Busy(_) => BUSY,
+            Error::Timeout | Error::Cancelled => TIMEOUT,
+            Error::Io(_) => UNAVAILABLE,
+        };
+        RpcError::new(code, e)
+    }
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": e.code, "message": e.message },
+        "id": id,
+    })
+}
+
+/// Turns JSON-RPC params into rservice arguments. Objects are only accepted
+/// for methods that have their arguments named in the introspection data.
+fn params_to_args(params: Option<Value>, names: Option<&Vec<String>>) -> Result<Vec<String>, RpcError> {
+    fn to_arg(v: Value) -> Result<String, RpcError> {
+        match v {
+            Value::String(s) => Ok(s),
+            Value::Number(_) | Value::Bool(_) => Ok(v.to_string()),
+            _ => Err(RpcError::new(INVALID_PARAMS, "arguments have to be strings, numbers or booleans")),
+        }
+    }
+
+    let mut named = match params {
+        None => return Ok(Vec::new()),
+        Some(Value::Array(args)) => return args.into_iter().map(to_arg).collect(),
+        Some(Value::Object(named)) => named,
+        Some(_) => return Err(RpcError::new(INVALID_REQUEST, "params have to be an array or an object")),
+    };
+    let Some(names) = names else {
+        return Err(RpcError::new(INVALID_PARAMS, "the service doesn't name the arguments of this method"));
+    };
+
+    let mut args = Vec::new();
+    let mut skipped = None;
+    for name in names {
+        let (key, rest) = match name.strip_suffix("...") {
+            Some(key) => (key, true),
+            None => (name.trim_end_matches('?'), false),
+        };
+        let Some(value) = named.remove(key) else {
+            skipped.get_or_insert(key);
+            continue;
+        };
+        // arguments go by position, so nothing can be given after a gap
+        if let Some(skipped) = skipped {
+            return Err(RpcError::new(INVALID_PARAMS, format!("{} given without {}", key, skipped)));
+        }
+        match value {
+            Value::Array(values) if rest => {
+                for v in values {
+                    args.push(to_arg(v)?);
+                }
+            },
+            v => args.push(to_arg(v)?),
+        }
+    }
+    if let Some(key) = named.keys().next() {
+        return Err(RpcError::new(INVALID_PARAMS, format!("unknown argument: {}", key)));
+    }
+    Ok(args)
+}
+
+fn introspection_to_json(info: &Introspection) -> Value {
+    let interfaces: Vec<Value> = info.interfaces.iter()
+        .map(|i| json!({ "name": i.name, "version": i.version }))
+        .collect();
+    let properties: Vec<Value> = info.properties.iter()
+        .map(|p| json!({ "name": p.name, "type": p.type_name, "writable": p.writable }))
+        .collect();
+    let metrics: Map<String, Value> = info.metrics.iter()
+        .map(|(k, v)| (k.clone(), json!(v)))
+        .collect();
+    json!({
+        "service": info.service,
+        "interfaces": interfaces,
+        "aliases": info.aliases,
+        "methods": info.methods,
+        "args": info.args,
+        "replies": info.replies,
+        "metrics": metrics,
+        "properties": properties,
+    })
+}
+
+/// Sends a notification to the JSON-RPC client, `false` once it's gone.
+pub type Notify = Arc<dyn Fn(Value) -> bool + Send + Sync>;
+
+/// Answers JSON-RPC requests of one connection, keeping a client per service it talked to.
+pub struct Bridge {
+    bus: Bus,
+    clients: HashMap<String, ServiceClient>,
+    info: HashMap<String, Introspection>,
+    notify: Notify,
+}
+
+impl Bridge {
+    pub fn new(bus: Bus, notify: Notify) -> Self {
+        Self {
+            bus,
+            clients: HashMap::new(),
+            info: HashMap::new(),
+            notify,
+        }
+    }
+
+    /// Handles one request or batch, returns what to answer, if anything.
+    pub fn handle_line(&mut self, line: &str) -> Option<Value> {
+        let req: Value = match serde_json::from_str(line) {
+            Ok(v) => v,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+        };
+
+        match req {
+            Value::Array(batch) if batch.is_empty() => {
+                Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "empty batch")))
+            },
+            Value::Array(batch) => {
+                let responses: Vec<Value> = batch.into_iter()
+                    .filter_map(|req| self.handle_request(req))
+                    .collect();
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            },
+            req => self.handle_request(req),
+        }
+    }
+
+    fn handle_request(&mut self, req: Value) -> Option<Value> {
+        let Value::Object(mut req) = req else {
+            return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "request has to be an object")));
+        };
+        // requests without an id are notifications, they never get an answer
+        let id = req.remove("id");
+        let method = match (req.remove("jsonrpc"), req.remove("method")) {
+            (Some(version), Some(Value::String(method))) if version == "2.0" => method,
+            _ => return Some(error_response(id.unwrap_or(Value::Null), RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request"))),
+        };
+
+        let result = self.call(&method, req.remove("params"));
+        let id = id?;
+        Some(match result {
+            Ok(v) => json!({ "jsonrpc": "2.0", "result": v, "id": id }),
+            Err(e) => error_response(id, e),
+        })
+    }
+
+    fn call(&mut self, method: &str, params: Option<Value>) -> Result<Value, RpcError> {
+        match method {
+            "rservice.list" => return self.list(),
+            "rservice.introspect" => {
+                let service = match params_to_args(params, None)?.into_iter().next() {
+                    Some(s) => s,
+                    None => return Err(RpcError::new(INVALID_PARAMS, "rservice.introspect takes a service name")),
+                };
+                // always fresh, the metrics are no good otherwise
+                self.info.remove(&service);
+                return self.introspect(&service).map(introspection_to_json);
+            },
+            "rservice.watch" => {
+                let mut args = params_to_args(params, None)?.into_iter();
+                let Some(service) = args.next() else {
+                    return Err(RpcError::new(INVALID_PARAMS, "rservice.watch takes a service name and property names"));
+                };
+                return self.watch(&service, args.collect());
+            },
+            _ => (),
+        }
+
+        let Some((service, method)) = method.rsplit_once('.') else {
+            return Err(RpcError::new(METHOD_NOT_FOUND, "methods are called as <service>.<method>"));
+        };
+
+        // the service might have been replaced by a newer one since we last looked
+        if !self.introspect(service)?.methods.iter().any(|m| m == method) {
+            self.info.remove(service);
+        }
+        let info = self.introspect(service)?;
+        if !info.methods.iter().any(|m| m == method) {
+            return Err(RpcError::new(METHOD_NOT_FOUND, format!("{} has no method {}", service, method)));
+        }
+        let args = params_to_args(params, info.args.get(method))?;
+        info.check_call(method, args.len())
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+
+        let reply = self.client(service)?.call(method, &args)?;
+        Ok(Value::String(reply))
+    }
+
+    fn client(&mut self, service: &str) -> Result<&mut ServiceClient, RpcError> {
+        if !self.clients.contains_key(service) {
+            let client = client::get_service_on(&self.bus, service)
+                .ok_or_else(|| RpcError::new(METHOD_NOT_FOUND, format!("no such service: {}", service)))?;
+            self.clients.insert(service.to_string(), client);
+        }
+        Ok(self.clients.get_mut(service).unwrap())
+    }
+
+    fn introspect(&mut self, service: &str) -> Result<&Introspection, RpcError> {
+        if !self.info.contains_key(service) {
+            let info = self.client(service)?.introspect()?;
+            self.info.insert(service.to_string(), info);
+        }
+        Ok(&self.info[service])
+    }
+
+    /// Forwards property changes as `rservice.changed` notifications, until
+    /// either the service or the JSON-RPC client goes away.
+    fn watch(&mut self, service: &str, names: Vec<String>) -> Result<Value, RpcError> {
+        let watcher = self.client(service)?.watch(names)?;
+        let notify = Arc::clone(&self.notify);
+        let service = service.to_string();
+        thread::spawn(move || {
+            for change in watcher {
+                let sent = notify(json!({
+                    "jsonrpc": "2.0",
+                    "method": "rservice.changed",
+                    "params": { "service": service, "property": change.name, "value": change.value },
+                }));
+                if !sent {
+                    break;
+                }
+            }
+        });
+        Ok(Value::String("OK".to_string()))
+    }
+
+    fn list(&self) -> Result<Value, RpcError> {
+        let Bus::Dir(dir) = &self.bus else {
+            return Err(RpcError::new(UNAVAILABLE, "services on an abstract bus can't be listed"));
+        };
+        let entries = fs::read_dir(dir)
+            .map_err(|e| RpcError::new(UNAVAILABLE, e))?;
+        let mut services: Vec<String> = entries
+            .filter_map(|v| v.ok())
+            .filter(|v| v.file_type().is_ok_and(|t| t.is_socket()))
+            .filter_map(|v| v.file_name().into_string().ok())
+            .collect();
+        services.sort();
+        Ok(json!(services))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn args(params: Value, described: &[&str]) -> Result<Vec<String>, i64> {
+        params_to_args(Some(params), Some(&names(described))).map_err(|e| e.code)
+    }
+
+    #[test]
+    fn positional_params_go_through_as_they_are() {
+        assert_eq!(params_to_args(None, None).ok(), Some(Vec::new()));
+        assert_eq!(params_to_args(Some(json!(["pad0", 2, true, 0.5])), None).ok(), Some(names(&["pad0", "2", "true", "0.5"])));
+        assert_eq!(params_to_args(Some(json!([null])), None).err().map(|e| e.code), Some(INVALID_PARAMS));
+        assert_eq!(params_to_args(Some(json!([["nested"]])), None).err().map(|e| e.code), Some(INVALID_PARAMS));
+        assert_eq!(params_to_args(Some(json!("pad0")), None).err().map(|e| e.code), Some(INVALID_REQUEST));
+    }
+
+    #[test]
+    fn named_params_follow_the_described_order() {
+        assert_eq!(args(json!({ "state": "on", "pad": 1 }), &["pad", "state"]), Ok(names(&["1", "on"])));
+        assert_eq!(args(json!({ "pad": 1 }), &["pad", "state?"]), Ok(names(&["1"])));
+        assert_eq!(args(json!({ "pad": 1, "state?": "on" }), &["pad", "state?"]), Err(INVALID_PARAMS));
+    }
+
+    #[test]
+    fn rest_params_take_arrays() {
+        let described = ["level", "message..."];
+        assert_eq!(args(json!({ "level": "warn", "message": ["disk", "full"] }), &described), Ok(names(&["warn", "disk", "full"])));
+        assert_eq!(args(json!({ "level": "warn", "message": "full" }), &described), Ok(names(&["warn", "full"])));
+        assert_eq!(args(json!({ "level": "warn" }), &described), Ok(names(&["warn"])));
+    }
+
+    #[test]
+    fn named_params_need_names() {
+        assert_eq!(params_to_args(Some(json!({ "pad": 1 })), None).err().map(|e| e.code), Some(INVALID_PARAMS));
+        assert_eq!(args(json!({ "pad": 1, "colour": "red" }), &["pad"]), Err(INVALID_PARAMS));
+        // positions can't have gaps
+        assert_eq!(args(json!({ "state": "on" }), &["pad?", "state?"]), Err(INVALID_PARAMS));
+    }
+}
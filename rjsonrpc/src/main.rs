@@ -0,0 +1,194 @@
+mod bridge;
+
+use std::{
+    env,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+use anyhow::{bail, Context, Result};
+use rservice::Bus;
+
+use crate::bridge::Bridge;
+
+static HELP_TEXT: &str = "Usage: rjsonrpc [--listen tcp:<addr>|unix:<path>] [--allow-remote]
+
+Exposes every service on the bus picked by RSERVICE_BUS over JSON-RPC 2.0,
+one request or batch per line. <service>.<method> calls a method, arguments
+go in an array, or in an object if the service names them. rservice.list
+and rservice.introspect tell what's there. rservice.watch [service, name...]
+sends rservice.changed notifications whenever those properties change.
+
+--listen        where to listen, tcp:127.0.0.1:7878 by default
+--allow-remote  allow listening on TCP addresses other than loopback
+";
+
+enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+fn parse_args() -> Result<Listen> {
+    let mut listen = Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], 7878)));
+    let mut allow_remote = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => {
+                let addr = args.next().context("--listen needs an address")?;
+                listen = match addr.split_once(':') {
+                    Some(("tcp", addr)) => Listen::Tcp(addr.parse().context("Invalid TCP address")?),
+                    Some(("unix", path)) => Listen::Unix(PathBuf::from(path)),
+                    _ => bail!("Addresses look like tcp:<addr> or unix:<path>"),
+                };
+            },
+            "--allow-remote" => allow_remote = true,
+            "--help" => {
+                print!("{}", HELP_TEXT);
+                std::process::exit(0);
+            },
+            _ => bail!("Unknown argument {}\n\n{}", arg, HELP_TEXT),
+        }
+    }
+
+    // nothing here checks who's calling, so don't let the whole network in by accident
+    if let Listen::Tcp(addr) = &listen {
+        if !addr.ip().is_loopback() && !allow_remote {
+            bail!("{} isn't a loopback address, pass --allow-remote if that's intended", addr);
+        }
+    }
+    Ok(listen)
+}
+
+fn handle_client(reader: impl Read, writer: impl Write + Send + 'static, bus: Bus) -> Result<()> {
+    // notifications come from other threads, lines mustn't get mixed up
+    let writer = Arc::new(Mutex::new(writer));
+    let ptr = Arc::clone(&writer);
+    let mut bridge = Bridge::new(bus, Arc::new(move |v| writeln!(ptr.lock().unwrap(), "{}", v).is_ok()));
+
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = bridge.handle_line(&line) {
+            writeln!(writer.lock().unwrap(), "{}", response)?;
+        }
+    }
+    Ok(())
+}
+
+fn serve<S: Read + Write + Send + 'static>(incoming: impl Iterator<Item = io::Result<S>>, try_clone: fn(&S) -> io::Result<S>, bus: Bus) {
+    for stream in incoming {
+        let (reader, writer) = match stream.and_then(|s| Ok((try_clone(&s)?, s))) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Failed to connect to client: {}", e);
+                continue;
+            }
+        };
+        let bus = bus.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_client(reader, writer, bus) {
+                eprintln!("Client error: {}", e);
+            }
+        });
+    }
+}
+
+/// Removes the socket a previous run left behind. Anything else at `path`,
+/// or a socket something still listens on, is left alone.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to look at {}", path.display())),
+    };
+    if !meta.file_type().is_socket() {
+        bail!("{} exists and isn't a socket", path.display());
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("Something is listening on {} already", path.display());
+    }
+    fs::remove_file(path).with_context(|| format!("Failed to remove the stale socket {}", path.display()))
+}
+
+fn main() -> Result<()> {
+    let listen = parse_args()?;
+    let bus = Bus::from_env();
+
+    match listen {
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .with_context(|| format!("Failed to listen on {}", addr))?;
+            serve(listener.incoming(), |s| s.try_clone(), bus);
+        },
+        Listen::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let listener = UnixListener::bind(&path)
+                .with_context(|| format!("Failed to listen on {}", path.display()))?;
+            serve(listener.incoming(), |s| s.try_clone(), bus);
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh path under the temp dir, gone again on drop
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("rjsonrpc-{}-{}", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn nothing_there_is_fine() {
+        let path = TempPath::new("missing");
+        assert!(remove_stale_socket(&path.0).is_ok());
+    }
+
+    #[test]
+    fn stale_sockets_are_removed() {
+        let path = TempPath::new("stale");
+        drop(UnixListener::bind(&path.0).unwrap());
+        assert!(remove_stale_socket(&path.0).is_ok());
+        assert!(!path.0.exists());
+    }
+
+    #[test]
+    fn sockets_in_use_are_kept() {
+        let path = TempPath::new("in-use");
+        let _listener = UnixListener::bind(&path.0).unwrap();
+        assert!(remove_stale_socket(&path.0).is_err());
+        assert!(path.0.exists());
+    }
+
+    #[test]
+    fn other_files_are_kept() {
+        let path = TempPath::new("file");
+        fs::write(&path.0, "keep me").unwrap();
+        assert!(remove_stale_socket(&path.0).is_err());
+        assert_eq!(fs::read_to_string(&path.0).unwrap(), "keep me");
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    future::Future,
+    io,
+    str::FromStr,
+    sync::atomic::Ordering,
+    time::Duration,
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+};
+use tokio::io::unix::AsyncFd;
+use crate::{
+    bus::Bus,
+    client::{call_frame, closed, into_reply, refusal, unexpected_value, CancelHandle, ACTIVATION_TIMEOUT},
+    error::{Error, Result},
+    introspect::{Introspection, INTROSPECT},
+    properties::{self, Change},
+    registry::REGISTRY,
+    wire::{self, Kind, Reply},
+};
+
+/// Same as [`crate::client::ServiceClient`], but calls don't block the runtime.
+///
+/// Dropping a call's future abandons it, the next call uses a fresh connection.
+pub struct ServiceClient {
+    bus: Bus,
+    name: String,
+    stream: Option<AsyncFd<UnixStream>>,
+    timeout: Option<Duration>,
+    cancel: CancelHandle,
+}
+
+impl ServiceClient {
+    pub(crate) fn from_socket(bus: &Bus, name: &str, input: UnixStream) -> Option<Self> {
+        input.set_nonblocking(true).ok()?;
+        Some(Self {
+            bus: bus.clone(),
+            name: name.to_string(),
+            stream: Some(AsyncFd::new(input).ok()?),
+            timeout: None,
+            cancel: CancelHandle::default(),
+        })
+    }
+    /// Sets the timeout used by every call made through this client, `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+    /// Asks the service to describe itself.
+    pub async fn introspect(&mut self) -> Result<Introspection> {
+        let no_args: [&str; 0] = [];
+        Ok(self.call(INTROSPECT, no_args).await?.parse()?)
+    }
+    pub async fn call(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Result<String> {
+        let no_fds: [BorrowedFd; 0] = [];
+        self.call_with_fds(method, args, no_fds).await.map(|r| r.value)
+    }
+    /// Like [`ServiceClient::call`], overriding the client's timeout for this one call.
+    pub async fn call_timeout(&mut self, timeout: Duration, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Result<String> {
+        let no_fds: [BorrowedFd; 0] = [];
+        self.call_inner(method, args, no_fds, Some(timeout)).await.map(|r| r.value)
+    }
+    pub async fn call_with_fds<F: AsFd + Sync>(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>, fds: impl IntoIterator<Item = F>) -> Result<Reply> {
+        self.call_inner(method, args, fds, self.timeout).await
+    }
+    /// Reads a [property](crate::properties), parsed as a `V`.
+    pub async fn get_property<V: FromStr>(&mut self, name: &str) -> Result<V> {
+        let value = self.call(properties::GET, [name]).await?;
+        value.parse().map_err(|_| unexpected_value(name, &value))
+    }
+    pub async fn set_property(&mut self, name: &str, value: impl ToString) -> Result<()> {
+        self.call(properties::SET, [name.to_string(), value.to_string()]).await.map(|_| ())
+    }
+    /// Names and values of every property of the service.
+    pub async fn get_all_properties(&mut self) -> Result<Vec<(String, String)>> {
+        let no_args: [&str; 0] = [];
+        Ok(properties::parse_all(&self.call(properties::GET_ALL, no_args).await?))
+    }
+    /// Watches the properties in `names`, or all of them if it's empty, on a connection of its own.
+    ///
+    /// The [`Watcher`] starts with the current values, then gets every change as it happens.
+    pub async fn watch(&self, names: impl IntoIterator<Item = impl ToString>) -> Result<Watcher> {
+        let mut client = ServiceClient {
+            bus: self.bus.clone(),
+            name: self.name.clone(),
+            stream: None,
+            timeout: self.timeout,
+            cancel: CancelHandle::default(),
+        };
+        client.call(properties::WATCH, names).await?;
+        let stream = client.stream.take().ok_or(Error::Cancelled)?;
+        Ok(Watcher { stream })
+    }
+    /// Calls a method that streams its replies, they're read one by one from [`Replies`].
+    ///
+    /// The client's timeout applies to each reply on its own.
+    pub async fn call_stream(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Result<Replies<'_>> {
+        let (kind, frame) = call_frame(method, args);
+        let stream = self.connect()?;
+        let send = wire::send_async::<OwnedFd>(&stream, kind, &frame, &[]);
+        self.guarded(&stream, self.timeout, send).await?;
+        Ok(Replies { client: self, stream: Some(stream) })
+    }
+    async fn call_inner<F: AsFd + Sync>(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>, fds: impl IntoIterator<Item = F>, timeout: Option<Duration>) -> Result<Reply> {
+        let method = method.to_string();
+        let (kind, frame) = call_frame(&method, args);
+        let fds: Vec<F> = fds.into_iter().collect();
+
+        let stream = self.connect()?;
+        let exchange = async {
+            if let Err(e) = wire::send_async(&stream, kind, &frame, &fds).await {
+                return refusal(wire::recv_async(&stream).await).map(Some).ok_or(e);
+            }
+            wire::recv_async(&stream).await
+        };
+        let resp = self.guarded(&stream, timeout, exchange).await?.ok_or_else(closed)?;
+        // the rest of the items would be taken for the replies to later calls
+        if resp.kind == Kind::Item {
+            return Err(Error::Remote(format!("{} streams its replies, see call_stream()", method)));
+        }
+        self.stream = Some(stream);
+        into_reply(resp)
+    }
+    fn connect(&mut self) -> Result<AsyncFd<UnixStream>> {
+        if let Some(s) = self.stream.take() {
+            return Ok(s);
+        }
+        let s = self.bus.connect(&self.name)?;
+        s.set_nonblocking(true)?;
+        Ok(AsyncFd::new(s)?)
+    }
+    // lets the cancel handle and the timeout interrupt `f`
+    async fn guarded<R>(&self, stream: &AsyncFd<UnixStream>, timeout: Option<Duration>, f: impl Future<Output = io::Result<R>>) -> Result<R> {
+        *self.cancel.in_progress.lock().unwrap() = Some(stream.get_ref().try_clone()?);
+        self.cancel.cancelled.store(false, Ordering::SeqCst);
+
+        let ret = match timeout {
+            Some(t) => tokio::time::timeout(t, f).await.unwrap_or(Err(io::ErrorKind::TimedOut.into())),
+            None => f.await,
+        };
+
+        *self.cancel.in_progress.lock().unwrap() = None;
+        if self.cancel.cancelled.swap(false, Ordering::SeqCst) {
+            return Err(Error::Cancelled);
+        }
+        Ok(ret?)
+    }
+}
+
+/// Replies of a streaming call, see [`ServiceClient::call_stream`].
+///
+/// Dropping it before the end hangs up on the service, which stops the stream.
+pub struct Replies<'a> {
+    client: &'a mut ServiceClient,
+    // None once the stream ended
+    stream: Option<AsyncFd<UnixStream>>,
+}
+
+impl Replies<'_> {
+    /// The next reply, `None` once the service is done.
+    pub async fn next_reply(&mut self) -> Result<Option<Reply>> {
+        let Some(stream) = self.stream.take() else {
+            return Ok(None);
+        };
+        let msg = self.client.guarded(&stream, self.client.timeout, wire::recv_async(&stream)).await?
+            .ok_or_else(closed)?;
+        match msg.kind {
+            Kind::Item => {
+                self.stream = Some(stream);
+                into_reply(msg).map(Some)
+            },
+            // the connection is good for another call after these
+            Kind::End => {
+                self.client.stream = Some(stream);
+                Ok(None)
+            },
+            _ => {
+                self.client.stream = Some(stream);
+                into_reply(msg).map(Some)
+            },
+        }
+    }
+}
+
+/// Property changes of a service, see [`ServiceClient::watch`]. Dropping it stops watching.
+pub struct Watcher {
+    stream: AsyncFd<UnixStream>,
+}
+
+impl Watcher {
+    /// Waits for the next change, fails once the service goes away or cuts
+    /// off a watcher that fell too far behind.
+    pub async fn next_change(&mut self) -> Result<Change> {
+        loop {
+            let msg = wire::recv_async(&self.stream).await?.ok_or_else(closed)?;
+            if msg.kind != Kind::Signal {
+                continue;
+            }
+            let mut args = msg.args.into_iter();
+            if let (Some(name), Some(value)) = (args.next(), args.next()) {
+                return Ok(Change { name, value });
+            }
+        }
+    }
+}
+
+/// Has to be called from within a tokio runtime.
+pub async fn get_service(name: &str) -> Option<ServiceClient> {
+    get_service_on(&Bus::from_env(), name).await
+}
+
+pub async fn get_service_on(bus: &Bus, name: &str) -> Option<ServiceClient> {
+    if let Ok(socket) = bus.connect(name) {
+        return ServiceClient::from_socket(bus, name, socket);
+    }
+    if name == "init" {
+        return None;
+    }
+
+    let mut init = Box::pin(get_service_on(bus, "init")).await?;
+    // init holds the registry too, `name` might be an alias of something else
+    let name = match init.call_timeout(ACTIVATION_TIMEOUT, "resolve", [name]).await {
+        Ok(service) if !service.is_empty() => service,
+        _ => name.to_string(),
+    };
+    let name = name.as_str();
+    if let Ok(socket) = bus.connect(name) {
+        return ServiceClient::from_socket(bus, name, socket);
+    }
+
+    if init.call_timeout(ACTIVATION_TIMEOUT, "launch_service", [name]).await.ok()? == "ERR" {
+        return None;
+    };
+
+    let wait = async {
+        loop {
+            if let Ok(socket) = bus.connect(name) {
+                return socket;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let socket = tokio::time::timeout(ACTIVATION_TIMEOUT, wait).await.ok()?;
+
+    ServiceClient::from_socket(bus, name, socket)
+}
+
+/// Connects to the service with the newest implementation of `interface` that's at least `min_version`.
+pub async fn find_service(interface: &str, min_version: u32) -> Option<ServiceClient> {
+    find_service_on(&Bus::from_env(), interface, min_version).await
+}
+
+pub async fn find_service_on(bus: &Bus, interface: &str, min_version: u32) -> Option<ServiceClient> {
+    let mut registry = get_service_on(bus, REGISTRY).await?;
+    let name = registry.call_timeout(ACTIVATION_TIMEOUT, "find", [interface.to_string(), min_version.to_string()]).await.ok()?;
+    if name.is_empty() {
+        return None;
+    }
+    get_service_on(bus, &name).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockService;
+
+    #[tokio::test]
+    async fn calls_blocking_services() {
+        let mock = MockService::builder("input")
+            .expect("set_led", ["1", "on"], "OK")
+            .on("list", |_| "pad0 pad1")
+            .start();
+
+        let mut client = get_service_on(mock.bus(), "input").await.unwrap();
+        assert_eq!(client.call("list", [""; 0]).await.unwrap(), "pad0 pad1");
+        assert_eq!(client.call("set_led", ["1", "on"]).await.unwrap(), "OK");
+        assert!(matches!(client.call("reboot", [""; 0]).await, Err(Error::Remote(_))));
+        assert_eq!(mock.calls().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn slow_calls_time_out_or_get_cancelled() {
+        let mock = MockService::builder("sleepy")
+            .on("nap", |args: Vec<String>| {
+                let ms = args.first().and_then(|a| a.parse().ok()).unwrap_or(0);
+                std::thread::sleep(Duration::from_millis(ms));
+                "rested"
+            })
+            .start();
+
+        let mut client = get_service_on(mock.bus(), "sleepy").await.unwrap();
+        client.set_timeout(Some(Duration::from_millis(50)));
+        assert!(matches!(client.call("nap", ["500"]).await, Err(Error::Timeout)));
+        assert_eq!(client.call("nap", ["0"]).await.unwrap(), "rested");
+
+        client.set_timeout(None);
+        let cancel = client.cancel_handle();
+        let canceller = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        assert!(matches!(client.call("nap", ["1000"]).await, Err(Error::Cancelled)));
+        canceller.await.unwrap();
+        assert_eq!(client.call("nap", ["0"]).await.unwrap(), "rested");
+    }
+
+    #[tokio::test]
+    async fn missing_services_are_none() {
+        let mock = MockService::builder("input").start();
+        assert!(get_service_on(mock.bus(), "output").await.is_none());
+    }
+}
@@ -0,0 +1,564 @@
+use std::{
+    fmt,
+    io,
+    sync::Arc,
+    future::Future,
+    pin::Pin,
+    collections::HashMap,
+    os::{
+        fd::OwnedFd,
+        unix::net::UnixStream,
+    },
+};
+use tokio::{
+    io::unix::AsyncFd,
+    net::UnixListener,
+    sync::{mpsc, watch, RwLock, OwnedRwLockReadGuard, OwnedRwLockWriteGuard},
+    task::JoinSet,
+};
+use tracing::Instrument;
+use crate::{
+    bus::Claim,
+    introspect::{Introspection, INTROSPECT},
+    limits::{ClientId, Load},
+    properties::{self, Properties},
+    registry,
+    error::Error,
+    trace::{self, CallTrace, Tracer},
+    wire::{self, Kind},
+};
+pub use crate::{builder::ServerBuilder, shutdown::Shutdown, wire::Reply};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// State access given to shared methods, derefs to `&T`.
+pub type Shared<T> = OwnedRwLockReadGuard<T>;
+/// State access given to exclusive methods, derefs to `&mut T`.
+pub type Exclusive<T> = OwnedRwLockWriteGuard<T>;
+
+/// `async_srv_fn!(f)` registers `async fn f(Exclusive<T>, Vec<String>)` under its own name,
+/// `async_srv_fn!(shared f)` does the same for `async fn f(Shared<T>, Vec<String>)` and
+/// `async_srv_fn!(stream f)` for a streaming `async fn f(Shared<T>, Vec<String>, ReplySender)`.
+#[macro_export]
+macro_rules! async_srv_fn {
+    (shared $a:expr) => {
+        (stringify!($a), $crate::async_server::Method::shared($a))
+    };
+    (stream $a:expr) => {
+        (stringify!($a), $crate::async_server::Method::stream($a))
+    };
+    ($a:expr) => {
+        (stringify!($a), $crate::async_server::Method::exclusive($a))
+    };
+}
+pub use async_srv_fn;
+
+/// Like [`async_srv_fn!`], for methods that also take file descriptors.
+#[macro_export]
+macro_rules! async_srv_fd_fn {
+    (shared $a:expr) => {
+        (stringify!($a), $crate::async_server::Method::shared_with_fds($a))
+    };
+    ($a:expr) => {
+        (stringify!($a), $crate::async_server::Method::exclusive_with_fds($a))
+    };
+}
+pub use async_srv_fd_fn;
+
+type SharedFn<T> = dyn Fn(Shared<T>, Vec<String>, Vec<OwnedFd>) -> BoxFuture<Reply> + Send + Sync;
+type ExclusiveFn<T> = dyn Fn(Exclusive<T>, Vec<String>, Vec<OwnedFd>) -> BoxFuture<Reply> + Send + Sync;
+type StreamFn<T> = dyn Fn(Shared<T>, Vec<String>, ReplySender) -> BoxFuture<Result<(), String>> + Send + Sync;
+
+pub enum Method<T> {
+    /// Holds a read lock, runs concurrently with other shared methods.
+    Shared(Box<SharedFn<T>>),
+    /// Holds the write lock, runs alone.
+    Exclusive(Box<ExclusiveFn<T>>),
+    /// Like a shared method, but sends any number of replies through a
+    /// [`ReplySender`]. Returning ends the stream, with the error if there is one.
+    ///
+    /// Drop the read lock once it isn't needed anymore, it's held until then.
+    Stream(Box<StreamFn<T>>),
+}
+
+/// Replies waiting to be sent, per streaming call.
+const STREAM_BUFFER: usize = 16;
+
+/// Sends the replies of a streaming method, see [`Method::Stream`].
+#[derive(Clone)]
+pub struct ReplySender(mpsc::Sender<Reply>);
+
+impl ReplySender {
+    /// Waits while the client is behind, fails with [`Error::Cancelled`] once it's gone.
+    /// An error reply ends the stream.
+    pub async fn send(&self, reply: impl Into<Reply>) -> crate::Result<()> {
+        self.0.send(reply.into()).await.map_err(|_| Error::Cancelled)
+    }
+}
+
+impl<T: 'static> Method<T> {
+    pub fn shared<R, Fut>(f: impl Fn(Shared<T>, Vec<String>) -> Fut + Send + Sync + 'static) -> Self
+    where
+        R: Into<Reply>,
+        Fut: Future<Output = R> + Send + 'static,
+    {
+        Method::Shared(Box::new(move |state, args, _| {
+            let fut = f(state, args);
+            Box::pin(async move { fut.await.into() })
+        }))
+    }
+    pub fn exclusive<R, Fut>(f: impl Fn(Exclusive<T>, Vec<String>) -> Fut + Send + Sync + 'static) -> Self
+    where
+        R: Into<Reply>,
+        Fut: Future<Output = R> + Send + 'static,
+    {
+        Method::Exclusive(Box::new(move |state, args, _| {
+            let fut = f(state, args);
+            Box::pin(async move { fut.await.into() })
+        }))
+    }
+    pub fn shared_with_fds<R, Fut>(f: impl Fn(Shared<T>, Vec<String>, Vec<OwnedFd>) -> Fut + Send + Sync + 'static) -> Self
+    where
+        R: Into<Reply>,
+        Fut: Future<Output = R> + Send + 'static,
+    {
+        Method::Shared(Box::new(move |state, args, fds| {
+            let fut = f(state, args, fds);
+            Box::pin(async move { fut.await.into() })
+        }))
+    }
+    pub fn exclusive_with_fds<R, Fut>(f: impl Fn(Exclusive<T>, Vec<String>, Vec<OwnedFd>) -> Fut + Send + Sync + 'static) -> Self
+    where
+        R: Into<Reply>,
+        Fut: Future<Output = R> + Send + 'static,
+    {
+        Method::Exclusive(Box::new(move |state, args, fds| {
+            let fut = f(state, args, fds);
+            Box::pin(async move { fut.await.into() })
+        }))
+    }
+    pub fn stream<E, Fut>(f: impl Fn(Shared<T>, Vec<String>, ReplySender) -> Fut + Send + Sync + 'static) -> Self
+    where
+        E: fmt::Display,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        Method::Stream(Box::new(move |state, args, tx| {
+            let fut = f(state, args, tx);
+            Box::pin(async move { fut.await.map_err(|e| e.to_string()) })
+        }))
+    }
+}
+
+/// Same as [`crate::server::ServiceServer`], but handlers are `async fn`s
+/// running on the tokio runtime and the state is behind an async lock.
+///
+/// Calls are limited the same way, except that there's no worker pool, see
+/// [`ServerBuilder::workers`].
+pub struct ServiceServer<T> {
+    state: Arc<RwLock<T>>,
+    listener: UnixListener,
+    methods: Arc<HashMap<String, Method<T>>>,
+    load: Arc<Load>,
+    info: Arc<Introspection>,
+    tracer: Arc<Tracer>,
+    properties: Properties,
+    _claim: Claim,
+}
+
+tokio::task_local! {
+    static CALLER: Option<UnixStream>;
+}
+
+fn caller() -> Option<UnixStream> {
+    CALLER.try_with(|c| c.as_ref().and_then(|s| s.try_clone().ok())).ok().flatten()
+}
+
+async fn caller_gone(stream: &AsyncFd<UnixStream>) {
+    loop {
+        let Ok(mut guard) = stream.readable().await else {
+            return;
+        };
+        match guard.try_io(|s| s.get_ref().peek(&mut [0])) {
+            Ok(Ok(0)) | Ok(Err(_)) => return,
+            // the next call is already waiting, so it's still there
+            Ok(Ok(_)) => return std::future::pending().await,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+async fn handle_call<T>(stream: &AsyncFd<UnixStream>, methods: &HashMap<String, Method<T>>, state: &Arc<RwLock<T>>, msg: wire::Message, client: ClientId, tracer: &Tracer) -> Option<()> {
+    let Some(call) = msg.into_call() else {
+        return wire::send_error_async(stream, "malformed call").await.ok();
+    };
+    let trace = tracer.start(&call.method, client, call.trace.unwrap_or_default());
+
+    let handler = match methods.get(&call.method) {
+        Some(Method::Shared(function)) => function(Arc::clone(state).read_owned().await, call.args, call.fds),
+        Some(Method::Exclusive(function)) => function(Arc::clone(state).write_owned().await, call.args, call.fds),
+        Some(Method::Stream(function)) => {
+            let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+            let producer = function(Arc::clone(state).read_owned().await, call.args, ReplySender(tx));
+            let producer = trace::scope(trace.id, producer.instrument(trace.span.clone()));
+            let producer = CALLER.scope(stream.get_ref().try_clone().ok(), producer);
+            return send_stream(stream, producer, rx, trace).await;
+        },
+        None => {
+            trace.finish("no such method");
+            return wire::send_error_async(stream, &format!("no such method: {}", call.method)).await.ok();
+        },
+    };
+    let handler = trace::scope(trace.id, handler.instrument(trace.span.clone()));
+    let handler = CALLER.scope(stream.get_ref().try_clone().ok(), handler);
+
+    // a client that disconnected or cancelled no longer cares about the result
+    let mut response = tokio::select! {
+        r = handler => r,
+        _ = caller_gone(stream) => {
+            trace.finish("caller gone");
+            return None;
+        },
+    };
+
+    let sent = wire::send_reply_async(stream, &response).await.ok();
+    if sent.is_some() {
+        response.sent();
+    }
+    trace.finish(match (sent, response.is_error) {
+        (None, _) => "reply failed",
+        (Some(_), true) => "error",
+        (Some(_), false) => "ok",
+    });
+    sent
+}
+
+async fn send_stream(stream: &AsyncFd<UnixStream>, producer: impl Future<Output = Result<(), String>>, mut rx: mpsc::Receiver<Reply>, trace: CallTrace) -> Option<()> {
+    // runs until every sender is gone, `Ok(true)` if an error reply ended it early
+    let forward = async {
+        while let Some(item) = rx.recv().await {
+            wire::send_item_async(stream, &item).await?;
+            if item.is_error {
+                return Ok(true);
+            }
+        }
+        io::Result::Ok(false)
+    };
+
+    let (result, forwarded) = tokio::select! {
+        v = async { tokio::join!(producer, forward) } => v,
+        _ = caller_gone(stream) => {
+            trace.finish("caller gone");
+            return None;
+        },
+    };
+    let sent = match (forwarded, result) {
+        (Err(_), _) => None,
+        (Ok(true), _) => {
+            trace.finish("error");
+            return Some(());
+        },
+        (Ok(false), Err(e)) => wire::send_error_async(stream, &e).await.ok().map(|_| "error"),
+        (Ok(false), Ok(())) => wire::send_async::<OwnedFd>(stream, Kind::End, &[], &[]).await.ok().map(|_| "ok"),
+    };
+    trace.finish(sent.unwrap_or("reply failed"));
+    sent.map(|_| ())
+}
+
+async fn handle_client<T>(stream: AsyncFd<UnixStream>, methods: Arc<HashMap<String, Method<T>>>, state: Arc<RwLock<T>>, load: Arc<Load>, info: Arc<Introspection>, tracer: Arc<Tracer>, mut draining: watch::Receiver<bool>) {
+    if !load.connected() {
+        let _ = wire::send_async::<OwnedFd>(&stream, Kind::Busy, &["too many connections".to_string()], &[]).await;
+        return;
+    }
+    let client = stream.get_ref().peer_cred().ok().and_then(|c| c.pid);
+    loop {
+        // only idle connections get cut off, calls in progress are left to finish
+        let msg = tokio::select! {
+            msg = wire::recv_async(&stream) => msg,
+            _ = draining.wait_for(|v| *v) => break,
+        };
+        let Ok(Some(msg)) = msg else {
+            break;
+        };
+
+        // no lock or load limit stands in the way of seeing the load
+        if msg.method() == Some(INTROSPECT) {
+            let mut info = (*info).clone();
+            info.metrics = load.metrics();
+            if wire::send_async::<OwnedFd>(&stream, Kind::Reply, &[info.to_string()], &[]).await.is_err() {
+                break;
+            }
+            continue;
+        }
+
+        let mut permit = match load.admit(client) {
+            Ok(permit) => permit,
+            Err(reason) => {
+                if wire::send_async::<OwnedFd>(&stream, Kind::Busy, &[reason.to_string()], &[]).await.is_err() {
+                    break;
+                }
+                continue;
+            },
+        };
+        permit.start();
+        let ret = handle_call(&stream, &methods, &state, msg, client, &tracer).await;
+        drop(permit);
+
+        if ret.is_none() || *draining.borrow() {
+            break;
+        }
+    }
+    load.disconnected();
+}
+
+impl<T: Send + Sync + 'static> ServerBuilder<T, Method<T>> {
+    /// Has to be called from within a tokio runtime.
+    pub fn build(mut self) -> Option<ServiceServer<T>> {
+        let (listener, claim) = match self.bus.claim(&self.name) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Failed to register {}: {}", self.name, e);
+                return None;
+            }
+        };
+
+        if !self.properties.is_empty() {
+            let props = [(); 4].map(|_| self.properties.clone());
+            let [get, set, get_all, watch] = props;
+            self.methods.extend([
+                (properties::GET.to_string(), Method::shared(move |_, args| std::future::ready(get.handle_get(args)))),
+                (properties::SET.to_string(), Method::shared(move |_, args| std::future::ready(set.handle_set(args)))),
+                (properties::GET_ALL.to_string(), Method::shared(move |_, _| std::future::ready(get_all.handle_get_all()))),
+                (properties::WATCH.to_string(), Method::shared(move |_, args| {
+                    let watch = watch.clone();
+                    // the caller is only known once the handler runs
+                    async move { watch.handle_watch(args, caller()) }
+                })),
+            ]);
+        }
+        let mut info = self.introspection();
+        info.streams = self.methods.iter()
+            .filter(|(_, m)| matches!(m, Method::Stream(_)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        info.streams.sort();
+        // blocks for a moment at most, and only once at startup
+        registry::announce(&self.bus, &info);
+        listener.set_nonblocking(true).ok()?;
+
+        Some(ServiceServer {
+            state: Arc::new(RwLock::new(self.state)),
+            listener: UnixListener::from_std(listener).ok()?,
+            methods: Arc::new(self.methods),
+            load: Load::new(self.limits),
+            info: Arc::new(info),
+            tracer: Arc::new(Tracer { service: self.name, level: self.trace_level }),
+            properties: self.properties,
+            _claim: claim,
+        })
+    }
+}
+
+impl<T: Send + Sync + 'static> ServiceServer<T> {
+    /// Has to be called from within a tokio runtime.
+    pub fn new(name: &str, methods: impl IntoIterator<Item = (impl ToString, Method<T>)>, v: T) -> Option<ServiceServer<T>> {
+        Self::builder(name, v).methods(methods).build()
+    }
+    pub fn builder(name: &str, v: T) -> ServerBuilder<T, Method<T>> {
+        ServerBuilder::new(name, v)
+    }
+    /// Handle to change the service's properties from outside of its handlers.
+    pub fn properties(&self) -> Properties {
+        self.properties.clone()
+    }
+    /// Serves calls until SIGTERM or SIGINT, then cleans up and exits the process.
+    pub async fn run(self) -> ! {
+        self.run_until(&Shutdown::on_signals()).await;
+        std::process::exit(0);
+    }
+    /// Serves calls until `shutdown` is triggered, then waits for the calls
+    /// in progress to finish and unregisters the service.
+    pub async fn run_until(self, shutdown: &Shutdown) {
+        let (drain_tx, drain_rx) = watch::channel(false);
+        let mut clients = JoinSet::new();
+        loop {
+            let stream = tokio::select! {
+                _ = shutdown.triggered() => break,
+                Some(_) = clients.join_next(), if !clients.is_empty() => continue,
+                stream = self.listener.accept() => stream,
+            };
+            match stream.and_then(|(s, _)| s.into_std()).and_then(AsyncFd::new) {
+                Ok(s) => {
+                    let state_ptr = Arc::clone(&self.state);
+                    let method_ptr = Arc::clone(&self.methods);
+                    let load_ptr = Arc::clone(&self.load);
+                    let info_ptr = Arc::clone(&self.info);
+                    let tracer_ptr = Arc::clone(&self.tracer);
+                    clients.spawn(handle_client(s, method_ptr, state_ptr, load_ptr, info_ptr, tracer_ptr, drain_rx.clone()));
+                },
+                Err(e) => eprintln!("Failed to connect to client: {}", e),
+            }
+        }
+
+        let _ = drain_tx.send(true);
+        self.properties.disconnect_all();
+        while clients.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::{async_client, testing::TestBus};
+
+    fn serve<T: Send + Sync + 'static>(bus: &TestBus, builder: ServerBuilder<T, Method<T>>) -> (Shutdown, tokio::task::JoinHandle<()>) {
+        let server = builder.bus(bus.bus()).build().expect("Failed to start the test service");
+        let shutdown = Shutdown::new();
+        let ptr = shutdown.clone();
+        (shutdown, tokio::spawn(async move { server.run_until(&ptr).await }))
+    }
+
+    fn counter() -> ServerBuilder<u32, Method<u32>> {
+        ServiceServer::builder("counter", 0u32)
+            .method("get", Method::shared(|n: Shared<u32>, _| async move { n.to_string() }))
+            .method("add", Method::exclusive(|mut n: Exclusive<u32>, args: Vec<String>| async move {
+                let by = args.first().and_then(|a| a.parse::<u32>().ok()).ok_or("add takes a number")?;
+                *n += by;
+                Ok::<_, &str>(n.to_string())
+            }))
+    }
+
+    #[tokio::test]
+    async fn handlers_read_and_change_the_state() {
+        let bus = TestBus::new();
+        let (shutdown, server) = serve(&bus, counter());
+
+        let mut client = async_client::get_service_on(&bus.bus(), "counter").await.unwrap();
+        assert_eq!(client.call("get", [""; 0]).await.unwrap(), "0");
+        assert_eq!(client.call("add", ["2"]).await.unwrap(), "2");
+        assert_eq!(client.call("add", ["3"]).await.unwrap(), "5");
+        assert!(matches!(client.call("add", ["many"]).await, Err(Error::Remote(e)) if e == "add takes a number"));
+        assert!(matches!(client.call("reset", [""; 0]).await, Err(Error::Remote(_))));
+        // another connection sees the same state
+        let mut other = async_client::get_service_on(&bus.bus(), "counter").await.unwrap();
+        assert_eq!(other.call("get", [""; 0]).await.unwrap(), "5");
+
+        shutdown.trigger();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shared_handlers_run_concurrently() {
+        let bus = TestBus::new();
+        let builder = ServiceServer::builder("sleepy", ())
+            .method("nap", Method::shared(|_: Shared<()>, _| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "rested"
+            }));
+        let (shutdown, server) = serve(&bus, builder);
+
+        let mut first = async_client::get_service_on(&bus.bus(), "sleepy").await.unwrap();
+        let mut second = async_client::get_service_on(&bus.bus(), "sleepy").await.unwrap();
+        let start = std::time::Instant::now();
+        let (a, b) = tokio::join!(first.call("nap", [""; 0]), second.call("nap", [""; 0]));
+        assert_eq!((a.unwrap(), b.unwrap()), ("rested".to_string(), "rested".to_string()));
+        // one after the other would take 400ms
+        assert!(start.elapsed() < Duration::from_millis(350));
+
+        shutdown.trigger();
+        server.await.unwrap();
+    }
+
+    // tells when the handler holding it is done, one way or another
+    struct Flag(std::sync::mpsc::Sender<()>);
+
+    impl Drop for Flag {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_are_dropped_once_the_caller_leaves() {
+        let bus = TestBus::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let builder = ServiceServer::builder("patient", ())
+            .method("wait", Method::shared(move |_: Shared<()>, _| {
+                let flag = Flag(tx.lock().unwrap().clone());
+                async move {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    drop(flag);
+                    "too late"
+                }
+            }));
+        let (shutdown, server) = serve(&bus, builder);
+
+        let mut client = async_client::get_service_on(&bus.bus(), "patient").await.unwrap();
+        assert!(matches!(client.call_timeout(Duration::from_millis(50), "wait", [""; 0]).await, Err(Error::Timeout)));
+        let dropped = tokio::task::spawn_blocking(move || rx.recv_timeout(Duration::from_secs(2)));
+        assert!(dropped.await.unwrap().is_ok());
+
+        shutdown.trigger();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_send_until_the_producer_returns() {
+        let bus = TestBus::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let builder = ServiceServer::builder("counter", ())
+            .method("count", Method::stream(|_: Shared<()>, args: Vec<String>, replies: ReplySender| async move {
+                let n = args.first().and_then(|a| a.parse::<usize>().ok()).ok_or("count takes a number")?;
+                for i in 0..n {
+                    replies.send(i.to_string()).await.map_err(|e| e.to_string())?;
+                }
+                Ok::<_, String>(())
+            }))
+            .method("forever", Method::stream(move |_: Shared<()>, _, replies: ReplySender| {
+                let flag = Flag(tx.lock().unwrap().clone());
+                async move {
+                    while replies.send("more").await.is_ok() {}
+                    drop(flag);
+                    Ok::<_, String>(())
+                }
+            }));
+        let (shutdown, server) = serve(&bus, builder);
+
+        let mut client = async_client::get_service_on(&bus.bus(), "counter").await.unwrap();
+        let mut replies = client.call_stream("count", ["3"]).await.unwrap();
+        let mut items = Vec::new();
+        while let Some(reply) = replies.next_reply().await.unwrap() {
+            items.push(reply.value);
+        }
+        assert_eq!(items, ["0", "1", "2"]);
+        let mut replies = client.call_stream("count", ["many"]).await.unwrap();
+        assert!(matches!(replies.next_reply().await, Err(Error::Remote(e)) if e == "count takes a number"));
+        assert!(matches!(client.call("count", ["1"]).await, Err(Error::Remote(e)) if e.contains("call_stream")));
+
+        let mut replies = client.call_stream("forever", [""; 0]).await.unwrap();
+        assert_eq!(replies.next_reply().await.unwrap().unwrap().value, "more");
+        drop(replies);
+        let stopped = tokio::task::spawn_blocking(move || rx.recv_timeout(Duration::from_secs(2)));
+        assert!(stopped.await.unwrap().is_ok());
+
+        shutdown.trigger();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn introspection_lists_the_methods() {
+        let bus = TestBus::new();
+        let (shutdown, server) = serve(&bus, counter().method_args("add", ["by"]));
+
+        let mut client = async_client::get_service_on(&bus.bus(), "counter").await.unwrap();
+        let info = client.introspect().await.unwrap();
+        assert_eq!(info.service, "counter");
+        assert_eq!(info.methods, ["add", "get", "introspect"]);
+        assert_eq!(info.args.get("add"), Some(&vec!["by".to_string()]));
+
+        shutdown.trigger();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::{
+    bus::Bus,
+    introspect::{Interface, Introspection, INTROSPECT},
+    limits::Limits,
+    properties::{self, Properties, Property, PropertyValue},
+    server::UNKNOWN_METHOD,
+    trace::Level,
+};
+
+/// Collects everything needed to start a service, finished by `build()` in
+/// [`crate::server`] or [`crate::async_server`] depending on the method type.
+pub struct ServerBuilder<T, M> {
+    pub(crate) name: String,
+    pub(crate) bus: Bus,
+    pub(crate) methods: HashMap<String, M>,
+    pub(crate) state: T,
+    pub(crate) interfaces: Vec<Interface>,
+    pub(crate) aliases: Vec<String>,
+    pub(crate) limits: Limits,
+    pub(crate) trace_level: Level,
+    pub(crate) args: BTreeMap<String, Vec<String>>,
+    pub(crate) replies: BTreeMap<String, Vec<String>>,
+    pub(crate) properties: Properties,
+}
+
+impl<T, M> ServerBuilder<T, M> {
+    pub(crate) fn new(name: &str, state: T) -> Self {
+        Self {
+            name: name.to_string(),
+            bus: Bus::from_env(),
+            methods: HashMap::new(),
+            state,
+            interfaces: Vec::new(),
+            aliases: Vec::new(),
+            limits: Limits::default(),
+            trace_level: Level::DEBUG,
+            args: BTreeMap::new(),
+            replies: BTreeMap::new(),
+            properties: Properties::default(),
+        }
+    }
+    /// Bus to register on, defaults to [`Bus::from_env`].
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.bus = bus;
+        self
+    }
+    pub fn method(mut self, name: impl ToString, method: M) -> Self {
+        self.methods.insert(name.to_string(), method);
+        self
+    }
+    pub fn methods(mut self, methods: impl IntoIterator<Item = (impl ToString, M)>) -> Self {
+        self.methods.extend(methods.into_iter().map(|(v1, v2)| (v1.to_string(), v2)));
+        self
+    }
+    /// Names the arguments of `method` in the service's introspection data, so that
+    /// bridges and tools can check calls before making them.
+    ///
+    /// `name?` marks an optional argument, `name...` one taking all the remaining ones.
+    pub fn method_args(mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.args.insert(method.to_string(), args.into_iter().map(|a| a.to_string()).collect());
+        self
+    }
+    /// Names the tab-separated fields of each line `method` answers with in
+    /// the service's introspection data, for methods that list things.
+    pub fn method_reply(mut self, method: impl ToString, fields: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.replies.insert(method.to_string(), fields.into_iter().map(|f| f.to_string()).collect());
+        self
+    }
+    /// Announces the service in the [registry](crate::registry) as an implementation of `interface`.
+    pub fn implements(mut self, interface: impl ToString, version: u32) -> Self {
+        self.interfaces.push(Interface::new(interface, version));
+        self
+    }
+    /// Makes the service reachable as `alias` too, taking it over from whoever had it.
+    pub fn alias(mut self, alias: impl ToString) -> Self {
+        self.aliases.push(alias.to_string());
+        self
+    }
+    /// Number of threads running handlers, 4 by default.
+    ///
+    /// Only the blocking server has its own threads, the async one runs on the tokio runtime.
+    /// Handlers calling back into their own service need more workers than
+    /// they can nest, or they'll wait on each other forever.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.limits.workers = workers.max(1);
+        self
+    }
+    /// Calls that may be handled or waiting for a worker at once, 64 by default.
+    /// Calls above that are refused with [`crate::Error::Busy`].
+    pub fn max_in_flight(mut self, calls: usize) -> Self {
+        self.limits.max_in_flight = calls.max(1);
+        self
+    }
+    /// Like [`ServerBuilder::max_in_flight`], for the calls of a single client process, 16 by default.
+    pub fn max_calls_per_client(mut self, calls: usize) -> Self {
+        self.limits.max_per_client = calls.max(1);
+        self
+    }
+    /// Connections the service keeps open at once, 256 by default. Clients
+    /// connecting past that are refused with [`crate::Error::Busy`].
+    pub fn max_connections(mut self, connections: usize) -> Self {
+        self.limits.max_connections = connections.max(1);
+        self
+    }
+    /// Level of the `tracing` span and event emitted for every call, `DEBUG` by default.
+    pub fn trace_level(mut self, level: Level) -> Self {
+        self.trace_level = level;
+        self
+    }
+    /// Declares a [property](crate::properties) clients can get, watch and,
+    /// if it's writable, set.
+    pub fn property<V: PropertyValue>(self, name: impl ToString, property: Property<V>) -> Self {
+        self.properties.insert(&name.to_string(), property);
+        self
+    }
+    pub(crate) fn introspection(&self) -> Introspection {
+        let mut methods: Vec<String> = self.methods.keys().filter(|name| *name != UNKNOWN_METHOD).cloned().collect();
+        methods.push(INTROSPECT.to_string());
+        methods.sort();
+        let mut args = self.args.clone();
+        args.insert(INTROSPECT.to_string(), Vec::new());
+        if !self.properties.is_empty() {
+            args.extend(properties::METHODS.iter()
+                .map(|(name, a)| (name.to_string(), a.iter().map(|v| v.to_string()).collect())));
+        }
+        Introspection {
+            service: self.name.clone(),
+            interfaces: self.interfaces.clone(),
+            aliases: self.aliases.clone(),
+            methods,
+            args,
+            replies: self.replies.clone(),
+            metrics: Vec::new(),
+            properties: self.properties.info(),
+            streams: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    env, fs, io,
+    fs::File,
+    path::PathBuf,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            fs::MetadataExt,
+            net::{SocketAddr, UnixListener, UnixStream},
+        },
+    },
+};
+
+/// Selects the bus used by [`Bus::from_env`].
+///
+/// `system` and `session` pick [`Bus::system`] and [`Bus::session`], `@prefix`
+/// picks abstract sockets named `prefix<service>`, anything else is taken as
+/// the directory holding the sockets.
+pub const BUS_ENV: &str = "RSERVICE_BUS";
+
+/// Where services put their sockets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Bus {
+    /// `<dir>/<service>` socket files.
+    Dir(PathBuf),
+    /// Linux abstract sockets named `<prefix><service>`, they never touch the
+    /// filesystem and go away together with the process.
+    Abstract(String),
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus::from_env()
+    }
+}
+
+impl Bus {
+    /// The system-wide bus, `/srv`.
+    pub fn system() -> Self {
+        Bus::Dir(PathBuf::from("/srv"))
+    }
+    /// Per-user bus in `$XDG_RUNTIME_DIR/srv`, or `/run/user/<uid>/srv` if that's unset.
+    pub fn session() -> Self {
+        let runtime_dir = match env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                let uid = fs::metadata("/proc/self").map(|m| m.uid()).unwrap_or(0);
+                PathBuf::from(format!("/run/user/{}", uid))
+            }
+        };
+        Bus::Dir(runtime_dir.join("srv"))
+    }
+    pub fn dir(path: impl Into<PathBuf>) -> Self {
+        Bus::Dir(path.into())
+    }
+    pub fn abstract_prefix(prefix: impl ToString) -> Self {
+        Bus::Abstract(prefix.to_string())
+    }
+    /// Reads [`BUS_ENV`], falling back to the system bus.
+    pub fn from_env() -> Self {
+        match env::var(BUS_ENV) {
+            Ok(v) if v == "system" || v.is_empty() => Bus::system(),
+            Ok(v) if v == "session" => Bus::session(),
+            Ok(v) => match v.strip_prefix('@') {
+                Some(prefix) => Bus::abstract_prefix(prefix),
+                None => Bus::dir(v),
+            },
+            Err(_) => Bus::system(),
+        }
+    }
+
+    /// Path of the service's socket, `None` for abstract sockets.
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        match self {
+            Bus::Dir(dir) => Some(dir.join(name)),
+            Bus::Abstract(_) => None,
+        }
+    }
+    pub(crate) fn addr(&self, name: &str) -> io::Result<SocketAddr> {
+        match self {
+            Bus::Dir(dir) => SocketAddr::from_pathname(dir.join(name)),
+            Bus::Abstract(prefix) => SocketAddr::from_abstract_name(format!("{}{}", prefix, name)),
+        }
+    }
+    pub(crate) fn connect(&self, name: &str) -> io::Result<UnixStream> {
+        UnixStream::connect_addr(&self.addr(name)?)
+    }
+    /// Binds the service's socket, taking over sockets left behind by a dead instance.
+    pub(crate) fn claim(&self, name: &str) -> io::Result<(UnixListener, Claim)> {
+        let Bus::Dir(dir) = self else {
+            // the kernel drops abstract names together with their owner, they can't go stale
+            let listener = UnixListener::bind_addr(&self.addr(name)?)?;
+            return Ok((listener, Claim { socket: None, _lock: None }));
+        };
+
+        fs::create_dir_all(dir)?;
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(format!(".{}.lock", name)))?;
+        if lock.try_lock().is_err() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already running", name)));
+        }
+
+        let socket = dir.join(name);
+        if socket.exists() {
+            if UnixStream::connect(&socket).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already running", name)));
+            }
+            eprintln!("Removing stale socket {}", socket.display());
+            fs::remove_file(&socket)?;
+        }
+
+        let listener = UnixListener::bind(&socket)?;
+        Ok((listener, Claim { socket: Some(socket), _lock: Some(lock) }))
+    }
+}
+
+/// Ownership of a service name, gives it up when dropped.
+pub(crate) struct Claim {
+    socket: Option<PathBuf>,
+    // the lock file itself stays, unlinking it would let two instances lock different files
+    _lock: Option<File>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            let _ = fs::remove_file(socket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileTypeExt;
+    use crate::{client, testing::{MockService, TestBus}};
+
+    #[test]
+    fn the_env_picks_the_bus() {
+        // the only test touching the environment
+        let saved = (env::var_os(BUS_ENV), env::var_os("XDG_RUNTIME_DIR"));
+        env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
+        for (value, bus) in [
+            (Some("system"), Bus::system()),
+            (Some(""), Bus::system()),
+            (None, Bus::system()),
+            (Some("session"), Bus::dir("/run/user/1000/srv")),
+            (Some("@test-"), Bus::abstract_prefix("test-")),
+            (Some("/tmp/srv"), Bus::dir("/tmp/srv")),
+        ] {
+            match value {
+                Some(v) => env::set_var(BUS_ENV, v),
+                None => env::remove_var(BUS_ENV),
+            }
+            assert_eq!(Bus::from_env(), bus, "{:?}", value);
+        }
+        for (name, value) in [(BUS_ENV, saved.0), ("XDG_RUNTIME_DIR", saved.1)] {
+            match value {
+                Some(v) => env::set_var(name, v),
+                None => env::remove_var(name),
+            }
+        }
+    }
+
+    #[test]
+    fn sockets_go_where_the_bus_says() {
+        let test_bus = TestBus::new();
+        let mock = MockService::builder("input").bus(test_bus.bus()).on("list", |_| "pad0").start();
+        let path = mock.bus().path("input").unwrap();
+        assert!(fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+        drop(mock);
+        assert!(!path.exists());
+
+        let bus = Bus::abstract_prefix(format!("rservice-bus-test-{}-", std::process::id()));
+        let mock = MockService::builder("input").bus(bus.clone()).on("list", |_| "pad0").start();
+        assert_eq!(bus.path("input"), None);
+        assert_eq!(client::get_service_on(&bus, "input").unwrap().call("list", [""; 0]).unwrap(), "pad0");
+        drop(mock);
+        assert!(bus.connect("input").is_err());
+    }
+
+    #[test]
+    fn stale_sockets_are_reclaimed() {
+        let test_bus = TestBus::new();
+        let bus = test_bus.bus();
+        // what a crashed instance leaves behind
+        drop(UnixListener::bind(bus.path("input").unwrap()).unwrap());
+        assert!(bus.connect("input").is_err());
+
+        let mock = MockService::builder("input").bus(bus.clone()).on("list", |_| "pad0").start();
+        assert_eq!(mock.client().call("list", [""; 0]).unwrap(), "pad0");
+    }
+
+    #[test]
+    fn running_services_keep_their_name() {
+        let mock = MockService::builder("input").on("list", |_| "pad0").start();
+        assert!(matches!(mock.bus().claim("input"), Err(e) if e.kind() == io::ErrorKind::AddrInUse));
+        assert_eq!(mock.client().call("list", [""; 0]).unwrap(), "pad0");
+
+        // someone listening without the lock, like an older version of the service
+        let test_bus = TestBus::new();
+        let bus = test_bus.bus();
+        let _listener = UnixListener::bind(bus.path("output").unwrap()).unwrap();
+        assert!(matches!(bus.claim("output"), Err(e) if e.kind() == io::ErrorKind::AddrInUse));
+        assert!(bus.connect("output").is_ok());
+    }
+
+    #[test]
+    fn buses_dont_see_each_other() {
+        let first = MockService::builder("input").on("list", |_| "first").start();
+        let second = MockService::builder("input").on("list", |_| "second").start();
+        assert_ne!(first.bus(), second.bus());
+        assert_eq!(first.client().call("list", [""; 0]).unwrap(), "first");
+        assert_eq!(second.client().call("list", [""; 0]).unwrap(), "second");
+        assert!(client::get_service_on(first.bus(), "output").is_none());
+    }
+}
@@ -1,62 +1,403 @@
 use std::{
-    collections::HashSet,
-    path::PathBuf,
-    os::unix::net::UnixStream,
-    io::{
-        Read,
-        Write,
+    io,
+    net::Shutdown,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Mutex,
     },
+    time::{Duration, Instant},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+};
+use crate::{
+    bus::Bus,
+    error::{Error, Result},
+    introspect::{Introspection, INTROSPECT},
+    properties::{self, Change},
+    registry::REGISTRY,
+    trace,
+    wire::{self, Kind, Reply},
 };
 
+/// How long [`get_service`] waits for init to start a service.
+pub const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct ServiceClient {
-    stream: UnixStream,
+    bus: Bus,
+    name: String,
+    // None after a call was interrupted, the reply could still arrive later and we
+    // don't want to mistake it for the answer to the next call
+    stream: Option<UnixStream>,
+    timeout: Option<Duration>,
+    cancel: CancelHandle,
+}
+
+/// Aborts the call currently in progress on a [`ServiceClient`], from any thread.
+#[derive(Clone, Default)]
+pub struct CancelHandle {
+    pub(crate) in_progress: Arc<Mutex<Option<UnixStream>>>,
+    pub(crate) cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Makes the pending call return [`Error::Cancelled`], does nothing if there is none.
+    pub fn cancel(&self) {
+        if let Some(stream) = self.in_progress.lock().unwrap().as_ref() {
+            self.cancelled.store(true, Ordering::SeqCst);
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
 }
 
 impl ServiceClient {
-    fn from_socket(mut input: UnixStream) -> Option<Self> {
+    pub(crate) fn from_socket(bus: &Bus, name: &str, input: UnixStream) -> Option<Self> {
         Some(Self {
-            stream: input,
+            bus: bus.clone(),
+            name: name.to_string(),
+            stream: Some(input),
+            timeout: None,
+            cancel: CancelHandle::default(),
         })
     }
-    pub fn call(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Option<String> {
-        let mut tmp: Vec<String> = Vec::new();
-        tmp.push(method.to_string());
-        tmp.extend(args.into_iter().map(|v| v.to_string()));
-        let mut full_call: String = tmp.into_iter()
-            .map(|v| format!("{}:", v))
-            .collect();
-        full_call.pop();
-        full_call.push('\n');
+    /// Sets the timeout used by every call made through this client, `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+    /// Asks the service to describe itself.
+    pub fn introspect(&mut self) -> Result<Introspection> {
+        let no_args: [&str; 0] = [];
+        Ok(self.call(INTROSPECT, no_args)?.parse()?)
+    }
+    pub fn call(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Result<String> {
+        let no_fds: [BorrowedFd; 0] = [];
+        self.call_with_fds(method, args, no_fds).map(|r| r.value)
+    }
+    /// Like [`ServiceClient::call`], overriding the client's timeout for this one call.
+    pub fn call_timeout(&mut self, timeout: Duration, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Result<String> {
+        let no_fds: [BorrowedFd; 0] = [];
+        self.call_inner(method, args, no_fds, Some(timeout)).map(|r| r.value)
+    }
+    pub fn call_with_fds<F: AsFd>(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>, fds: impl IntoIterator<Item = F>) -> Result<Reply> {
+        self.call_inner(method, args, fds, self.timeout)
+    }
+    /// Reads a [property](crate::properties), parsed as a `V`.
+    pub fn get_property<V: FromStr>(&mut self, name: &str) -> Result<V> {
+        let value = self.call(properties::GET, [name])?;
+        value.parse().map_err(|_| unexpected_value(name, &value))
+    }
+    pub fn set_property(&mut self, name: &str, value: impl ToString) -> Result<()> {
+        self.call(properties::SET, [name.to_string(), value.to_string()]).map(|_| ())
+    }
+    /// Names and values of every property of the service.
+    pub fn get_all_properties(&mut self) -> Result<Vec<(String, String)>> {
+        let no_args: [&str; 0] = [];
+        Ok(properties::parse_all(&self.call(properties::GET_ALL, no_args)?))
+    }
+    /// Watches the properties in `names`, or all of them if it's empty, on a connection of its own.
+    ///
+    /// The [`Watcher`] starts with the current values, then gets every change as it happens.
+    pub fn watch(&self, names: impl IntoIterator<Item = impl ToString>) -> Result<Watcher> {
+        let mut client = ServiceClient {
+            bus: self.bus.clone(),
+            name: self.name.clone(),
+            stream: None,
+            timeout: self.timeout,
+            cancel: CancelHandle::default(),
+        };
+        client.call(properties::WATCH, names)?;
+        let stream = client.stream.take().ok_or(Error::Cancelled)?;
+        Ok(Watcher { stream, timeout: None })
+    }
+    /// Calls a method that streams its replies, they're read one by one from [`Replies`].
+    ///
+    /// The client's timeout applies to each reply on its own.
+    pub fn call_stream(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> Result<Replies<'_>> {
+        let (kind, frame) = call_frame(method, args);
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let stream = self.connect(deadline)?;
+        self.guarded(&stream, || wire::send_until::<OwnedFd>(&stream, kind, &frame, &[], deadline))?;
+        Ok(Replies { client: self, stream: Some(stream) })
+    }
+    fn call_inner<F: AsFd>(&mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>, fds: impl IntoIterator<Item = F>, timeout: Option<Duration>) -> Result<Reply> {
+        let method = method.to_string();
+        let (kind, frame) = call_frame(&method, args);
+        let fds: Vec<F> = fds.into_iter().collect();
+        let deadline = timeout.map(|t| Instant::now() + t);
 
-        self.stream.write_all(full_call.as_str().as_bytes()).ok()?;
+        let stream = self.connect(deadline)?;
+        let resp = self.guarded(&stream, || {
+            if let Err(e) = wire::send_until(&stream, kind, &frame, &fds, deadline) {
+                return refusal(wire::recv_until(&stream, deadline)).map(Some).ok_or(e);
+            }
+            wire::recv_until(&stream, deadline)
+        })?;
+        let resp = resp.ok_or_else(closed)?;
+        // the rest of the items would be taken for the replies to later calls
+        if resp.kind == Kind::Item {
+            return Err(Error::Remote(format!("{} streams its replies, see call_stream()", method)));
+        }
+        self.stream = Some(stream);
+        into_reply(resp)
+    }
+    fn connect(&mut self, deadline: Option<Instant>) -> Result<UnixStream> {
+        let stream = match self.stream.take() {
+            Some(s) => s,
+            None => self.bus.connect(&self.name)?,
+        };
+        if deadline.is_none() {
+            stream.set_read_timeout(None)?;
+            stream.set_write_timeout(None)?;
+        }
+        Ok(stream)
+    }
+    // lets the cancel handle interrupt `f`
+    fn guarded<R>(&self, stream: &UnixStream, f: impl FnOnce() -> io::Result<R>) -> Result<R> {
+        *self.cancel.in_progress.lock().unwrap() = Some(stream.try_clone()?);
+        self.cancel.cancelled.store(false, Ordering::SeqCst);
+
+        let ret = f();
+
+        *self.cancel.in_progress.lock().unwrap() = None;
+        if self.cancel.cancelled.swap(false, Ordering::SeqCst) {
+            return Err(Error::Cancelled);
+        }
+        Ok(ret?)
+    }
+}
+
+/// Replies of a streaming call, see [`ServiceClient::call_stream`].
+///
+/// Dropping it before the end hangs up on the service, which stops the stream.
+pub struct Replies<'a> {
+    client: &'a mut ServiceClient,
+    // None once the stream ended
+    stream: Option<UnixStream>,
+}
 
-        let mut buf = Vec::new();
-        self.stream.read_to_end(&mut buf).ok()?;
-        
-        Some(String::from_utf8_lossy(&buf).trim().to_string())
+impl Replies<'_> {
+    /// The next reply, `None` once the service is done.
+    pub fn next_reply(&mut self) -> Result<Option<Reply>> {
+        let Some(stream) = self.stream.take() else {
+            return Ok(None);
+        };
+        let deadline = self.client.timeout.map(|t| Instant::now() + t);
+        if deadline.is_none() {
+            stream.set_read_timeout(None)?;
+        }
+        let msg = self.client.guarded(&stream, || wire::recv_until(&stream, deadline))?
+            .ok_or_else(closed)?;
+        match msg.kind {
+            Kind::Item => {
+                self.stream = Some(stream);
+                into_reply(msg).map(Some)
+            },
+            // the connection is good for another call after these
+            Kind::End => {
+                self.client.stream = Some(stream);
+                Ok(None)
+            },
+            _ => {
+                self.client.stream = Some(stream);
+                into_reply(msg).map(Some)
+            },
+        }
+    }
+}
+
+impl Iterator for Replies<'_> {
+    type Item = Result<String>;
+    /// Like [`Replies::next_reply`], without the file descriptors.
+    fn next(&mut self) -> Option<Result<String>> {
+        self.next_reply().transpose().map(|r| r.map(|v| v.value))
     }
 }
 
+/// The frame calling `method`, continuing the trace of whatever is being handled at the moment.
+pub(crate) fn call_frame(method: impl ToString, args: impl IntoIterator<Item = impl ToString>) -> (Kind, Vec<String>) {
+    let mut frame: Vec<String> = Vec::new();
+    let kind = match trace::current() {
+        Some(id) => {
+            frame.push(id.to_string());
+            Kind::TracedCall
+        },
+        None => Kind::Call,
+    };
+    frame.push(method.to_string());
+    frame.extend(args.into_iter().map(|v| v.to_string()));
+    (kind, frame)
+}
+
+/// A service refusing a connection says why before hanging up, which can
+/// make sending the call fail. `received` is what could be read after that.
+pub(crate) fn refusal(received: io::Result<Option<wire::Message>>) -> Option<wire::Message> {
+    received.ok().flatten().filter(|msg| msg.kind == Kind::Busy)
+}
+
+/// What the service answered, as far as the caller is concerned.
+pub(crate) fn into_reply(msg: wire::Message) -> Result<Reply> {
+    let value = msg.args.into_iter().next().unwrap_or_default();
+    match msg.kind {
+        Kind::Reply | Kind::Item => Ok(Reply { value, fds: msg.fds, ..Default::default() }),
+        Kind::Busy => Err(Error::Busy(value)),
+        _ => Err(Error::Remote(value)),
+    }
+}
+
+pub(crate) fn closed() -> Error {
+    Error::Io(io::ErrorKind::UnexpectedEof.into())
+}
+
+pub(crate) fn unexpected_value(name: &str, value: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected value for {}: {}", name, value)))
+}
+
+/// Property changes of a service, see [`ServiceClient::watch`]. Dropping it stops watching.
+pub struct Watcher {
+    stream: UnixStream,
+    timeout: Option<Duration>,
+}
+
+impl Watcher {
+    /// How long [`Watcher::next_change`] waits, `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    /// Waits for the next change, fails once the service goes away or cuts
+    /// off a watcher that fell too far behind.
+    pub fn next_change(&mut self) -> Result<Change> {
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        if deadline.is_none() {
+            self.stream.set_read_timeout(None)?;
+        }
+        loop {
+            let msg = wire::recv_until(&self.stream, deadline)?.ok_or_else(closed)?;
+            if msg.kind != Kind::Signal {
+                continue;
+            }
+            let mut args = msg.args.into_iter();
+            if let (Some(name), Some(value)) = (args.next(), args.next()) {
+                return Ok(Change { name, value });
+            }
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Change;
+    /// Like [`Watcher::next_change`], ending on any error.
+    fn next(&mut self) -> Option<Change> {
+        self.next_change().ok()
+    }
+}
+
+/// Connects to `name` on the bus from [`Bus::from_env`], asking init to start it if needed.
 pub fn get_service(name: &str) -> Option<ServiceClient> {
-    let path: PathBuf = ["/srv/", name].iter().collect();
-    if !path.exists() {
-        if name == "init" {
+    get_service_on(&Bus::from_env(), name)
+}
+
+pub fn get_service_on(bus: &Bus, name: &str) -> Option<ServiceClient> {
+    if let Ok(socket) = bus.connect(name) {
+        return ServiceClient::from_socket(bus, name, socket);
+    }
+    if name == "init" {
+        return None;
+    }
+
+    let mut init = get_service_on(bus, "init")?;
+    // init holds the registry too, `name` might be an alias of something else
+    let name = match init.call_timeout(ACTIVATION_TIMEOUT, "resolve", [name]) {
+        Ok(service) if !service.is_empty() => service,
+        _ => name.to_string(),
+    };
+    let name = name.as_str();
+    if let Ok(socket) = bus.connect(name) {
+        return ServiceClient::from_socket(bus, name, socket);
+    }
+
+    if init.call_timeout(ACTIVATION_TIMEOUT, "launch_service", [name]).ok()? == "ERR" {
+        return None;
+    };
+
+    let deadline = Instant::now() + ACTIVATION_TIMEOUT;
+    loop {
+        if let Ok(socket) = bus.connect(name) {
+            return ServiceClient::from_socket(bus, name, socket);
+        }
+        if Instant::now() > deadline {
             return None;
         }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
 
-        let mut init = get_service("init")?;
-        if init.call("launch_service", [name])? == "ERR" {
-            return None;
-        };
-        std::thread::sleep(std::time::Duration::from_millis(100));
+/// Connects to the service with the newest implementation of `interface` that's at least
+/// `min_version`, as announced in the [registry](crate::registry).
+pub fn find_service(interface: &str, min_version: u32) -> Option<ServiceClient> {
+    find_service_on(&Bus::from_env(), interface, min_version)
+}
 
-        return get_service(name);
+pub fn find_service_on(bus: &Bus, interface: &str, min_version: u32) -> Option<ServiceClient> {
+    let mut registry = get_service_on(bus, REGISTRY)?;
+    let name = registry.call_timeout(ACTIVATION_TIMEOUT, "find", [interface.to_string(), min_version.to_string()]).ok()?;
+    if name.is_empty() {
+        return None;
     }
-    
-    let socket = match UnixStream::connect(path) {
-        Ok(s) => s,
-        Err(_) => return None,
-    };
+    get_service_on(bus, &name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::testing::MockService;
 
-    ServiceClient::from_socket(socket)
+    // `nap <ms>` answers after sleeping that long
+    fn sleepy() -> MockService {
+        MockService::builder("sleepy")
+            .on("nap", |args: Vec<String>| {
+                let ms = args.first().and_then(|a| a.parse().ok()).unwrap_or(0);
+                thread::sleep(Duration::from_millis(ms));
+                "rested"
+            })
+            .start()
+    }
+
+    #[test]
+    fn slow_calls_time_out() {
+        let mock = sleepy();
+        let mut client = mock.client();
+        client.set_timeout(Some(Duration::from_millis(50)));
+        assert!(matches!(client.call("nap", ["500"]), Err(Error::Timeout)));
+        // the late reply isn't taken for the answer to the next call
+        assert_eq!(client.call("nap", ["0"]).unwrap(), "rested");
+        assert_eq!(client.call_timeout(Duration::from_secs(5), "nap", ["100"]).unwrap(), "rested");
+        assert!(matches!(client.call_timeout(Duration::from_millis(50), "nap", ["500"]), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn calls_can_be_cancelled_from_another_thread() {
+        let mock = sleepy();
+        let mut client = mock.client();
+        let cancel = client.cancel_handle();
+        // nothing in progress, nothing to do
+        cancel.cancel();
+        assert_eq!(client.call("nap", ["0"]).unwrap(), "rested");
+
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let start = Instant::now();
+        assert!(matches!(client.call("nap", ["1000"]), Err(Error::Cancelled)));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        canceller.join().unwrap();
+        assert_eq!(client.call("nap", ["0"]).unwrap(), "rested");
+    }
 }
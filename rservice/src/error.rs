@@ -0,0 +1,48 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Talking to the service failed.
+    Io(io::Error),
+    /// The service didn't answer in time.
+    Timeout,
+    /// The call was aborted through a [`crate::client::CancelHandle`].
+    Cancelled,
+    /// The service answered with an error, e.g. because the method doesn't exist.
+    Remote(String),
+    /// The service is overloaded and refused the call, it may work later.
+    Busy(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Timeout => write!(f, "call timed out"),
+            Error::Cancelled => write!(f, "call was cancelled"),
+            Error::Remote(e) => write!(f, "service returned an error: {}", e),
+            Error::Busy(e) => write!(f, "service is busy: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // that's what SO_RCVTIMEO/SO_SNDTIMEO expiring looks like
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::{collections::BTreeMap, fmt, io, str::FromStr};
+use crate::properties::PropertyInfo;
+
+/// Method every server answers with its [`Introspection`], in the text form
+/// produced by its `Display` impl.
+pub const INTROSPECT: &str = "introspect";
+
+/// A named, versioned set of methods a service promises to implement, written
+/// as `org.rsystem.Input@2`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Interface {
+    pub name: String,
+    pub version: u32,
+}
+
+impl Interface {
+    pub fn new(name: impl ToString, version: u32) -> Self {
+        Self { name: name.to_string(), version }
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.name, self.version)
+    }
+}
+
+impl FromStr for Interface {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Self> {
+        let (name, version) = s.split_once('@')
+            .ok_or_else(|| invalid(s))?;
+        let version = version.parse().map_err(|_| invalid(s))?;
+        if name.is_empty() {
+            return Err(invalid(s));
+        }
+        Ok(Self::new(name, version))
+    }
+}
+
+fn invalid(s: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed interface: {}", s))
+}
+
+/// What a service says about itself.
+///
+/// Sent as one `<key> <value>` pair per line, keys this version doesn't know
+/// about are skipped so older clients keep working with newer services.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Introspection {
+    pub service: String,
+    pub interfaces: Vec<Interface>,
+    pub aliases: Vec<String>,
+    pub methods: Vec<String>,
+    /// Argument names of the methods that have them described, see
+    /// [`ServerBuilder::method_args`](crate::builder::ServerBuilder::method_args).
+    pub args: BTreeMap<String, Vec<String>>,
+    /// Fields of each line the methods that have them described answer with,
+    /// see [`ServerBuilder::method_reply`](crate::builder::ServerBuilder::method_reply).
+    pub replies: BTreeMap<String, Vec<String>>,
+    /// Load figures like `queue_depth`, as of the moment of the call.
+    pub metrics: Vec<(String, u64)>,
+    pub properties: Vec<PropertyInfo>,
+    /// Methods answering with a stream of replies.
+    pub streams: Vec<String>,
+}
+
+impl fmt::Display for Introspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "service {}", self.service)?;
+        for iface in &self.interfaces {
+            writeln!(f, "interface {}", iface)?;
+        }
+        for alias in &self.aliases {
+            writeln!(f, "alias {}", alias)?;
+        }
+        for method in &self.methods {
+            writeln!(f, "method {}", method)?;
+        }
+        for (key, lists) in [("args", &self.args), ("reply", &self.replies)] {
+            for (method, words) in lists {
+                write!(f, "{} {}", key, method)?;
+                for word in words {
+                    write!(f, " {}", word)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        for (name, value) in &self.metrics {
+            writeln!(f, "metric {} {}", name, value)?;
+        }
+        for method in &self.streams {
+            writeln!(f, "stream {}", method)?;
+        }
+        for prop in &self.properties {
+            writeln!(f, "property {} {} {}", prop.name, prop.type_name, if prop.writable { "rw" } else { "ro" })?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Introspection {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Self> {
+        let mut ret = Self::default();
+        for line in s.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            if key == "args" || key == "reply" {
+                let mut words = value.split(' ').filter(|w| !w.is_empty()).map(str::to_string);
+                if let Some(method) = words.next() {
+                    let lists = if key == "args" { &mut ret.args } else { &mut ret.replies };
+                    lists.insert(method, words.collect());
+                }
+                continue;
+            }
+            match key {
+                "service" => ret.service = value.to_string(),
+                "interface" => ret.interfaces.push(value.parse()?),
+                "alias" => ret.aliases.push(value.to_string()),
+                "method" => ret.methods.push(value.to_string()),
+                "stream" => ret.streams.push(value.to_string()),
+                "metric" => {
+                    let metric = value.split_once(' ')
+                        .and_then(|(name, value)| Some((name.to_string(), value.parse().ok()?)));
+                    match metric {
+                        Some(metric) => ret.metrics.push(metric),
+                        None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed metric: {}", value))),
+                    }
+                },
+                "property" => {
+                    // <name> <type> <rw|ro>, the type may have spaces in it
+                    let prop = value.split_once(' ')
+                        .and_then(|(name, rest)| Some((name, rest.rsplit_once(' ')?)))
+                        .and_then(|(name, (type_name, access))| Some(PropertyInfo {
+                            name: name.to_string(),
+                            type_name: type_name.to_string(),
+                            writable: match access { "rw" => true, "ro" => false, _ => return None },
+                        }));
+                    match prop {
+                        Some(prop) => ret.properties.push(prop),
+                        None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed property: {}", value))),
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl Introspection {
+    /// Checks that a call to `method` with `argc` arguments would make sense to the service.
+    ///
+    /// In argument lists `name?` is optional and `name...` takes whatever is left over,
+    /// methods without a description accept any number of arguments.
+    pub fn check_call(&self, method: &str, argc: usize) -> Result<(), String> {
+        if !self.methods.iter().any(|m| m == method) {
+            return Err(format!("no such method: {}", method));
+        }
+        let Some(args) = self.args.get(method) else {
+            return Ok(());
+        };
+
+        let required = args.iter().filter(|a| !a.ends_with('?') && !a.ends_with("...")).count();
+        let max = if args.iter().any(|a| a.ends_with("...")) { usize::MAX } else { args.len() };
+        if argc < required || argc > max {
+            return Err(format!("{} takes ({}), got {} arguments", method, args.join(" "), argc));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn sample() -> Introspection {
+        Introspection {
+            service: "input".to_string(),
+            interfaces: vec![Interface::new("org.rsystem.Input", 2)],
+            aliases: strings(&["gamepad"]),
+            methods: strings(&["introspect", "list", "set_led"]),
+            args: BTreeMap::from([("set_led".to_string(), strings(&["pad", "state?"]))]),
+            replies: BTreeMap::from([("list".to_string(), strings(&["pad", "name", "leds"]))]),
+            metrics: vec![("queue_depth".to_string(), 3)],
+            properties: vec![PropertyInfo { name: "volume".to_string(), type_name: "Option<u8>".to_string(), writable: true }],
+            streams: strings(&["events"]),
+        }
+    }
+
+    #[test]
+    fn parsing_reverses_display() {
+        let info = sample();
+        assert_eq!(info.to_string().parse::<Introspection>().unwrap(), info);
+    }
+
+    #[test]
+    fn unknown_keys_are_skipped() {
+        let info: Introspection = "service input\nfuture thing\nmethod list\nnonsense\n".parse().unwrap();
+        assert_eq!(info.service, "input");
+        assert_eq!(info.methods, strings(&["list"]));
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        for text in ["interface org.rsystem.Input", "interface @2", "interface x@two", "metric depth", "metric depth many", "property volume u8", "property volume u8 wo"] {
+            assert!(text.parse::<Introspection>().is_err(), "{} parsed", text);
+        }
+    }
+
+    #[test]
+    fn interfaces_parse() {
+        assert_eq!("org.rsystem.Input@2".parse::<Interface>().unwrap(), Interface::new("org.rsystem.Input", 2));
+        assert!("org.rsystem.Input".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn calls_are_checked_against_the_args() {
+        let mut info = sample();
+        info.args.insert("log".to_string(), strings(&["level", "message..."]));
+        info.methods.push("log".to_string());
+
+        assert!(info.check_call("reboot", 0).is_err());
+        // no description, anything goes
+        assert!(info.check_call("list", 5).is_ok());
+        assert!(info.check_call("set_led", 0).is_err());
+        assert!(info.check_call("set_led", 1).is_ok());
+        assert!(info.check_call("set_led", 2).is_ok());
+        assert!(info.check_call("set_led", 3).is_err());
+        assert!(info.check_call("log", 0).is_err());
+        assert!(info.check_call("log", 10).is_ok());
+    }
+}
@@ -1,4 +1,30 @@
 #![feature(trait_alias)]
+#![feature(unix_socket_ancillary_data)]
+#![feature(unix_socket_peek)]
+#![feature(peer_credentials_unix_socket)]
 
+mod wire;
+mod builder;
+mod limits;
+pub mod bus;
+pub mod error;
+pub mod introspect;
+pub mod properties;
+pub mod registry;
+pub mod shutdown;
+pub mod trace;
 pub mod client;
 pub mod server;
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "async")]
+pub mod async_server;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use bus::Bus;
+pub use error::{Error, Result};
+pub use introspect::{Interface, Introspection};
+pub use properties::{Properties, Property};
+pub use shutdown::Shutdown;
+pub use wire::Reply;
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// How much work a server takes on before refusing calls with [`crate::Error::Busy`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    /// Threads running handlers, only used by the blocking server.
+    pub workers: usize,
+    /// Calls being handled or waiting for a worker, across all clients.
+    pub max_in_flight: usize,
+    /// Same, for a single client process.
+    pub max_per_client: usize,
+    /// Open connections, idle or not. Each one may hold a partly read frame.
+    pub max_connections: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            max_in_flight: 64,
+            max_per_client: 16,
+            max_connections: 256,
+        }
+    }
+}
+
+/// Client processes are told apart by the pid the kernel reports for the socket.
+pub(crate) type ClientId = Option<i32>;
+
+#[derive(Default)]
+struct Counts {
+    queued: usize,
+    running: usize,
+    connections: usize,
+    rejected: u64,
+    per_client: HashMap<ClientId, usize>,
+}
+
+/// Book-keeping of the calls a server is working on.
+pub(crate) struct Load {
+    limits: Limits,
+    counts: Mutex<Counts>,
+}
+
+impl Load {
+    pub fn new(limits: Limits) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    /// Takes on a call from `client`, or says why it can't.
+    pub fn admit(self: &Arc<Self>, client: ClientId) -> Result<Permit, &'static str> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.queued + counts.running >= self.limits.max_in_flight {
+            counts.rejected += 1;
+            return Err("too many calls in flight");
+        }
+        let per_client = counts.per_client.entry(client).or_default();
+        if *per_client >= self.limits.max_per_client {
+            counts.rejected += 1;
+            return Err("too many calls from this client");
+        }
+        *per_client += 1;
+        counts.queued += 1;
+        Ok(Permit { load: Arc::clone(self), client, started: false })
+    }
+    /// Counts a new connection, `false` if there are too many already.
+    pub fn connected(&self) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.connections >= self.limits.max_connections {
+            counts.rejected += 1;
+            return false;
+        }
+        counts.connections += 1;
+        true
+    }
+    pub fn disconnected(&self) {
+        self.counts.lock().unwrap().connections -= 1;
+    }
+    pub fn metrics(&self) -> Vec<(String, u64)> {
+        let counts = self.counts.lock().unwrap();
+        [
+            ("queue_depth", counts.queued as u64),
+            ("running", counts.running as u64),
+            ("connections", counts.connections as u64),
+            ("rejected", counts.rejected),
+            ("max_in_flight", self.limits.max_in_flight as u64),
+            ("max_per_client", self.limits.max_per_client as u64),
+            ("max_connections", self.limits.max_connections as u64),
+        ].into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+    }
+}
+
+/// One admitted call, counted as queued until [`Permit::start`] and gone when dropped.
+pub(crate) struct Permit {
+    load: Arc<Load>,
+    client: ClientId,
+    started: bool,
+}
+
+impl Permit {
+    pub fn start(&mut self) {
+        let mut counts = self.load.counts.lock().unwrap();
+        counts.queued -= 1;
+        counts.running += 1;
+        self.started = true;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.load.counts.lock().unwrap();
+        if self.started {
+            counts.running -= 1;
+        } else {
+            counts.queued -= 1;
+        }
+        if let Some(n) = counts.per_client.get_mut(&self.client) {
+            *n -= 1;
+            if *n == 0 {
+                counts.per_client.remove(&self.client);
+            }
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! Typed state clients can read, change and watch, without hand-written getters and setters.
+//!
+//! A server with properties answers four more methods: [`GET`], [`SET`],
+//! [`GET_ALL`] and [`WATCH`]. Values travel as their `Display` form and are
+//! parsed back with `FromStr`.
+
+use std::{
+    any::{type_name, Any},
+    collections::BTreeMap,
+    fmt,
+    net::Shutdown,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    os::unix::net::UnixStream,
+};
+use crate::wire::{self, Kind, Reply};
+
+/// `get_property <name>`, the current value.
+pub const GET: &str = "get_property";
+/// `set_property <name> <value>`, for writable properties.
+pub const SET: &str = "set_property";
+/// `get_all_properties`, one `<name> <value>` line per property, see [`parse_all`].
+pub const GET_ALL: &str = "get_all_properties";
+/// `watch_properties <name>...`, watches the given properties or all of them.
+/// The connection then receives the current values followed by every change.
+pub const WATCH: &str = "watch_properties";
+
+/// The property methods and their arguments.
+pub(crate) const METHODS: [(&str, &[&str]); 4] = [
+    (GET, &["name"]),
+    (SET, &["name", "value"]),
+    (GET_ALL, &[]),
+    (WATCH, &["names..."]),
+];
+
+/// Anything that can be a property.
+pub trait PropertyValue: fmt::Display + FromStr + Clone + PartialEq + Send + Sync + 'static {}
+impl<T: fmt::Display + FromStr + Clone + PartialEq + Send + Sync + 'static> PropertyValue for T {}
+
+type Setter<T> = Box<dyn Fn(&T) -> Result<(), String> + Send + Sync>;
+
+/// Declaration of a property, given to [`ServerBuilder::property`](crate::builder::ServerBuilder::property).
+pub struct Property<T> {
+    value: T,
+    writable: bool,
+    on_set: Option<Setter<T>>,
+}
+
+impl<T: PropertyValue> Property<T> {
+    /// Only the service itself can change it, through [`Properties::set`].
+    pub fn read_only(value: T) -> Self {
+        Self { value, writable: false, on_set: None }
+    }
+    pub fn read_write(value: T) -> Self {
+        Self { value, writable: true, on_set: None }
+    }
+    /// Runs `f` when a client sets a new value, before it's stored. Returning
+    /// an error keeps the old value and fails the client's call.
+    ///
+    /// `f` runs with the properties locked, it mustn't use [`Properties`] itself.
+    pub fn on_set(mut self, f: impl Fn(&T) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        self.on_set = Some(Box::new(f));
+        self
+    }
+}
+
+// std::any::type_name() with the module paths stripped, alloc::string::String -> String
+fn short_type_name<T>() -> String {
+    let mut ret = String::new();
+    let mut word = String::new();
+    for c in type_name::<T>().chars() {
+        if c.is_alphanumeric() || c == '_' || c == ':' {
+            word.push(c);
+            continue;
+        }
+        ret.push_str(word.rsplit("::").next().unwrap_or_default());
+        word.clear();
+        ret.push(c);
+    }
+    ret.push_str(word.rsplit("::").next().unwrap_or_default());
+    ret
+}
+
+trait AnyProperty: Send + Sync {
+    fn value(&self) -> String;
+    /// Returns whether the value changed.
+    fn set_str(&mut self, value: &str) -> Result<bool, String>;
+    fn writable(&self) -> bool;
+    fn type_name(&self) -> String;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: PropertyValue> AnyProperty for Property<T> {
+    fn value(&self) -> String {
+        self.value.to_string()
+    }
+    fn set_str(&mut self, value: &str) -> Result<bool, String> {
+        let value: T = value.parse()
+            .map_err(|_| format!("not a valid {}: {}", short_type_name::<T>(), value))?;
+        if let Some(on_set) = &self.on_set {
+            on_set(&value)?;
+        }
+        let changed = value != self.value;
+        self.value = value;
+        Ok(changed)
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+    fn type_name(&self) -> String {
+        short_type_name::<T>()
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A property as listed in the introspection data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PropertyInfo {
+    pub name: String,
+    pub type_name: String,
+    pub writable: bool,
+}
+
+/// One value pushed to a watcher.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub name: String,
+    pub value: String,
+}
+
+struct Subscriber {
+    id: usize,
+    stream: UnixStream,
+    // empty means all of them
+    names: Vec<String>,
+    // the watch call has to be answered before anything else is sent
+    active: bool,
+}
+
+impl Subscriber {
+    fn wants(&self, name: &str) -> bool {
+        self.names.is_empty() || self.names.iter().any(|n| n == name)
+    }
+    /// `false` if the watcher can't keep up, it's cut off then.
+    fn push(&self, name: &str, value: &str) -> bool {
+        if wire::try_send(&self.stream, Kind::Signal, &[name.to_string(), value.to_string()]) {
+            return true;
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+        false
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    props: BTreeMap<String, Box<dyn AnyProperty>>,
+    subscribers: Vec<Subscriber>,
+    next_id: usize,
+}
+
+impl Store {
+    fn notify(&mut self, name: &str) {
+        let Some(value) = self.props.get(name).map(|p| p.value()) else {
+            return;
+        };
+        self.subscribers.retain(|s| !s.active || !s.wants(name) || s.push(name, &value));
+    }
+}
+
+/// Properties of a service, shared between the server and whoever holds a clone.
+#[derive(Clone, Default)]
+pub struct Properties {
+    store: Arc<Mutex<Store>>,
+}
+
+impl Properties {
+    pub(crate) fn insert<T: PropertyValue>(&self, name: &str, prop: Property<T>) {
+        self.store.lock().unwrap().props.insert(name.to_string(), Box::new(prop));
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.store.lock().unwrap().props.is_empty()
+    }
+    pub(crate) fn info(&self) -> Vec<PropertyInfo> {
+        self.store.lock().unwrap().props.iter()
+            .map(|(name, p)| PropertyInfo { name: name.clone(), type_name: p.type_name(), writable: p.writable() })
+            .collect()
+    }
+
+    /// `None` if there's no such property or it isn't a `T`.
+    pub fn get<T: PropertyValue>(&self, name: &str) -> Option<T> {
+        let mut store = self.store.lock().unwrap();
+        let prop = store.props.get_mut(name)?.as_any_mut().downcast_mut::<Property<T>>()?;
+        Some(prop.value.clone())
+    }
+    /// Changes a property, read-only ones included, and tells the watchers.
+    /// Returns `false` if there's no such property or it isn't a `T`.
+    pub fn set<T: PropertyValue>(&self, name: &str, value: T) -> bool {
+        let mut store = self.store.lock().unwrap();
+        let Some(prop) = store.props.get_mut(name).and_then(|p| p.as_any_mut().downcast_mut::<Property<T>>()) else {
+            return false;
+        };
+        if prop.value != value {
+            prop.value = value;
+            store.notify(name);
+        }
+        true
+    }
+
+    pub(crate) fn handle_get(&self, args: Vec<String>) -> Reply {
+        let store = self.store.lock().unwrap();
+        match args.first().and_then(|name| store.props.get(name)) {
+            Some(prop) => Reply::new(prop.value()),
+            None => Reply::error(format!("no such property: {}", args.first().map_or("", |v| v.as_str()))),
+        }
+    }
+    pub(crate) fn handle_set(&self, args: Vec<String>) -> Reply {
+        let [name, value] = args.as_slice() else {
+            return Reply::error("set_property takes a name and a value");
+        };
+        let mut store = self.store.lock().unwrap();
+        let Some(prop) = store.props.get_mut(name) else {
+            return Reply::error(format!("no such property: {}", name));
+        };
+        if !prop.writable() {
+            return Reply::error(format!("{} is read-only", name));
+        }
+        match prop.set_str(value) {
+            Ok(changed) => {
+                if changed {
+                    store.notify(name);
+                }
+                Reply::new("OK")
+            },
+            Err(e) => Reply::error(e),
+        }
+    }
+    pub(crate) fn handle_get_all(&self) -> Reply {
+        let store = self.store.lock().unwrap();
+        let lines: Vec<String> = store.props.iter()
+            .map(|(name, p)| format!("{} {}", name, escape(&p.value())))
+            .collect();
+        Reply::new(lines.join("\n"))
+    }
+    /// `caller` is the connection the watch call came in on, it's kept to push changes to.
+    pub(crate) fn handle_watch(&self, names: Vec<String>, caller: Option<UnixStream>) -> Reply {
+        let Some(stream) = caller else {
+            return Reply::error("can't watch from here");
+        };
+        let mut store = self.store.lock().unwrap();
+        if let Some(name) = names.iter().find(|n| !store.props.contains_key(*n)) {
+            return Reply::error(format!("no such property: {}", name));
+        }
+        store.next_id += 1;
+        let id = store.next_id;
+        store.subscribers.push(Subscriber { id, stream, names, active: false });
+
+        let ptr = self.clone();
+        Reply::new("OK").on_sent(move || ptr.activate(id))
+    }
+    // starts the watcher off with the values as they are now
+    fn activate(&self, id: usize) {
+        let mut store = self.store.lock().unwrap();
+        let Store { props, subscribers, .. } = &mut *store;
+        subscribers.retain_mut(|s| {
+            if s.id != id {
+                return true;
+            }
+            s.active = true;
+            props.iter()
+                .filter(|(name, _)| s.wants(name))
+                .all(|(name, p)| s.push(name, &p.value()))
+        });
+    }
+    /// Hangs up on every watcher, for when the server goes away.
+    pub(crate) fn disconnect_all(&self) {
+        for s in self.store.lock().unwrap().subscribers.drain(..) {
+            let _ = s.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some(c) => ret.push(c),
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+/// Splits the answer to [`GET_ALL`] into names and values.
+pub fn parse_all(reply: &str) -> Vec<(String, String)> {
+    reply.lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(name, value)| (name.to_string(), unescape(value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+    use crate::{
+        client::{self, ServiceClient},
+        error::Error,
+        server::ServiceServer,
+        shutdown::Shutdown,
+        testing::TestBus,
+    };
+
+    struct Mixer {
+        _bus: TestBus,
+        props: Properties,
+        shutdown: Shutdown,
+        thread: Option<thread::JoinHandle<()>>,
+        client: ServiceClient,
+    }
+
+    impl Drop for Mixer {
+        fn drop(&mut self) {
+            self.shutdown.trigger();
+            let _ = self.thread.take().map(thread::JoinHandle::join);
+        }
+    }
+
+    fn mixer() -> Mixer {
+        let bus = TestBus::new();
+        let server = ServiceServer::builder("mixer", ())
+            .bus(bus.bus())
+            .property("volume", Property::read_write(50u8).on_set(|v| match *v {
+                0..=100 => Ok(()),
+                _ => Err("volume goes up to 100".to_string()),
+            }))
+            .property("card", Property::read_only("HDA Intel\nPCH".to_string()))
+            .build()
+            .unwrap();
+        let props = server.properties();
+        let shutdown = Shutdown::new();
+        let ptr = shutdown.clone();
+        let thread = thread::spawn(move || server.run_until(&ptr));
+        let client = client::get_service_on(&bus.bus(), "mixer").unwrap();
+        Mixer { _bus: bus, props, shutdown, thread: Some(thread), client }
+    }
+
+    fn change(name: &str, value: &str) -> Change {
+        Change { name: name.to_string(), value: value.to_string() }
+    }
+
+    #[test]
+    fn clients_get_and_set_properties() {
+        let mut mixer = mixer();
+        let client = &mut mixer.client;
+        assert_eq!(client.get_property::<u8>("volume").unwrap(), 50);
+        client.set_property("volume", 70).unwrap();
+        assert_eq!(client.get_property::<u8>("volume").unwrap(), 70);
+        assert_eq!(mixer.props.get::<u8>("volume"), Some(70));
+
+        for (name, value) in [("volume", "loud"), ("volume", "150"), ("card", "USB"), ("balance", "0")] {
+            assert!(matches!(client.set_property(name, value), Err(Error::Remote(_))), "{} {}", name, value);
+        }
+        assert!(matches!(client.get_property::<u8>("balance"), Err(Error::Remote(_))));
+        assert_eq!(client.get_property::<u8>("volume").unwrap(), 70);
+
+        assert_eq!(client.get_all_properties().unwrap(), vec![
+            ("card".to_string(), "HDA Intel\nPCH".to_string()),
+            ("volume".to_string(), "70".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn the_service_changes_its_own_properties() {
+        let mut mixer = mixer();
+        assert!(mixer.props.set("card", "USB".to_string()));
+        // wrong type or no such property
+        assert!(!mixer.props.set("volume", 10u32));
+        assert!(!mixer.props.set("balance", 0u8));
+        assert_eq!(mixer.props.get::<u32>("volume"), None);
+        assert_eq!(mixer.client.get_property::<String>("card").unwrap(), "USB");
+    }
+
+    #[test]
+    fn properties_are_introspected() {
+        let mut mixer = mixer();
+        let info = mixer.client.introspect().unwrap();
+        assert_eq!(info.properties, vec![
+            PropertyInfo { name: "card".to_string(), type_name: "String".to_string(), writable: false },
+            PropertyInfo { name: "volume".to_string(), type_name: "u8".to_string(), writable: true },
+        ]);
+        assert!(METHODS.iter().all(|(name, _)| info.methods.iter().any(|m| m == name)));
+    }
+
+    #[test]
+    fn watchers_get_the_current_values_then_changes() {
+        let mut mixer = mixer();
+        let mut volume = mixer.client.watch(["volume"]).unwrap();
+        let mut everything = mixer.client.watch([""; 0]).unwrap();
+        volume.set_timeout(Some(Duration::from_secs(5)));
+        everything.set_timeout(Some(Duration::from_secs(5)));
+        assert_eq!(volume.next_change().unwrap(), change("volume", "50"));
+        assert_eq!(everything.next_change().unwrap(), change("card", "HDA Intel\nPCH"));
+        assert_eq!(everything.next_change().unwrap(), change("volume", "50"));
+
+        mixer.client.set_property("volume", 60).unwrap();
+        // the same value again isn't a change
+        mixer.client.set_property("volume", 60).unwrap();
+        mixer.props.set("card", "USB".to_string());
+        mixer.props.set("volume", 80u8);
+        assert_eq!(volume.next_change().unwrap(), change("volume", "60"));
+        assert_eq!(volume.next_change().unwrap(), change("volume", "80"));
+        assert_eq!(everything.next_change().unwrap(), change("volume", "60"));
+        assert_eq!(everything.next_change().unwrap(), change("card", "USB"));
+        assert_eq!(everything.next_change().unwrap(), change("volume", "80"));
+
+        volume.set_timeout(Some(Duration::from_millis(100)));
+        assert!(matches!(volume.next_change(), Err(Error::Timeout)));
+        assert!(mixer.client.watch(["balance"]).is_err());
+
+        // watchers are hung up on when the service goes away
+        drop(mixer);
+        everything.set_timeout(Some(Duration::from_secs(5)));
+        assert!(matches!(everything.next_change(), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn get_all_replies_survive_newlines() {
+        let reply = "card HDA\\nPCH\npath C:\\\\audio\nvolume 50";
+        assert_eq!(parse_all(reply), vec![
+            ("card".to_string(), "HDA\nPCH".to_string()),
+            ("path".to_string(), "C:\\audio".to_string()),
+            ("volume".to_string(), "50".to_string()),
+        ]);
+        assert_eq!(unescape(&escape("a\\nb\nc")), "a\\nb\nc");
+    }
+}
@@ -0,0 +1,216 @@
+//! Lets clients ask for an interface or an alias instead of a socket name.
+//!
+//! Servers built with [`ServerBuilder::implements`] or [`ServerBuilder::alias`]
+//! announce themselves to the [`REGISTRY`] service, which answers:
+//!
+//! - `register <service> <entry>...`, where entries containing `@` are
+//!   interfaces (`org.rsystem.Input@2`) and the rest are aliases
+//! - `resolve <name>`, giving the service behind an alias
+//! - `find <interface> <min version>`, giving the service with the newest
+//!   matching implementation
+//!
+//! Lookups that find nothing return an empty string.
+//!
+//! [`ServerBuilder::implements`]: crate::builder::ServerBuilder::implements
+//! [`ServerBuilder::alias`]: crate::builder::ServerBuilder::alias
+
+use std::{collections::HashMap, time::Duration};
+use crate::{
+    bus::Bus,
+    client::ServiceClient,
+    introspect::{Interface, Introspection},
+    server::Method,
+};
+
+/// Service holding the registry, init serves it next to service activation.
+pub const REGISTRY: &str = "init";
+
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Entry {
+    interfaces: Vec<Interface>,
+    aliases: Vec<String>,
+    // later registrations win ties
+    seq: u64,
+}
+
+/// State of the registry service.
+///
+/// Entries outlive the services that made them, so a client can still find
+/// an implementation after it exited and have init start it again.
+#[derive(Default)]
+pub struct Registry {
+    entries: HashMap<String, Entry>,
+    next_seq: u64,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Replaces whatever `service` announced before. Aliases held by other
+    /// services move over to this one.
+    pub fn register(&mut self, service: &str, interfaces: Vec<Interface>, aliases: Vec<String>) {
+        for entry in self.entries.values_mut() {
+            entry.aliases.retain(|a| !aliases.contains(a));
+        }
+        self.next_seq += 1;
+        self.entries.insert(service.to_string(), Entry { interfaces, aliases, seq: self.next_seq });
+    }
+    /// Service behind `name`, which may be an alias or a service name.
+    pub fn resolve(&self, name: &str) -> Option<&str> {
+        if let Some((service, _)) = self.entries.get_key_value(name) {
+            return Some(service);
+        }
+        self.entries.iter()
+            .find(|(_, e)| e.aliases.iter().any(|a| a == name))
+            .map(|(service, _)| service.as_str())
+    }
+    /// Service implementing the newest version of `interface` that's at least `min_version`.
+    pub fn find(&self, interface: &str, min_version: u32) -> Option<&str> {
+        self.entries.iter()
+            .filter_map(|(service, e)| {
+                e.interfaces.iter()
+                    .filter(|i| i.name == interface && i.version >= min_version)
+                    .map(|i| (i.version, e.seq, service))
+                    .max()
+            })
+            .max()
+            .map(|(_, _, service)| service.as_str())
+    }
+    /// Methods to serve the registry with.
+    pub fn methods() -> [(&'static str, Method<Registry>); 3] {
+        [
+            ("register", Method::exclusive(register)),
+            ("resolve", Method::shared(resolve)),
+            ("find", Method::shared(find)),
+        ]
+    }
+}
+
+fn register(reg: &mut Registry, args: Vec<String>) -> String {
+    let mut args = args.into_iter();
+    let Some(service) = args.next() else {
+        return String::from("ERR");
+    };
+
+    let mut interfaces = Vec::new();
+    let mut aliases = Vec::new();
+    for entry in args {
+        if entry.contains('@') {
+            match entry.parse() {
+                Ok(iface) => interfaces.push(iface),
+                Err(e) => eprintln!("Ignoring {} announced by {}: {}", entry, service, e),
+            }
+        } else {
+            aliases.push(entry);
+        }
+    }
+    reg.register(&service, interfaces, aliases);
+
+    String::from("OK")
+}
+
+fn resolve(reg: &Registry, args: Vec<String>) -> String {
+    args.first()
+        .and_then(|name| reg.resolve(name))
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn find(reg: &Registry, args: Vec<String>) -> String {
+    let (Some(interface), Some(Ok(min_version))) = (args.first(), args.get(1).map(|v| v.parse())) else {
+        return String::new();
+    };
+    reg.find(interface, min_version)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Tells the registry about a freshly started service, if there's anything to tell.
+pub(crate) fn announce(bus: &Bus, info: &Introspection) {
+    if info.service == REGISTRY || (info.interfaces.is_empty() && info.aliases.is_empty()) {
+        return;
+    }
+    // no activation here, a bus without init just doesn't get a registry
+    let Some(mut registry) = bus.connect(REGISTRY).ok().and_then(|s| ServiceClient::from_socket(bus, REGISTRY, s)) else {
+        eprintln!("No registry on the bus, {} stays unannounced", info.service);
+        return;
+    };
+
+    let entries = info.interfaces.iter()
+        .map(|i| i.to_string())
+        .chain(info.aliases.iter().cloned());
+    let args = std::iter::once(info.service.clone()).chain(entries);
+    if let Err(e) = registry.call_timeout(ANNOUNCE_TIMEOUT, "register", args) {
+        eprintln!("Failed to announce {}: {}", info.service, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(version: u32) -> Vec<Interface> {
+        vec![Interface::new("org.rsystem.Input", version)]
+    }
+
+    #[test]
+    fn find_picks_the_newest_version() {
+        let mut reg = Registry::new();
+        reg.register("old", input(1), Vec::new());
+        reg.register("new", input(3), Vec::new());
+        reg.register("other", vec![Interface::new("org.rsystem.Audio", 5)], Vec::new());
+
+        assert_eq!(reg.find("org.rsystem.Input", 1), Some("new"));
+        assert_eq!(reg.find("org.rsystem.Input", 3), Some("new"));
+        assert_eq!(reg.find("org.rsystem.Input", 4), None);
+        assert_eq!(reg.find("org.rsystem.Video", 0), None);
+    }
+
+    #[test]
+    fn find_prefers_the_latest_registration_on_ties() {
+        let mut reg = Registry::new();
+        reg.register("first", input(2), Vec::new());
+        reg.register("second", input(2), Vec::new());
+        assert_eq!(reg.find("org.rsystem.Input", 2), Some("second"));
+
+        reg.register("first", input(2), Vec::new());
+        assert_eq!(reg.find("org.rsystem.Input", 2), Some("first"));
+    }
+
+    #[test]
+    fn aliases_move_to_the_latest_service() {
+        let mut reg = Registry::new();
+        reg.register("pipewire", Vec::new(), vec!["audio".to_string()]);
+        assert_eq!(reg.resolve("audio"), Some("pipewire"));
+        assert_eq!(reg.resolve("pipewire"), Some("pipewire"));
+
+        reg.register("pulse", Vec::new(), vec!["audio".to_string()]);
+        assert_eq!(reg.resolve("audio"), Some("pulse"));
+        assert_eq!(reg.resolve("video"), None);
+    }
+
+    #[test]
+    fn registrations_replace_earlier_ones() {
+        let mut reg = Registry::new();
+        reg.register("input", input(2), vec!["gamepad".to_string()]);
+        reg.register("input", Vec::new(), Vec::new());
+        assert_eq!(reg.find("org.rsystem.Input", 0), None);
+        assert_eq!(reg.resolve("gamepad"), None);
+    }
+
+    #[test]
+    fn methods_take_text_arguments() {
+        let mut reg = Registry::new();
+        let args = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<String>>();
+        assert_eq!(register(&mut reg, args(&["input", "org.rsystem.Input@2", "not@valid", "gamepad"])), "OK");
+        assert_eq!(register(&mut reg, Vec::new()), "ERR");
+
+        assert_eq!(resolve(&reg, args(&["gamepad"])), "input");
+        assert_eq!(resolve(&reg, args(&["keyboard"])), "");
+        assert_eq!(find(&reg, args(&["org.rsystem.Input", "1"])), "input");
+        assert_eq!(find(&reg, args(&["org.rsystem.Input", "one"])), "");
+        assert_eq!(find(&reg, args(&["org.rsystem.Input"])), "");
+    }
+}
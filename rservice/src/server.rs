@@ -1,92 +1,709 @@
 use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, PipeWriter, Read, Write},
+    iter,
+    panic::{self, AssertUnwindSafe},
     sync::{
+        mpsc,
         Arc,
+        Condvar,
         Mutex,
+        RwLock,
     },
     thread,
-    io::{Read, Write, BufReader, BufRead},
-    path::PathBuf,
-    collections::HashMap,
-    os::unix::net::{UnixStream, UnixListener},
+    time::{Duration, Instant},
+    collections::{HashMap, VecDeque},
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::net::{UnixStream, UnixListener},
+    },
+};
+use crate::{
+    bus::Claim,
+    introspect::{Introspection, INTROSPECT},
+    limits::{ClientId, Load, Permit},
+    properties::{self, Properties},
+    registry,
+    trace::{self, CallTrace, Tracer},
+    wire::{self, FrameReader, FrameWriter, Kind, Progress},
 };
+pub use crate::{builder::ServerBuilder, shutdown::Shutdown, wire::Reply};
 
+/// `srv_fn!(f)` registers `f(&mut T, Vec<String>)` under its own name,
+/// `srv_fn!(shared f)` does the same for `f(&T, Vec<String>)` and
+/// `srv_fn!(stream f)` for a streaming `f(&T, Vec<String>) -> ReplyStream`.
 #[macro_export]
 macro_rules! srv_fn {
+    (shared $a:expr) => {
+        (stringify!($a), $crate::server::Method::shared($a))
+    };
+    (stream $a:expr) => {
+        (stringify!($a), $crate::server::Method::stream($a))
+    };
     ($a:expr) => {
-        (stringify!($a), $a as fn(Arc<Mutex<Box<_>>>, Vec<_>) -> String)
-    }
+        (stringify!($a), $crate::server::Method::exclusive($a))
+    };
 }
 pub use srv_fn;
 
-//type FnMap: HashMap<String, fn(&Arc<Mutex<Box<T>>>, Vec<String>)>;
-type Method<T> = fn(Arc<Mutex<Box<T>>>, Vec<String>) -> String;
+/// Like [`srv_fn!`], for methods that also take file descriptors.
+#[macro_export]
+macro_rules! srv_fd_fn {
+    (shared $a:expr) => {
+        (stringify!($a), $crate::server::Method::shared_with_fds($a))
+    };
+    ($a:expr) => {
+        (stringify!($a), $crate::server::Method::exclusive_with_fds($a))
+    };
+}
+pub use srv_fd_fn;
+
+type SharedFn<T> = dyn Fn(&T, Vec<String>, Vec<OwnedFd>) -> Reply + Send + Sync;
+type ExclusiveFn<T> = dyn Fn(&mut T, Vec<String>, Vec<OwnedFd>) -> Reply + Send + Sync;
+type StreamFn<T> = dyn Fn(&T, Vec<String>) -> ReplyStream + Send + Sync;
+
+pub enum Method<T> {
+    /// Gets `&T`, runs concurrently with other shared methods.
+    Shared(Box<SharedFn<T>>),
+    /// Gets `&mut T`, runs alone.
+    Exclusive(Box<ExclusiveFn<T>>),
+    /// Gets `&T` to set up a [`ReplyStream`], which then runs without the state
+    /// locked. Keeps a worker busy until it ends.
+    Stream(Box<StreamFn<T>>),
+}
+
+/// Replies of a streaming method, sent one by one. An error reply ends the stream.
+///
+/// The next reply is only asked for once the previous one went out, so a slow
+/// client slows down whatever produces them rather than making them pile up.
+pub struct ReplyStream(Box<dyn Iterator<Item = Reply> + Send>);
+
+impl ReplyStream {
+    pub fn new<I>(items: I) -> Self
+    where
+        I: IntoIterator,
+        I::IntoIter: Send + 'static,
+        I::Item: Into<Reply> + 'static,
+    {
+        Self(Box::new(items.into_iter().map(Into::into)))
+    }
+    /// A stream failing right away.
+    pub fn error(msg: impl ToString) -> Self {
+        Self::new([Reply::error(msg)])
+    }
+}
 
+/// Lets stream setup fail with `?`.
+impl<E: fmt::Display> From<Result<ReplyStream, E>> for ReplyStream {
+    fn from(value: Result<ReplyStream, E>) -> Self {
+        value.unwrap_or_else(Self::error)
+    }
+}
+
+impl<T> Method<T> {
+    pub fn shared<R: Into<Reply>>(f: impl Fn(&T, Vec<String>) -> R + Send + Sync + 'static) -> Self {
+        Method::Shared(Box::new(move |state, args, _| f(state, args).into()))
+    }
+    pub fn exclusive<R: Into<Reply>>(f: impl Fn(&mut T, Vec<String>) -> R + Send + Sync + 'static) -> Self {
+        Method::Exclusive(Box::new(move |state, args, _| f(state, args).into()))
+    }
+    pub fn shared_with_fds<R: Into<Reply>>(f: impl Fn(&T, Vec<String>, Vec<OwnedFd>) -> R + Send + Sync + 'static) -> Self {
+        Method::Shared(Box::new(move |state, args, fds| f(state, args, fds).into()))
+    }
+    pub fn exclusive_with_fds<R: Into<Reply>>(f: impl Fn(&mut T, Vec<String>, Vec<OwnedFd>) -> R + Send + Sync + 'static) -> Self {
+        Method::Exclusive(Box::new(move |state, args, fds| f(state, args, fds).into()))
+    }
+    pub fn stream<S: Into<ReplyStream>>(f: impl Fn(&T, Vec<String>) -> S + Send + Sync + 'static) -> Self {
+        Method::Stream(Box::new(move |state, args| f(state, args).into()))
+    }
+}
+
+/// Removes its socket when dropped.
 pub struct ServiceServer<T> {
-    state: Arc<Mutex<Box<T>>>,
+    state: Arc<RwLock<T>>,
     listener: UnixListener,
     methods: Arc<HashMap<String, Method<T>>>,
+    load: Arc<Load>,
+    info: Introspection,
+    tracer: Arc<Tracer>,
+    properties: Properties,
+    _claim: Claim,
 }
 
-fn handle_client<T>(stream: UnixStream, methods: Arc<HashMap<String, Method<T>>>, state: Arc<Mutex<Box<T>>>) -> Option<()> {
-    let mut argv_str = String::new();
-    let mut reader = BufReader::new(stream);
-    reader.read_line(&mut argv_str).ok()?;
+/// Time a client gets to take its reply before the worker gives up on it.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where [`ServerBuilder::fallback`] keeps its method, no method name is empty.
+pub(crate) const UNKNOWN_METHOD: &str = "";
 
-    if argv_str.chars().filter(|v| v == &':').count() == 0 {
-        argv_str.pop();
-        argv_str.push(':');
-        argv_str.push('\n');
+struct Conn {
+    stream: UnixStream,
+    // idle connections are read a bit at a time, so a slow client doesn't hold up the others
+    reader: FrameReader,
+    client: ClientId,
+    load: Arc<Load>,
+}
+
+impl Conn {
+    /// Gives the stream back if there are too many connections already.
+    fn new(stream: UnixStream, load: &Arc<Load>) -> Result<Self, UnixStream> {
+        if !load.connected() {
+            return Err(stream);
+        }
+        Ok(Self {
+            client: stream.peer_cred().ok().and_then(|c| c.pid),
+            stream,
+            reader: FrameReader::new(),
+            load: Arc::clone(load),
+        })
     }
+}
 
-    let mut argv: Vec<String> = argv_str
-        .split(':')
-        .map(|v| v.to_string())
-        .filter(|v| v.len() > 0)
-        .collect();
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.load.disconnected();
+    }
+}
+
+struct Job {
+    conn: Conn,
+    msg: wire::Message,
+    permit: Permit,
+}
+
+#[derive(Default)]
+struct JobList {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
 
-    println!("{:#?}", &argv);
-    let method = argv.remove(0);
+/// Calls waiting for a worker.
+#[derive(Default)]
+struct Queue {
+    list: Mutex<JobList>,
+    ready: Condvar,
+}
+
+impl Queue {
+    fn push(&self, job: Job) {
+        self.list.lock().unwrap().jobs.push_back(job);
+        self.ready.notify_one();
+    }
+    /// `None` once the queue is closed and everything in it was taken.
+    fn pop(&self) -> Option<Job> {
+        let mut list = self.list.lock().unwrap();
+        loop {
+            if let Some(job) = list.jobs.pop_front() {
+                return Some(job);
+            }
+            if list.closed {
+                return None;
+            }
+            list = self.ready.wait(list).unwrap();
+        }
+    }
+    fn close(&self) {
+        self.list.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+thread_local! {
+    static CALLER: RefCell<Option<UnixStream>> = const { RefCell::new(None) };
+}
+
+/// Lets a long-running handler check whether the client it works for
+/// disconnected or cancelled the call, so it can stop early.
+pub fn caller_gone() -> bool {
+    CALLER.with(|c| c.borrow().as_ref().is_some_and(peer_closed))
+}
 
-    println!("{:#?}", &argv);
-    let response = if let Some(function) = methods.get(&method) {
-        function(state, argv)
-    } else {
-        String::from("\n")
+fn caller() -> Option<UnixStream> {
+    CALLER.with(|c| c.borrow().as_ref().and_then(|s| s.try_clone().ok()))
+}
+
+// nobody reads from the connection while its call is being handled,
+// so flipping it to non-blocking for a moment is fine
+fn peer_closed(stream: &UnixStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let closed = match stream.peek(&mut [0]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    };
+    let _ = stream.set_nonblocking(false);
+    closed
+}
+
+fn handle_call<T>(stream: &UnixStream, methods: &HashMap<String, Method<T>>, state: &RwLock<T>, msg: wire::Message, client: ClientId, tracer: &Tracer) -> Option<()> {
+    let Some(call) = msg.into_call() else {
+        return wire::send_error(stream, "malformed call").ok();
+    };
+    let trace = tracer.start(&call.method, client, call.trace.unwrap_or_default());
+
+    CALLER.with(|c| *c.borrow_mut() = stream.try_clone().ok());
+    let (method, args) = match methods.get(&call.method) {
+        Some(method) => (Some(method), call.args),
+        // the catch-all gets the method's name ahead of its arguments
+        None => (methods.get(UNKNOWN_METHOD), iter::once(call.method.clone()).chain(call.args).collect()),
+    };
+    let mut response = match method {
+        // a handler panicking doesn't make the state any less usable for the others
+        Some(Method::Shared(function)) => {
+            let state = state.read().unwrap_or_else(|e| e.into_inner());
+            trace.span.in_scope(|| trace::with(trace.id, || function(&state, args, call.fds)))
+        },
+        Some(Method::Exclusive(function)) => {
+            let mut state = state.write().unwrap_or_else(|e| e.into_inner());
+            trace.span.in_scope(|| trace::with(trace.id, || function(&mut state, args, call.fds)))
+        },
+        Some(Method::Stream(function)) => {
+            let items = {
+                let state = state.read().unwrap_or_else(|e| e.into_inner());
+                trace.span.in_scope(|| trace::with(trace.id, || function(&state, args)))
+            };
+            let ret = send_stream(stream, items, trace);
+            CALLER.with(|c| *c.borrow_mut() = None);
+            return ret;
+        },
+        None => {
+            trace.finish("no such method");
+            return wire::send_error(stream, &format!("no such method: {}", call.method)).ok();
+        },
     };
+    CALLER.with(|c| *c.borrow_mut() = None);
 
-    let mut stream = reader.into_inner();
-    stream.write_all(response.as_str().as_bytes()).ok()?;
-    stream.shutdown(std::net::Shutdown::Both).ok()?;
-    Some(())
+    if peer_closed(stream) {
+        trace.finish("caller gone");
+        return None;
+    }
+
+    let sent = wire::send_reply(stream, &response, Some(Instant::now() + REPLY_TIMEOUT)).ok();
+    if sent.is_some() {
+        response.sent();
+    }
+    trace.finish(match (sent, response.is_error) {
+        (None, _) => "reply failed",
+        (Some(_), true) => "error",
+        (Some(_), false) => "ok",
+    });
+    sent
 }
 
-impl<T: Send + Sync + 'static> ServiceServer<T> {
-    pub fn new(name: &str, methods: impl IntoIterator<Item = (impl ToString, Method<T>)>, v: T) -> Option<ServiceServer<T>> {
-        let path: PathBuf = ["/srv/", name].iter().collect();
-        if path.exists() {
+fn send_stream(stream: &UnixStream, mut items: ReplyStream, trace: CallTrace) -> Option<()> {
+    loop {
+        if peer_closed(stream) {
+            trace.finish("caller gone");
             return None;
         }
+        let Some(item) = trace.span.in_scope(|| trace::with(trace.id, || items.0.next())) else {
+            break;
+        };
+        if wire::send_item(stream, &item, Some(Instant::now() + REPLY_TIMEOUT)).is_err() {
+            trace.finish("reply failed");
+            return None;
+        }
+        if item.is_error {
+            trace.finish("error");
+            return Some(());
+        }
+    }
+    let sent = wire::send_until::<OwnedFd>(stream, Kind::End, &[], &[], Some(Instant::now() + REPLY_TIMEOUT)).ok();
+    trace.finish(if sent.is_some() { "ok" } else { "reply failed" });
+    sent
+}
+
+fn worker<T>(queue: Arc<Queue>, methods: Arc<HashMap<String, Method<T>>>, state: Arc<RwLock<T>>, tracer: Arc<Tracer>, done: mpsc::Sender<Conn>, mut wake: PipeWriter) {
+    while let Some(Job { conn, msg, mut permit }) = queue.pop() {
+        permit.start();
+        let ret = conn.stream.set_nonblocking(false).ok().and_then(|_| {
+            // a panicking handler would take the worker with it, and nothing starts a new one
+            panic::catch_unwind(AssertUnwindSafe(|| handle_call(&conn.stream, &methods, &state, msg, conn.client, &tracer)))
+                .unwrap_or_else(|_| {
+                    CALLER.with(|c| *c.borrow_mut() = None);
+                    wire::send_error(&conn.stream, "handler panicked").ok()
+                })
+        });
+        drop(permit);
+
+        // back to the poll loop to wait for the next call
+        if ret.is_some() && conn.stream.set_nonblocking(true).is_ok() && done.send(conn).is_ok() {
+            let _ = wake.write(&[0]);
+        }
+    }
+}
 
-        let listener = UnixListener::bind(path).ok()?;
+// whatever doesn't fit in the socket at once would hold up the poll loop, a client
+// that lets its replies pile up like that doesn't get to stay
+fn reply_now(conn: Conn, kind: Kind, value: String, idle: &mut Vec<Conn>) {
+    match FrameWriter::new::<OwnedFd>(kind, &[value], &[]).and_then(|mut w| w.advance(&conn.stream)) {
+        Ok(true) => idle.push(conn),
+        _ => eprintln!("Dropping client that doesn't take its replies"),
+    }
+}
 
-        Some(Self {
-            state: Arc::new(Mutex::new(Box::new(v))),
+fn poll_readable(fds: &[RawFd]) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<libc::pollfd> = fds.iter()
+        .map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+        .collect();
+    // SAFETY: the pointer and length describe a valid slice of pollfds
+    if unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(pollfds.iter().map(|p| p.revents != 0).collect())
+}
+
+impl<T: Send + Sync + 'static> ServerBuilder<T, Method<T>> {
+    /// Answers calls to methods that weren't registered, with the called
+    /// method's name as the first argument.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn fallback(self, method: Method<T>) -> Self {
+        self.method(UNKNOWN_METHOD, method)
+    }
+    pub fn build(mut self) -> Option<ServiceServer<T>> {
+        let (listener, claim) = match self.bus.claim(&self.name) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Failed to register {}: {}", self.name, e);
+                return None;
+            }
+        };
+        listener.set_nonblocking(true).ok()?;
+
+        if !self.properties.is_empty() {
+            let props = [(); 4].map(|_| self.properties.clone());
+            let [get, set, get_all, watch] = props;
+            self.methods.extend([
+                (properties::GET.to_string(), Method::shared(move |_: &T, args| get.handle_get(args))),
+                (properties::SET.to_string(), Method::shared(move |_: &T, args| set.handle_set(args))),
+                (properties::GET_ALL.to_string(), Method::shared(move |_: &T, _| get_all.handle_get_all())),
+                (properties::WATCH.to_string(), Method::shared(move |_: &T, args| watch.handle_watch(args, caller()))),
+            ]);
+        }
+        let mut info = self.introspection();
+        info.streams = self.methods.iter()
+            .filter(|(_, m)| matches!(m, Method::Stream(_)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        info.streams.sort();
+        registry::announce(&self.bus, &info);
+
+        Some(ServiceServer {
+            state: Arc::new(RwLock::new(self.state)),
             listener,
-            methods: Arc::new(methods.into_iter().map(|(v1, v2)| (v1.to_string(), v2)).collect()),
+            methods: Arc::new(self.methods),
+            load: Load::new(self.limits),
+            info,
+            tracer: Arc::new(Tracer { service: self.name, level: self.trace_level }),
+            properties: self.properties,
+            _claim: claim,
         })
     }
+}
+
+impl<T: Send + Sync + 'static> ServiceServer<T> {
+    /// Registers `name` on the bus from [`crate::Bus::from_env`].
+    pub fn new(name: &str, methods: impl IntoIterator<Item = (impl ToString, Method<T>)>, v: T) -> Option<ServiceServer<T>> {
+        Self::builder(name, v).methods(methods).build()
+    }
+    pub fn builder(name: &str, v: T) -> ServerBuilder<T, Method<T>> {
+        ServerBuilder::new(name, v)
+    }
+    /// Handle to change the service's properties from outside of its handlers.
+    pub fn properties(&self) -> Properties {
+        self.properties.clone()
+    }
+    /// Serves calls until SIGTERM or SIGINT, then cleans up and exits the process.
     pub fn run(self) -> ! {
-        loop {
-            for stream in self.listener.incoming() {
-                match stream {
-                    Ok(s) => {
-                        let state_ptr = Arc::clone(&self.state);
-                        let method_ptr = Arc::clone(&self.methods);
-                        thread::spawn(move || handle_client(s, method_ptr, state_ptr));
+        self.run_until(&Shutdown::on_signals());
+        std::process::exit(0);
+    }
+    /// Serves calls until `shutdown` is triggered, then waits for the calls
+    /// in progress to finish and unregisters the service.
+    ///
+    /// Idle connections are watched from the calling thread, calls are handed
+    /// to a fixed set of worker threads, see [`ServerBuilder::workers`].
+    pub fn run_until(self, shutdown: &Shutdown) {
+        let (mut wake_rx, wake_tx) = match io::pipe() {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Failed to start serving: {}", e);
+                return;
+            }
+        };
+        let (done_tx, done_rx) = mpsc::channel();
+        let queue = Arc::new(Queue::default());
+
+        let workers: Vec<_> = (0..self.load.limits().workers)
+            .filter_map(|_| {
+                let queue_ptr = Arc::clone(&queue);
+                let method_ptr = Arc::clone(&self.methods);
+                let state_ptr = Arc::clone(&self.state);
+                let tracer_ptr = Arc::clone(&self.tracer);
+                let (done, wake) = (done_tx.clone(), wake_tx.try_clone().ok()?);
+                Some(thread::spawn(move || worker(queue_ptr, method_ptr, state_ptr, tracer_ptr, done, wake)))
+            })
+            .collect();
+        drop(done_tx);
+
+        let mut wake = wake_tx;
+        shutdown.on_trigger(move || drop(wake.write(&[0])));
+
+        let mut idle: Vec<Conn> = Vec::new();
+        while !shutdown.is_triggered() {
+            let fds: Vec<RawFd> = [wake_rx.as_raw_fd(), self.listener.as_raw_fd()].into_iter()
+                .chain(idle.iter().map(|c| c.stream.as_raw_fd()))
+                .collect();
+            let ready = match poll_readable(&fds) {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Failed to wait for clients: {}", e);
+                    break;
+                }
+            };
+
+            // going backwards keeps the indexes valid while removing
+            for i in (0..idle.len()).rev().filter(|&i| ready[i + 2]) {
+                let conn = &mut idle[i];
+                match conn.reader.advance(&conn.stream) {
+                    Ok(Progress::Done(msg)) => {
+                        let conn = idle.swap_remove(i);
+                        // answered right here, the load is most interesting when all workers are stuck
+                        if msg.method() == Some(INTROSPECT) {
+                            let mut info = self.info.clone();
+                            info.metrics = self.load.metrics();
+                            info.metrics.push(("workers".to_string(), workers.len() as u64));
+                            reply_now(conn, Kind::Reply, info.to_string(), &mut idle);
+                            continue;
+                        }
+                        match self.load.admit(conn.client) {
+                            Ok(permit) => queue.push(Job { conn, msg, permit }),
+                            Err(reason) => reply_now(conn, Kind::Busy, reason.to_string(), &mut idle),
+                        }
                     },
-                    Err(e) => eprintln!("Failed to connect to client: {}", e),
+                    Ok(Progress::Pending) => (),
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => (),
+                    Ok(Progress::Closed) | Err(_) => drop(idle.swap_remove(i)),
+                }
+            }
+
+            if ready[0] {
+                let _ = wake_rx.read(&mut [0; 64]);
+                idle.extend(done_rx.try_iter());
+            }
+
+            if ready[1] {
+                loop {
+                    match self.listener.accept() {
+                        Ok((s, _)) if s.set_nonblocking(true).is_ok() => match Conn::new(s, &self.load) {
+                            Ok(conn) => idle.push(conn),
+                            Err(s) => drop(wire::try_send(&s, Kind::Busy, &["too many connections".to_string()])),
+                        },
+                        Ok(_) => (),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            eprintln!("Failed to connect to client: {}", e);
+                            break;
+                        },
+                    }
                 }
             }
         }
+
+        // idle clients and watchers get cut off, calls already taken on are left to finish
+        drop(idle);
+        self.properties.disconnect_all();
+        queue.close();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bus::Bus, client, error::Error, testing::TestBus};
+
+    fn serve<T: Send + Sync + 'static>(builder: ServerBuilder<T, Method<T>>) -> (Bus, Shutdown, thread::JoinHandle<()>) {
+        let bus = Bus::abstract_prefix(format!("rservice-server-test-{}-", std::process::id()));
+        let server = builder.bus(bus.clone()).build().expect("Failed to start the test service");
+        let shutdown = Shutdown::new();
+        let ptr = shutdown.clone();
+        let thread = thread::spawn(move || server.run_until(&ptr));
+        (bus, shutdown, thread)
+    }
+
+    #[test]
+    fn panicking_handler_keeps_its_worker() {
+        let builder = ServiceServer::builder("panics", ())
+            .workers(1)
+            .method("boom", Method::shared(|_: &(), _| -> String { panic!("boom") }))
+            .method("ok", Method::shared(|_: &(), _| "fine"));
+        let (bus, shutdown, thread) = serve(builder);
+
+        let mut client = client::get_service_on(&bus, "panics").unwrap();
+        // a dead worker would leave the call unanswered
+        client.set_timeout(Some(Duration::from_secs(5)));
+        for _ in 0..3 {
+            assert!(matches!(client.call("boom", [""; 0]), Err(Error::Remote(_))));
+            assert_eq!(client.call("ok", [""; 0]).unwrap(), "fine");
+        }
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn handlers_notice_their_caller_leaving() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let builder = ServiceServer::builder("patient", ())
+            .method("wait", Method::shared(move |_: &(), _| {
+                let start = Instant::now();
+                while !caller_gone() {
+                    if start.elapsed() > Duration::from_secs(5) {
+                        return "nobody left";
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                tx.lock().unwrap().send(()).unwrap();
+                "gone"
+            }));
+        let (bus, shutdown, thread) = serve(builder);
+
+        let mut client = client::get_service_on(&bus, "patient").unwrap();
+        assert!(matches!(client.call_timeout(Duration::from_millis(50), "wait", [""; 0]), Err(Error::Timeout)));
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_ok());
+        // handlers not working for anyone don't see it
+        assert!(!caller_gone());
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_lets_calls_in_progress_finish() {
+        let test_bus = TestBus::new();
+        let bus = test_bus.bus();
+        let server = ServiceServer::builder("slow", ())
+            .bus(bus.clone())
+            .method("nap", Method::shared(|_: &(), _| {
+                thread::sleep(Duration::from_millis(200));
+                "rested"
+            }))
+            .build()
+            .unwrap();
+        let shutdown = Shutdown::new();
+        let ptr = shutdown.clone();
+        let thread = thread::spawn(move || server.run_until(&ptr));
+
+        let mut idle = client::get_service_on(&bus, "slow").unwrap();
+        let mut busy = client::get_service_on(&bus, "slow").unwrap();
+        let call = thread::spawn(move || busy.call("nap", [""; 0]));
+        thread::sleep(Duration::from_millis(50));
+
+        shutdown.trigger();
+        assert_eq!(call.join().unwrap().unwrap(), "rested");
+        thread.join().unwrap();
+        // unregistered, and idle clients are cut off
+        assert!(!bus.path("slow").unwrap().exists());
+        assert!(client::get_service_on(&bus, "slow").is_none());
+        idle.set_timeout(Some(Duration::from_secs(1)));
+        assert!(idle.call("nap", [""; 0]).is_err());
+    }
+
+    #[test]
+    fn streams_end_and_leave_the_connection_usable() {
+        let builder = ServiceServer::builder("counter", ())
+            .method("count", Method::stream(|_: &(), args: Vec<String>| {
+                let n = args.first().and_then(|a| a.parse::<usize>().ok()).ok_or("count takes a number")?;
+                Ok::<_, &str>(ReplyStream::new((0..n).map(|i| i.to_string())))
+            }))
+            .method("broken", Method::stream(|_: &(), _| ReplyStream::new([Reply::new("a"), Reply::error("broke"), Reply::new("never")])))
+            .method("ok", Method::shared(|_: &(), _| "fine"));
+        let (bus, shutdown, thread) = serve(builder);
+
+        let mut client = client::get_service_on(&bus, "counter").unwrap();
+        let items: Vec<String> = client.call_stream("count", ["3"]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(items, ["0", "1", "2"]);
+        assert_eq!(client.call_stream("count", ["0"]).unwrap().count(), 0);
+        assert_eq!(client.call("ok", [""; 0]).unwrap(), "fine");
+
+        let mut replies = client.call_stream("broken", [""; 0]).unwrap();
+        assert_eq!(replies.next().unwrap().unwrap(), "a");
+        assert!(matches!(replies.next(), Some(Err(Error::Remote(e))) if e == "broke"));
+        assert!(replies.next().is_none());
+        assert!(matches!(client.call_stream("count", ["many"]).unwrap().next(), Some(Err(Error::Remote(_)))));
+
+        // a plain call can't take them
+        assert!(matches!(client.call("count", ["3"]), Err(Error::Remote(e)) if e.contains("call_stream")));
+        assert_eq!(client.introspect().unwrap().streams, ["broken", "count"]);
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn streams_go_at_the_pace_of_their_reader() {
+        let produced = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ptr = Arc::clone(&produced);
+        let builder = ServiceServer::builder("firehose", ())
+            .method("drink", Method::stream(move |_: &(), _| {
+                let ptr = Arc::clone(&ptr);
+                ReplyStream::new(iter::repeat_with(move || {
+                    ptr.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    "x".repeat(64 * 1024)
+                }))
+            }));
+        let (bus, shutdown, thread) = serve(builder);
+
+        let mut client = client::get_service_on(&bus, "firehose").unwrap();
+        let mut replies = client.call_stream("drink", [""; 0]).unwrap();
+        assert_eq!(replies.next().unwrap().unwrap().len(), 64 * 1024);
+        thread::sleep(Duration::from_millis(200));
+        // as much as fits in the socket, not as much as it can make
+        let ahead = produced.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(ahead < 64, "{} items produced", ahead);
+
+        // hanging up stops it
+        drop(replies);
+        thread::sleep(Duration::from_millis(200));
+        let stopped = produced.load(std::sync::atomic::Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(produced.load(std::sync::atomic::Ordering::SeqCst), stopped);
+
+        shutdown.trigger();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connections_past_the_limit_are_refused() {
+        let builder = ServiceServer::builder("crowded", ())
+            .max_connections(2)
+            .method("ok", Method::shared(|_: &(), _| "fine"));
+        let (bus, shutdown, thread) = serve(builder);
+
+        let mut clients: Vec<_> = (0..2).map(|_| client::get_service_on(&bus, "crowded").unwrap()).collect();
+        for client in &mut clients {
+            assert_eq!(client.call("ok", [""; 0]).unwrap(), "fine");
+        }
+        let mut third = client::get_service_on(&bus, "crowded").unwrap();
+        assert!(matches!(third.call("ok", [""; 0]), Err(Error::Busy(_))));
+
+        // room again once one of them is gone
+        clients.pop();
+        let reply = (0..100).find_map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            third.call("ok", [""; 0]).ok()
+        });
+        assert_eq!(reply.as_deref(), Some("fine"));
+
+        shutdown.trigger();
+        thread.join().unwrap();
     }
 }
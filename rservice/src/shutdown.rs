@@ -0,0 +1,106 @@
+use std::{
+    io::Read,
+    os::fd::IntoRawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc,
+        Mutex,
+        OnceLock,
+    },
+    thread,
+};
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Inner {
+    triggered: AtomicBool,
+    hooks: Mutex<Vec<Hook>>,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify,
+}
+
+/// Tells servers to stop accepting calls, finish the ones in progress and go away.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    // SAFETY: write() is async-signal-safe, the pipe lives until the process exits
+    unsafe { libc::write(fd, [1u8].as_ptr().cast(), 1) };
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Process-wide handle triggered by SIGTERM or SIGINT.
+    pub fn on_signals() -> Self {
+        static SIGNALS: OnceLock<Shutdown> = OnceLock::new();
+        SIGNALS.get_or_init(|| {
+            let shutdown = Shutdown::new();
+            let (mut rx, tx) = match std::io::pipe() {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Failed to set up signal handling: {}", e);
+                    return shutdown;
+                }
+            };
+            SIGNAL_PIPE.store(tx.into_raw_fd(), Ordering::Relaxed);
+
+            let handler = on_signal as extern "C" fn(libc::c_int);
+            // SAFETY: the handler only does async-signal-safe things
+            unsafe {
+                libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+                libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+            }
+
+            let ptr = shutdown.clone();
+            thread::spawn(move || {
+                let mut buf = [0u8];
+                let _ = rx.read(&mut buf);
+                ptr.trigger();
+            });
+            shutdown
+        }).clone()
+    }
+    pub fn trigger(&self) {
+        if self.inner.triggered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let hooks = std::mem::take(&mut *self.inner.hooks.lock().unwrap());
+        for hook in hooks {
+            hook();
+        }
+        #[cfg(feature = "async")]
+        self.inner.notify.notify_waiters();
+    }
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+    /// Runs `f` on trigger, or right away if that already happened.
+    pub(crate) fn on_trigger(&self, f: impl FnOnce() + Send + 'static) {
+        let mut hooks = self.inner.hooks.lock().unwrap();
+        if self.is_triggered() {
+            drop(hooks);
+            f();
+        } else {
+            hooks.push(Box::new(f));
+        }
+    }
+    /// Resolves once the shutdown is triggered.
+    #[cfg(feature = "async")]
+    pub async fn triggered(&self) {
+        let notified = self.inner.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.is_triggered() {
+            return;
+        }
+        notified.await;
+    }
+}
@@ -0,0 +1,379 @@
+//! Stand-ins for real services, so clients can be tested without `/srv` or a running daemon.
+//!
+//! ```no_run
+//! use rservice::testing::MockService;
+//!
+//! let mock = MockService::builder("input")
+//!     .expect("set_led", ["1", "on"], "OK")
+//!     .on("list", |_| "pad0 pad1")
+//!     .start();
+//!
+//! let mut client = mock.client();
+//! assert_eq!(client.call("list", [""; 0]).unwrap(), "pad0 pad1");
+//! assert_eq!(client.call("set_led", ["1", "on"]).unwrap(), "OK");
+//!
+//! assert_eq!(mock.calls().len(), 2);
+//! mock.verify();
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    env, fs,
+    net::Shutdown as SocketShutdown,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    os::unix::net::UnixStream,
+};
+use crate::{
+    bus::Bus,
+    client::{self, ServiceClient},
+    server::{Method, ServiceServer},
+    shutdown::Shutdown,
+    wire::{self, Reply},
+};
+
+fn unique_name(what: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!("rservice-{}-{}-{}", what, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A bus in a fresh temporary directory, deleted together with everything in it on drop.
+pub struct TestBus {
+    dir: PathBuf,
+}
+
+impl TestBus {
+    pub fn new() -> Self {
+        let dir = env::temp_dir().join(unique_name("bus"));
+        fs::create_dir_all(&dir).expect("Failed to create a temporary bus");
+        Self { dir }
+    }
+    pub fn bus(&self) -> Bus {
+        Bus::dir(&self.dir)
+    }
+}
+
+impl Default for TestBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A call the mock received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedCall {
+    pub method: String,
+    pub args: Vec<String>,
+    /// Number of file descriptors that came with it.
+    pub fds: usize,
+}
+
+type Handler = Arc<dyn Fn(Vec<String>) -> Reply + Send + Sync>;
+
+struct Expectation {
+    method: String,
+    args: Vec<String>,
+    reply: Reply,
+}
+
+#[derive(Default)]
+struct Script {
+    expected: VecDeque<Expectation>,
+    handlers: HashMap<String, Handler>,
+    calls: Vec<RecordedCall>,
+    failures: Vec<String>,
+}
+
+impl Script {
+    /// The reply, or the handler that makes it. Handlers run without the
+    /// script locked, so a slow one doesn't hold up the other calls.
+    fn dispatch(&mut self, method: String, args: &[String], fds: usize) -> Result<Reply, Handler> {
+        self.calls.push(RecordedCall { method: method.clone(), args: args.to_vec(), fds });
+
+        // expectations only cover their own method, other methods may be called in between
+        if let Some(pos) = self.expected.iter().position(|e| e.method == method) {
+            let exp = self.expected.remove(pos).unwrap();
+            if exp.args != args {
+                let msg = format!("{} called with {:?}, expected {:?}", method, args, exp.args);
+                self.failures.push(msg.clone());
+                return Ok(Reply::error(msg));
+            }
+            return Ok(exp.reply);
+        }
+        if let Some(handler) = self.handlers.get(&method) {
+            return Err(Arc::clone(handler));
+        }
+
+        let msg = format!("unexpected call to {} with {:?}", method, args);
+        self.failures.push(msg.clone());
+        Ok(Reply::error(msg))
+    }
+}
+
+fn answer(script: &Mutex<Script>, method: String, args: Vec<String>, fds: usize) -> Reply {
+    let ret = script.lock().unwrap().dispatch(method, &args, fds);
+    ret.unwrap_or_else(|handler| handler(args))
+}
+
+/// Sets up a [`MockService`].
+pub struct MockBuilder {
+    name: String,
+    bus: Option<Bus>,
+    script: Script,
+}
+
+impl MockBuilder {
+    /// Serves on `bus` instead of a [`TestBus`] of its own.
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+    /// Expects one call to `method` with exactly `args`, answered with `reply`.
+    /// Expected calls of the same method have to come in the order they were added.
+    pub fn expect(mut self, method: impl ToString, args: impl IntoIterator<Item = impl ToString>, reply: impl Into<Reply>) -> Self {
+        self.script.expected.push_back(Expectation {
+            method: method.to_string(),
+            args: args.into_iter().map(|a| a.to_string()).collect(),
+            reply: reply.into(),
+        });
+        self
+    }
+    /// Answers every call to `method` not covered by an expectation with `f`.
+    pub fn on<R: Into<Reply>>(mut self, method: impl ToString, f: impl Fn(Vec<String>) -> R + Send + Sync + 'static) -> Self {
+        self.script.handlers.insert(method.to_string(), Arc::new(move |args| f(args).into()));
+        self
+    }
+
+    /// Starts serving on the bus.
+    pub fn start(self) -> MockService {
+        let (test_bus, bus) = match self.bus {
+            Some(bus) => (None, bus),
+            None => {
+                let test_bus = TestBus::new();
+                let bus = test_bus.bus();
+                (Some(test_bus), bus)
+            },
+        };
+
+        let script = Arc::new(Mutex::new(self.script));
+        let methods: Vec<String> = {
+            let script = script.lock().unwrap();
+            script.expected.iter().map(|e| e.method.clone())
+                .chain(script.handlers.keys().cloned())
+                .collect()
+        };
+        let methods = methods.into_iter().map(|name| {
+            let (script, method) = (Arc::clone(&script), name.clone());
+            (name, Method::shared_with_fds(move |_: &(), args, fds| {
+                answer(&script, method.clone(), args, fds.len())
+            }))
+        });
+
+        // calls to anything else are recorded too, and fail the verification
+        let ptr = Arc::clone(&script);
+        let fallback = Method::shared_with_fds(move |_: &(), mut args: Vec<String>, fds| {
+            let method = args.remove(0);
+            answer(&ptr, method, args, fds.len())
+        });
+
+        let server = ServiceServer::builder(&self.name, ())
+            .bus(bus.clone())
+            .methods(methods)
+            .fallback(fallback)
+            .build()
+            .expect("Failed to start the mock service");
+        let shutdown = Shutdown::new();
+        let ptr = shutdown.clone();
+        let thread = thread::spawn(move || server.run_until(&ptr));
+
+        MockService {
+            name: self.name,
+            bus,
+            script,
+            stop: Stop::Server(shutdown),
+            thread: Some(thread),
+            _test_bus: test_bus,
+        }
+    }
+
+    /// Serves a single client over a socketpair, without touching any bus.
+    ///
+    /// The client can't reconnect, so a call that was cancelled or timed out
+    /// leaves it unusable.
+    pub fn start_pair(self) -> (MockService, ServiceClient) {
+        let (ours, theirs) = UnixStream::pair().expect("Failed to create a socketpair");
+        // nobody listens here, the client has nowhere to reconnect to
+        let bus = Bus::abstract_prefix(unique_name("pair"));
+        let script = Arc::new(Mutex::new(self.script));
+
+        let ptr = Arc::clone(&script);
+        let stream = ours.try_clone().expect("Failed to clone the socket");
+        let thread = thread::spawn(move || {
+            while let Ok(Some(msg)) = wire::recv_until(&stream, None) {
+                let Some(call) = msg.into_call() else {
+                    let _ = wire::send_error(&stream, "malformed call");
+                    continue;
+                };
+                let reply = answer(&ptr, call.method, call.args, call.fds.len());
+                if wire::send_reply(&stream, &reply, None).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let client = ServiceClient::from_socket(&bus, &self.name, theirs).expect("Failed to set up the client");
+        let mock = MockService {
+            name: self.name,
+            bus,
+            script,
+            stop: Stop::Pair(ours),
+            thread: Some(thread),
+            _test_bus: None,
+        };
+        (mock, client)
+    }
+}
+
+enum Stop {
+    Server(Shutdown),
+    Pair(UnixStream),
+}
+
+/// A service answering from a script, stopped when dropped.
+pub struct MockService {
+    name: String,
+    bus: Bus,
+    script: Arc<Mutex<Script>>,
+    stop: Stop,
+    thread: Option<JoinHandle<()>>,
+    _test_bus: Option<TestBus>,
+}
+
+impl MockService {
+    pub fn builder(name: &str) -> MockBuilder {
+        MockBuilder {
+            name: name.to_string(),
+            bus: None,
+            script: Script::default(),
+        }
+    }
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+    /// A new connection to the mock, not available for [`MockBuilder::start_pair`] mocks.
+    pub fn client(&self) -> ServiceClient {
+        client::get_service_on(&self.bus, &self.name).expect("Failed to connect to the mock service")
+    }
+    /// Every call received so far, in order.
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.script.lock().unwrap().calls.clone()
+    }
+    /// Panics if a call went unexpected, got the wrong arguments, or an expected one never came.
+    pub fn verify(&self) {
+        let script = self.script.lock().unwrap();
+        let mut problems = script.failures.clone();
+        problems.extend(script.expected.iter().map(|e| format!("{} with {:?} was never called", e.method, e.args)));
+        if !problems.is_empty() {
+            panic!("mock service {} wasn't used as expected:\n{}", self.name, problems.join("\n"));
+        }
+    }
+}
+
+impl Drop for MockService {
+    fn drop(&mut self) {
+        match &self.stop {
+            Stop::Server(shutdown) => shutdown.trigger(),
+            Stop::Pair(stream) => drop(stream.shutdown(SocketShutdown::Both)),
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_answers_over_a_test_bus() {
+        let bus = TestBus::new();
+        let mock = MockService::builder("input")
+            .bus(bus.bus())
+            .expect("set_led", ["1", "on"], "OK")
+            .on("list", |_| "pad0 pad1")
+            .start();
+
+        let mut client = client::get_service_on(&bus.bus(), "input").unwrap();
+        assert_eq!(client.call("list", [""; 0]).unwrap(), "pad0 pad1");
+        assert_eq!(client.call("set_led", ["1", "on"]).unwrap(), "OK");
+        assert_eq!(client.call("list", ["all"]).unwrap(), "pad0 pad1");
+
+        assert_eq!(mock.calls(), vec![
+            RecordedCall { method: "list".to_string(), args: vec![], fds: 0 },
+            RecordedCall { method: "set_led".to_string(), args: vec!["1".to_string(), "on".to_string()], fds: 0 },
+            RecordedCall { method: "list".to_string(), args: vec!["all".to_string()], fds: 0 },
+        ]);
+        mock.verify();
+    }
+
+    #[test]
+    fn slow_handlers_dont_hold_up_other_calls() {
+        let mock = MockService::builder("input")
+            .on("scan", |_| {
+                thread::sleep(std::time::Duration::from_millis(500));
+                "pad0"
+            })
+            .on("list", |_| "pad0 pad1")
+            .start();
+
+        let mut scanning = mock.client();
+        let scan = thread::spawn(move || scanning.call("scan", [""; 0]).unwrap());
+        thread::sleep(std::time::Duration::from_millis(50));
+        let mut client = mock.client();
+        client.set_timeout(Some(std::time::Duration::from_millis(200)));
+        assert_eq!(client.call("list", [""; 0]).unwrap(), "pad0 pad1");
+        assert_eq!(scan.join().unwrap(), "pad0");
+    }
+
+    #[test]
+    fn undeclared_methods_are_recorded() {
+        let mock = MockService::builder("input").on("list", |_| "").start();
+        let mut client = mock.client();
+        assert!(client.call("reboot", ["now"]).is_err());
+        assert_eq!(mock.calls(), vec![RecordedCall { method: "reboot".to_string(), args: vec!["now".to_string()], fds: 0 }]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected call to reboot")]
+    fn undeclared_methods_fail_verification() {
+        let mock = MockService::builder("input").on("list", |_| "").start();
+        let _ = mock.client().call("reboot", [""; 0]);
+        mock.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "never called")]
+    fn missing_expectations_fail_verification() {
+        let mock = MockService::builder("input").expect("set_led", ["1", "on"], "OK").start();
+        mock.verify();
+    }
+
+    #[test]
+    fn wrong_arguments_fail_the_call() {
+        let (mock, mut client) = MockService::builder("input").expect("set_led", ["1", "on"], "OK").start_pair();
+        assert!(client.call("set_led", ["1", "off"]).is_err());
+        assert!(mock.script.lock().unwrap().failures[0].contains("expected [\"1\", \"on\"]"));
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    cell::Cell,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    num::ParseIntError,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+use tracing::Span;
+pub use tracing::Level;
+use crate::limits::ClientId;
+
+/// Ties together every call made on behalf of one original call, so it can be
+/// followed from service to service.
+///
+/// A call carries the trace of whatever the caller is handling at the moment,
+/// calls made from outside of any handler start a new one on arrival.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceId(pub u64);
+
+impl TraceId {
+    pub fn new() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        // random per process, and never the same twice within it
+        TraceId(RandomState::new().hash_one(COUNTER.fetch_add(1, Ordering::Relaxed)))
+    }
+}
+
+impl Default for TraceId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for TraceId {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, ParseIntError> {
+        u64::from_str_radix(s, 16).map(TraceId)
+    }
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceId>> = const { Cell::new(None) };
+}
+
+#[cfg(feature = "async")]
+tokio::task_local! {
+    static TASK_CURRENT: TraceId;
+}
+
+/// Trace of the call being handled right now, if any.
+pub fn current() -> Option<TraceId> {
+    #[cfg(feature = "async")]
+    if let Ok(id) = TASK_CURRENT.try_with(|id| *id) {
+        return Some(id);
+    }
+    CURRENT.get()
+}
+
+/// Runs `f` as part of trace `id`, calls made from it carry that id along.
+pub fn with<R>(id: TraceId, f: impl FnOnce() -> R) -> R {
+    let prev = CURRENT.replace(Some(id));
+    let ret = f();
+    CURRENT.set(prev);
+    ret
+}
+
+/// Like [`with`], for a future.
+#[cfg(feature = "async")]
+pub async fn scope<F: std::future::Future>(id: TraceId, f: F) -> F::Output {
+    TASK_CURRENT.scope(id, f).await
+}
+
+// the level of a span or event is baked into its callsite, so pick one of five
+macro_rules! at_level {
+    ($level:expr, $mac:ident!($($args:tt)*)) => {
+        match $level {
+            Level::ERROR => tracing::$mac!(Level::ERROR, $($args)*),
+            Level::WARN => tracing::$mac!(Level::WARN, $($args)*),
+            Level::INFO => tracing::$mac!(Level::INFO, $($args)*),
+            Level::DEBUG => tracing::$mac!(Level::DEBUG, $($args)*),
+            Level::TRACE => tracing::$mac!(Level::TRACE, $($args)*),
+        }
+    };
+}
+
+/// Where and how loudly a server reports its calls.
+pub(crate) struct Tracer {
+    pub service: String,
+    pub level: Level,
+}
+
+impl Tracer {
+    pub fn start(&self, method: &str, client: ClientId, id: TraceId) -> CallTrace {
+        let span = at_level!(self.level, span!("call",
+            service = %self.service,
+            method = %method,
+            caller_pid = client,
+            trace_id = %id,
+        ));
+        CallTrace { span, level: self.level, id, start: Instant::now() }
+    }
+}
+
+/// One call being handled, reported when finished.
+pub(crate) struct CallTrace {
+    pub span: Span,
+    pub id: TraceId,
+    level: Level,
+    start: Instant,
+}
+
+impl CallTrace {
+    pub fn finish(self, result: &str) {
+        let duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        let _enter = self.span.enter();
+        at_level!(self.level, event!(duration_ms, result, "call finished"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::{
+        client,
+        server::{Method, ServiceServer},
+        shutdown::Shutdown,
+        testing::TestBus,
+        Bus,
+    };
+
+    // `whoami` answers with the trace it's part of, `relay` asks `inner` the same
+    fn serve_pair(bus: &Bus) -> (Shutdown, Vec<thread::JoinHandle<()>>) {
+        let shutdown = Shutdown::new();
+        let inner = ServiceServer::builder("inner", ())
+            .bus(bus.clone())
+            .method("whoami", Method::shared(|_: &(), _| current().map(|id| id.to_string()).unwrap_or_default()))
+            .build()
+            .unwrap();
+        let ptr = bus.clone();
+        let outer = ServiceServer::builder("outer", ())
+            .bus(bus.clone())
+            .method("relay", Method::shared(move |_: &(), _| {
+                let inner = client::get_service_on(&ptr, "inner").unwrap().call("whoami", [""; 0]).unwrap();
+                format!("{} {}", current().unwrap(), inner)
+            }))
+            .build()
+            .unwrap();
+        let threads = [inner, outer].map(|server| {
+            let ptr = shutdown.clone();
+            thread::spawn(move || server.run_until(&ptr))
+        });
+        (shutdown, threads.into())
+    }
+
+    fn stop((shutdown, threads): (Shutdown, Vec<thread::JoinHandle<()>>)) {
+        shutdown.trigger();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn ids_survive_their_text_form() {
+        let id = TraceId::new();
+        assert_ne!(id, TraceId::new());
+        assert_eq!(id.to_string().len(), 16);
+        assert_eq!(id.to_string().parse::<TraceId>().unwrap(), id);
+        assert!("not hex".parse::<TraceId>().is_err());
+    }
+
+    #[test]
+    fn with_sets_the_trace_for_its_duration() {
+        let (outer, inner) = (TraceId::new(), TraceId::new());
+        assert_eq!(current(), None);
+        with(outer, || {
+            assert_eq!(current(), Some(outer));
+            with(inner, || assert_eq!(current(), Some(inner)));
+            assert_eq!(current(), Some(outer));
+        });
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn calls_carry_the_trace_along() {
+        let test_bus = TestBus::new();
+        let servers = serve_pair(&test_bus.bus());
+        let mut client = client::get_service_on(&test_bus.bus(), "outer").unwrap();
+
+        let id = TraceId::new();
+        let reply = with(id, || client.call("relay", [""; 0])).unwrap();
+        assert_eq!(reply, format!("{} {}", id, id));
+
+        // a call from outside of any trace starts one, the nested call continues it
+        let reply = client.call("relay", [""; 0]).unwrap();
+        let (outer, inner) = reply.split_once(' ').unwrap();
+        assert_eq!(outer, inner);
+        assert_ne!(outer, id.to_string());
+
+        stop(servers);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_calls_carry_the_trace_along() {
+        let test_bus = TestBus::new();
+        let servers = serve_pair(&test_bus.bus());
+        let mut client = crate::async_client::get_service_on(&test_bus.bus(), "outer").await.unwrap();
+
+        let id = TraceId::new();
+        let reply = scope(id, client.call("relay", [""; 0])).await.unwrap();
+        assert_eq!(reply, format!("{} {}", id, id));
+
+        stop(servers);
+    }
+}
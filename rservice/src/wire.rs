@@ -0,0 +1,613 @@
+//! Wire format shared by the client and the server.
+//!
+//! Every message is a single frame:
+//!
+//! ```text
+//! u32 LE   length of everything below
+//! u8       kind
+//! u32 LE   number of arguments
+//! repeated for every argument:
+//!     u32 LE   length
+//!     [u8]     UTF-8 bytes
+//! ```
+//!
+//! File descriptors travel as `SCM_RIGHTS` ancillary data attached to the
+//! first byte of the frame they belong to.
+
+use std::{
+    fmt,
+    io::{self, IoSlice, IoSliceMut, Write},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::{AncillaryData, SocketAncillary, UnixStream},
+    },
+    time::Instant,
+};
+#[cfg(feature = "async")]
+use tokio::io::unix::AsyncFd;
+use crate::trace::TraceId;
+
+/// Linux refuses to pass more than this many descriptors in one message.
+pub const MAX_FDS: usize = 253;
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Call = 1,
+    Reply = 2,
+    Error = 3,
+    /// The server refused the call because of its load limits.
+    Busy = 4,
+    /// A call continuing a trace, the first argument is its [`TraceId`].
+    TracedCall = 5,
+    /// Pushed to a watcher, `[name, value]` of a property that changed.
+    Signal = 6,
+    /// One reply of a streaming method, more follow until [`Kind::End`] or [`Kind::Error`].
+    Item = 7,
+    /// A streaming method is done.
+    End = 8,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = io::Error;
+    fn try_from(v: u8) -> io::Result<Self> {
+        match v {
+            1 => Ok(Kind::Call),
+            2 => Ok(Kind::Reply),
+            3 => Ok(Kind::Error),
+            4 => Ok(Kind::Busy),
+            5 => Ok(Kind::TracedCall),
+            6 => Ok(Kind::Signal),
+            7 => Ok(Kind::Item),
+            8 => Ok(Kind::End),
+            _ => Err(invalid("unknown frame kind")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub kind: Kind,
+    pub args: Vec<String>,
+    pub fds: Vec<OwnedFd>,
+}
+
+impl Message {
+    /// Name of the method this frame calls, `None` if it isn't a call.
+    pub fn method(&self) -> Option<&str> {
+        match self.kind {
+            Kind::Call => self.args.first(),
+            Kind::TracedCall => self.args.get(1),
+            _ => None,
+        }.map(|v| v.as_str())
+    }
+    /// Splits a call frame into its parts, `None` if it isn't one.
+    pub fn into_call(self) -> Option<Call> {
+        let mut args = self.args.into_iter();
+        let trace = match self.kind {
+            Kind::Call => None,
+            // an id that doesn't parse loses the trace, not the call
+            Kind::TracedCall => args.next()?.parse().ok(),
+            _ => return None,
+        };
+        let method = args.next()?;
+        Some(Call { trace, method, args: args.collect(), fds: self.fds })
+    }
+}
+
+pub struct Call {
+    pub trace: Option<TraceId>,
+    pub method: String,
+    pub args: Vec<String>,
+    pub fds: Vec<OwnedFd>,
+}
+
+/// Response of a method, a string optionally accompanied by file descriptors.
+#[derive(Default)]
+pub struct Reply {
+    pub value: String,
+    pub fds: Vec<OwnedFd>,
+    pub(crate) is_error: bool,
+    pub(crate) on_sent: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reply")
+            .field("value", &self.value)
+            .field("fds", &self.fds)
+            .field("is_error", &self.is_error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Reply {
+    pub fn new(value: impl ToString) -> Self {
+        Self {
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+    /// Makes the call fail with [`crate::Error::Remote`] carrying `msg`.
+    pub fn error(msg: impl ToString) -> Self {
+        Self {
+            value: msg.to_string(),
+            is_error: true,
+            ..Default::default()
+        }
+    }
+    pub fn with_fd(mut self, fd: impl Into<OwnedFd>) -> Self {
+        self.fds.push(fd.into());
+        self
+    }
+    pub fn is_error(&self) -> bool {
+        self.is_error
+    }
+    /// Runs `f` once the reply went out, for things that must not reach the caller before it.
+    pub(crate) fn on_sent(mut self, f: impl FnOnce() + Send + Sync + 'static) -> Self {
+        self.on_sent = Some(Box::new(f));
+        self
+    }
+    pub(crate) fn sent(&mut self) {
+        if let Some(f) = self.on_sent.take() {
+            f();
+        }
+    }
+}
+
+impl From<String> for Reply {
+    fn from(value: String) -> Self {
+        Self { value, ..Default::default() }
+    }
+}
+
+/// Lets handlers return `Result`s, errors go back to the caller as [`crate::Error::Remote`].
+impl<T: Into<Reply>, E: fmt::Display> From<Result<T, E>> for Reply {
+    fn from(value: Result<T, E>) -> Self {
+        match value {
+            Ok(v) => v.into(),
+            Err(e) => Reply::error(e),
+        }
+    }
+}
+
+impl From<&str> for Reply {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// cmsg headers have to be aligned, a bare [u8; N] gives no such guarantee
+#[repr(C, align(8))]
+struct CmsgBuf([u8; 2048]);
+
+fn encode(kind: Kind, args: &[String]) -> Vec<u8> {
+    let body_len = 1 + 4 + args.iter().map(|v| 4 + v.len()).sum::<usize>();
+    let mut out = Vec::with_capacity(4 + body_len);
+    out.extend_from_slice(&(body_len as u32).to_le_bytes());
+    out.push(kind as u8);
+    out.extend_from_slice(&(args.len() as u32).to_le_bytes());
+    for arg in args {
+        out.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+        out.extend_from_slice(arg.as_bytes());
+    }
+    out
+}
+
+fn decode(body: &[u8]) -> io::Result<(Kind, Vec<String>)> {
+    fn take<'a>(body: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+        if body.len() < len {
+            return Err(invalid("truncated frame"));
+        }
+        let (head, tail) = body.split_at(len);
+        *body = tail;
+        Ok(head)
+    }
+    fn take_u32(body: &mut &[u8]) -> io::Result<usize> {
+        Ok(u32::from_le_bytes(take(body, 4)?.try_into().unwrap()) as usize)
+    }
+
+    let mut body = body;
+    let kind = Kind::try_from(take(&mut body, 1)?[0])?;
+    let argc = take_u32(&mut body)?;
+    let mut args = Vec::with_capacity(argc.min(64));
+    for _ in 0..argc {
+        let len = take_u32(&mut body)?;
+        let raw = take(&mut body, len)?;
+        args.push(String::from_utf8(raw.to_vec()).map_err(|_| invalid("argument is not UTF-8"))?);
+    }
+    if !body.is_empty() {
+        return Err(invalid("trailing bytes in frame"));
+    }
+    Ok((kind, args))
+}
+
+/// Outgoing frame, written out by repeatedly calling [`FrameWriter::advance`].
+pub struct FrameWriter<'a> {
+    frame: Vec<u8>,
+    pos: usize,
+    fds: Vec<BorrowedFd<'a>>,
+}
+
+impl<'a> FrameWriter<'a> {
+    pub fn new<F: AsFd>(kind: Kind, args: &[String], fds: &'a [F]) -> io::Result<Self> {
+        if fds.len() > MAX_FDS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many file descriptors"));
+        }
+        Ok(Self {
+            frame: encode(kind, args),
+            pos: 0,
+            fds: fds.iter().map(|v| v.as_fd()).collect(),
+        })
+    }
+
+    /// Writes as much as the socket accepts, returns `true` once the whole frame is out.
+    pub fn advance(&mut self, stream: &UnixStream) -> io::Result<bool> {
+        if self.pos == self.frame.len() {
+            return Ok(true);
+        }
+
+        let sent = if self.pos == 0 && !self.fds.is_empty() {
+            let raw_fds: Vec<RawFd> = self.fds.iter().map(|v| v.as_raw_fd()).collect();
+            let mut cmsg = CmsgBuf([0; 2048]);
+            let mut ancillary = SocketAncillary::new(&mut cmsg.0);
+            if !ancillary.add_fds(&raw_fds) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many file descriptors"));
+            }
+            stream.send_vectored_with_ancillary(&[IoSlice::new(&self.frame)], &mut ancillary)?
+        } else {
+            (&mut &*stream).write(&self.frame[self.pos..])?
+        };
+
+        if sent == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        self.pos += sent;
+        Ok(self.pos == self.frame.len())
+    }
+}
+
+pub enum Progress {
+    Pending,
+    Done(Message),
+    Closed,
+}
+
+/// Most a single [`FrameReader::advance`] reads, the buffer only grows by what actually arrives.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Incoming frame, read in by repeatedly calling [`FrameReader::advance`].
+///
+/// Reads never go past the end of the current frame, so descriptors
+/// belonging to the next frame are not picked up early.
+pub struct FrameReader {
+    // length header, then as much of the frame as arrived
+    buf: Vec<u8>,
+    // frame length from the header, once it's complete
+    len: Option<usize>,
+    fds: Vec<OwnedFd>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            len: None,
+            fds: Vec::new(),
+        }
+    }
+
+    /// Does a single read from the socket.
+    pub fn advance(&mut self, stream: &UnixStream) -> io::Result<Progress> {
+        let want = match self.len {
+            Some(len) => (4 + len - self.buf.len()).min(READ_CHUNK),
+            None => 4 - self.buf.len(),
+        };
+        let start = self.buf.len();
+        self.buf.resize(start + want, 0);
+
+        let mut cmsg = CmsgBuf([0; 2048]);
+        let mut ancillary = SocketAncillary::new(&mut cmsg.0);
+        let read = stream.recv_vectored_with_ancillary(&mut [IoSliceMut::new(&mut self.buf[start..])], &mut ancillary);
+        self.buf.truncate(start + *read.as_ref().unwrap_or(&0));
+        let n = read?;
+
+        for msg in ancillary.messages() {
+            if let Ok(AncillaryData::ScmRights(rights)) = msg {
+                // SAFETY: the kernel just installed these descriptors for us
+                self.fds.extend(rights.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
+        }
+        if ancillary.truncated() {
+            return Err(invalid("file descriptors were truncated"));
+        }
+
+        if n == 0 {
+            if self.buf.is_empty() {
+                return Ok(Progress::Closed);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        if self.len.is_none() && self.buf.len() == 4 {
+            let len = u32::from_le_bytes(self.buf[..4].try_into().unwrap()) as usize;
+            if len > MAX_FRAME {
+                return Err(invalid("frame too large"));
+            }
+            self.len = Some(len);
+        }
+        if self.len.is_none_or(|len| self.buf.len() < 4 + len) {
+            return Ok(Progress::Pending);
+        }
+
+        let (kind, args) = decode(&self.buf[4..])?;
+        let fds = std::mem::take(&mut self.fds);
+        *self = Self::new();
+        Ok(Progress::Done(Message { kind, args, fds }))
+    }
+}
+
+// SO_RCVTIMEO/SO_SNDTIMEO are per read/write, stretch them to cover the whole frame
+fn arm_deadline(stream: &UnixStream, deadline: Option<Instant>, read: bool) -> io::Result<()> {
+    let Some(deadline) = deadline else {
+        return Ok(());
+    };
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    if read {
+        stream.set_read_timeout(Some(left))
+    } else {
+        stream.set_write_timeout(Some(left))
+    }
+}
+
+/// Sends one frame, passing `fds` along with it.
+pub fn send<F: AsFd>(stream: &UnixStream, kind: Kind, args: &[String], fds: &[F]) -> io::Result<()> {
+    send_until(stream, kind, args, fds, None)
+}
+
+/// Like [`send`], but gives up with `TimedOut`/`WouldBlock` once `deadline` passes.
+pub fn send_until<F: AsFd>(stream: &UnixStream, kind: Kind, args: &[String], fds: &[F], deadline: Option<Instant>) -> io::Result<()> {
+    let mut writer = FrameWriter::new(kind, args, fds)?;
+    loop {
+        arm_deadline(stream, deadline, false)?;
+        match writer.advance(stream) {
+            Ok(true) => return Ok(()),
+            Ok(false) => (),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends a method's response, as an error frame if it is one.
+pub fn send_reply(stream: &UnixStream, reply: &Reply, deadline: Option<Instant>) -> io::Result<()> {
+    let kind = if reply.is_error { Kind::Error } else { Kind::Reply };
+    send_until(stream, kind, std::slice::from_ref(&reply.value), &reply.fds, deadline)
+}
+
+/// Sends one item of a streaming method, or the error ending it.
+pub fn send_item(stream: &UnixStream, item: &Reply, deadline: Option<Instant>) -> io::Result<()> {
+    let kind = if item.is_error { Kind::Error } else { Kind::Item };
+    send_until(stream, kind, std::slice::from_ref(&item.value), &item.fds, deadline)
+}
+
+pub fn send_error(stream: &UnixStream, msg: &str) -> io::Result<()> {
+    send::<OwnedFd>(stream, Kind::Error, &[msg.to_string()], &[])
+}
+
+/// Sends a frame without ever blocking, for pushing to peers that might not be reading.
+///
+/// Returns `false` if the frame didn't fit in the socket buffer, part of it
+/// may have gone out then and the stream is no good anymore.
+pub fn try_send(stream: &UnixStream, kind: Kind, args: &[String]) -> bool {
+    let frame = encode(kind, args);
+    // SAFETY: frame is valid for frame.len() bytes
+    let sent = unsafe {
+        libc::send(stream.as_raw_fd(), frame.as_ptr().cast(), frame.len(), libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL)
+    };
+    sent == frame.len() as isize
+}
+
+/// Receives one frame, `Ok(None)` means the peer closed the connection.
+/// Gives up with `TimedOut`/`WouldBlock` once `deadline` passes.
+pub fn recv_until(stream: &UnixStream, deadline: Option<Instant>) -> io::Result<Option<Message>> {
+    let mut reader = FrameReader::new();
+    loop {
+        arm_deadline(stream, deadline, true)?;
+        match reader.advance(stream) {
+            Ok(Progress::Done(msg)) => return Ok(Some(msg)),
+            Ok(Progress::Closed) => return Ok(None),
+            Ok(Progress::Pending) => (),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub async fn send_async<F: AsFd>(stream: &AsyncFd<UnixStream>, kind: Kind, args: &[String], fds: &[F]) -> io::Result<()> {
+    let mut writer = FrameWriter::new(kind, args, fds)?;
+    loop {
+        let mut guard = stream.writable().await?;
+        match guard.try_io(|s| writer.advance(s.get_ref())) {
+            Ok(Ok(true)) => return Ok(()),
+            Ok(Ok(false)) => (),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => (),
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => (),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub async fn send_reply_async(stream: &AsyncFd<UnixStream>, reply: &Reply) -> io::Result<()> {
+    let kind = if reply.is_error { Kind::Error } else { Kind::Reply };
+    send_async(stream, kind, std::slice::from_ref(&reply.value), &reply.fds).await
+}
+
+#[cfg(feature = "async")]
+pub async fn send_item_async(stream: &AsyncFd<UnixStream>, item: &Reply) -> io::Result<()> {
+    let kind = if item.is_error { Kind::Error } else { Kind::Item };
+    send_async(stream, kind, std::slice::from_ref(&item.value), &item.fds).await
+}
+
+#[cfg(feature = "async")]
+pub async fn send_error_async(stream: &AsyncFd<UnixStream>, msg: &str) -> io::Result<()> {
+    send_async::<OwnedFd>(stream, Kind::Error, &[msg.to_string()], &[]).await
+}
+
+#[cfg(feature = "async")]
+pub async fn recv_async(stream: &AsyncFd<UnixStream>) -> io::Result<Option<Message>> {
+    let mut reader = FrameReader::new();
+    loop {
+        let mut guard = stream.readable().await?;
+        match guard.try_io(|s| reader.advance(s.get_ref())) {
+            Ok(Ok(Progress::Done(msg))) => return Ok(Some(msg)),
+            Ok(Ok(Progress::Closed)) => return Ok(None),
+            Ok(Ok(Progress::Pending)) => (),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => (),
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    // reads one frame off a nonblocking socket, `None` once the peer is gone
+    fn read_frame(reader: &mut FrameReader, stream: &UnixStream) -> io::Result<Option<Message>> {
+        loop {
+            match reader.advance(stream) {
+                Ok(Progress::Pending) => (),
+                Ok(Progress::Done(msg)) => return Ok(Some(msg)),
+                Ok(Progress::Closed) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_reverses_encode() {
+        for sent in [args(&[]), args(&["ping"]), args(&["", "two words", "zażółć", "\0\n"])] {
+            let frame = encode(Kind::Reply, &sent);
+            assert_eq!(u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize, frame.len() - 4);
+            let (kind, got) = decode(&frame[4..]).unwrap();
+            assert_eq!(kind, Kind::Reply);
+            assert_eq!(got, sent);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_broken_frames() {
+        let frame = encode(Kind::Call, &args(&["method", "arg"]));
+        let body = &frame[4..];
+        assert!(decode(&body[..body.len() - 1]).is_err(), "truncated");
+        assert!(decode(&[body, &[0]].concat()).is_err(), "trailing bytes");
+        assert!(decode(&[&[0], &body[1..]].concat()).is_err(), "unknown kind");
+        assert!(decode(&[]).is_err(), "empty");
+
+        let mut bad_utf8 = encode(Kind::Call, &args(&["ab"]));
+        let end = bad_utf8.len();
+        bad_utf8[end - 1] = 0xff;
+        assert!(decode(&bad_utf8[4..]).is_err(), "not UTF-8");
+    }
+
+    #[test]
+    fn call_frames_split_into_calls() {
+        let msg = Message { kind: Kind::Call, args: args(&["list", "all"]), fds: Vec::new() };
+        assert_eq!(msg.method(), Some("list"));
+        let call = msg.into_call().unwrap();
+        assert_eq!((call.trace, call.method.as_str(), call.args), (None, "list", args(&["all"])));
+
+        let msg = Message { kind: Kind::TracedCall, args: args(&["not a trace", "list"]), fds: Vec::new() };
+        assert_eq!(msg.method(), Some("list"));
+        let call = msg.into_call().unwrap();
+        assert_eq!((call.trace, call.method.as_str()), (None, "list"));
+
+        let msg = Message { kind: Kind::Reply, args: args(&["list"]), fds: Vec::new() };
+        assert_eq!(msg.method(), None);
+        assert!(msg.into_call().is_none());
+    }
+
+    #[test]
+    fn frames_arriving_in_pieces_are_put_together() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        rx.set_nonblocking(true).unwrap();
+        let sent = args(&["method", &"x".repeat(3 * READ_CHUNK)]);
+        let frame = encode(Kind::Call, &sent);
+
+        let mut reader = FrameReader::new();
+        for piece in frame.chunks(1000) {
+            tx.write_all(piece).unwrap();
+            match read_frame(&mut reader, &rx) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Ok(Some(msg)) => {
+                    assert_eq!((msg.kind, msg.args), (Kind::Call, sent));
+                    return;
+                },
+                other => panic!("unexpected read result: {:?}", other.map(|m| m.map(|m| m.kind))),
+            }
+        }
+        panic!("the frame never completed");
+    }
+
+    #[test]
+    fn back_to_back_frames_keep_their_descriptors() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let (pipe, _) = io::pipe().unwrap();
+        send(&tx, Kind::Reply, &args(&["first"]), &[pipe]).unwrap();
+        send::<OwnedFd>(&tx, Kind::Reply, &args(&["second"]), &[]).unwrap();
+        drop(tx);
+
+        let mut reader = FrameReader::new();
+        let first = read_frame(&mut reader, &rx).unwrap().unwrap();
+        assert_eq!((first.args, first.fds.len()), (args(&["first"]), 1));
+        let second = read_frame(&mut reader, &rx).unwrap().unwrap();
+        assert_eq!((second.args, second.fds.len()), (args(&["second"]), 0));
+        assert!(read_frame(&mut reader, &rx).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        tx.write_all(&(MAX_FRAME as u32 + 1).to_le_bytes()).unwrap();
+        assert_eq!(read_frame(&mut FrameReader::new(), &rx).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn peer_closing_mid_frame_is_an_error() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        tx.write_all(&encode(Kind::Reply, &args(&["cut short"]))[..8]).unwrap();
+        drop(tx);
+        assert_eq!(read_frame(&mut FrameReader::new(), &rx).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn header_alone_does_not_allocate_the_frame() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        rx.set_nonblocking(true).unwrap();
+        tx.write_all(&(MAX_FRAME as u32).to_le_bytes()).unwrap();
+        tx.write_all(&[0; 100]).unwrap();
+
+        let mut reader = FrameReader::new();
+        while let Ok(Progress::Pending) = reader.advance(&rx) {}
+        assert_eq!(reader.len, Some(MAX_FRAME));
+        assert_eq!(reader.buf.len(), 4 + 100);
+        assert!(reader.buf.capacity() <= 2 * (4 + READ_CHUNK));
+    }
+}